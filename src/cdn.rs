@@ -5,4 +5,389 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-mod cloudflare;
+use std::collections::HashSet;
+use std::future::Future;
+use std::ops::Range;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use futures_util::future::BoxFuture;
+use futures_util::{stream, StreamExt as _};
+use indicatif::ProgressBar;
+use percent_encoding::{percent_encode, AsciiSet, CONTROLS};
+use rusqlite::Connection;
+use tokio::runtime::{self, Runtime};
+use url::Url;
+
+use crate::config::{Config, Provider};
+
+pub mod cloudflare;
+pub mod fastly;
+
+use self::cloudflare::Cloudflare;
+use self::fastly::Fastly;
+
+/// How many purge requests are in flight at the same time by default. Kept low, to stay well
+/// within the rate limits of the CDN APIs.
+pub const DEFAULT_CONCURRENCY: usize = 2;
+
+/// Timeout of each request to the CDN
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runtime driving the requests to the CDN. A single thread is enough, as the requests in flight
+/// are multiplexed over HTTP/2 and the rest of the pipeline waits for them anyway.
+static RUNTIME: LazyLock<Runtime> = LazyLock::new(|| {
+    runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the runtime of the CDN client")
+});
+
+/// Above this number of URLs to purge, confirmation is asked by default, as it’s likely that
+/// something regenerated the whole site
+pub const DEFAULT_CONFIRM_THRESHOLD: usize = 1000;
+
+/// A CDN, whose cache can be purged URL by URL
+pub trait Cdn: Sync {
+    /// Maximum number of URLs purged by a single API call
+    fn max_batch_size(&self) -> usize;
+
+    /// Purge the cache of these URLs, in a single API call
+    fn purge<'a>(&'a self, urls: &'a [Url]) -> BoxFuture<'a, Result<()>>;
+
+    /// Purge the cache of every URL starting with one of these prefixes, in a single API call.
+    /// Only called when the configuration enables it, see [`Provider::prefix_purge`].
+    fn purge_prefixes<'a>(&'a self, _prefixes: &'a [Url]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async { bail!("the CDN can’t purge URLs by prefix") })
+    }
+}
+
+/// Wait for the requests to the CDN, from the synchronous code
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    RUNTIME.block_on(future)
+}
+
+/// Client of the CDN APIs, keeping its connections open between requests. It negotiates HTTP/2,
+/// so that concurrent requests share a connection.
+pub fn http_client() -> Result<reqwest::Client> {
+    // Ignored when already installed, by an earlier client
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    reqwest::Client::builder()
+        .timeout(TIMEOUT)
+        .build()
+        .context("failed to build the HTTP client")
+}
+
+/// Build the client of the CDN API from the configuration. This retrieves the API token and the
+/// identifiers missing from the configuration, cached in the database.
+pub fn from_config(config: &Config, conn: &Connection) -> Result<Box<dyn Cdn>> {
+    let api_token = config.api_token()?;
+    Ok(match &config.provider {
+        Provider::Cloudflare(settings) => {
+            Box::new(Cloudflare::new(settings, config.host(), api_token, conn)?)
+        }
+        Provider::Fastly(settings) => Box::new(Fastly::new(settings, api_token)?),
+    })
+}
+
+/// Characters percent-encoded in a path segment, like the `url` crate does for special schemes
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'<')
+    .add(b'>')
+    .add(b'`')
+    .add(b'#')
+    .add(b'?')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/')
+    .add(b'%')
+    .add(b'\\');
+
+/// URLs under which the file at the relative path is served. Directory indexes are served both
+/// under their own name and under the name of the directory, like `blog/index.html` and `blog/`.
+/// The path is made of the bytes of file names, which may not be valid UTF-8.
+pub fn urls(base_url: &Url, rel_path: &[u8]) -> Result<Vec<Url>> {
+    let segments: Vec<&[u8]> = rel_path.split(|&b| b == b'/').collect();
+    let mut urls = vec![join(base_url, &segments)?];
+    if let Some((&b"index.html", dir)) = segments.split_last() {
+        // The empty segment adds the trailing slash
+        urls.push(join(base_url, &[dir, &[b""]].concat())?);
+    }
+    Ok(urls)
+}
+
+/// Prefix of the URLs of the files under the directory at the relative path, with a trailing slash
+pub fn prefix(base_url: &Url, rel_dir: &[u8]) -> Result<Url> {
+    let mut segments: Vec<&[u8]> = rel_dir.split(|&b| b == b'/').collect();
+    segments.push(b"");
+    join(base_url, &segments)
+}
+
+fn join(base_url: &Url, segments: &[&[u8]]) -> Result<Url> {
+    if base_url.cannot_be_a_base() {
+        bail!("{base_url} can’t be used as a base URL");
+    }
+    // Like `pop_if_empty`, drop the trailing slash of the base path
+    let base_path = base_url.path();
+    let mut path = base_path.strip_suffix('/').unwrap_or(base_path).to_owned();
+    for segment in segments {
+        path.push('/');
+        path.extend(percent_encode(segment, PATH_SEGMENT));
+    }
+    let mut url = base_url.clone();
+    url.set_path(&path);
+    Ok(url)
+}
+
+/// Drop the URLs of the files already listed for a previous file, to purge each of them once
+pub fn dedup<T>(files: &mut [(T, Vec<Url>)]) {
+    let mut seen = HashSet::new();
+    for (_, urls) in files {
+        urls.retain(|url| seen.insert(url.clone()));
+    }
+}
+
+/// Files purged in a single API call, along with their URLs
+pub type Batch<'a, T> = &'a [(T, Vec<Url>)];
+
+/// Batch sent, with its outcome and when it was sent and answered
+pub type Purged<'a, T> = (Batch<'a, T>, Result<()>, Range<Instant>);
+
+/// Group the URLs of the files into batches of at most `max` URLs, keeping all the URLs of a file
+/// in the same batch
+pub fn batches<T>(files: &[(T, Vec<Url>)], max: usize) -> Vec<Batch<'_, T>> {
+    let mut batches = Vec::new();
+    let (mut start, mut len) = (0, 0);
+    for (i, (_, urls)) in files.iter().enumerate() {
+        if len + urls.len() > max && i > start {
+            batches.push(&files[start..i]);
+            (start, len) = (i, 0);
+        }
+        len += urls.len();
+    }
+    if start < files.len() {
+        batches.push(&files[start..]);
+    }
+    batches
+}
+
+/// Purge the batches, with at most `concurrency` API calls in flight. Returns the outcome of each
+/// batch, in the order they were answered, with when it was sent and answered. The progress is
+/// incremented for each batch sent.
+pub fn purge_batches<'a, T: Sync>(
+    cdn: &dyn Cdn,
+    batches: Vec<Batch<'a, T>>,
+    concurrency: usize,
+    progress: &ProgressBar,
+) -> Vec<Purged<'a, T>> {
+    let purges = stream::iter(batches)
+        .map(|batch| async move {
+            let urls: Vec<Url> = batch
+                .iter()
+                .flat_map(|(_, urls)| urls.iter().cloned())
+                .collect();
+            let started = Instant::now();
+            // All the URLs of the batch may already be purged with other files
+            let result = if urls.is_empty() {
+                Ok(())
+            } else {
+                cdn.purge(&urls).await
+            };
+            progress.inc(1);
+            (batch, result, started..Instant::now())
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect();
+    block_on(purges)
+}
+
+/// ETags served by the CDN for the URLs, with at most `concurrency` requests in flight. The
+/// items are returned with the outcome of the request for their URL, `None` when the CDN sends no
+/// ETag.
+pub fn edge_etags<T>(
+    urls: Vec<(T, Url)>,
+    concurrency: usize,
+    progress: &ProgressBar,
+) -> Result<Vec<(T, Result<Option<String>>)>> {
+    let client = http_client()?;
+    let etags = stream::iter(urls)
+        .map(|(item, url)| {
+            let client = &client;
+            async move {
+                let etag = async {
+                    let response = client.head(url.as_str()).send().await?;
+                    let etag = response
+                        .error_for_status()?
+                        .headers()
+                        .get("etag")
+                        .and_then(|etag| etag.to_str().ok())
+                        .map(str::to_owned);
+                    Ok::<_, reqwest::Error>(etag)
+                }
+                .await
+                .with_context(|| format!("failed to fetch {url}"));
+                progress.inc(1);
+                (item, etag)
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect();
+    Ok(block_on(etags))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn file_urls() -> Result<()> {
+        let base_url = Url::parse("https://example.com/")?;
+        let urls = |rel_path: &str| -> Result<Vec<String>> {
+            Ok(urls(&base_url, rel_path.as_bytes())?
+                .into_iter()
+                .map(String::from)
+                .collect())
+        };
+
+        assert_eq!(vec!["https://example.com/style.css"], urls("style.css")?);
+        assert_eq!(
+            vec!["https://example.com/a%20b/c%3Fd.html"],
+            urls("a b/c?d.html")?
+        );
+        assert_eq!(
+            vec!["https://example.com/index.html", "https://example.com/"],
+            urls("index.html")?
+        );
+        assert_eq!(
+            vec![
+                "https://example.com/blog/index.html",
+                "https://example.com/blog/"
+            ],
+            urls("blog/index.html")?
+        );
+        assert_eq!(
+            vec!["https://example.com/100%25/caf%C3%A9.html"],
+            urls("100%/caf\u{e9}.html")?
+        );
+        // Latin-1 file name, not valid UTF-8
+        assert_eq!(
+            "https://example.com/caf%E9.html",
+            super::urls(&base_url, b"caf\xe9.html")?[0].as_str()
+        );
+
+        let base_url = Url::parse("https://example.com/sub")?;
+        assert_eq!(
+            "https://example.com/sub/index.html",
+            super::urls(&base_url, b"index.html")?[0].as_str()
+        );
+        assert_eq!(
+            "https://example.com/sub/blog/2024%3F/",
+            prefix(&base_url, b"blog/2024?")?.as_str()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn batching() -> Result<()> {
+        let url = Url::parse("https://example.com/")?;
+        let files: Vec<(usize, Vec<Url>)> = [1, 2, 1, 1, 2]
+            .into_iter()
+            .enumerate()
+            .map(|(i, n)| (i, vec![url.clone(); n]))
+            .collect();
+        let sizes = |max| -> Vec<usize> {
+            batches(&files, max)
+                .into_iter()
+                .map(|batch| batch.len())
+                .collect()
+        };
+
+        assert_eq!(vec![2, 2, 1], sizes(3));
+        assert_eq!(vec![5], sizes(30));
+        // A file with more URLs than the maximum still gets its own batch
+        assert_eq!(vec![1, 1, 1, 1, 1], sizes(1));
+        assert!(batches::<()>(&[], 30).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn deduplicated_urls() -> Result<()> {
+        let base_url = Url::parse("https://example.com/")?;
+        let mut files = vec![
+            ("blog/index.html", urls(&base_url, b"blog/index.html")?),
+            ("style.css", urls(&base_url, b"style.css")?),
+            ("copy", urls(&base_url, b"blog/index.html")?),
+        ];
+        dedup(&mut files);
+        assert_eq!(
+            vec![2, 1, 0],
+            files.iter().map(|(_, urls)| urls.len()).collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    /// Fails the batches starting with the URL of the error page, after letting the other
+    /// requests in flight progress
+    #[derive(Default)]
+    struct FakeCdn {
+        in_flight: AtomicUsize,
+        max_in_flight: AtomicUsize,
+    }
+
+    impl Cdn for FakeCdn {
+        fn max_batch_size(&self) -> usize {
+            2
+        }
+
+        fn purge<'a>(&'a self, urls: &'a [Url]) -> BoxFuture<'a, Result<()>> {
+            Box::pin(async move {
+                let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+                for _ in 0..3 {
+                    tokio::task::yield_now().await;
+                }
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                if urls[0].path() == "/error.html" {
+                    bail!("rate limited");
+                }
+                Ok(())
+            })
+        }
+    }
+
+    #[test]
+    fn concurrent_purges() -> Result<()> {
+        let base_url = Url::parse("https://example.com/")?;
+        let mut files = Vec::new();
+        for name in [
+            "a.html",
+            "b.html",
+            "error.html",
+            "c.html",
+            "d.html",
+            "e.html",
+        ] {
+            files.push((name, urls(&base_url, name.as_bytes())?));
+        }
+        let cdn = FakeCdn::default();
+        let batches = batches(&files, cdn.max_batch_size());
+        let progress = ProgressBar::hidden();
+        let purged = purge_batches(&cdn, batches, 2, &progress);
+
+        assert_eq!(3, purged.len());
+        assert_eq!(2, cdn.max_in_flight.load(Ordering::SeqCst));
+        assert_eq!(3, progress.position());
+        let failed: Vec<_> = purged
+            .iter()
+            .filter(|(_, result, _)| result.is_err())
+            .map(|(batch, _, _)| batch[0].0)
+            .collect();
+        assert_eq!(vec!["error.html"], failed);
+        Ok(())
+    }
+}
@@ -4,3 +4,188 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
+
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::BoxFuture;
+use reqwest::{Client, StatusCode};
+use rusqlite::Connection;
+use serde::de::IgnoredAny;
+use serde_derive::{Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use super::{block_on, http_client, Cdn};
+use crate::db;
+
+const API_URL: &str = "https://api.cloudflare.com/client/v4";
+
+/// Maximum number of URLs per purge request, on most plans. See
+/// https://developers.cloudflare.com/cache/how-to/purge-cache/purge-by-single-file/
+const MAX_URLS: usize = 30;
+
+/// Settings of the `[provider.cloudflare]` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Identifier of the zone, found in the Cloudflare dashboard. Looked up from the zone name
+    /// when not set.
+    pub zone_id: Option<String>,
+    /// Name of the zone, like example.com. Defaults to the host of the base URL or the closest
+    /// of its parent domains that is a zone.
+    pub zone_name: Option<String>,
+    /// Purge the old URLs of renamed directories by prefix, with a single URL per directory.
+    /// Only available on Enterprise plans.
+    #[serde(default)]
+    pub prefix_purge: bool,
+}
+
+pub struct Cloudflare {
+    zone_id: String,
+    api_token: String,
+    client: Client,
+}
+
+#[derive(Serialize)]
+struct PurgeRequest<'a> {
+    files: &'a [Url],
+}
+
+#[derive(Serialize)]
+struct PrefixPurgeRequest<'a> {
+    /// Without the scheme, like example.com/blog/
+    prefixes: Vec<&'a str>,
+}
+
+/// Common part of all the responses of the API, with the result specific to each endpoint
+#[derive(Deserialize)]
+struct Response<T> {
+    success: bool,
+    #[serde(default)]
+    errors: Vec<ResponseError>,
+    result: Option<T>,
+}
+
+impl<T> Response<T> {
+    /// The result, or the errors returned by the API
+    fn result(self, status: StatusCode) -> Result<Option<T>> {
+        if !self.success {
+            let errors: Vec<String> = self
+                .errors
+                .iter()
+                .map(|e| format!("{} (code {})", e.message, e.code))
+                .collect();
+            bail!("Cloudflare API returned {status}: {}", errors.join(", "));
+        }
+        Ok(self.result)
+    }
+}
+
+#[derive(Deserialize)]
+struct Zone {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+impl Cloudflare {
+    /// Client for the zone of the settings. When only its name is known, the zone is looked up
+    /// from the API once and cached in the database.
+    pub fn new(
+        settings: &Settings,
+        host: &str,
+        api_token: String,
+        conn: &Connection,
+    ) -> Result<Self> {
+        let mut cloudflare = Self {
+            zone_id: String::new(),
+            api_token,
+            client: http_client()?,
+        };
+        cloudflare.zone_id = match &settings.zone_id {
+            Some(zone_id) => zone_id.clone(),
+            None => {
+                cloudflare.cached_zone_id(settings.zone_name.as_deref().unwrap_or(host), conn)?
+            }
+        };
+        Ok(cloudflare)
+    }
+
+    fn cached_zone_id(&self, name: &str, conn: &Connection) -> Result<String> {
+        let key = format!("cloudflare.zone_id.{name}");
+        if let Some(zone_id) = db::cached_value(conn, &key)? {
+            return Ok(zone_id);
+        }
+        let zone_id = block_on(self.find_zone_id(name))?;
+        info!("Found the Cloudflare zone of {name}: {zone_id}");
+        db::set_cached_value(conn, &key, &zone_id)?;
+        Ok(zone_id)
+    }
+
+    /// Identifier of the zone with this name or, failing that, of its closest parent domain
+    async fn find_zone_id(&self, name: &str) -> Result<String> {
+        let mut candidate = name;
+        // Zones have at least two labels, like example.com
+        while candidate.contains('.') {
+            let response = self
+                .client
+                .get(format!("{API_URL}/zones"))
+                .query(&[("name", candidate)])
+                .bearer_auth(&self.api_token)
+                .send()
+                .await?;
+            let status = response.status();
+            let zones = response
+                .json::<Response<Vec<Zone>>>()
+                .await?
+                .result(status)
+                .with_context(|| format!("failed to look up the zone {candidate}"))?;
+            if let Some(zone) = zones.into_iter().flatten().next() {
+                return Ok(zone.id);
+            }
+            candidate = candidate.split_once('.').map_or("", |(_, parent)| parent);
+        }
+        Err(anyhow!(
+            "no Cloudflare zone for {name}, set zone_name or zone_id in [provider.cloudflare]"
+        ))
+    }
+
+    /// Send the purge request, whose body lists what to purge
+    async fn purge_cache(&self, body: impl serde::Serialize) -> Result<()> {
+        let response = self
+            .client
+            .post(format!("{API_URL}/zones/{}/purge_cache", self.zone_id))
+            .bearer_auth(&self.api_token)
+            .json(&body)
+            .send()
+            .await?;
+        let status = response.status();
+        response
+            .json::<Response<IgnoredAny>>()
+            .await?
+            .result(status)?;
+        Ok(())
+    }
+}
+
+impl Cdn for Cloudflare {
+    fn max_batch_size(&self) -> usize {
+        MAX_URLS
+    }
+
+    fn purge<'a>(&'a self, urls: &'a [Url]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(self.purge_cache(PurgeRequest { files: urls }))
+    }
+
+    /// See https://developers.cloudflare.com/cache/how-to/purge-cache/purge_by_prefix/
+    fn purge_prefixes<'a>(&'a self, prefixes: &'a [Url]) -> BoxFuture<'a, Result<()>> {
+        let prefixes = prefixes
+            .iter()
+            .map(|prefix| &prefix[url::Position::BeforeHost..])
+            .collect();
+        Box::pin(self.purge_cache(PrefixPurgeRequest { prefixes }))
+    }
+}
@@ -0,0 +1,74 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use anyhow::{bail, Result};
+use futures_util::future::BoxFuture;
+use reqwest::Client;
+use serde_derive::Deserialize;
+use url::Url;
+
+use super::{http_client, Cdn};
+
+const API_URL: &str = "https://api.fastly.com";
+
+/// Settings of the `[provider.fastly]` table of the configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Mark the content as stale instead of removing it, so that it can still be served if the
+    /// origin is down. See https://www.fastly.com/documentation/guides/concepts/edge-state/cache/purging/#soft-vs-hard-purging
+    #[serde(default)]
+    pub soft_purge: bool,
+}
+
+pub struct Fastly {
+    soft_purge: bool,
+    api_token: String,
+    client: Client,
+}
+
+impl Fastly {
+    pub fn new(settings: &Settings, api_token: String) -> Result<Self> {
+        Ok(Self {
+            soft_purge: settings.soft_purge,
+            api_token,
+            client: http_client()?,
+        })
+    }
+}
+
+impl Cdn for Fastly {
+    /// URLs are purged one by one, see
+    /// https://www.fastly.com/documentation/reference/api/purging/#purge-single-url
+    fn max_batch_size(&self) -> usize {
+        1
+    }
+
+    fn purge<'a>(&'a self, urls: &'a [Url]) -> BoxFuture<'a, Result<()>> {
+        Box::pin(async move {
+            for url in urls {
+                // The URL is passed without its scheme
+                let cached_url = &url[url::Position::BeforeHost..];
+                let mut request = self
+                    .client
+                    .post(format!("{API_URL}/purge/{cached_url}"))
+                    .header("Fastly-Key", &self.api_token)
+                    .header("Accept", "application/json");
+                if self.soft_purge {
+                    request = request.header("Fastly-Soft-Purge", "1");
+                }
+                let response = request.send().await?;
+                let status = response.status();
+                if !status.is_success() {
+                    let body = response.text().await.unwrap_or_default();
+                    bail!("Fastly API returned {status} for {url}: {body}");
+                }
+            }
+            Ok(())
+        })
+    }
+}
@@ -5,28 +5,388 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::fs::File;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::{File, Metadata};
 use std::hash::Hasher as _;
-use std::io::Read;
-use std::path::Path;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, RwLock};
+use std::time::UNIX_EPOCH;
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use flate2::read::MultiGzDecoder;
+use rayon::prelude::*;
+use regex::bytes::Regex;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef};
 use rusqlite::ToSql;
+use serde_derive::Deserialize;
+use tracing::debug;
 use twox_hash::XxHash64;
 
-const SEED: u64 = 0x431C_71C5_AD99_39B4;
-const CHUNK_SIZE: usize = 1 << 16;
+mod hasher;
+pub mod read_size;
+#[cfg(target_os = "linux")]
+mod uring;
 
-#[derive(Debug, Default, Clone, Copy)]
-pub struct Checksum {
-    sum: [u8; 8],
+pub use hasher::Hasher;
+pub use read_size::ReadSize;
+
+/// Scheme of the checksums computed by this version, unless another algorithm is configured
+pub const SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::XxHash64,
+    seed: 0x431C_71C5_AD99_39B4,
+    chunk_size: 1 << 16,
+    decompressed: false,
+};
+
+/// Scheme of the checksums computed before [`Algorithm::XxHash64`] hashed only the bytes read
+pub const LEGACY_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::XxHash64Legacy,
+    ..SCHEME
+};
+
+/// Files from this size are split in segments hashed in parallel, so that a single huge file
+/// doesn’t keep one core busy while the others are idle
+pub const PARALLEL_THRESHOLD: u64 = 128 << 20;
+
+/// Scheme of the checksums of the files from [`PARALLEL_THRESHOLD`], the chunks are the segments
+pub const TREE_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::XxHash64Tree,
+    seed: SCHEME.seed,
+    chunk_size: 16 << 20,
+    decompressed: false,
+};
+
+/// Scheme of the checksums of `--fast-hash`, the chunks are the parts hashed at the start and at
+/// the end of the files
+pub const PARTIAL_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::XxHash64Partial,
+    seed: SCHEME.seed,
+    chunk_size: 64 << 10,
+    decompressed: false,
+};
+
+/// Scheme of the checksums of HTML files with the parts matching `html_ignore` left out
+pub const STRIPPED_HTML_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::StrippedHtml,
+    ..SCHEME
+};
+
+/// Parts of HTML files left out of their checksums, from `html_ignore`
+static IGNORED_IN_HTML: RwLock<Vec<Regex>> = RwLock::new(Vec::new());
+
+/// Leave the parts of HTML files matching the regular expressions out of their checksums, from
+/// now on
+pub fn ignore_in_html(patterns: &[String]) -> Result<()> {
+    *IGNORED_IN_HTML.write().expect("not poisoned") = compile(patterns)?;
+    Ok(())
+}
+
+fn compile(patterns: &[String]) -> Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .map(|pattern| {
+            Regex::new(pattern)
+                .with_context(|| format!("invalid regular expression in html_ignore: {pattern}"))
+        })
+        .collect()
+}
+
+/// The content without the parts matching the regular expressions
+fn strip<'a>(content: &'a [u8], ignored: &[Regex]) -> Cow<'a, [u8]> {
+    let mut content = Cow::Borrowed(content);
+    for regex in ignored {
+        if regex.is_match(&content) {
+            content = Cow::Owned(regex.replace_all(&content, &b""[..]).into_owned());
+        }
+    }
+    content
+}
+
+/// Scheme of the checksums of the files larger than `max_hash_size`, whose size and modification
+/// time are hashed instead of their content
+pub const METADATA_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::Metadata,
+    ..SCHEME
+};
+
+/// Scheme of the SHA-256 digests computed by this version, the seed is unused
+pub const SHA256_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::Sha256,
+    seed: 0,
+    chunk_size: 1 << 16,
+    decompressed: false,
+};
+
+/// Scheme of the BLAKE3 digests computed by this version, the seed is unused
+pub const BLAKE3_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::Blake3,
+    ..SHA256_SCHEME
+};
+
+/// Scheme of the checksums of HTML files with normalized whitespace computed by this version
+pub const NORMALIZED_HTML_SCHEME: Scheme = Scheme {
+    algorithm: Algorithm::NormalizedHtml,
+    ..SCHEME
+};
+
+/// Checksums of the small files, read ahead through io_uring, many at a time. The files left out
+/// are to be hashed with [`Checksum::compute`].
+pub fn prefetch(files: &[(&Path, Scheme)]) -> HashMap<PathBuf, Checksum> {
+    #[cfg(target_os = "linux")]
+    return uring::compute_all(files);
+    #[cfg(not(target_os = "linux"))]
+    {
+        tracing::warn!("io_uring is only available on Linux, reading files with blocking reads");
+        HashMap::new()
+    }
+}
+
+/// Checksums of the files with several hard links, computed once for all their paths. Links
+/// checked at the same time may still be hashed each.
+#[derive(Default)]
+pub struct Linked(Mutex<HashMap<(LinkId, Scheme), Checksum>>);
+
+/// Device and inode of a file, along with the time of the last change of its status and its
+/// size, the same for all its hard links until one of them is written to
+type LinkId = (u64, u64, i64, i64, u64);
+
+impl Linked {
+    /// Checksum of the file, unless another hard link to it was already hashed with the scheme
+    pub fn compute(&self, path: &Path, metadata: &Metadata, scheme: Scheme) -> Result<Checksum> {
+        let Some(id) = link_id(metadata) else {
+            return Checksum::compute(path, scheme);
+        };
+        if let Some(checksum) = self.0.lock().expect("not poisoned").get(&(id, scheme)) {
+            debug!("{path:?} is a hard link to a file already hashed");
+            return Ok(*checksum);
+        }
+        let checksum = Checksum::compute(path, scheme)?;
+        self.0
+            .lock()
+            .expect("not poisoned")
+            .insert((id, scheme), checksum);
+        Ok(checksum)
+    }
+}
+
+#[cfg(unix)]
+fn link_id(metadata: &Metadata) -> Option<LinkId> {
+    use std::os::unix::fs::MetadataExt;
+
+    (metadata.nlink() > 1).then(|| {
+        (
+            metadata.dev(),
+            metadata.ino(),
+            metadata.ctime(),
+            metadata.ctime_nsec(),
+            metadata.len(),
+        )
+    })
+}
+
+#[cfg(not(unix))]
+fn link_id(_metadata: &Metadata) -> Option<LinkId> {
+    None
+}
+
+/// How a checksum is computed. It’s stored with each checksum, so that checksums are only compared
+/// with checksums computed the same way, even after the scheme changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Scheme {
+    pub algorithm: Algorithm,
+    pub seed: u64,
+    /// Size of the chunks hashed by the legacy, tree and partial algorithms, which changes their
+    /// checksums. Other algorithms read files [`read_size::current`] bytes at a time.
+    pub chunk_size: usize,
+    /// Whether the decompressed content of precompressed files is hashed, so that compressing
+    /// them again with other settings doesn’t change their checksum
+    pub decompressed: bool,
+}
+
+impl Scheme {
+    /// The same scheme, hashing the decompressed content of the file when it’s precompressed
+    pub fn decompressing(self, path: &Path) -> Scheme {
+        Scheme {
+            decompressed: Compression::of(path).is_some(),
+            ..self
+        }
+    }
+}
+
+/// Compression of precompressed variants, like `index.html.gz`, served as is to the clients that
+/// accept them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Compression {
+    Gzip,
+    Brotli,
+}
+
+impl Compression {
+    /// Compression of the file, from its extension
+    fn of(path: &Path) -> Option<Compression> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(Compression::Gzip),
+            "br" => Some(Compression::Brotli),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+pub enum Algorithm {
+    /// Fast, to detect changes
+    #[default]
+    #[serde(rename = "xxh64")]
+    XxHash64,
+    /// Like [`Algorithm::XxHash64`], but whole chunks are hashed, including what’s left in the
+    /// buffer from the previous chunk after the last reads. Only kept to compare with the checksums
+    /// computed by older versions.
+    #[serde(skip)]
+    XxHash64Legacy,
+    /// Cryptographic digest of the content, the same as `sha256sum` prints, to compare with other
+    /// tools
+    #[serde(rename = "sha256")]
+    Sha256,
+    /// Cryptographic digest of the content, faster than SHA-256, the same as `b3sum` prints
+    #[serde(rename = "blake3")]
+    Blake3,
+    /// Like [`Algorithm::XxHash64`], with runs of whitespace hashed as a single space in HTML
+    /// files, so that reformatting them isn’t a change. Other files use [`Algorithm::XxHash64`].
+    #[serde(rename = "normalized-html")]
+    NormalizedHtml,
+    /// Checksum of the checksums of segments of the file, computed in parallel, used in place of
+    /// [`Algorithm::XxHash64`] for large files
+    #[serde(skip)]
+    XxHash64Tree,
+    /// Checksum of the size, the first chunk and the last chunk of the file only
+    #[serde(skip)]
+    XxHash64Partial,
+    /// Like [`Algorithm::XxHash64`], with the parts matching `html_ignore` left out, used in place
+    /// of it for HTML files when `html_ignore` is set
+    #[serde(skip)]
+    StrippedHtml,
+    /// Checksum of the size and the modification time of the file, without reading it
+    #[serde(skip)]
+    Metadata,
+}
+
+impl Algorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::XxHash64 => "xxh64-v2",
+            Algorithm::XxHash64Legacy => "xxh64",
+            Algorithm::Sha256 => "sha256",
+            Algorithm::Blake3 => "blake3",
+            Algorithm::NormalizedHtml => "normalized-html",
+            Algorithm::XxHash64Tree => "xxh64-tree",
+            Algorithm::XxHash64Partial => "xxh64-partial",
+            Algorithm::StrippedHtml => "stripped-html",
+            Algorithm::Metadata => "metadata",
+        }
+    }
+
+    /// Whether the checksums are cryptographic digests, which practically never miss a change
+    pub fn is_cryptographic(self) -> bool {
+        matches!(self, Algorithm::Sha256 | Algorithm::Blake3)
+    }
+
+    /// Scheme of the checksums computed by this version with the algorithm
+    pub fn scheme(self) -> Scheme {
+        match self {
+            Algorithm::XxHash64 => SCHEME,
+            Algorithm::XxHash64Legacy => LEGACY_SCHEME,
+            Algorithm::Sha256 => SHA256_SCHEME,
+            Algorithm::Blake3 => BLAKE3_SCHEME,
+            Algorithm::NormalizedHtml => NORMALIZED_HTML_SCHEME,
+            Algorithm::XxHash64Tree => TREE_SCHEME,
+            Algorithm::XxHash64Partial => PARTIAL_SCHEME,
+            Algorithm::StrippedHtml => STRIPPED_HTML_SCHEME,
+            Algorithm::Metadata => METADATA_SCHEME,
+        }
+    }
+
+    /// Scheme of the checksums computed by this version with the algorithm, for a file at the
+    /// path with this size
+    pub fn scheme_for(self, path: &Path, size: u64) -> Scheme {
+        match self {
+            Algorithm::XxHash64
+                if is_html(path) && !IGNORED_IN_HTML.read().expect("not poisoned").is_empty() =>
+            {
+                STRIPPED_HTML_SCHEME
+            }
+            Algorithm::XxHash64 if size >= PARALLEL_THRESHOLD => TREE_SCHEME,
+            Algorithm::NormalizedHtml if !is_html(path) => {
+                Algorithm::XxHash64.scheme_for(path, size)
+            }
+            _ => self.scheme(),
+        }
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "xxh64-v2" => Ok(Algorithm::XxHash64),
+            "xxh64" => Ok(Algorithm::XxHash64Legacy),
+            "sha256" => Ok(Algorithm::Sha256),
+            "blake3" => Ok(Algorithm::Blake3),
+            "normalized-html" => Ok(Algorithm::NormalizedHtml),
+            "xxh64-tree" => Ok(Algorithm::XxHash64Tree),
+            "xxh64-partial" => Ok(Algorithm::XxHash64Partial),
+            "stripped-html" => Ok(Algorithm::StrippedHtml),
+            "metadata" => Ok(Algorithm::Metadata),
+            _ => bail!("unknown checksum algorithm {s}"),
+        }
+    }
+}
+
+/// Whether the file is an HTML page, from its extension
+fn is_html(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            extension.eq_ignore_ascii_case("html") || extension.eq_ignore_ascii_case("htm")
+        })
+}
+
+impl ToSql for Algorithm {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.name().into())
+    }
+}
+
+impl FromSql for Algorithm {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|e: anyhow::Error| FromSqlError::Other(e.into()))
+    }
+}
+
+/// Checksum of the content of a file, with as many bytes as its algorithm produces. Checksums
+/// are told apart by their size only, as their algorithm is stored next to them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Checksum {
+    Bits64([u8; 8]),
+    Bits256([u8; 32]),
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self::Bits64([0; 8])
+    }
 }
 
 impl From<u64> for Checksum {
     fn from(value: u64) -> Self {
-        Self {
-            sum: value.to_le_bytes(),
-        }
+        Self::Bits64(value.to_le_bytes())
     }
 }
 
@@ -34,25 +394,476 @@ impl ToSql for Checksum {
     fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
         // Need to store as bytes, because a u64 can be bigger than a i64 and sqlite only
         // supports i64 (https://www.sqlite.org/datatype3.html)
-        self.sum.to_sql()
+        match self {
+            Self::Bits64(sum) => sum.to_sql(),
+            Self::Bits256(sum) => sum.to_sql(),
+        }
+    }
+}
+
+impl FromSql for Checksum {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_blob()?.len() {
+            32 => FromSql::column_result(value).map(Self::Bits256),
+            _ => FromSql::column_result(value).map(Self::Bits64),
+        }
+    }
+}
+
+impl Display for Checksum {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bits64(sum) => write!(f, "{:016x}", u64::from_le_bytes(*sum)),
+            Self::Bits256(sum) => sum.iter().try_for_each(|byte| write!(f, "{byte:02x}")),
+        }
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = anyhow::Error;
+
+    /// Parse the hexadecimal representation, as displayed
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 64 {
+            return Ok(u64::from_str_radix(s, 16).map(Self::from)?);
+        }
+        let mut sum = [0; 32];
+        for (byte, hex) in sum.iter_mut().zip(s.as_bytes().chunks(2)) {
+            *byte = u8::from_str_radix(std::str::from_utf8(hex)?, 16)?;
+        }
+        Ok(Self::Bits256(sum))
     }
 }
 
 impl Checksum {
-    pub fn compute(path: &Path) -> Result<Checksum> {
-        let mut f = File::open(path)?;
-        let mut b = [0u8; CHUNK_SIZE];
-        let mut hasher = XxHash64::with_seed(SEED);
-        loop {
-            let n = f.read(&mut b)?;
-            // This will hash trailing null bytes, but it's fine: if a file differs only by
-            // null bytes, for our purpose, we can deem it equal and we use the size for
-            // further comparison anyway.
-            hasher.write(&b);
-            if n == 0 {
-                break;
-            }
-        }
-        Ok(hasher.finish().into())
+    /// Checksum of the file, computed with the scheme
+    pub fn compute(path: &Path, scheme: Scheme) -> Result<Checksum> {
+        if scheme.algorithm == Algorithm::Metadata {
+            return Ok(Self::of_metadata(&path.metadata()?, scheme));
+        }
+        let f = File::open(path)?;
+        if scheme.decompressed {
+            return Self::decompress(f, path, scheme);
+        }
+        Self::read(f, scheme)
+    }
+
+    /// Checksum of the size and the modification time of the file, which change along with its
+    /// content unless it’s rewritten in place with a forged modification time
+    fn of_metadata(metadata: &Metadata, scheme: Scheme) -> Checksum {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let mut hasher = XxHash64::with_seed(scheme.seed);
+        hasher.write(&metadata.len().to_le_bytes());
+        hasher.write(&modified.as_nanos().to_le_bytes());
+        hasher.finish().into()
+    }
+
+    /// Checksum of the decompressed content of the file. Precompressed files are small text
+    /// files, so they’re decompressed in memory.
+    fn decompress(f: File, path: &Path, scheme: Scheme) -> Result<Checksum> {
+        let mut content = Vec::new();
+        match Compression::of(path) {
+            Some(Compression::Gzip) => MultiGzDecoder::new(f).read_to_end(&mut content),
+            Some(Compression::Brotli) => {
+                brotli_decompressor::Decompressor::new(f, read_size::current())
+                    .read_to_end(&mut content)
+            }
+            None => BufReader::new(f).read_to_end(&mut content),
+        }
+        .with_context(|| format!("failed to decompress {path:?}"))?;
+        Ok(Self::of_bytes(&content, scheme))
+    }
+
+    /// Checksum of the content of the file, read chunk by chunk
+    fn read(mut f: File, scheme: Scheme) -> Result<Checksum> {
+        let Scheme {
+            algorithm,
+            seed,
+            chunk_size,
+            ..
+        } = scheme;
+        match algorithm {
+            Algorithm::XxHash64Legacy => {
+                let mut b = vec![0u8; chunk_size];
+                let mut hasher = XxHash64::with_seed(seed);
+                loop {
+                    let n = f.read(&mut b)?;
+                    // This will hash trailing null bytes, but it's fine: if a file differs only by
+                    // null bytes, for our purpose, we can deem it equal and we use the size for
+                    // further comparison anyway.
+                    hasher.write(&b);
+                    if n == 0 {
+                        break;
+                    }
+                }
+                Ok(hasher.finish().into())
+            }
+            Algorithm::XxHash64Partial => {
+                let size = f.metadata()?.len();
+                let mut hasher = XxHash64::with_seed(seed);
+                hasher.write(&size.to_le_bytes());
+                let mut start = Vec::with_capacity(chunk_size);
+                f.by_ref().take(chunk_size as u64).read_to_end(&mut start)?;
+                hasher.write(&start);
+                // The end doesn’t overlap with the start
+                let end_len = size
+                    .saturating_sub(chunk_size as u64)
+                    .min(chunk_size as u64);
+                let mut end = Vec::with_capacity(end_len as usize);
+                f.seek(SeekFrom::End(-(end_len as i64)))?;
+                f.take(end_len).read_to_end(&mut end)?;
+                hasher.write(&end);
+                Ok(hasher.finish().into())
+            }
+            Algorithm::StrippedHtml => {
+                // The parts to leave out may span several reads
+                let mut content = Vec::new();
+                f.read_to_end(&mut content)?;
+                Ok(Self::of_bytes(&content, scheme))
+            }
+            _ => {
+                let mut b = vec![0u8; read_size::current()];
+                let mut hasher = hasher::new(scheme).expect("the content is hashed in order");
+                loop {
+                    let n = f.read(&mut b)?;
+                    if n == 0 {
+                        break;
+                    }
+                    hasher.update(&b[..n]);
+                }
+                Ok(hasher.finish())
+            }
+        }
+    }
+
+    /// Checksum of the content, the same as [`Checksum::read`] computes for a file holding it
+    fn of_bytes(content: &[u8], scheme: Scheme) -> Checksum {
+        let Scheme {
+            algorithm,
+            seed,
+            chunk_size,
+            ..
+        } = scheme;
+        match algorithm {
+            Algorithm::XxHash64Legacy => {
+                let mut hasher = XxHash64::with_seed(seed);
+                let whole = content.len() / chunk_size * chunk_size;
+                for chunk in content[..whole].chunks_exact(chunk_size) {
+                    hasher.write(chunk);
+                }
+                // After the last read, the buffer still holds the end of the previous chunk, or
+                // zeros, and it’s hashed again when the next read finds the end of the file
+                let mut b = match whole {
+                    0 => vec![0u8; chunk_size],
+                    _ => content[whole - chunk_size..whole].to_vec(),
+                };
+                let rest = &content[whole..];
+                b[..rest.len()].copy_from_slice(rest);
+                if !rest.is_empty() {
+                    hasher.write(&b);
+                }
+                hasher.write(&b);
+                hasher.finish().into()
+            }
+            Algorithm::XxHash64Tree => {
+                let segments: Vec<u64> = content
+                    .par_chunks(chunk_size)
+                    .map(|segment| XxHash64::oneshot(seed, segment))
+                    .collect();
+                let mut tree = hasher::Tree::new(seed, chunk_size);
+                for segment in segments {
+                    tree.push(segment);
+                }
+                Hasher::finish(Box::new(tree))
+            }
+            Algorithm::XxHash64Partial => {
+                let mut hasher = XxHash64::with_seed(seed);
+                hasher.write(&(content.len() as u64).to_le_bytes());
+                let (start, rest) = content.split_at(chunk_size.min(content.len()));
+                hasher.write(start);
+                hasher.write(&rest[rest.len().saturating_sub(chunk_size)..]);
+                hasher.finish().into()
+            }
+            Algorithm::StrippedHtml => {
+                let ignored = IGNORED_IN_HTML.read().expect("not poisoned");
+                XxHash64::oneshot(seed, &strip(content, &ignored)).into()
+            }
+            _ => {
+                let mut hasher = hasher::new(scheme).expect("the content is hashed in order");
+                hasher.update(content);
+                hasher.finish()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_round_trip() {
+        let checksum = Checksum::from(0x431C_71C5_AD99_39B4);
+        assert_eq!("431c71c5ad9939b4", checksum.to_string());
+        assert_eq!(checksum, checksum.to_string().parse().unwrap());
+    }
+
+    #[test]
+    fn bytes_like_read() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file");
+        let content: Vec<u8> = (0..100).collect();
+        for len in [0, 5, 16, 20, 32, 40, 100] {
+            std::fs::write(&path, &content[..len])?;
+            for algorithm in [
+                Algorithm::XxHash64,
+                Algorithm::XxHash64Legacy,
+                Algorithm::Sha256,
+                Algorithm::Blake3,
+                Algorithm::NormalizedHtml,
+                Algorithm::XxHash64Tree,
+                Algorithm::XxHash64Partial,
+            ] {
+                let scheme = Scheme {
+                    chunk_size: 16,
+                    ..algorithm.scheme()
+                };
+                assert_eq!(
+                    Checksum::read(File::open(&path)?, scheme)?,
+                    Checksum::of_bytes(&content[..len], scheme),
+                    "{len} bytes with {algorithm:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn partial() {
+        let scheme = Scheme {
+            chunk_size: 4,
+            ..PARTIAL_SCHEME
+        };
+        let checksum = Checksum::of_bytes(b"start middle end", scheme);
+        // Only the size, the start and the end are hashed
+        assert_eq!(checksum, Checksum::of_bytes(b"startxmiddlx end", scheme));
+        assert_ne!(checksum, Checksum::of_bytes(b"Start middle end", scheme));
+        assert_ne!(checksum, Checksum::of_bytes(b"start middle eNd", scheme));
+        assert_ne!(checksum, Checksum::of_bytes(b"start midle end", scheme));
+    }
+
+    #[test]
+    fn stripped_html() -> Result<()> {
+        let ignored = compile(&[
+            r#"<meta name="build-time" content="[^"]*">"#.to_owned(),
+            r#"nonce="[0-9a-f]+""#.to_owned(),
+        ])?;
+        let checksum =
+            |content: &str| XxHash64::oneshot(SCHEME.seed, &strip(content.as_bytes(), &ignored));
+        let page = |built: &str, nonce: &str, body: &str| {
+            format!(
+                "<html><head><meta name=\"build-time\" content=\"{built}\">\
+                 <script nonce=\"{nonce}\"></script></head><body>{body}</body></html>"
+            )
+        };
+        // Only meaningful changes change the checksum
+        assert_eq!(
+            checksum(&page("2024-01-01T00:00:00Z", "a1", "Hello")),
+            checksum(&page("2024-06-30T12:34:56Z", "b2", "Hello"))
+        );
+        assert_ne!(
+            checksum(&page("2024-01-01T00:00:00Z", "a1", "Hello")),
+            checksum(&page("2024-01-01T00:00:00Z", "a1", "Hello!"))
+        );
+        assert_eq!(
+            "<html><head><script ></script></head><body>Hello</body></html>",
+            String::from_utf8_lossy(&strip(page("x", "a1", "Hello").as_bytes(), &ignored))
+        );
+        assert!(matches!(strip(b"<p>", &ignored), Cow::Borrowed(_)));
+        assert!(compile(&["(unclosed".to_owned()]).is_err());
+
+        assert_eq!(
+            Algorithm::StrippedHtml,
+            STRIPPED_HTML_SCHEME.algorithm.name().parse()?
+        );
+        assert!(is_html(Path::new("blog/index.HTML")));
+        assert!(is_html(Path::new("old.htm")));
+        assert!(!is_html(Path::new("style.css")));
+        Ok(())
+    }
+
+    #[test]
+    fn metadata() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("video.mp4");
+        let modified = UNIX_EPOCH + std::time::Duration::from_secs(1_700_000_000);
+        let write = |content: &str| -> Result<Checksum> {
+            std::fs::write(&path, content)?;
+            File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(modified)?;
+            Checksum::compute(&path, METADATA_SCHEME)
+        };
+        let checksum = write("content")?;
+        // Only the size and the modification time are hashed
+        assert_eq!(checksum, write("CONTENT")?);
+        assert_ne!(checksum, write("longer content")?);
+        write("content")?;
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified + std::time::Duration::from_nanos(1))?;
+        assert_ne!(checksum, Checksum::compute(&path, METADATA_SCHEME)?);
+        assert_ne!(checksum, Checksum::compute(&path, SCHEME)?);
+        Ok(())
+    }
+
+    #[test]
+    fn decompressed() -> Result<()> {
+        use std::io::Write as _;
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("index.html.gz");
+        let scheme = SCHEME.decompressing(&path);
+        assert!(scheme.decompressed);
+        assert!(
+            !SCHEME
+                .decompressing(&dir.path().join("index.html"))
+                .decompressed
+        );
+
+        let mut checksums = Vec::new();
+        for level in [1, 9] {
+            let mut encoder = flate2::write::GzEncoder::new(
+                File::create(&path)?,
+                flate2::Compression::new(level),
+            );
+            encoder.write_all(&b"<html>".repeat(100))?;
+            encoder.finish()?;
+            checksums.push(Checksum::compute(&path, scheme)?);
+            assert_ne!(checksums.last(), Some(&Checksum::compute(&path, SCHEME)?));
+        }
+        // The compression level doesn’t matter, only the content
+        assert_eq!(checksums[0], checksums[1]);
+        assert_eq!(
+            checksums[0],
+            Checksum::of_bytes(&b"<html>".repeat(100), scheme)
+        );
+
+        std::fs::write(&path, "not gzip")?;
+        assert!(Checksum::compute(&path, scheme).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sha256() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file");
+        std::fs::write(&path, "content")?;
+        let checksum = Checksum::compute(&path, SHA256_SCHEME)?;
+        // Like `sha256sum`
+        let hex = "ed7002b439e9ac845f22357d822bac1444730fbdb6016d3ec9432297b9ec9f73";
+        assert_eq!(hex, checksum.to_string());
+        assert_eq!(checksum, hex.parse()?);
+
+        let blake3 = Checksum::compute(&path, BLAKE3_SCHEME)?;
+        assert_eq!(64, blake3.to_string().len());
+        assert_ne!(checksum, blake3);
+        assert_eq!(blake3, blake3.to_string().parse()?);
+        Ok(())
+    }
+
+    #[test]
+    fn scheme() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file");
+        std::fs::write(&path, "content")?;
+        let other_seed = Scheme { seed: 1, ..SCHEME };
+        let other_chunk_size = Scheme {
+            chunk_size: 16,
+            ..SCHEME
+        };
+        let checksum = Checksum::compute(&path, SCHEME)?;
+        assert_eq!(checksum, Checksum::compute(&path, SCHEME)?);
+        assert_ne!(checksum, Checksum::compute(&path, other_seed)?);
+        // Only the bytes read are hashed
+        assert_eq!(checksum, Checksum::compute(&path, other_chunk_size)?);
+        assert_eq!(
+            checksum,
+            Checksum::from(XxHash64::oneshot(SCHEME.seed, b"content"))
+        );
+        let legacy = Checksum::compute(&path, LEGACY_SCHEME)?;
+        assert_ne!(checksum, legacy);
+        assert_ne!(
+            legacy,
+            Checksum::compute(
+                &path,
+                Scheme {
+                    chunk_size: 16,
+                    ..LEGACY_SCHEME
+                }
+            )?
+        );
+
+        for scheme in [
+            SCHEME,
+            LEGACY_SCHEME,
+            SHA256_SCHEME,
+            BLAKE3_SCHEME,
+            NORMALIZED_HTML_SCHEME,
+            TREE_SCHEME,
+            PARTIAL_SCHEME,
+        ] {
+            assert_eq!(scheme.algorithm, scheme.algorithm.name().parse()?);
+            assert_eq!(scheme, scheme.algorithm.scheme());
+        }
+        assert_eq!(
+            SCHEME,
+            Algorithm::XxHash64.scheme_for(&path, PARALLEL_THRESHOLD - 1)
+        );
+        assert_eq!(
+            TREE_SCHEME,
+            Algorithm::XxHash64.scheme_for(&path, PARALLEL_THRESHOLD)
+        );
+        assert_eq!(
+            SHA256_SCHEME,
+            Algorithm::Sha256.scheme_for(&path, PARALLEL_THRESHOLD)
+        );
+        // Only HTML files are normalized
+        assert_eq!(SCHEME, Algorithm::NormalizedHtml.scheme_for(&path, 0));
+        assert_eq!(
+            NORMALIZED_HTML_SCHEME,
+            Algorithm::NormalizedHtml.scheme_for(Path::new("a/index.HTML"), 0)
+        );
+        assert!("md5".parse::<Algorithm>().is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hard_links_hashed_once() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let (path, link) = (dir.path().join("file"), dir.path().join("link"));
+        std::fs::write(&path, "content")?;
+        std::fs::hard_link(&path, &link)?;
+        let linked = Linked::default();
+        let checksum = linked.compute(&path, &path.metadata()?, SCHEME)?;
+        assert_eq!(1, linked.0.lock().unwrap().len());
+        assert_eq!(checksum, linked.compute(&link, &link.metadata()?, SCHEME)?);
+        assert_eq!(1, linked.0.lock().unwrap().len());
+
+        // Written to through the link
+        std::fs::write(&link, "other content")?;
+        assert_eq!(
+            Checksum::compute(&path, SCHEME)?,
+            linked.compute(&path, &path.metadata()?, SCHEME)?
+        );
+        assert_ne!(checksum, Checksum::compute(&path, SCHEME)?);
+        Ok(())
     }
 }
@@ -0,0 +1,225 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Hashers of the algorithms computed from the content of the files in order, whatever the size
+//! of the reads feeding them
+
+use std::hash::Hasher as _;
+
+use ring::digest;
+use twox_hash::XxHash64;
+
+use super::{Algorithm, Checksum, Scheme};
+
+/// Computation of a checksum from the content of a file, fed in order
+pub trait Hasher {
+    /// Hash the next bytes of the content
+    fn update(&mut self, bytes: &[u8]);
+
+    /// Checksum of all the content fed
+    fn finish(self: Box<Self>) -> Checksum;
+}
+
+/// Hasher of the algorithm of the scheme, unless its checksums aren’t computed from the content
+/// in order
+pub fn new(scheme: Scheme) -> Option<Box<dyn Hasher>> {
+    let Scheme {
+        algorithm,
+        seed,
+        chunk_size,
+        ..
+    } = scheme;
+    Some(match algorithm {
+        Algorithm::XxHash64 => Box::new(XxHash64::with_seed(seed)),
+        Algorithm::Sha256 => Box::new(digest::Context::new(&digest::SHA256)),
+        Algorithm::Blake3 => Box::new(blake3::Hasher::new()),
+        Algorithm::XxHash64Tree => Box::new(Tree::new(seed, chunk_size)),
+        Algorithm::NormalizedHtml => Box::new(NormalizedHtml::new(seed)),
+        // Depend on the size of the reads or skip parts of the content
+        Algorithm::XxHash64Legacy | Algorithm::XxHash64Partial => return None,
+        // The parts left out may span several updates
+        Algorithm::StrippedHtml => return None,
+        // Doesn’t read the content at all
+        Algorithm::Metadata => return None,
+    })
+}
+
+impl Hasher for XxHash64 {
+    fn update(&mut self, bytes: &[u8]) {
+        self.write(bytes);
+    }
+
+    fn finish(self: Box<Self>) -> Checksum {
+        std::hash::Hasher::finish(&*self).into()
+    }
+}
+
+impl Hasher for digest::Context {
+    fn update(&mut self, bytes: &[u8]) {
+        digest::Context::update(self, bytes);
+    }
+
+    fn finish(self: Box<Self>) -> Checksum {
+        let mut sum = [0; 32];
+        sum.copy_from_slice((*self).finish().as_ref());
+        Checksum::Bits256(sum)
+    }
+}
+
+impl Hasher for blake3::Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        blake3::Hasher::update(self, bytes);
+    }
+
+    fn finish(self: Box<Self>) -> Checksum {
+        Checksum::Bits256(self.finalize().into())
+    }
+}
+
+/// Checksum of the checksums of the segments of the content, the last one being shorter
+pub struct Tree {
+    seed: u64,
+    segment_size: usize,
+    root: XxHash64,
+    segment: XxHash64,
+    /// Bytes hashed in the current segment
+    filled: usize,
+}
+
+impl Tree {
+    pub fn new(seed: u64, segment_size: usize) -> Self {
+        Self {
+            seed,
+            segment_size,
+            root: XxHash64::with_seed(seed),
+            segment: XxHash64::with_seed(seed),
+            filled: 0,
+        }
+    }
+
+    /// Add the checksum of a whole segment to the root
+    pub fn push(&mut self, segment: u64) {
+        self.root.write(&segment.to_le_bytes());
+    }
+
+    fn end_segment(&mut self) {
+        let segment = std::mem::replace(&mut self.segment, XxHash64::with_seed(self.seed));
+        self.push(std::hash::Hasher::finish(&segment));
+        self.filled = 0;
+    }
+}
+
+impl Hasher for Tree {
+    fn update(&mut self, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let (head, rest) = bytes.split_at((self.segment_size - self.filled).min(bytes.len()));
+            self.segment.write(head);
+            self.filled += head.len();
+            if self.filled == self.segment_size {
+                self.end_segment();
+            }
+            bytes = rest;
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> Checksum {
+        if self.filled > 0 {
+            self.end_segment();
+        }
+        std::hash::Hasher::finish(&self.root).into()
+    }
+}
+
+/// Checksum of the content with runs of whitespace hashed as a single space and whitespace at the
+/// end ignored, so that reindenting HTML doesn’t change its checksum. It’s also the case in
+/// `<pre>` elements, where whitespace matters.
+pub struct NormalizedHtml {
+    hasher: XxHash64,
+    /// Whether the last byte fed was whitespace
+    in_whitespace: bool,
+}
+
+impl NormalizedHtml {
+    fn new(seed: u64) -> Self {
+        Self {
+            hasher: XxHash64::with_seed(seed),
+            in_whitespace: false,
+        }
+    }
+}
+
+impl Hasher for NormalizedHtml {
+    fn update(&mut self, bytes: &[u8]) {
+        let mut start = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            match (byte.is_ascii_whitespace(), self.in_whitespace) {
+                (true, false) => {
+                    self.hasher.write(&bytes[start..i]);
+                    self.in_whitespace = true;
+                }
+                (false, true) => {
+                    self.hasher.write(b" ");
+                    self.in_whitespace = false;
+                    start = i;
+                }
+                _ => (),
+            }
+        }
+        if !self.in_whitespace {
+            self.hasher.write(&bytes[start..]);
+        }
+    }
+
+    fn finish(self: Box<Self>) -> Checksum {
+        std::hash::Hasher::finish(&self.hasher).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksum(scheme: Scheme, content: &[u8]) -> Checksum {
+        let mut hasher = new(scheme).unwrap();
+        hasher.update(content);
+        hasher.finish()
+    }
+
+    #[test]
+    fn normalized_html() {
+        let scheme = Algorithm::NormalizedHtml.scheme();
+        let html = checksum(scheme, b"<p>\n  Some  text\n</p>\n");
+        assert_eq!(html, checksum(scheme, b"<p> Some\ttext </p>"));
+        assert_ne!(html, checksum(scheme, b"<p>Some text</p>"));
+        assert_ne!(html, checksum(scheme, b"<p> Some text. </p>"));
+
+        // Whitespace split across updates
+        let mut hasher = new(scheme).unwrap();
+        for part in [&b"<p>\n "[..], b" Some ", b" text\n", b"</p>\n"] {
+            hasher.update(part);
+        }
+        assert_eq!(html, hasher.finish());
+    }
+
+    #[test]
+    fn tree_segments() {
+        let scheme = Scheme {
+            chunk_size: 4,
+            ..Algorithm::XxHash64Tree.scheme()
+        };
+        let content: Vec<u8> = (0..10).collect();
+        let mut hasher = new(scheme).unwrap();
+        for part in content.chunks(3) {
+            hasher.update(part);
+        }
+        let mut tree = Tree::new(scheme.seed, 4);
+        for segment in content.chunks(4) {
+            tree.push(XxHash64::oneshot(scheme.seed, segment));
+        }
+        assert_eq!(Box::new(tree).finish(), hasher.finish());
+    }
+}
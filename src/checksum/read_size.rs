@@ -0,0 +1,147 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Size of the reads of the files hashed. It doesn’t change their checksums, only how fast they
+//! are computed: network file systems serve large reads much better.
+
+use std::num::NonZeroUsize;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_derive::Deserialize;
+use tracing::debug;
+
+/// Size of the reads, unless configured otherwise
+const DEFAULT: usize = 1 << 16;
+/// Size of the reads on network file systems, picked by [`ReadSize::Auto`]
+const NETWORK: usize = 4 << 20;
+/// Largest size picked by [`ReadSize::Auto`] from the preferred size of a local file system
+const MAX_LOCAL: usize = 1 << 20;
+
+/// Size of the reads of this run
+static CURRENT: AtomicUsize = AtomicUsize::new(DEFAULT);
+
+/// Configured size of the reads, like 1048576 or "auto"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged, expecting = "a number of bytes above 0 or \"auto\"")]
+pub enum ReadSize {
+    /// Picked from the file system of the root directory, once per run
+    Auto(Auto),
+    Bytes(NonZeroUsize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Auto {
+    Auto,
+}
+
+impl Default for ReadSize {
+    fn default() -> Self {
+        Self::Bytes(NonZeroUsize::new(DEFAULT).expect("not zero"))
+    }
+}
+
+impl ReadSize {
+    /// Size of the reads of the files under the directory
+    pub fn resolve(self, dir: &Path) -> usize {
+        match self {
+            ReadSize::Bytes(bytes) => bytes.get(),
+            ReadSize::Auto(_) if is_network(dir) => NETWORK,
+            ReadSize::Auto(_) => preferred(dir).clamp(DEFAULT, MAX_LOCAL),
+        }
+    }
+}
+
+/// Use reads of this size for the rest of the run
+pub fn set(size: usize) {
+    debug!("reading files {size} bytes at a time");
+    CURRENT.store(size, Ordering::Relaxed);
+}
+
+/// Size of the reads of this run
+pub fn current() -> usize {
+    CURRENT.load(Ordering::Relaxed)
+}
+
+/// Size of the reads the file system prefers
+#[cfg(unix)]
+fn preferred(dir: &Path) -> usize {
+    use std::os::unix::fs::MetadataExt;
+
+    dir.metadata()
+        .map_or(DEFAULT, |metadata| metadata.blksize() as usize)
+}
+
+#[cfg(not(unix))]
+fn preferred(_dir: &Path) -> usize {
+    DEFAULT
+}
+
+/// Magic numbers of the network file systems, as reported by statfs(2): NFS, SMB, CIFS, SMB2,
+/// FUSE (sshfs, s3fs…), Ceph, 9P and AFS
+#[cfg(target_os = "linux")]
+const NETWORK_FILE_SYSTEMS: &[u32] = &[
+    0x6969,
+    0x517B,
+    0xFF53_4D42,
+    0xFE53_4D42,
+    0x6573_5546,
+    0x00C3_6400,
+    0x0102_1997,
+    0x5346_414F,
+];
+
+/// Whether the directory is on a network file system
+#[cfg(target_os = "linux")]
+fn is_network(dir: &Path) -> bool {
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(path) = CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    let mut stat = MaybeUninit::<libc::statfs>::uninit();
+    // SAFETY: the path is nul-terminated and statfs fills stat when it succeeds
+    if unsafe { libc::statfs(path.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    // SAFETY: statfs succeeded
+    let f_type = unsafe { stat.assume_init() }.f_type;
+    // The magic numbers fit in 32 bits, whatever the size of f_type
+    NETWORK_FILE_SYSTEMS.contains(&(f_type as u32))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_network(_dir: &Path) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let bytes = |bytes| ReadSize::Bytes(NonZeroUsize::new(bytes).unwrap());
+        assert_eq!(DEFAULT, ReadSize::default().resolve(dir.path()));
+        assert_eq!(1 << 20, bytes(1 << 20).resolve(dir.path()));
+        let auto = ReadSize::Auto(Auto::Auto).resolve(dir.path());
+        assert!((DEFAULT..=NETWORK).contains(&auto));
+
+        assert_eq!(
+            ReadSize::Auto(Auto::Auto),
+            serde_json::from_value("auto".into())?
+        );
+        assert_eq!(bytes(4096), serde_json::from_value(4096.into())?);
+        assert!(serde_json::from_value::<ReadSize>(0.into()).is_err());
+        assert!(serde_json::from_value::<ReadSize>("fast".into()).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Reads of many small files through io_uring, which keeps many of them in flight from a single
+//! thread instead of one blocking read per thread. Fast storage, like NVMe disks, serves them
+//! concurrently. The files read are then hashed in parallel.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, ErrorKind};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+
+use io_uring::{opcode, types, IoUring};
+use rayon::prelude::*;
+use tracing::{debug, warn};
+
+use super::{hasher, Checksum, Scheme};
+
+/// Reads in flight at once, which is also the number of files hashed together
+const QUEUE_DEPTH: u32 = 64;
+/// Files from this size are left to blocking reads, rather than read whole in memory
+const MAX_WHOLE_READ: u64 = 4 << 20;
+
+/// File being read
+struct InFlight {
+    file: File,
+    /// As large as the file, which is small
+    content: Vec<u8>,
+    /// Bytes read so far
+    filled: usize,
+}
+
+/// Checksums of the files read through io_uring, by path. Files that are large, that couldn’t be
+/// read or whose scheme doesn’t hash the whole content in order are left out, for the caller to
+/// hash them with blocking reads.
+pub fn compute_all(files: &[(&Path, Scheme)]) -> HashMap<PathBuf, Checksum> {
+    let mut checksums = HashMap::new();
+    let mut ring = match IoUring::new(QUEUE_DEPTH) {
+        Ok(ring) => ring,
+        Err(e) => {
+            warn!("io_uring is unavailable, reading files with blocking reads: {e}");
+            return checksums;
+        }
+    };
+
+    let mut pending = files
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, scheme))| supported(*scheme));
+    let mut in_flight: HashMap<u64, InFlight> = HashMap::new();
+    let mut read: Vec<(usize, Vec<u8>)> = Vec::new();
+    loop {
+        while in_flight.len() < QUEUE_DEPTH as usize {
+            let Some((i, (path, _))) = pending.next() else {
+                break;
+            };
+            let Some(mut entry) = open(path) else {
+                continue;
+            };
+            if entry.content.is_empty() {
+                read.push((i, entry.content));
+                continue;
+            }
+            let key = i as u64;
+            submit(&mut ring, key, &mut entry);
+            in_flight.insert(key, entry);
+        }
+        if in_flight.is_empty() {
+            break;
+        }
+
+        match ring.submit_and_wait(1) {
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("io_uring failed, reading the remaining files with blocking reads: {e}");
+                // The kernel may still write to the buffers of the reads in flight
+                std::mem::forget(in_flight);
+                break;
+            }
+        }
+        let completions: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (key, result) in completions {
+            let Some(entry) = in_flight.get_mut(&key) else {
+                continue;
+            };
+            match result {
+                e if e < 0 => {
+                    debug!(
+                        "failed to read {:?} through io_uring: {}",
+                        files[key as usize].0,
+                        io::Error::from_raw_os_error(-e)
+                    );
+                    in_flight.remove(&key);
+                }
+                n => {
+                    entry.filled += n as usize;
+                    // The file shrank since it was opened
+                    if n == 0 {
+                        entry.content.truncate(entry.filled);
+                    }
+                    if entry.filled == entry.content.len() {
+                        let entry = in_flight.remove(&key).expect("in flight");
+                        read.push((key as usize, entry.content));
+                    } else {
+                        submit(&mut ring, key, entry);
+                    }
+                }
+            }
+        }
+
+        if read.len() >= QUEUE_DEPTH as usize {
+            hash(files, &mut read, &mut checksums);
+        }
+    }
+    hash(files, &mut read, &mut checksums);
+    checksums
+}
+
+/// Whether checksums of the scheme are computed from the whole content in order
+fn supported(scheme: Scheme) -> bool {
+    !scheme.decompressed && hasher::new(scheme).is_some()
+}
+
+/// Open the file to read it whole, unless it’s too large for that
+fn open(path: &Path) -> Option<InFlight> {
+    let file = File::open(path).ok()?;
+    let len = file.metadata().ok()?.len();
+    if len >= MAX_WHOLE_READ {
+        return None;
+    }
+    Some(InFlight {
+        file,
+        content: vec![0; len as usize],
+        filled: 0,
+    })
+}
+
+/// Queue the read of the rest of the file
+fn submit(ring: &mut IoUring, key: u64, entry: &mut InFlight) {
+    let rest = &mut entry.content[entry.filled..];
+    let read = opcode::Read::new(
+        types::Fd(entry.file.as_raw_fd()),
+        rest.as_mut_ptr(),
+        rest.len() as u32,
+    )
+    .offset(entry.filled as u64)
+    .build()
+    .user_data(key);
+    // SAFETY: the file and the buffer are only dropped once the read completed, and the buffer
+    // doesn’t move when the entry does
+    unsafe {
+        ring.submission()
+            .push(&read)
+            .expect("no more reads in flight than the queue holds");
+    }
+}
+
+/// Hash the files read in parallel
+fn hash(
+    files: &[(&Path, Scheme)],
+    read: &mut Vec<(usize, Vec<u8>)>,
+    checksums: &mut HashMap<PathBuf, Checksum>,
+) {
+    let hashed: Vec<(PathBuf, Checksum)> = read
+        .par_drain(..)
+        .map(|(i, content)| {
+            let (path, scheme) = files[i];
+            (path.to_owned(), Checksum::of_bytes(&content, scheme))
+        })
+        .collect();
+    checksums.extend(hashed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::{LEGACY_SCHEME, SCHEME, SHA256_SCHEME};
+
+    #[test]
+    fn like_blocking_reads() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let mut paths = Vec::new();
+        for i in 0..(2 * QUEUE_DEPTH as usize + 3) {
+            let path = dir.path().join(format!("{i}.html"));
+            std::fs::write(&path, "x".repeat(i * 100))?;
+            paths.push(path);
+        }
+        let files: Vec<(&Path, Scheme)> = paths
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                let scheme = if i % 2 == 0 { SCHEME } else { SHA256_SCHEME };
+                (path.as_path(), scheme)
+            })
+            .chain([(Path::new("missing"), SCHEME), (&paths[0], LEGACY_SCHEME)])
+            .collect();
+
+        let checksums = compute_all(&files);
+        // Without io_uring, as in some containers, every file is left to blocking reads
+        if checksums.is_empty() {
+            return Ok(());
+        }
+        assert_eq!(paths.len(), checksums.len());
+        for (path, scheme) in &files[..paths.len()] {
+            assert_eq!(Checksum::compute(path, *scheme)?, checksums[*path]);
+        }
+        Ok(())
+    }
+}
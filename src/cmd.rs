@@ -0,0 +1,17 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Subcommands, apart from the default one that scans the root directory
+
+pub mod auth;
+pub mod config;
+pub mod db;
+pub mod export;
+pub mod import;
+pub mod list;
+pub mod snapshot;
+pub mod verify;
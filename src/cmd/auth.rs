@@ -0,0 +1,47 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use anyhow::{Context, Result};
+use tracing::info;
+
+use crate::config::Config;
+use crate::exit::Failure;
+use crate::output;
+
+/// Store the API token of the site in the OS keyring, where it’s used when the configuration
+/// sets neither `api_token` nor `api_token_cmd`. The token is read from the standard input, so
+/// that it can be piped from another program.
+pub fn login(config: &Config) -> Result<()> {
+    let api_token = output::read_secret("API token:").context(Failure::Config)?;
+    config
+        .keyring_entry()
+        .and_then(|entry| Ok(entry.set_password(&api_token)?))
+        .context("failed to store the API token in the keyring")
+        .context(Failure::Config)?;
+    info!("API token of {} stored in the keyring", config.host());
+    Ok(())
+}
+
+/// Remove the API token of the site from the OS keyring
+pub fn logout(config: &Config) -> Result<()> {
+    match config
+        .keyring_entry()
+        .and_then(|entry| Ok(entry.delete_credential()?))
+    {
+        Err(e) if matches!(e.downcast_ref(), Some(keyring::Error::NoEntry)) => {
+            info!("No API token of {} in the keyring", config.host());
+            Ok(())
+        }
+        result => {
+            result
+                .context("failed to remove the API token from the keyring")
+                .context(Failure::Config)?;
+            info!("API token of {} removed from the keyring", config.host());
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,88 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{error, info};
+
+use crate::config::{self, migration, Format, Problem, Profile};
+use crate::exit::Failure;
+use crate::output;
+
+/// Check the configuration thoroughly and report all the problems found, so that they don’t
+/// show up in the middle of a run. With `check_token`, the API token is also retrieved.
+pub fn validate(path: Option<&Path>, profile: Profile, check_token: bool) -> Result<ExitCode> {
+    let (content, path) = config::read(path, None).context(Failure::Config)?;
+    let mut problems = config::unknown_keys(&content, Format::from_path(&path))
+        .with_context(|| format!("invalid configuration {path:?}"))
+        .context(Failure::Config)?;
+
+    match config::from_content(&content, &path, profile) {
+        Ok(config) => {
+            if check_token || !config.api_token_runs_cmd() {
+                if let Err(e) = config.api_token() {
+                    problems.push(Problem {
+                        line: None,
+                        message: format!("{e:#}"),
+                    });
+                }
+            }
+        }
+        Err(e) => problems.push(Problem {
+            line: None,
+            message: format!("{e:#}"),
+        }),
+    }
+
+    for problem in &problems {
+        error!("{}: {problem}", path.display());
+    }
+    if problems.is_empty() {
+        info!("{} is valid", path.display());
+        Ok(ExitCode::SUCCESS)
+    } else {
+        Ok(Failure::Config.into())
+    }
+}
+
+/// Upgrade the configuration file written for an older layout, after confirmation unless `yes`
+pub fn migrate(path: Option<&Path>, yes: bool) -> Result<()> {
+    let (content, path) = config::read_raw(path, None).context(Failure::Config)?;
+    let format = Format::from_path(&path);
+    if config::is_sops_encrypted(&content, format) {
+        return Err(anyhow!(
+            "{path:?} is encrypted with sops, decrypt it to migrate it"
+        ))
+        .context(Failure::Config);
+    }
+    let Some(migrated) = migration::migrated_content(&content, format)
+        .with_context(|| format!("invalid configuration {path:?}"))
+        .context(Failure::Config)?
+    else {
+        info!("{} is up to date", path.display());
+        return Ok(());
+    };
+
+    info!("Migrated configuration:\n{migrated}");
+    let question = format!("Overwrite {}?", path.display());
+    if !yes && !output::confirm(&question).context(Failure::Config)? {
+        info!("{} left unchanged", path.display());
+        return Ok(());
+    }
+    fs::write(&path, migrated)
+        .with_context(|| format!("failed to write {path:?}"))
+        .context(Failure::Config)?;
+    info!(
+        "{} migrated to version {}",
+        path.display(),
+        migration::CURRENT_VERSION
+    );
+    Ok(())
+}
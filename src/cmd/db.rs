@@ -0,0 +1,214 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::io::{self, ErrorKind, Write as _};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, SystemTime};
+
+use anyhow::{Context, Result};
+use indicatif::{HumanBytes, ParallelProgressIterator};
+use rayon::prelude::*;
+use rusqlite::Connection;
+use tracing::{error, info};
+
+use crate::checksum::{Checksum, Scheme};
+use crate::db::{self, FileEntry};
+use crate::exit::Failure;
+use crate::output;
+
+/// Remove the history older than `keep` and reclaim the space it used
+pub fn gc(conn: &mut Connection, db_path: &Path, keep: Duration) -> Result<()> {
+    let size_before = size_on_disk(db_path);
+
+    let before = SystemTime::now()
+        .checked_sub(keep)
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let tx = conn.transaction().context(Failure::Db)?;
+    let (history, runs) = db::prune_history(&tx, before).context(Failure::Db)?;
+    tx.commit().context(Failure::Db)?;
+    info!("Removed {history} history entries and {runs} runs");
+
+    db::vacuum(conn).context(Failure::Db)?;
+    let size_after = size_on_disk(db_path);
+    info!(
+        "Reclaimed {}, the database now takes {}",
+        HumanBytes(size_before.saturating_sub(size_after)),
+        HumanBytes(size_after)
+    );
+    Ok(())
+}
+
+/// Upper bounds of the buckets of file sizes, in bytes
+const SIZE_BUCKETS: [u64; 5] = [1 << 10, 10 << 10, 100 << 10, 1 << 20, 10 << 20];
+/// Upper bounds of the buckets of the time since the last modification, in days
+const AGE_BUCKETS: [u64; 4] = [1, 7, 30, 365];
+
+/// Print statistics about the database, to understand why runs are slow
+pub fn stats(conn: &Connection, db_path: &Path) -> Result<()> {
+    let tables = db::table_counts(conn).context(Failure::Db)?;
+    let indexes = db::indexes(conn).context(Failure::Db)?;
+    let mut sizes = [0; SIZE_BUCKETS.len() + 1];
+    let mut ages = [0; AGE_BUCKETS.len() + 1];
+    let now = SystemTime::now();
+    db::for_each_metadata(conn, |metadata_values| {
+        sizes[bucket(&SIZE_BUCKETS, metadata_values.size())] += 1;
+        let age = now
+            .duration_since(metadata_values.modified())
+            .unwrap_or_default();
+        ages[bucket(&AGE_BUCKETS, age.as_secs() / (24 * 3600))] += 1;
+    })
+    .context(Failure::Db)?;
+
+    let mut stdout = io::stdout().lock();
+    writeln!(
+        stdout,
+        "{}: {}, write-ahead log: {}",
+        db_path.display(),
+        HumanBytes(file_size(db_path)),
+        HumanBytes(file_size(&wal_path(db_path)))
+    )?;
+    writeln!(stdout, "\nRows")?;
+    for (table, count) in tables {
+        writeln!(stdout, "  {table:<16} {count:>10}")?;
+    }
+    writeln!(stdout, "\nIndexes")?;
+    for (index, table) in indexes {
+        writeln!(stdout, "  {index} on {table}")?;
+    }
+    writeln!(stdout, "\nFile sizes")?;
+    for (i, count) in sizes.iter().enumerate() {
+        let range = match SIZE_BUCKETS.get(i) {
+            Some(&max) => format!("< {}", HumanBytes(max)),
+            None => format!(">= {}", HumanBytes(SIZE_BUCKETS[i - 1])),
+        };
+        writeln!(stdout, "  {range:<16} {count:>10}")?;
+    }
+    writeln!(stdout, "\nLast modified")?;
+    for (i, count) in ages.iter().enumerate() {
+        let days = |days| {
+            if days == 1 {
+                "1 day".to_owned()
+            } else {
+                format!("{days} days")
+            }
+        };
+        let range = match AGE_BUCKETS.get(i) {
+            Some(&max) => format!("< {} ago", days(max)),
+            None => format!(">= {} ago", days(AGE_BUCKETS[i - 1])),
+        };
+        writeln!(stdout, "  {range:<16} {count:>10}")?;
+    }
+    Ok(())
+}
+
+/// Hash again, with the current scheme, the files whose checksum was computed with another one and
+/// whose content didn’t change since, so that the next runs don’t compute both. Files that changed
+/// are left to the next run, to be purged.
+pub fn rebaseline(
+    conn: &mut Connection,
+    root_dir: &Path,
+    current_scheme: impl Fn(&Path, u64) -> Scheme + Sync,
+) -> Result<ExitCode> {
+    let entries: Vec<FileEntry> = db::list_entries(conn, None)
+        .context(Failure::Db)?
+        .into_iter()
+        .filter(|entry| {
+            let path = root_dir.join(entry.path.to_path());
+            entry.checksum_scheme != current_scheme(&path, entry.metadata_values.size())
+        })
+        .collect();
+
+    info!("Rebaselining {} files in {root_dir:?}", entries.len());
+    let results: Vec<_> = entries
+        .par_iter()
+        .progress_with(output::progress_bar("Rebaselining", entries.len() as u64))
+        .map(|entry| (entry, rehash(root_dir, entry, &current_scheme)))
+        .collect();
+
+    let (mut rebaselined, mut changed, mut errors) = (0, 0, 0);
+    let tx = conn.transaction().context(Failure::Db)?;
+    for (entry, result) in results {
+        match result {
+            Ok(Some((checksum, scheme))) => {
+                db::rebaseline(&tx, &entry.path, checksum, scheme).context(Failure::Db)?;
+                rebaselined += 1;
+            }
+            Ok(None) => changed += 1,
+            Err(e) => {
+                errors += 1;
+                error!(
+                    "failed to rebaseline {}: {e}",
+                    entry.path.get_relative_path()
+                );
+            }
+        }
+    }
+    tx.commit().context(Failure::Db)?;
+
+    info!("Rebaselined {rebaselined} files, {changed} changed files are left to the next run.");
+    Ok(if errors > 0 {
+        Failure::Scan.into()
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Checksum of the file with the current scheme, unless its content changed since it was stored
+fn rehash(
+    root_dir: &Path,
+    entry: &FileEntry,
+    current_scheme: impl Fn(&Path, u64) -> Scheme,
+) -> Result<Option<(Checksum, Scheme)>> {
+    let path = root_dir.join(entry.path.to_path());
+    let size = match path.metadata() {
+        Ok(metadata) => metadata.len(),
+        // Deleted, which the next run handles as well
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if (!entry.checksum_scheme.decompressed && size != entry.metadata_values.size())
+        || Checksum::compute(&path, entry.checksum_scheme)? != entry.checksum
+    {
+        return Ok(None);
+    }
+    let scheme = current_scheme(&path, size);
+    Ok(Some((Checksum::compute(&path, scheme)?, scheme)))
+}
+
+/// Index of the first bucket whose upper bound is above the value, or past the last bucket
+fn bucket(bounds: &[u64], value: u64) -> usize {
+    bounds.partition_point(|&max| max <= value)
+}
+
+fn wal_path(db_path: &Path) -> PathBuf {
+    let mut wal_path = PathBuf::from(db_path).into_os_string();
+    wal_path.push("-wal");
+    wal_path.into()
+}
+
+fn file_size(path: &Path) -> u64 {
+    path.metadata().map_or(0, |metadata| metadata.len())
+}
+
+/// Size of the database file with its write-ahead log
+fn size_on_disk(db_path: &Path) -> u64 {
+    file_size(db_path) + file_size(&wal_path(db_path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets() {
+        assert_eq!(0, bucket(&SIZE_BUCKETS, 0));
+        assert_eq!(0, bucket(&SIZE_BUCKETS, 1023));
+        assert_eq!(1, bucket(&SIZE_BUCKETS, 1024));
+        assert_eq!(SIZE_BUCKETS.len(), bucket(&SIZE_BUCKETS, u64::MAX));
+    }
+}
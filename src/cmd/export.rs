@@ -0,0 +1,173 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::io::{self, Write};
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use rusqlite::Connection;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::checksum::{self, Scheme};
+use crate::db::{self, FileEntry, MetadataValues};
+use crate::exit::Failure;
+
+/// Prefix of the first line of CSV exports, followed by the schema version
+pub const CSV_VERSION_PREFIX: &str = "# schema_version: ";
+
+/// Format of the exported state
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    #[default]
+    Json,
+    Csv,
+}
+
+/// Whole exported state, in JSON
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Export {
+    /// Version of the database schema the export was made from
+    pub schema_version: usize,
+    pub files: Vec<Record>,
+}
+
+/// Exported row of the files table. Unlike in the database, the checksum and its seed are
+/// hexadecimal, to be easier to compare with other tools. Exports made before the checksum scheme
+/// was stored used the current one.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Record {
+    pub path: String,
+    pub modified_since_epoch_sec: f64,
+    pub size: u64,
+    pub checksum: String,
+    pub last_purged_since_epoch_sec: Option<f64>,
+    #[serde(default = "default_checksum_algorithm")]
+    pub checksum_algorithm: String,
+    #[serde(default = "default_checksum_seed")]
+    pub checksum_seed: String,
+    #[serde(default = "default_checksum_chunk_size")]
+    pub checksum_chunk_size: usize,
+    #[serde(default)]
+    pub checksum_decompressed: bool,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub cdn_etag: Option<String>,
+    #[serde(default)]
+    pub cdn_etag_since_epoch_sec: Option<f64>,
+    #[serde(default)]
+    pub audit_digest: Option<String>,
+    #[serde(default)]
+    pub audit_digest_algorithm: Option<String>,
+}
+
+fn default_checksum_algorithm() -> String {
+    checksum::LEGACY_SCHEME.algorithm.name().to_owned()
+}
+
+fn default_checksum_seed() -> String {
+    format!("{:016x}", checksum::LEGACY_SCHEME.seed)
+}
+
+fn default_checksum_chunk_size() -> usize {
+    checksum::LEGACY_SCHEME.chunk_size
+}
+
+impl From<FileEntry> for Record {
+    fn from(entry: FileEntry) -> Self {
+        Self {
+            path: entry.path.get_relative_path().to_owned(),
+            modified_since_epoch_sec: entry.metadata_values.modified_since_epoch_sec(),
+            size: entry.metadata_values.size(),
+            checksum: entry.checksum.to_string(),
+            last_purged_since_epoch_sec: entry.last_purged_since_epoch_sec,
+            checksum_algorithm: entry.checksum_scheme.algorithm.name().to_owned(),
+            checksum_seed: format!("{:016x}", entry.checksum_scheme.seed),
+            checksum_chunk_size: entry.checksum_scheme.chunk_size,
+            checksum_decompressed: entry.checksum_scheme.decompressed,
+            content_type: entry.content_type,
+            cdn_etag: entry.cdn_etag,
+            cdn_etag_since_epoch_sec: entry.cdn_etag_since_epoch_sec,
+            audit_digest: entry.audit_digest.map(|(digest, _)| digest.to_string()),
+            audit_digest_algorithm: entry
+                .audit_digest
+                .map(|(_, algorithm)| algorithm.name().to_owned()),
+        }
+    }
+}
+
+impl TryFrom<Record> for FileEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(record: Record) -> Result<Self> {
+        let audit_digest = match (&record.audit_digest, &record.audit_digest_algorithm) {
+            (Some(digest), Some(algorithm)) => Some((
+                digest
+                    .parse()
+                    .with_context(|| format!("invalid audit digest for {}", record.path))?,
+                algorithm.parse().with_context(|| {
+                    format!("invalid audit digest algorithm for {}", record.path)
+                })?,
+            )),
+            _ => None,
+        };
+        Ok(Self {
+            checksum: record
+                .checksum
+                .parse()
+                .with_context(|| format!("invalid checksum for {}", record.path))?,
+            checksum_scheme: Scheme {
+                algorithm: record
+                    .checksum_algorithm
+                    .parse()
+                    .with_context(|| format!("invalid checksum algorithm for {}", record.path))?,
+                seed: u64::from_str_radix(&record.checksum_seed, 16)
+                    .with_context(|| format!("invalid checksum seed for {}", record.path))?,
+                chunk_size: record.checksum_chunk_size,
+                decompressed: record.checksum_decompressed,
+            },
+            content_type: record.content_type,
+            cdn_etag: record.cdn_etag,
+            cdn_etag_since_epoch_sec: record.cdn_etag_since_epoch_sec,
+            path: record.path.into(),
+            metadata_values: MetadataValues::new(record.modified_since_epoch_sec, record.size),
+            last_purged_since_epoch_sec: record.last_purged_since_epoch_sec,
+            audit_digest,
+        })
+    }
+}
+
+/// Write the whole state of the database on the standard output
+pub fn run(conn: &Connection, format: Format) -> Result<()> {
+    let schema_version = db::schema_version(conn).context(Failure::Db)?;
+    let files = db::list_entries(conn, None)
+        .context(Failure::Db)?
+        .into_iter()
+        .map(Record::from)
+        .collect();
+    let export = Export {
+        schema_version,
+        files,
+    };
+
+    let mut stdout = io::stdout().lock();
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut stdout, &export)?;
+            writeln!(stdout)?;
+        }
+        Format::Csv => {
+            writeln!(stdout, "{CSV_VERSION_PREFIX}{}", export.schema_version)?;
+            let mut writer = csv::Writer::from_writer(stdout);
+            for record in &export.files {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+    }
+    Ok(())
+}
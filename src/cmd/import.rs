@@ -0,0 +1,101 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fs;
+use std::io::{self, Read as _};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use rusqlite::Connection;
+use tracing::info;
+
+use super::export::{Export, Format, CSV_VERSION_PREFIX};
+use crate::db::{self, FileEntry};
+use crate::exit::Failure;
+
+/// Load a state previously exported, from a file or the standard input (when the path is `-`).
+/// Unless `replace` is set, the imported rows are merged with the existing ones.
+pub fn run(conn: &mut Connection, path: &Path, format: Format, replace: bool) -> Result<()> {
+    let content = if path == Path::new("-") {
+        let mut s = String::new();
+        io::stdin().read_to_string(&mut s)?;
+        s
+    } else {
+        fs::read_to_string(path).with_context(|| format!("failed to read {path:?}"))?
+    };
+    let export = parse(&content, format)?;
+
+    let schema_version = db::schema_version(conn).context(Failure::Db)?;
+    if export.schema_version > schema_version {
+        bail!(
+            "the export comes from a newer version of the database schema ({} > {schema_version}), \
+             please upgrade {}",
+            export.schema_version,
+            env!("CARGO_PKG_NAME")
+        );
+    }
+
+    let entries = export
+        .files
+        .into_iter()
+        .map(FileEntry::try_from)
+        .collect::<Result<Vec<_>>>()?;
+
+    let tx = conn.transaction().context(Failure::Db)?;
+    if replace {
+        let n = db::clear(&tx).context(Failure::Db)?;
+        info!("Removed {n} existing entries");
+    }
+    for entry in &entries {
+        db::upsert_file_entry(&tx, entry).context(Failure::Db)?;
+    }
+    tx.commit().context(Failure::Db)?;
+
+    info!("Imported {} entries", entries.len());
+    Ok(())
+}
+
+fn parse(content: &str, format: Format) -> Result<Export> {
+    Ok(match format {
+        Format::Json => serde_json::from_str(content)?,
+        Format::Csv => {
+            let (first_line, rest) = content.split_once('\n').unwrap_or((content, ""));
+            let Some(schema_version) = first_line.trim_end().strip_prefix(CSV_VERSION_PREFIX)
+            else {
+                bail!("missing schema version, the first line should start with {CSV_VERSION_PREFIX:?}");
+            };
+            let files = csv::Reader::from_reader(rest.as_bytes())
+                .deserialize()
+                .collect::<Result<_, _>>()?;
+            Export {
+                schema_version: schema_version.parse()?,
+                files,
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_csv() -> Result<()> {
+        let export = parse(
+            "# schema_version: 3\n\
+             path,modified_since_epoch_sec,size,checksum,last_purged_since_epoch_sec\n\
+             index.html,12.5,10,000000000000000a,\n",
+            Format::Csv,
+        )?;
+        assert_eq!(3, export.schema_version);
+        assert_eq!(1, export.files.len());
+        assert_eq!(None, export.files[0].last_purged_since_epoch_sec);
+
+        assert!(parse("path\nindex.html\n", Format::Csv).is_err());
+        Ok(())
+    }
+}
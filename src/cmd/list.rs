@@ -0,0 +1,41 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::io::{self, Write as _};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use humantime::format_rfc3339_seconds;
+use rusqlite::Connection;
+
+use crate::db;
+use crate::exit::Failure;
+
+/// Print the tracked files matching the pattern, one per line, with tab-separated columns (so
+/// that it’s easy to process with cut, awk…)
+pub fn run(conn: &Connection, pattern: Option<&str>) -> Result<()> {
+    let entries = db::list_entries(conn, pattern).context(Failure::Db)?;
+
+    let mut stdout = io::stdout().lock();
+    for entry in entries {
+        let last_purged = entry
+            .last_purged_since_epoch_sec
+            .map_or("never".to_owned(), |secs| {
+                format_rfc3339_seconds(UNIX_EPOCH + Duration::from_secs_f64(secs)).to_string()
+            });
+        writeln!(
+            stdout,
+            "{}\t{}\t{}\t{}\t{}",
+            entry.path.get_relative_path(),
+            entry.metadata_values.size(),
+            format_rfc3339_seconds(entry.metadata_values.modified()),
+            entry.checksum,
+            last_purged,
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,45 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use rusqlite::Connection;
+use tracing::info;
+
+use crate::db;
+use crate::exit::Failure;
+
+/// Save the current state of the database under the name, to restore it later
+pub fn save(conn: &Connection, db_path: &Path, name: &str, force: bool) -> Result<()> {
+    let snapshot = db::snapshot_path(db_path, name).context(Failure::Config)?;
+    if snapshot.exists() && !force {
+        return Err(anyhow!(
+            "the snapshot {name} already exists, pass --force to overwrite it"
+        ))
+        .context(Failure::Db);
+    }
+    let dir = snapshot.parent().expect("snapshots are in a directory");
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create {}", dir.display()))
+        .context(Failure::Db)?;
+    db::backup(conn, &snapshot).context(Failure::Db)?;
+    info!("Saved the state in {}", snapshot.display());
+    Ok(())
+}
+
+/// Replace the state of the database with the snapshot saved under the name
+pub fn restore(conn: &mut Connection, db_path: &Path, name: &str) -> Result<()> {
+    let snapshot = db::snapshot_path(db_path, name).context(Failure::Config)?;
+    if !snapshot.exists() {
+        return Err(anyhow!("there is no snapshot {}", snapshot.display())).context(Failure::Db);
+    }
+    db::restore(conn, &snapshot).context(Failure::Db)?;
+    info!("Restored the state saved in {}", snapshot.display());
+    Ok(())
+}
@@ -0,0 +1,114 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fmt::Display;
+use std::io::{self, ErrorKind, Write as _};
+use std::path::Path;
+use std::process::ExitCode;
+
+use anyhow::{Context, Result};
+use indicatif::ParallelProgressIterator;
+use rayon::prelude::*;
+use rusqlite::Connection;
+use tracing::{error, info};
+
+use crate::checksum::Checksum;
+use crate::db::{self, FileEntry, MetadataValues};
+use crate::exit::Failure;
+use crate::output;
+
+/// Difference between a file on disk and what the database records about it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mismatch {
+    /// The file is tracked but is not on disk anymore
+    Missing,
+    /// The content differs even though the metadata are the same, so the fast change detection
+    /// misses it
+    Stale,
+    /// Both the content and the metadata differ, the next run will pick it up
+    Changed,
+    /// The checksum matches but not the stronger audit digest, so the checksum misses a change,
+    /// like with `fast_hash`, or the file got corrupted
+    Digest,
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Mismatch::Missing => "missing",
+            Mismatch::Stale => "stale",
+            Mismatch::Changed => "changed",
+            Mismatch::Digest => "digest",
+        })
+    }
+}
+
+/// Hash again every tracked file, regardless of its metadata, and print the files that don’t
+/// match the database, with the kind of mismatch (tab separated)
+pub fn run(conn: &Connection, root_dir: &Path) -> Result<ExitCode> {
+    let entries = db::list_entries(conn, None).context(Failure::Db)?;
+
+    info!("Verifying {} files in {root_dir:?}", entries.len());
+    let results: Vec<(&FileEntry, Result<Option<Mismatch>>)> = entries
+        .par_iter()
+        .progress_with(output::progress_bar("Verifying", entries.len() as u64))
+        .map(|entry| (entry, check(root_dir, entry)))
+        .collect();
+
+    let mut stdout = io::stdout().lock();
+    let (mut mismatches, mut errors) = (0, 0);
+    for (entry, result) in results {
+        match result {
+            Ok(None) => (),
+            Ok(Some(mismatch)) => {
+                mismatches += 1;
+                writeln!(stdout, "{mismatch}\t{}", entry.path.get_relative_path())?;
+            }
+            Err(e) => {
+                errors += 1;
+                error!("failed to verify {}: {e}", entry.path.get_relative_path());
+            }
+        }
+    }
+
+    info!("{mismatches} mismatches found.");
+    Ok(if errors > 0 {
+        Failure::Scan.into()
+    } else if mismatches > 0 {
+        Failure::Verify.into()
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+fn check(root_dir: &Path, entry: &FileEntry) -> Result<Option<Mismatch>> {
+    let path = root_dir.join(entry.path.to_path());
+    let metadata = match path.metadata() {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Some(Mismatch::Missing)),
+        Err(e) => return Err(e.into()),
+    };
+    let metadata_values = MetadataValues::from(&metadata);
+    let checksum = Checksum::compute(&path, entry.checksum_scheme)?;
+    let same_checksum = checksum == entry.checksum
+        && (entry.checksum_scheme.decompressed
+            || metadata_values.size() == entry.metadata_values.size());
+    let same_digest = match entry.audit_digest {
+        Some((digest, algorithm)) => Checksum::compute(&path, algorithm.scheme())? == digest,
+        None => true,
+    };
+
+    Ok(if same_checksum && same_digest {
+        None
+    } else if same_checksum {
+        Some(Mismatch::Digest)
+    } else if metadata_values.same_as(&entry.metadata_values) {
+        Some(Mismatch::Stale)
+    } else {
+        Some(Mismatch::Changed)
+    })
+}
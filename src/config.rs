@@ -5,38 +5,780 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::env;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Context, Result};
 use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+use tracing::{debug, info, warn};
+use url::Url;
 
+use crate::cdn::{cloudflare, fastly};
+use crate::checksum;
+use crate::db;
+use crate::hook::shell_command;
+use crate::metrics;
+use crate::notify;
+use crate::otlp;
+use crate::output;
+use crate::rel_path::PathCase;
+use crate::secret;
+use crate::state;
+use crate::xdg;
+
+pub mod migration;
+
+/// Configuration of the site selected. Keys of the `[sites.<name>]` table of the selected site
+/// override the top-level ones, so that several sites can be configured in the same file. Keys of
+/// the `[env.<name>]` table of the selected environment, like staging, override both, and
+/// environment variables like `STATIC_CDN_BASE_URL` override everything.
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
-    // TODO Pull that from the API, would be more ergonomic. Then replace witrh the site name
-    // (i.e. cj.rs)
-    pub site_uuid: String,
-    pub api_token_cmd: String,
+    /// File the configuration was read from
+    #[serde(skip)]
+    pub path: PathBuf,
+    /// CDN serving the site, with its settings
+    pub provider: Provider,
+    /// Command printing the API token, unless the token is set in another way
+    pub api_token_cmd: Option<String>,
+    /// API token, meant to be set with `STATIC_CDN_API_TOKEN` rather than in the file
+    pub api_token: Option<String>,
+    /// Environment variable holding the API token, typically a secret injected by the CI
+    pub api_token_env: Option<String>,
+    /// File holding the API token on its first line
+    pub api_token_file: Option<PathBuf>,
+    /// API token encrypted with age, ASCII-armored, so that it can be committed
+    pub api_token_age: Option<String>,
+    /// Identity file decrypting `api_token_age`
+    pub age_identity: Option<PathBuf>,
+    /// URL under which the root directory is served
+    pub base_url: Url,
+    /// Directories holding the static site, overridden by the command line
+    #[serde(default)]
+    pub root_dirs: Vec<PathBuf>,
+    /// Where to store the database, overridden by the command line
+    pub db_path: Option<PathBuf>,
+    /// Tuning of the database
+    #[serde(default)]
+    pub sqlite: db::Settings,
+    /// Object store holding the database between runs, instead of the local file only
+    pub state: Option<state::Settings>,
+    /// Number of threads used to hash files and to walk the directories, overridden by the command
+    /// line
+    pub threads: Option<usize>,
+    /// Maximum number of purge requests in flight, overridden by the command line
+    pub cdn_concurrency: Option<usize>,
+    /// Ask for confirmation before purging more URLs than this
+    pub confirm_threshold: Option<usize>,
+    /// Always hash the files, like with `--force-deep-check`
+    #[serde(default)]
+    pub force_deep_check: bool,
+    /// Check the content of every file while all the modification times change on each run, but
+    /// not the content
+    #[serde(default)]
+    pub detect_unreliable_mtimes: bool,
+    /// Skip the directories unchanged since the previous run, like with `--skip-unchanged-dirs`
+    #[serde(default)]
+    pub skip_unchanged_dirs: bool,
+    /// Only hash the start and the end of the files, like with `--fast-hash`
+    #[serde(default)]
+    pub fast_hash: bool,
+    /// Files larger than this, in bytes, are not hashed: only their size and modification time
+    /// tell whether they changed
+    pub max_hash_size: Option<u64>,
+    /// Never purge anything, like with `--dry-run`
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Algorithm of the checksums of the files changed from now on
+    #[serde(default)]
+    pub checksum: checksum::Algorithm,
+    /// Regular expressions of the parts of HTML files left out of their checksums, like the build
+    /// timestamps embedded in each page
+    #[serde(default)]
+    pub html_ignore: Vec<String>,
+    /// Hash the decompressed content of the .gz and .br files
+    #[serde(default)]
+    pub hash_decompressed: bool,
+    /// Algorithm of a stronger digest stored next to the checksum of the files changed from now
+    /// on, checked by `verify`
+    #[serde(default)]
+    pub audit_digest: Option<checksum::Algorithm>,
+    /// Read the small files to hash through io_uring, on Linux
+    #[serde(default)]
+    pub io_uring: bool,
+    /// Size of the reads of the files hashed, in bytes, or picked from the file system
+    #[serde(default)]
+    pub read_size: checksum::ReadSize,
+    /// Whether paths differing only by their case are the same file
+    #[serde(default)]
+    pub path_case: PathCase,
+    /// Fetch the purged files from the CDN, to record the ETag it serves
+    #[serde(default)]
+    pub record_cdn_etags: bool,
+    /// Maximum number of URLs per purge request, below the limit of the CDN, overridden by the
+    /// command line
+    pub batch_size: Option<usize>,
+    /// Glob patterns of the files and directories to skip, on top of the `--exclude` ones
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Command run before each run, like a build, which is aborted if the command fails
+    pub pre_run_cmd: Option<String>,
+    /// Command run after each run, with `STATIC_CDN_RESULT` set to `success` or `failure`
+    pub post_run_cmd: Option<String>,
+    /// Command run after purging, with the purged URLs on its standard input, one per line
+    pub post_purge_cmd: Option<String>,
+    /// Webhook notified after each run
+    pub notify: Option<notify::Settings>,
+    /// Where to export the metrics of each run
+    pub metrics: Option<metrics::Settings>,
+    /// OpenTelemetry collector receiving the spans of each run
+    pub otlp: Option<otlp::Settings>,
+    /// File the messages are also appended to, overridden by the command line
+    pub log_file: Option<PathBuf>,
+    /// When the log file is rotated
+    #[serde(default)]
+    pub log_rotation: output::Rotation,
+    /// Rotated log files kept
+    pub log_files_kept: Option<usize>,
+}
+
+/// CDN serving the site, from the single `[provider.<name>]` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    Cloudflare(cloudflare::Settings),
+    Fastly(fastly::Settings),
+}
+
+impl Provider {
+    /// Name of the table of the provider
+    pub fn name(&self) -> &'static str {
+        match self {
+            Provider::Cloudflare(_) => "cloudflare",
+            Provider::Fastly(_) => "fastly",
+        }
+    }
+
+    /// Whether renamed directories are purged by prefix
+    pub fn prefix_purge(&self) -> bool {
+        match self {
+            Provider::Cloudflare(settings) => settings.prefix_purge,
+            Provider::Fastly(_) => false,
+        }
+    }
+}
+
+impl Config {
+    /// Host of the base URL, identifying the site
+    pub fn host(&self) -> &str {
+        self.base_url.host_str().unwrap_or(self.base_url.as_str())
+    }
+
+    /// The API token, from the first of these that is set:
+    /// - `api_token`,
+    /// - the environment variable named by `api_token_env`,
+    /// - the first line of `api_token_file`,
+    /// - the first line of `api_token_age`, once decrypted,
+    /// - the first line of the output of `api_token_cmd`,
+    /// - the OS keyring, where `auth login` stores it.
+    pub fn api_token(&self) -> Result<String> {
+        if let Some(api_token) = &self.api_token {
+            return Ok(api_token.clone());
+        }
+        if let Some(var) = &self.api_token_env {
+            return env::var(var)
+                .ok()
+                .filter(|token| !token.is_empty())
+                .ok_or_else(|| anyhow!("the environment variable {var} holds no API token"));
+        }
+        if let Some(path) = &self.api_token_file {
+            return fs::read_to_string(path)
+                .with_context(|| format!("failed to read the API token from {path:?}"))
+                .and_then(|content| {
+                    first_line(&content).ok_or_else(|| anyhow!("{path:?} holds no API token"))
+                });
+        }
+        if let Some(armored) = &self.api_token_age {
+            let identity = match &self.age_identity {
+                Some(identity) => identity.clone(),
+                None => xdg::config_dir()
+                    .ok_or_else(|| anyhow!("no home directory, set age_identity"))?
+                    .join(AGE_IDENTITY_PATH),
+            };
+            return secret::age_decrypt(armored, &identity)
+                .context("failed to decrypt api_token_age")
+                .and_then(|token| {
+                    first_line(&token).ok_or_else(|| anyhow!("api_token_age holds no API token"))
+                });
+        }
+        let Some(api_token_cmd) = &self.api_token_cmd else {
+            return match self.keyring_entry()?.get_password() {
+                Err(keyring::Error::NoEntry) => bail!(
+                    "no API token, run `{} auth login`, set api_token_cmd or {ENV_PREFIX}API_TOKEN",
+                    env!("CARGO_PKG_NAME")
+                ),
+                result => result.context("failed to read the API token from the keyring"),
+            };
+        };
+        let output = shell_command(api_token_cmd)
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("failed to run `{api_token_cmd}`"))?;
+        if !output.status.success() {
+            bail!("`{api_token_cmd}` failed with {}", output.status);
+        }
+        first_line(&String::from_utf8(output.stdout)?)
+            .ok_or_else(|| anyhow!("`{api_token_cmd}` printed no API token"))
+    }
+
+    /// Whether getting the API token runs `api_token_cmd`, which may be slow or interactive
+    pub fn api_token_runs_cmd(&self) -> bool {
+        self.api_token.is_none()
+            && self.api_token_env.is_none()
+            && self.api_token_file.is_none()
+            && self.api_token_age.is_none()
+            && self.api_token_cmd.is_some()
+    }
+
+    /// Where the API token of the site is stored in the OS keyring
+    pub fn keyring_entry(&self) -> Result<keyring::Entry> {
+        keyring::Entry::new(env!("CARGO_PKG_NAME"), self.host())
+            .context("failed to access the keyring")
+    }
 }
 
-const PATH: &'static str = concat!(env!("CARGO_PKG_NAME"), ".toml");
-static DEFAULT_CONTENT: &'static str = include_str!("default-config.toml");
+/// First line of the text, without surrounding whitespace, if it’s not empty
+fn first_line(text: &str) -> Option<String> {
+    text.lines()
+        .next()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+}
+
+const PATH: &str = concat!(env!("CARGO_PKG_NAME"), ".toml");
+/// Names of the configuration file looked for, in order of preference
+const FILE_NAMES: [&str; 4] = [
+    PATH,
+    concat!(env!("CARGO_PKG_NAME"), ".yaml"),
+    concat!(env!("CARGO_PKG_NAME"), ".yml"),
+    concat!(env!("CARGO_PKG_NAME"), ".json"),
+];
+static DEFAULT_CONTENT: &str = include_str!("default-config.toml");
+/// Default identity file of age, in the configuration directory
+const AGE_IDENTITY_PATH: &str = "age-identity.txt";
+
+/// Table holding the site profiles in the configuration file
+const SITES_KEY: &str = "sites";
+/// Table holding the environment profiles in the configuration file
+const ENVS_KEY: &str = "env";
+/// Tables of profiles, in the order they are applied
+const PROFILE_KEYS: [&str; 2] = [SITES_KEY, ENVS_KEY];
+/// Table holding the settings of the CDN
+const PROVIDER_KEY: &str = "provider";
+/// Key of the path of the database, which is different for each profile
+const DB_PATH_KEY: &str = "db_path";
+/// Prefix of the environment variables overriding the configuration keys, like
+/// `STATIC_CDN_BASE_URL` for `base_url`
+const ENV_PREFIX: &str = "STATIC_CDN_";
 
-pub fn load() -> Result<Config> {
-    let path = Path::new(PATH);
+/// Keys of the configuration, apart from the profiles
+const KEYS: &[&str] = &[
+    migration::VERSION_KEY,
+    PROVIDER_KEY,
+    "api_token_cmd",
+    "api_token",
+    "api_token_env",
+    "api_token_file",
+    "api_token_age",
+    "age_identity",
+    "base_url",
+    "root_dirs",
+    "db_path",
+    "sqlite",
+    "state",
+    "threads",
+    "cdn_concurrency",
+    "confirm_threshold",
+    "force_deep_check",
+    "detect_unreliable_mtimes",
+    "skip_unchanged_dirs",
+    "fast_hash",
+    "max_hash_size",
+    "dry_run",
+    "checksum",
+    "html_ignore",
+    "hash_decompressed",
+    "audit_digest",
+    "read_size",
+    "path_case",
+    "io_uring",
+    "record_cdn_etags",
+    "batch_size",
+    "exclude",
+    "pre_run_cmd",
+    "post_run_cmd",
+    "post_purge_cmd",
+    "notify",
+    "metrics",
+    "otlp",
+    "log_file",
+    "log_rotation",
+    "log_files_kept",
+];
 
-    let mut s = String::new();
-    let content = if path.exists() {
-        let mut file = File::open(path)?;
-        file.read_to_string(&mut s)?;
-        &s
+/// Profiles of the configuration file selected, overriding its top-level keys
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Profile<'a> {
+    /// Name of the `[sites.<name>]` table
+    pub site: Option<&'a str>,
+    /// Name of the `[env.<name>]` table
+    pub env: Option<&'a str>,
+}
+
+impl Profile<'_> {
+    /// Name of the profile selected in the given table of profiles
+    fn name(&self, profiles_key: &str) -> Option<&str> {
+        match profiles_key {
+            SITES_KEY => self.site,
+            _ => self.env,
+        }
+    }
+}
+
+/// Load the configuration from the given file, or from the default file if none is given.
+/// Relative paths in the configuration are relative to the directory of the file, or to the
+/// current directory for environment variables.
+pub fn load(path: Option<&Path>, profile: Profile, search_from: Option<&Path>) -> Result<Config> {
+    let (content, path) = read(path, search_from)?;
+    from_content(&content, &path, profile)
+}
+
+/// Content and path of the given configuration file, or of the default one, searched from the
+/// given directory (typically the root directory) and the current directory
+pub fn read(path: Option<&Path>, search_from: Option<&Path>) -> Result<(String, PathBuf)> {
+    let (content, path) = read_raw(path, search_from)?;
+    Ok((decrypt(content, &path)?, path))
+}
+
+/// Like [`read`], without decrypting the content
+pub fn read_raw(path: Option<&Path>, search_from: Option<&Path>) -> Result<(String, PathBuf)> {
+    match path {
+        Some(path) => Ok((
+            fs::read_to_string(path)
+                .with_context(|| format!("failed to read configuration file {path:?}"))?,
+            path.to_owned(),
+        )),
+        None => {
+            let (content, path) = load_default(search_from)?;
+            info!("Using the configuration in {}", path.display());
+            Ok((content, path))
+        }
+    }
+}
+
+/// The content of the file, decrypted with sops if it’s encrypted. sops doesn’t support TOML.
+fn decrypt(content: String, path: &Path) -> Result<String> {
+    let format = Format::from_path(path);
+    let sops_format = match format {
+        Format::Toml => return Ok(content),
+        Format::Yaml => "yaml",
+        Format::Json => "json",
+    };
+    if !is_sops_encrypted(&content, format) {
+        return Ok(content);
+    }
+    debug!("decrypting {} with sops", path.display());
+    secret::sops_decrypt(path, sops_format)
+}
+
+/// Whether the content has the metadata added by sops. Syntax errors are reported later.
+pub fn is_sops_encrypted(content: &str, format: Format) -> bool {
+    format
+        .table(content)
+        .is_ok_and(|table| table.contains_key(secret::SOPS_KEY))
+}
+
+/// Format of the configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl Format {
+    /// Format from the extension of the file, TOML being the default
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("json") => Format::Json,
+            _ => Format::Toml,
+        }
+    }
+
+    fn table(self, content: &str) -> Result<Map<String, Value>> {
+        Ok(match self {
+            Format::Toml => basic_toml::from_str(content)?,
+            Format::Yaml => serde_yaml_ng::from_str(content)?,
+            Format::Json => serde_json::from_str(content)?,
+        })
+    }
+
+    /// Whether the line sets the key, as a best effort for error messages
+    fn is_key(self, line: &str, key: &str) -> bool {
+        let line = line.trim_start();
+        let (rest, separator) = match self {
+            Format::Toml => (line.strip_prefix(key), '='),
+            Format::Yaml => (line.strip_prefix(key), ':'),
+            Format::Json => (
+                line.strip_prefix('"')
+                    .and_then(|line| line.strip_prefix(key))
+                    .and_then(|line| line.strip_prefix('"')),
+                ':',
+            ),
+        };
+        rest.is_some_and(|rest| rest.trim_start().starts_with(separator))
+    }
+}
+
+/// Configuration from the content of the file at the given path
+pub fn from_content(content: &str, path: &Path, profile: Profile) -> Result<Config> {
+    let format = Format::from_path(path);
+    let mut config = parse(content, format, profile, env_overrides(env::vars())?)?;
+
+    // Paths from the environment are already absolute
+    let dir = path.parent().unwrap_or(Path::new(""));
+    config.root_dirs = config.root_dirs.iter().map(|d| dir.join(d)).collect();
+    config.db_path = config.db_path.map(|db_path| dir.join(db_path));
+    config.api_token_file = config.api_token_file.map(|path| dir.join(path));
+    config.age_identity = config.age_identity.map(|path| dir.join(path));
+    config.log_file = config.log_file.map(|path| dir.join(path));
+    if let Some(metrics) = &mut config.metrics {
+        metrics.textfile = metrics.textfile.take().map(|path| dir.join(path));
+    }
+    config.path = path.to_owned();
+    Ok(config)
+}
+
+/// Configuration keys set by the environment variables. Lists of paths are separated like in
+/// `PATH`, lists of globs by commas and lists of regular expressions by line feeds.
+fn env_overrides(vars: impl IntoIterator<Item = (String, String)>) -> Result<Map<String, Value>> {
+    let absolute =
+        |path| -> Result<Value> { Ok(std::path::absolute(path)?.to_string_lossy().into()) };
+
+    let mut overrides = Map::new();
+    for (name, value) in vars {
+        let Some(key) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let key = key.to_lowercase();
+        let value = match key.as_str() {
+            SITES_KEY | ENVS_KEY => bail!("{name} can’t be set from the environment"),
+            "threads" | "cdn_concurrency" | "confirm_threshold" | "batch_size"
+            | "log_files_kept" => value
+                .parse::<usize>()
+                .with_context(|| format!("{name} is not a number"))?
+                .into(),
+            "max_hash_size" => value
+                .parse::<u64>()
+                .with_context(|| format!("{name} is not a number"))?
+                .into(),
+            "force_deep_check"
+            | "detect_unreliable_mtimes"
+            | "skip_unchanged_dirs"
+            | "fast_hash"
+            | "dry_run"
+            | "hash_decompressed"
+            | "io_uring"
+            | "record_cdn_etags" => value
+                .parse::<bool>()
+                .with_context(|| format!("{name} is neither true nor false"))?
+                .into(),
+            "read_size" | "log_rotation" => match value.parse::<usize>() {
+                Ok(bytes) => bytes.into(),
+                Err(_) => value.into(),
+            },
+            "exclude" => value.split(',').collect(),
+            "html_ignore" => value.lines().collect(),
+            "db_path" | "api_token_file" | "age_identity" | "log_file" => {
+                absolute(PathBuf::from(value))?
+            }
+            "root_dirs" => Value::Array(
+                env::split_paths(&value)
+                    .map(absolute)
+                    .collect::<Result<_>>()?,
+            ),
+            _ => value.into(),
+        };
+        overrides.insert(key, value);
+    }
+    Ok(overrides)
+}
+
+/// Parse the configuration, with the keys of the selected profiles overriding the top-level ones
+/// and the overrides taking precedence over all of them
+fn parse(
+    content: &str,
+    format: Format,
+    profile: Profile,
+    overrides: Map<String, Value>,
+) -> Result<Config> {
+    let mut table = format.table(content)?;
+    if migration::migrate(&mut table)? {
+        warn!(
+            "the configuration uses an outdated layout, run `{} config migrate` to upgrade it",
+            env!("CARGO_PKG_NAME")
+        );
+    }
+    table.remove(migration::VERSION_KEY);
+    let mut profiles = PROFILE_KEYS.map(|key| table.remove(key));
+    // Profiles selected after the table setting the database, which would otherwise share it
+    let mut sharing_db: Vec<&str> = Vec::new();
+    for (key, profiles) in PROFILE_KEYS.iter().zip(&mut profiles) {
+        if let Some(name) = profile.name(key) {
+            let Some(Value::Object(profile_table)) = profiles
+                .as_mut()
+                .and_then(|profiles| profiles.get_mut(name))
+                .map(Value::take)
+            else {
+                bail!("no [{key}.{name}] table in the configuration");
+            };
+            if profile_table.contains_key(DB_PATH_KEY) {
+                sharing_db.clear();
+            } else {
+                sharing_db.push(name);
+            }
+            table.extend(profile_table);
+        }
+    }
+    if let (Some(Value::String(db_path)), false) =
+        (table.get_mut(DB_PATH_KEY), sharing_db.is_empty())
+    {
+        *db_path = profile_db_path(db_path, &sharing_db.join("."));
+    }
+    for (key, value) in &mut table {
+        interpolate(value, &|name| env::var(name).ok())
+            .with_context(|| format!("failed to expand `{key}`"))?;
+    }
+    table.extend(overrides);
+    // Keys of older layouts can still be set by environment variables, like STATIC_CDN_SITE_UUID
+    migration::rename_keys(&mut table, 1);
+    serde_json::from_value(Value::Object(table)).map_err(|e| {
+        let [sites, envs] = &profiles;
+        if profile.site.is_none() && sites.is_some() {
+            anyhow!("{e}, you may need to select a site with --site")
+        } else if profile.env.is_none() && envs.is_some() {
+            anyhow!("{e}, you may need to select an environment with --env")
+        } else {
+            e.into()
+        }
+    })
+}
+
+/// Path of the database of the profile, from the one shared by all the profiles, with the name of
+/// the profile before the extension. Profiles would otherwise overwrite the state of one another
+/// for identical paths.
+fn profile_db_path(shared: &str, name: &str) -> String {
+    let shared = Path::new(shared);
+    let mut file_name = shared.file_stem().unwrap_or_default().to_owned();
+    file_name.push(".");
+    file_name.push(name);
+    if let Some(extension) = shared.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    shared
+        .with_file_name(file_name)
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Replace `${VAR}` in the strings of the value with the variable, looked up with `var`. `$${`
+/// is kept as a literal `${`.
+fn interpolate(value: &mut Value, var: &impl Fn(&str) -> Option<String>) -> Result<()> {
+    match value {
+        Value::String(string) if string.contains("${") => {
+            let mut expanded = String::with_capacity(string.len());
+            let mut rest = string.as_str();
+            while let Some(start) = rest.find("${") {
+                if let Some(before) = rest[..start].strip_suffix('$') {
+                    expanded.push_str(before);
+                    expanded.push_str("${");
+                    rest = &rest[start + 2..];
+                    continue;
+                }
+                expanded.push_str(&rest[..start]);
+                let Some(end) = rest[start..].find('}') else {
+                    bail!("missing `}}` after `${{` in {string:?}");
+                };
+                let name = &rest[start + 2..start + end];
+                match var(name) {
+                    Some(value) => expanded.push_str(&value),
+                    None => bail!("environment variable {name} is not set"),
+                }
+                rest = &rest[start + end + 1..];
+            }
+            expanded.push_str(rest);
+            *string = expanded;
+        }
+        Value::Array(values) => {
+            for value in values {
+                interpolate(value, var)?;
+            }
+        }
+        Value::Object(table) => {
+            for value in table.values_mut() {
+                interpolate(value, var)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Problem found in the configuration file, on the given line when it is known
+#[derive(Debug)]
+pub struct Problem {
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "line {line}: {}", self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+/// Keys of the configuration that are not used, typically because of a typo, in the top-level
+/// table and in all the profiles. Fails on syntax errors.
+pub fn unknown_keys(content: &str, format: Format) -> Result<Vec<Problem>> {
+    let table = format.table(content)?;
+    let mut problems = Vec::new();
+    let mut check = |table: &Map<String, Value>, profile: Option<(&str, &str)>| {
+        for key in table.keys() {
+            let message = if migration::is_renamed(key) {
+                format!(
+                    "`{key}` is outdated, run `{} config migrate` to upgrade it",
+                    env!("CARGO_PKG_NAME")
+                )
+            } else if !KEYS.contains(&key.as_str()) {
+                format!("unknown key `{key}`")
+            } else {
+                continue;
+            };
+            problems.push(Problem {
+                line: key_line(content, format, profile, key),
+                message,
+            });
+        }
+    };
+
+    let mut top_level = table;
+    let profiles = PROFILE_KEYS.map(|key| top_level.remove(key));
+    check(&top_level, None);
+    for (key, profiles) in PROFILE_KEYS.iter().zip(profiles) {
+        match profiles {
+            Some(Value::Object(profiles)) => {
+                for (name, profile) in &profiles {
+                    if let Value::Object(profile) = profile {
+                        check(profile, Some((key, name)));
+                    } else {
+                        bail!("`{key}.{name}` is not a table");
+                    }
+                }
+            }
+            Some(_) => bail!("`{key}` is not a table"),
+            None => (),
+        }
+    }
+    Ok(problems)
+}
+
+/// Line number of the key, in the given profile (table of profiles and name) or at the
+/// top-level. This is a best effort, for error messages, that doesn’t handle inline tables for
+/// instance.
+fn key_line(
+    content: &str,
+    format: Format,
+    profile: Option<(&str, &str)>,
+    key: &str,
+) -> Option<usize> {
+    let lines: Vec<&str> = content.lines().collect();
+    let start = match (profile, format) {
+        (None, _) => 0,
+        (Some((profiles, name)), Format::Toml) => {
+            let header = format!("[{profiles}.{name}]");
+            lines.iter().position(|line| line.trim() == header)? + 1
+        }
+        (Some((profiles, name)), Format::Yaml | Format::Json) => {
+            let start = lines
+                .iter()
+                .position(|line| format.is_key(line, profiles))?;
+            start
+                + lines[start..]
+                    .iter()
+                    .position(|line| format.is_key(line, name))?
+                + 1
+        }
+    };
+    lines
+        .iter()
+        .skip(start)
+        .position(|line| format.is_key(line, key))
+        .map(|index| start + index + 1)
+}
+
+/// Configuration file in the directory, in any of the supported formats
+fn find_in(dir: &Path) -> Option<PathBuf> {
+    FILE_NAMES
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+/// Closest configuration file in the directory or its ancestors, like git does for repositories
+fn find_upward(dir: &Path) -> Option<PathBuf> {
+    std::path::absolute(dir).ok()?.ancestors().find_map(find_in)
+}
+
+/// Content and path of the default configuration file. That’s the closest file found from the
+/// given directory or from the current directory, going up, or the file in the configuration
+/// directory, created with placeholder content if needed.
+fn load_default(search_from: Option<&Path>) -> Result<(String, PathBuf)> {
+    let found = search_from
+        .and_then(find_upward)
+        .or_else(|| find_upward(Path::new(".")));
+    if let Some(path) = found {
+        return Ok((fs::read_to_string(&path)?, path));
+    }
+    let path = match xdg::config_dir() {
+        Some(config_dir) => {
+            fs::create_dir_all(&config_dir)
+                .with_context(|| format!("failed to create {config_dir:?}"))?;
+            if let Some(path) = find_in(&config_dir) {
+                return Ok((fs::read_to_string(&path)?, path));
+            }
+            config_dir.join(PATH)
+        }
+        None => PathBuf::from(PATH),
+    };
+
+    if path.exists() {
+        Ok((fs::read_to_string(&path)?, path))
     } else {
-        let mut file = File::create(PATH)?;
+        let mut file = File::create(&path).with_context(|| format!("failed to create {path:?}"))?;
         file.write_all(DEFAULT_CONTENT.as_bytes())?;
-        DEFAULT_CONTENT
-    };
-    Ok(basic_toml::from_str(&content)?)
+        Ok((DEFAULT_CONTENT.to_owned(), path))
+    }
 }
 
 #[cfg(test)]
@@ -44,9 +786,394 @@ mod test {
 
     use super::*;
 
+    fn site(name: &str) -> Profile<'_> {
+        Profile {
+            site: Some(name),
+            env: None,
+        }
+    }
+
     #[test]
     fn default_config() -> Result<()> {
-        let _: Config = basic_toml::from_str(&DEFAULT_CONTENT)?;
+        let _: Config = parse(
+            DEFAULT_CONTENT,
+            Format::Toml,
+            Profile::default(),
+            Map::new(),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn site_profiles() -> Result<()> {
+        let content = r#"
+            api_token_cmd = "pass cloudflare"
+            threads = 4
+
+            [sites.blog]
+            base_url = "https://blog.example.com/"
+            root_dirs = ["blog/public"]
+            provider.cloudflare.zone_id = "blog-zone"
+
+            [sites.docs]
+            base_url = "https://docs.example.com/"
+            threads = 8
+            provider.fastly = {}
+        "#;
+
+        let blog = parse(content, Format::Toml, site("blog"), Map::new())?;
+        assert!(matches!(
+            blog.provider,
+            Provider::Cloudflare(cloudflare::Settings { ref zone_id, .. })
+                if zone_id.as_deref() == Some("blog-zone")
+        ));
+        assert_eq!(Some("pass cloudflare"), blog.api_token_cmd.as_deref());
+        assert_eq!(vec![PathBuf::from("blog/public")], blog.root_dirs);
+        assert_eq!(Some(4), blog.threads);
+
+        let docs = parse(content, Format::Toml, site("docs"), Map::new())?;
+        assert_eq!("https://docs.example.com/", docs.base_url.as_str());
+        assert_eq!(Some(8), docs.threads);
+        assert!(matches!(docs.provider, Provider::Fastly(_)));
+
+        assert!(parse(content, Format::Toml, Profile::default(), Map::new()).is_err());
+        assert!(parse(content, Format::Toml, site("shop"), Map::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn environment_profiles() -> Result<()> {
+        let content = r#"
+            base_url = "https://example.com/"
+            api_token_cmd = "pass cloudflare/production"
+            provider.cloudflare.zone_id = "production-zone"
+
+            [sites.blog]
+            base_url = "https://blog.example.com/"
+
+            [env.staging]
+            base_url = "https://staging.example.com/"
+            api_token_cmd = "pass cloudflare/staging"
+            provider.cloudflare.zone_id = "staging-zone"
+        "#;
+        let staging = |site| Profile {
+            site,
+            env: Some("staging"),
+        };
+
+        let production = parse(content, Format::Toml, Profile::default(), Map::new())?;
+        assert_eq!("https://example.com/", production.base_url.as_str());
+
+        let config = parse(content, Format::Toml, staging(None), Map::new())?;
+        assert_eq!("https://staging.example.com/", config.base_url.as_str());
+        assert_eq!(
+            Some("pass cloudflare/staging"),
+            config.api_token_cmd.as_deref()
+        );
+        assert!(matches!(
+            config.provider,
+            Provider::Cloudflare(cloudflare::Settings { ref zone_id, .. })
+                if zone_id.as_deref() == Some("staging-zone")
+        ));
+
+        // The environment takes precedence over the site
+        let config = parse(content, Format::Toml, staging(Some("blog")), Map::new())?;
+        assert_eq!("https://staging.example.com/", config.base_url.as_str());
+
+        let unknown = Profile {
+            site: None,
+            env: Some("preview"),
+        };
+        assert!(parse(content, Format::Toml, unknown, Map::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn profile_databases() -> Result<()> {
+        let content = r#"
+            base_url = "https://example.com/"
+            provider.cloudflare.zone_id = "zone"
+            db_path = "state/static-cdn.sqlite"
+
+            [sites.blog]
+            [sites.docs]
+            db_path = "docs.sqlite"
+
+            [env.staging]
+        "#;
+        let db_path = |site, env| -> Result<PathBuf> {
+            let config = parse(content, Format::Toml, Profile { site, env }, Map::new())?;
+            Ok(config.db_path.unwrap())
+        };
+        assert_eq!(Path::new("state/static-cdn.sqlite"), db_path(None, None)?);
+        assert_eq!(
+            Path::new("state/static-cdn.blog.sqlite"),
+            db_path(Some("blog"), None)?
+        );
+        assert_eq!(
+            Path::new("state/static-cdn.blog.staging.sqlite"),
+            db_path(Some("blog"), Some("staging"))?
+        );
+        // Set for the site alone
+        assert_eq!(Path::new("docs.sqlite"), db_path(Some("docs"), None)?);
+        assert_eq!(
+            Path::new("docs.staging.sqlite"),
+            db_path(Some("docs"), Some("staging"))?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn documented_keys_are_known() -> Result<()> {
+        // Uncomment the examples
+        let content: String = DEFAULT_CONTENT
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(example) if example.contains(" = ") || example.starts_with('[') => example,
+                _ => line,
+            })
+            .map(|line| format!("{line}\n"))
+            .collect();
+        assert!(unknown_keys(&content, Format::Toml)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_keys_with_lines() -> Result<()> {
+        let content = r#"
+            base_url = "https://example.com/"
+            treads = 4
+
+            [sites.blog]
+            base_url = "https://blog.example.com/"
+            root_dir = "public"
+
+            [env.staging]
+            zone = "staging"
+        "#;
+        let problems: Vec<_> = unknown_keys(content, Format::Toml)?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            vec![
+                "line 3: unknown key `treads`",
+                "line 7: unknown key `root_dir`",
+                "line 10: unknown key `zone`"
+            ],
+            problems
+        );
+        assert!(unknown_keys("base_url =", Format::Toml).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn yaml_and_json() -> Result<()> {
+        let yaml = r#"
+api_token_cmd: pass cloudflare
+provider:
+  cloudflare:
+    zone_id: zone
+sites:
+  blog:
+    base_url: https://blog.example.com/
+    threads: 2
+    treads: 3
+"#;
+        let config = parse(yaml, Format::Yaml, site("blog"), Map::new())?;
+        assert_eq!(Some(2), config.threads);
+        let problems: Vec<_> = unknown_keys(yaml, Format::Yaml)?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(vec!["line 10: unknown key `treads`"], problems);
+
+        let json = r#"{
+            "base_url": "https://example.com/",
+            "provider": { "fastly": {} },
+            "treads": 3
+        }"#;
+        let config = parse(json, Format::Json, Profile::default(), Map::new())?;
+        assert!(matches!(config.provider, Provider::Fastly(_)));
+        let problems: Vec<_> = unknown_keys(json, Format::Json)?
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(vec!["line 4: unknown key `treads`"], problems);
+        Ok(())
+    }
+
+    #[test]
+    fn providers() -> Result<()> {
+        let legacy = r#"
+            site_uuid = "zone"
+            base_url = "https://example.com/"
+        "#;
+        let config = parse(legacy, Format::Toml, Profile::default(), Map::new())?;
+        assert!(matches!(
+            config.provider,
+            Provider::Cloudflare(cloudflare::Settings { ref zone_id, .. })
+                if zone_id.as_deref() == Some("zone")
+        ));
+
+        let fastly = r#"
+            base_url = "https://example.com/"
+            [provider.fastly]
+            soft_purge = true
+        "#;
+        let config = parse(fastly, Format::Toml, Profile::default(), Map::new())?;
+        assert!(matches!(
+            config.provider,
+            Provider::Fastly(fastly::Settings { soft_purge: true })
+        ));
+
+        let unknown_setting = r#"
+            base_url = "https://example.com/"
+            [provider.cloudflare]
+            zone_id = "zone"
+            zone = "typo"
+        "#;
+        assert!(parse(
+            unknown_setting,
+            Format::Toml,
+            Profile::default(),
+            Map::new()
+        )
+        .is_err());
+        let several = r#"
+            base_url = "https://example.com/"
+            provider.cloudflare.zone_id = "zone"
+            provider.fastly = {}
+        "#;
+        assert!(parse(several, Format::Toml, Profile::default(), Map::new()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn sops_encrypted() {
+        let yaml = "
+base_url: ENC[AES256_GCM,data:bm90IGEgcmVhbCBvbmU=,type:str]
+sops:
+    version: 3.9.0
+";
+        assert!(is_sops_encrypted(yaml, Format::Yaml));
+        assert!(!is_sops_encrypted(
+            "base_url: https://example.com/",
+            Format::Yaml
+        ));
+        assert!(!is_sops_encrypted(DEFAULT_CONTENT, Format::Toml));
+    }
+
+    #[test]
+    fn interpolation() {
+        let var = |name: &str| (name == "BRANCH").then(|| "main".to_owned());
+        let expand = |string: &str| {
+            let mut value = serde_json::json!({ "key": [string] });
+            interpolate(&mut value, &var).map(|()| value["key"][0].as_str().unwrap().to_owned())
+        };
+        assert_eq!(
+            "https://main.example.com/",
+            expand("https://${BRANCH}.example.com/").unwrap()
+        );
+        assert_eq!("${BRANCH}-main", expand("$${BRANCH}-${BRANCH}").unwrap());
+        assert_eq!("$HOME $ {}", expand("$HOME $ {}").unwrap());
+        assert_eq!(
+            "environment variable TOKEN is not set",
+            expand("echo ${TOKEN}").unwrap_err().to_string()
+        );
+        assert!(expand("${BRANCH").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn api_token_first_line() -> Result<()> {
+        let mut config = parse(
+            DEFAULT_CONTENT,
+            Format::Toml,
+            Profile::default(),
+            Map::new(),
+        )?;
+        config.api_token_cmd = Some("printf 'token\\nrest'".to_owned());
+        assert_eq!("token", config.api_token()?);
+
+        config.api_token_cmd = Some("true".to_owned());
+        assert!(config.api_token().is_err());
+        config.api_token_cmd = Some("echo token; false".to_owned());
+        assert!(config.api_token().is_err());
+
+        config.api_token = Some("from env".to_owned());
+        assert_eq!("from env", config.api_token()?);
         Ok(())
     }
+
+    #[test]
+    fn api_token_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("token");
+        let mut config = parse(
+            DEFAULT_CONTENT,
+            Format::Toml,
+            Profile::default(),
+            Map::new(),
+        )?;
+        config.api_token_file = Some(path.clone());
+        assert!(config.api_token().is_err());
+
+        fs::write(&path, "token\n")?;
+        assert_eq!("token", config.api_token()?);
+        fs::write(&path, "\n")?;
+        assert!(config.api_token().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn environment_overrides() -> Result<()> {
+        let vars = [
+            ("STATIC_CDN_BASE_URL", "https://cdn.example.com/"),
+            ("STATIC_CDN_API_TOKEN", "secret"),
+            ("STATIC_CDN_THREADS", "3"),
+            ("STATIC_CDN_ROOT_DIRS", "/srv/public"),
+            ("STATIC_CDN_DRY_RUN", "true"),
+            ("STATIC_CDN_EXCLUDE", "*.map,drafts"),
+            ("STATIC_CDN_HTML_IGNORE", "id=\"[a-z]{1,8}\"\nnonce"),
+            ("HOME", "/root"),
+        ]
+        .map(|(name, value)| (name.to_owned(), value.to_owned()));
+        let config = parse(
+            DEFAULT_CONTENT,
+            Format::Toml,
+            Profile::default(),
+            env_overrides(vars)?,
+        )?;
+        assert_eq!("https://cdn.example.com/", config.base_url.as_str());
+        assert_eq!("secret", config.api_token()?);
+        assert_eq!(Some(3), config.threads);
+        assert_eq!(vec![PathBuf::from("/srv/public")], config.root_dirs);
+        assert!(config.dry_run);
+        assert_eq!(vec!["*.map", "drafts"], config.exclude);
+        assert_eq!(vec!["id=\"[a-z]{1,8}\"", "nonce"], config.html_ignore);
+
+        let vars = [("STATIC_CDN_THREADS".to_owned(), "many".to_owned())];
+        assert!(env_overrides(vars).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn found_in_ancestors() -> Result<()> {
+        let project = tempfile::tempdir()?;
+        let nested = project.path().join("public/blog");
+        fs::create_dir_all(&nested)?;
+        assert_eq!(None, find_upward(&nested));
+
+        fs::write(project.path().join(PATH), DEFAULT_CONTENT)?;
+        assert_eq!(Some(project.path().join(PATH)), find_upward(&nested));
+        Ok(())
+    }
+
+    #[test]
+    fn explicit_path_is_not_created() {
+        let path = Path::new("/made_up/for_testing/static-cdn.toml");
+        assert!(load(Some(path), Profile::default(), None).is_err());
+        assert!(!path.exists());
+    }
 }
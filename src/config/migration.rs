@@ -0,0 +1,206 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Upgrade of configuration files written for an older layout. Files are migrated in memory when
+//! they are read, and rewritten by `config migrate`.
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+
+use super::{Format, PROFILE_KEYS};
+
+/// Key holding the version of the layout of the configuration
+pub const VERSION_KEY: &str = "version";
+/// Version of the layout of the configuration, increased with every migration
+pub const CURRENT_VERSION: u64 = 2;
+
+/// Changes of the layout from the previous version
+struct Migration {
+    /// Version of the layout after the migration
+    to: u64,
+    /// Keys moved, with their new path of dotted keys
+    renames: &'static [(&'static str, &'static str)],
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    to: 2,
+    // Cloudflare was the only provider before the `[provider.<name>]` tables
+    renames: &[("site_uuid", "provider.cloudflare.zone_id")],
+}];
+
+/// Version of the layout of the table. Files without a version have the first one.
+pub fn version(table: &Map<String, Value>) -> Result<u64> {
+    let Some(version) = table.get(VERSION_KEY) else {
+        return Ok(1);
+    };
+    match version.as_u64() {
+        Some(version) if version > CURRENT_VERSION => bail!(
+            "the configuration has version {version}, written for a newer {}",
+            env!("CARGO_PKG_NAME")
+        ),
+        Some(version) if version > 0 => Ok(version),
+        _ => bail!("`{VERSION_KEY}` is not a valid version: {version}"),
+    }
+}
+
+/// Migrate the table and its profiles to the current version, which is then set. Returns whether
+/// anything was moved.
+pub fn migrate(table: &mut Map<String, Value>) -> Result<bool> {
+    let version = version(table)?;
+    let mut moved = rename_keys(table, version);
+    for key in PROFILE_KEYS {
+        if let Some(Value::Object(profiles)) = table.get_mut(key) {
+            for profile in profiles.values_mut() {
+                if let Value::Object(profile) = profile {
+                    moved |= rename_keys(profile, version);
+                }
+            }
+        }
+    }
+    table.insert(VERSION_KEY.to_owned(), CURRENT_VERSION.into());
+    Ok(moved)
+}
+
+/// Move the keys renamed since the version, in this table only. Returns whether anything was
+/// moved.
+pub fn rename_keys(table: &mut Map<String, Value>, version: u64) -> bool {
+    let mut moved = false;
+    for (old, new) in renames(version) {
+        let Some(value) = table.remove(old) else {
+            continue;
+        };
+        insert(table, new, value);
+        moved = true;
+    }
+    moved
+}
+
+/// Insert the value at the path of dotted keys, creating the missing tables
+fn insert(table: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        None => {
+            table.insert(path.to_owned(), value);
+        }
+        Some((key, rest)) => {
+            let entry = table
+                .entry(key)
+                .or_insert_with(|| Value::Object(Map::new()));
+            // Otherwise, deserialization fails anyway
+            if let Value::Object(child) = entry {
+                insert(child, rest, value);
+            }
+        }
+    }
+}
+
+/// Whether the key was renamed by a migration
+pub fn is_renamed(key: &str) -> bool {
+    renames(0).any(|(old, _)| old == key)
+}
+
+fn renames(version: u64) -> impl Iterator<Item = (&'static str, &'static str)> {
+    MIGRATIONS
+        .iter()
+        .filter(move |migration| migration.to > version)
+        .flat_map(|migration| migration.renames.iter().copied())
+}
+
+/// Content of the configuration file migrated to the current version, or `None` when it’s up to
+/// date. Comments of TOML files are kept, as keys are renamed line by line.
+pub fn migrated_content(content: &str, format: Format) -> Result<Option<String>> {
+    let mut table = format.table(content)?;
+    let version = version(&table)?;
+    if version == CURRENT_VERSION {
+        return Ok(None);
+    }
+    migrate(&mut table)?;
+    let migrated = match format {
+        Format::Toml => {
+            let migrated = rewrite_toml(content, version);
+            if format.table(&migrated).ok().as_ref() != Some(&table) {
+                bail!(
+                    "failed to migrate the file, which uses unsupported syntax, migrate it by hand"
+                );
+            }
+            migrated
+        }
+        Format::Yaml => serde_yaml_ng::to_string(&table)?,
+        Format::Json => serde_json::to_string_pretty(&table)? + "\n",
+    };
+    Ok(Some(migrated))
+}
+
+/// Rename the keys of the TOML file, with dotted keys for the nested ones, and set its version
+fn rewrite_toml(content: &str, version: u64) -> String {
+    let version_line = format!("{VERSION_KEY} = {CURRENT_VERSION}");
+    let mut has_version = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| {
+            if Format::Toml.is_key(line, VERSION_KEY) {
+                has_version = true;
+                return version_line.clone();
+            }
+            renames(version)
+                .find(|(old, _)| Format::Toml.is_key(line, old))
+                .map_or_else(|| line.to_owned(), |(old, new)| line.replacen(old, new, 1))
+        })
+        .collect();
+    if !has_version {
+        lines.insert(0, version_line);
+    }
+    lines.iter().map(|line| format!("{line}\n")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn site_uuid() -> Result<()> {
+        let content = r#"# Old layout
+site_uuid = "zone"
+base_url = "https://example.com/"
+
+[sites.blog]
+  site_uuid = "blog-zone"
+"#;
+        let migrated = migrated_content(content, Format::Toml)?.unwrap();
+        assert_eq!(
+            r#"version = 2
+# Old layout
+provider.cloudflare.zone_id = "zone"
+base_url = "https://example.com/"
+
+[sites.blog]
+  provider.cloudflare.zone_id = "blog-zone"
+"#,
+            migrated
+        );
+        assert_eq!(None, migrated_content(&migrated, Format::Toml)?);
+
+        let yaml = migrated_content("site_uuid: zone\n", Format::Yaml)?.unwrap();
+        assert_eq!(
+            "provider:\n  cloudflare:\n    zone_id: zone\nversion: 2\n",
+            yaml
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn versions() {
+        let table = |version: Value| {
+            let mut table = Map::new();
+            table.insert(VERSION_KEY.to_owned(), version);
+            table
+        };
+        assert_eq!(1, version(&Map::new()).unwrap());
+        assert_eq!(2, version(&table(2.into())).unwrap());
+        assert!(version(&table(3.into())).is_err());
+        assert!(version(&table("2".into())).is_err());
+    }
+}
@@ -5,16 +5,23 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::fs::Metadata;
+use std::collections::HashMap;
+use std::fs::{self, File, Metadata, TryLockError};
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
-use std::time::UNIX_EPOCH;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+use anyhow::{bail, Context as _};
+use rusqlite::types::ToSqlOutput;
+use rusqlite::ErrorCode;
 use rusqlite::Result;
-use rusqlite::{params, Connection, Transaction};
+use rusqlite::{params, Connection, DatabaseName, ToSql, Transaction};
 use rusqlite_migration::{Migrations, M};
+use serde_derive::Deserialize;
 
+use crate::checksum::{Algorithm, Scheme};
 use crate::rel_path::RelPath;
-use crate::Checksum;
+use crate::{xdg, Checksum};
 
 #[cfg(test)]
 mod tests;
@@ -23,123 +30,1000 @@ static MIGRATIONS: LazyLock<Migrations<'static>> = LazyLock::new(|| {
     Migrations::new(vec![
         M::up(include_str!("db/1_up.sql")),
         M::up(include_str!("db/2_up.sql")),
+        M::up(include_str!("db/3_up.sql")),
+        M::up(include_str!("db/4_up.sql")),
+        M::up(include_str!("db/5_up.sql")),
+        M::up(include_str!("db/6_up.sql")),
+        M::up(include_str!("db/7_up.sql")),
+        M::up(include_str!("db/8_up.sql")),
+        M::up(include_str!("db/9_up.sql")),
+        M::up(include_str!("db/10_up.sql")),
+        M::up(include_str!("db/11_up.sql")),
+        M::up(include_str!("db/12_up.sql")),
+        M::up(include_str!("db/13_up.sql")),
+        M::up(include_str!("db/14_up.sql")),
+        M::up(include_str!("db/15_up.sql")),
+        M::up(include_str!("db/16_up.sql")),
+        M::up(include_str!("db/17_up.sql")),
     ])
 });
 
-static DB_NAME: &'static str = concat!("./", env!("CARGO_PKG_NAME"), ".sqlite");
+const FILE_NAME: &str = concat!(env!("CARGO_PKG_NAME"), ".sqlite");
 
-// Set up a connection, with PRAGMAs and schema migrations
-fn setup(mut conn: Connection) -> anyhow::Result<Connection> {
-    // WAL mode is required to for concurrent read
-    conn.execute_batch(
-        "PRAGMA journal_mode = WAL; \
-         PRAGMA synchronous = NORMAL; \
-         PRAGMA locking_mode = EXCLUSIVE; \
+/// Database in the current directory, where older versions created it for all the sites, if there
+/// is one
+pub fn legacy_path() -> Option<PathBuf> {
+    let in_current_dir = PathBuf::from(FILE_NAME);
+    in_current_dir.exists().then_some(in_current_dir)
+}
+
+/// Default location of the database, when neither the command line nor the configuration set
+/// one. That’s a database in the state directory, with one subdirectory per site, or in the
+/// current directory, named after the site, when there is no state directory.
+pub fn default_path(site: &str) -> anyhow::Result<PathBuf> {
+    let Some(state_dir) = xdg::state_dir() else {
+        return Ok(PathBuf::from(format!(
+            "{}.{site}.sqlite",
+            env!("CARGO_PKG_NAME")
+        )));
+    };
+    let dir = state_dir.join(site);
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {dir:?}"))?;
+    Ok(dir.join(FILE_NAME))
+}
+
+/// Wait for the locks held by other connections, like the ones of the other threads, by default
+const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+/// Changes committed at once by default
+const DEFAULT_TRANSACTION_SIZE: usize = 10_000;
+
+/// Settings of the `[sqlite]` table of the configuration, tuning the PRAGMAs of the connections.
+/// See https://www.sqlite.org/pragma.html
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Defaults to WAL, letting other processes read while the database is written
+    pub journal_mode: Option<JournalMode>,
+    /// Defaults to normal, which is safe in WAL mode
+    pub synchronous: Option<Synchronous>,
+    /// Milliseconds to wait for the locks held by other connections before failing
+    pub busy_timeout: Option<u64>,
+    /// Pages of cache when positive, KiB when negative, like the PRAGMA
+    pub cache_size: Option<i64>,
+    /// Maximum number of files changed by a write transaction. Smaller transactions release the
+    /// lock sooner and keep the write-ahead log small, larger ones are faster.
+    pub transaction_size: Option<usize>,
+}
+
+impl Settings {
+    pub fn transaction_size(&self) -> usize {
+        self.transaction_size
+            .unwrap_or(DEFAULT_TRANSACTION_SIZE)
+            .max(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JournalMode {
+    Delete,
+    Truncate,
+    Persist,
+    Memory,
+    #[default]
+    Wal,
+    Off,
+}
+
+impl JournalMode {
+    fn name(self) -> &'static str {
+        match self {
+            JournalMode::Delete => "DELETE",
+            JournalMode::Truncate => "TRUNCATE",
+            JournalMode::Persist => "PERSIST",
+            JournalMode::Memory => "MEMORY",
+            JournalMode::Wal => "WAL",
+            JournalMode::Off => "OFF",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Synchronous {
+    Off,
+    #[default]
+    Normal,
+    Full,
+    Extra,
+}
+
+impl Synchronous {
+    fn name(self) -> &'static str {
+        match self {
+            Synchronous::Off => "OFF",
+            Synchronous::Normal => "NORMAL",
+            Synchronous::Full => "FULL",
+            Synchronous::Extra => "EXTRA",
+        }
+    }
+}
+
+// Set up a connection, with PRAGMAs and schema migrations. A run uses a single connection: the
+// threads hashing files work from what [`known_files`] loads, instead of opening their own.
+fn setup(mut conn: Connection, settings: &Settings) -> anyhow::Result<Connection> {
+    // Set first, as changing the journal mode takes a lock
+    conn.busy_timeout(Duration::from_millis(
+        settings.busy_timeout.unwrap_or(DEFAULT_BUSY_TIMEOUT_MS),
+    ))?;
+    conn.execute_batch(&format!(
+        "PRAGMA journal_mode = {}; \
+         PRAGMA synchronous = {}; \
          PRAGMA temp_store = MEMORY;",
-    )?;
+        settings.journal_mode.unwrap_or_default().name(),
+        settings.synchronous.unwrap_or_default().name(),
+    ))?;
+    if let Some(cache_size) = settings.cache_size {
+        conn.pragma_update(None, "cache_size", cache_size)?;
+    }
 
     MIGRATIONS.to_latest(&mut conn)?;
 
     Ok(conn)
 }
 
-pub fn open() -> anyhow::Result<Connection> {
-    let conn = Connection::open(DB_NAME)?;
-    setup(conn)
+pub fn open(path: &Path, settings: &Settings) -> anyhow::Result<Connection> {
+    let conn = Connection::open(path)?;
+    setup(conn, settings)
+}
+
+/// Lock the database for a run, so that concurrent runs don’t race to write it. The lock is held
+/// on a file next to the database until the returned file is dropped, even if the process is
+/// killed. Returns `None` when another process holds the lock.
+pub fn lock(path: &Path) -> anyhow::Result<Option<File>> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let file =
+        File::create(&lock_path).with_context(|| format!("failed to create {lock_path:?}"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => {
+            Err(e).with_context(|| format!("failed to lock {lock_path:?}"))
+        }
+    }
+}
+
+/// Problems found by a quick check of the integrity of the database, none when it’s sound
+pub fn quick_check(conn: &Connection) -> Result<Vec<String>> {
+    let mut stmt = conn.prepare("PRAGMA quick_check")?;
+    let rows = stmt.query_map([], |row| row.get::<_, String>(0))?;
+    rows.filter(|row| !matches!(row.as_deref(), Ok("ok")))
+        .collect()
+}
+
+/// Whether the error comes from a corrupted database, or from a file that isn’t a database at all
+pub fn is_corruption(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        matches!(
+            cause.downcast_ref::<rusqlite::Error>(),
+            Some(rusqlite::Error::SqliteFailure(e, _))
+                if matches!(e.code, ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase)
+        )
+    })
+}
+
+/// Move the database and its write-ahead log to `<path>.corrupt`, so that a new database is
+/// created in its place. Returns where the database was moved.
+pub fn set_aside(path: &Path) -> anyhow::Result<PathBuf> {
+    let with_suffix = |path: &Path, suffix: &str| {
+        let mut path = path.as_os_str().to_owned();
+        path.push(suffix);
+        PathBuf::from(path)
+    };
+    let aside = with_suffix(path, ".corrupt");
+    for suffix in ["", "-wal"] {
+        let from = with_suffix(path, suffix);
+        if from.exists() {
+            let to = with_suffix(&aside, suffix);
+            fs::rename(&from, &to).with_context(|| format!("failed to move {from:?} to {to:?}"))?;
+        }
+    }
+    // Only holds locks, it’s recreated
+    let shm = with_suffix(path, "-shm");
+    if shm.exists() {
+        fs::remove_file(&shm).with_context(|| format!("failed to remove {shm:?}"))?;
+    }
+    Ok(aside)
+}
+
+/// Where the snapshot of the database with this name is kept, `<path>.snapshots/<name>.sqlite`
+pub fn snapshot_path(path: &Path, name: &str) -> anyhow::Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        bail!("{name:?} can’t be the name of a snapshot, as it’s not a valid file name");
+    }
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".snapshots");
+    Ok(PathBuf::from(dir).join(format!("{name}.sqlite")))
+}
+
+/// Copy a consistent state of the database to the file, with the backup API of SQLite
+pub fn backup(conn: &Connection, to: &Path) -> Result<()> {
+    conn.backup(DatabaseName::Main, to, None)
+}
+
+/// Replace the content of the database with the one of the file, written by [`backup`], and
+/// upgrade its schema, as it may be older
+pub fn restore(conn: &mut Connection, from: &Path) -> anyhow::Result<()> {
+    conn.restore(DatabaseName::Main, from, None::<fn(_)>)?;
+    MIGRATIONS.to_latest(conn)?;
+    Ok(())
 }
 
 /// In memory transient database
 #[cfg(test)]
 pub fn open_transient() -> anyhow::Result<Connection> {
     let conn = Connection::open_in_memory()?;
-    setup(conn)
+    setup(conn, &Settings::default())
 }
 
-pub fn exists_by_metadata(
-    conn: &mut Connection,
-    path: &RelPath,
-    metadata_values: &MetadataValues,
-) -> Result<bool> {
+/// What’s stored about a tracked file, to tell whether it changed
+#[derive(Debug)]
+pub struct Known {
+    metadata_values: MetadataValues,
+    checksum: Checksum,
+    checksum_scheme: Scheme,
+    confirmed: bool,
+    /// Start of the run that last checked the file
+    checked_since_epoch_sec: Option<f64>,
+}
+
+/// Coarsest precision of the modification times of the file systems, that of FAT
+const MTIME_GRANULARITY_SEC: f64 = 2.;
+
+impl Known {
+    /// Whether the file is unchanged, from its metadata, and its purge confirmed
+    pub fn same_metadata(&self, metadata_values: &MetadataValues) -> bool {
+        self.confirmed && !self.racy() && self.metadata_values.same_as(metadata_values)
+    }
+
+    /// Whether the file was modified so shortly before it was last checked that later writes may
+    /// have kept the same modification time, on file systems storing it coarsely
+    fn racy(&self) -> bool {
+        self.checked_since_epoch_sec.is_some_and(|checked| {
+            self.metadata_values.modified_since_epoch_sec + MTIME_GRANULARITY_SEC > checked
+        })
+    }
+
+    /// Why the metadata of the file on disk don’t tell that it’s unchanged, for humans
+    pub fn why_check(&self, metadata_values: &MetadataValues) -> String {
+        let mut reasons = self.metadata_values.differences(metadata_values);
+        if !self.confirmed {
+            reasons.push("its purge isn’t confirmed".to_owned());
+        }
+        if self.racy() {
+            reasons.push("it was modified shortly before its last check".to_owned());
+        }
+        reasons.join(", ")
+    }
+
+    /// How the content of the file compares to the stored one, for humans
+    pub fn content_difference(
+        &self,
+        metadata_values: &MetadataValues,
+        checksum: Checksum,
+    ) -> String {
+        if self.checksum != checksum {
+            format!("checksum differs: {} → {checksum}", self.checksum)
+        } else if !self.checksum_scheme.decompressed
+            && self.metadata_values.size != metadata_values.size
+        {
+            "checksum matches, but size differs".to_owned()
+        } else {
+            "checksum matches".to_owned()
+        }
+    }
+
+    /// Whether the file has a status change time or an inode number that isn’t stored yet, like
+    /// for files tracked by older versions
+    pub fn lacks_metadata(&self, metadata_values: &MetadataValues) -> bool {
+        (self.metadata_values.changed_since_epoch_sec.is_none()
+            && metadata_values.changed_since_epoch_sec.is_some())
+            || (self.metadata_values.inode.is_none() && metadata_values.inode.is_some())
+    }
+
+    /// Whether the content of the file is unchanged, from its size and checksum, and its purge
+    /// confirmed. The size of precompressed files hashed decompressed isn’t compared.
+    pub fn same_content(&self, metadata_values: &MetadataValues, checksum: Checksum) -> bool {
+        self.confirmed
+            && (self.checksum_scheme.decompressed
+                || self.metadata_values.size == metadata_values.size)
+            && self.checksum == checksum
+    }
+
+    pub fn modified_since_epoch_sec(&self) -> f64 {
+        self.metadata_values.modified_since_epoch_sec
+    }
+
+    /// Size and checksum of the stored content, to find where a deleted file was moved
+    pub fn content(&self) -> (u64, Checksum) {
+        (self.metadata_values.size, self.checksum)
+    }
+
+    /// Scheme of the stored checksum, to compute the one of the file on disk the same way
+    pub fn checksum_scheme(&self) -> Scheme {
+        self.checksum_scheme
+    }
+}
+
+/// What’s stored about all the tracked files, loaded in a single pass, as looking each scanned
+/// file up costs a lot more on large sites. Changes, deletions and moves are all detected against
+/// it, and only the files that differ are written back.
+pub fn known_files(conn: &Connection) -> Result<HashMap<RelPath, Known>> {
+    let mut stmt = conn.prepare(
+        r#"SELECT path, modified_since_epoch_sec, size, checksum,
+                checksum_algorithm, checksum_seed, checksum_chunk_size, checksum_decompressed,
+                purge_state = 'confirmed', changed_since_epoch_sec, inode, checked_since_epoch_sec
+            FROM files"#,
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get(0)?,
+            Known {
+                metadata_values: MetadataValues {
+                    changed_since_epoch_sec: row.get(9)?,
+                    inode: row.get::<_, Option<i64>>(10)?.map(|inode| inode as u64),
+                    ..MetadataValues::new(row.get(1)?, row.get(2)?)
+                },
+                checksum: row.get(3)?,
+                checksum_scheme: scheme(row, 4)?,
+                confirmed: row.get(8)?,
+                checked_since_epoch_sec: row.get(11)?,
+            },
+        ))
+    })?;
+    rows.collect()
+}
+
+/// Seconds since the UNIX epoch, as stored in the database
+pub fn since_epoch_sec(time: SystemTime) -> f64 {
+    time.duration_since(UNIX_EPOCH)
+        .expect("the clock is set after the UNIX epoch")
+        .as_secs_f64()
+}
+
+/// Record the start of a run changing the files, returning its identifier for the history
+pub fn start_run(conn: &Connection) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO runs (started_since_epoch_sec) VALUES (?1)",
+        params![since_epoch_sec(SystemTime::now())],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+/// Tag the current state as a deployment, moving the marker with the same label
+pub fn mark(conn: &Connection, label: &str) -> Result<()> {
+    conn.execute(
+        r#"INSERT INTO markers (label, last_run_id, marked_since_epoch_sec)
+            VALUES (?1, (SELECT max(id) FROM runs), ?2)
+            ON CONFLICT(label) DO UPDATE SET
+                last_run_id = excluded.last_run_id,
+                marked_since_epoch_sec = excluded.marked_since_epoch_sec"#,
+        params![label, since_epoch_sec(SystemTime::now())],
+    )?;
+    Ok(())
+}
+
+/// Files changed, added or deleted since the deployment marker, sorted by path. `None` when
+/// there is no marker with this label. Changes whose history was removed are missing.
+pub fn changed_since_marker(conn: &Connection, label: &str) -> Result<Option<Vec<RelPath>>> {
+    let mut stmt = conn.prepare_cached("SELECT last_run_id FROM markers WHERE label = ?1")?;
+    let mut rows = stmt.query_map(params![label], |row| row.get::<_, Option<i64>>(0))?;
+    let Some(last_run_id) = rows.next().transpose()? else {
+        return Ok(None);
+    };
     let mut stmt = conn.prepare_cached(
-        r#"SELECT *
-            FROM files
-            WHERE path = ?1 AND modified_since_epoch_sec = ?2 AND size = ?3"#,
+        "SELECT DISTINCT path FROM file_history WHERE run_id > ifnull(?1, 0) ORDER BY path",
     )?;
-    let MetadataValues {
-        modified_since_epoch_sec,
-        size,
-    } = metadata_values;
-    let mut rows = stmt.query(params![path, modified_since_epoch_sec, size,])?;
-    Ok(rows.next()?.is_some())
+    let rows = stmt.query_map(params![last_run_id], |row| row.get(0))?;
+    rows.collect::<Result<_>>().map(Some)
 }
 
-pub fn exists_by_len_and_checksum(
-    conn: &mut Connection,
-    path: &RelPath,
-    metadata_values: &MetadataValues,
-    checksum: Checksum,
-) -> Result<bool> {
+/// Changes recorded by a run, from the history
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunChanges {
+    pub started: SystemTime,
+    pub added: usize,
+    pub changed: usize,
+    pub deleted: usize,
+    /// Size of the files added or changed
+    pub bytes: u64,
+}
+
+/// Changes of the last runs, oldest first, including the runs that changed nothing. Changes whose
+/// history was removed are missing.
+pub fn run_changes(conn: &Connection, last_runs: usize) -> Result<Vec<RunChanges>> {
     let mut stmt = conn.prepare_cached(
-        r#"SELECT *
-            FROM files
-            WHERE path = ?1 AND size = ?2 AND checksum = ?3"#,
+        r#"SELECT runs.started_since_epoch_sec,
+                sum(h.old_checksum IS NULL AND h.new_checksum IS NOT NULL),
+                sum(h.old_checksum IS NOT NULL AND h.new_checksum IS NOT NULL),
+                sum(h.id IS NOT NULL AND h.new_checksum IS NULL),
+                ifnull(sum(h.new_size), 0)
+            FROM (SELECT * FROM runs ORDER BY id DESC LIMIT ?1) AS runs
+            LEFT JOIN file_history AS h ON h.run_id = runs.id
+            GROUP BY runs.id
+            ORDER BY runs.id"#,
+    )?;
+    let rows = stmt.query_map(params![last_runs], |row| {
+        Ok(RunChanges {
+            started: UNIX_EPOCH + Duration::from_secs_f64(row.get::<_, f64>(0)?.max(0.)),
+            added: row.get(1)?,
+            changed: row.get(2)?,
+            deleted: row.get(3)?,
+            bytes: row.get(4)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// Add the change of the content of the file to its history, with the values still in the files
+/// table as the old ones
+fn record_history(
+    tx: &Transaction,
+    run_id: i64,
+    path: &RelPath,
+    new: Option<(&MetadataValues, Checksum)>,
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        r#"INSERT INTO file_history
+            (run_id, path, old_size, old_checksum, new_size, new_checksum,
+             new_modified_since_epoch_sec, recorded_since_epoch_sec)
+            SELECT ?1, ?2, files.size, files.checksum, ?3, ?4, ?5, ?6
+            FROM (SELECT 1) LEFT JOIN files ON files.path = ?2"#,
     )?;
-    let mut rows = stmt.query(params![path, metadata_values.size, checksum,])?;
-    Ok(rows.next()?.is_some())
+    stmt.execute(params![
+        run_id,
+        path,
+        new.map(|(metadata_values, _)| metadata_values.size),
+        new.map(|(_, checksum)| checksum),
+        new.map(|(metadata_values, _)| metadata_values.modified_since_epoch_sec),
+        since_epoch_sec(SystemTime::now()),
+    ])?;
+    Ok(())
+}
+
+/// Where the purge of the CDN cache for the last change of a file stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PurgeState {
+    /// To be sent to the CDN
+    Pending,
+    /// Sent to the CDN, without an answer yet
+    Submitted,
+    /// Done by the CDN, the file is up to date
+    Confirmed,
+    /// Rejected by the CDN, or not sent because of an error
+    Failed,
+}
+
+impl ToSql for PurgeState {
+    fn to_sql(&self) -> Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            PurgeState::Pending => "pending",
+            PurgeState::Submitted => "submitted",
+            PurgeState::Confirmed => "confirmed",
+            PurgeState::Failed => "failed",
+        }
+        .into())
+    }
 }
 
+/// Insert the file or update its content, keeping track of the change in its history, along with
+/// the scheme of its checksum. The file is only considered unchanged once its purge state is
+/// confirmed.
+#[allow(clippy::too_many_arguments)]
 pub fn upsert_entry(
     tx: &Transaction,
+    run_id: i64,
     path: &RelPath,
     metadata_values: &MetadataValues,
     checksum: Checksum,
+    scheme: Scheme,
+    content_type: &str,
+    purge_state: PurgeState,
 ) -> Result<()> {
+    record_history(tx, run_id, path, Some((metadata_values, checksum)))?;
     let mut stmt = tx.prepare_cached(
-        r#"INSERT OR REPLACE INTO files (path, modified_since_epoch_sec, size, checksum)
-            VALUES (?1, ?2, ?3, ?4)"#,
+        r#"INSERT INTO files
+            (path, modified_since_epoch_sec, size, checksum, purge_state,
+             checksum_algorithm, checksum_seed, checksum_chunk_size, content_type, display_path,
+             checksum_decompressed, changed_since_epoch_sec, inode, checked_since_epoch_sec)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13,
+                (SELECT started_since_epoch_sec FROM runs WHERE id = ?14))
+            ON CONFLICT(path) DO UPDATE SET
+                modified_since_epoch_sec = excluded.modified_since_epoch_sec,
+                size = excluded.size,
+                changed_since_epoch_sec = excluded.changed_since_epoch_sec,
+                inode = excluded.inode,
+                checked_since_epoch_sec = excluded.checked_since_epoch_sec,
+                checksum = excluded.checksum,
+                purge_state = excluded.purge_state,
+                checksum_algorithm = excluded.checksum_algorithm,
+                checksum_seed = excluded.checksum_seed,
+                checksum_chunk_size = excluded.checksum_chunk_size,
+                checksum_decompressed = excluded.checksum_decompressed,
+                audit_digest = NULL,
+                audit_digest_algorithm = NULL,
+                content_type = excluded.content_type"#,
     )?;
     let MetadataValues {
         modified_since_epoch_sec,
         size,
+        changed_since_epoch_sec,
+        inode,
     } = metadata_values;
+    let Scheme {
+        algorithm,
+        seed,
+        chunk_size,
+        decompressed,
+    } = scheme;
     let n = stmt
-        .execute(params![path, modified_since_epoch_sec, size, checksum,])
-        .expect(&format!(
-            "should be able to insert {path:?}, {metadata_values:?}, {checksum:?}"
-        ));
+        .execute(params![
+            path,
+            modified_since_epoch_sec,
+            size,
+            checksum,
+            purge_state,
+            algorithm,
+            seed as i64,
+            chunk_size,
+            content_type,
+            path.get_relative_path(),
+            decompressed,
+            changed_since_epoch_sec,
+            inode.map(|inode| inode as i64),
+            run_id,
+        ])
+        .unwrap_or_else(|e| {
+            panic!("should be able to insert {path:?}, {metadata_values:?}, {checksum:?}: {e}")
+        });
     debug_assert_eq!(1, n, "exactly one row should change for {path:?}");
     Ok(())
 }
 
 pub fn update_metadata(
     tx: &Transaction,
+    run_id: i64,
     path: &RelPath,
     metadata_values: &MetadataValues,
 ) -> Result<()> {
     let mut stmt = tx.prepare_cached(
         r#"UPDATE OR FAIL files
-           SET modified_since_epoch_sec = ?2, size = ?3
+           SET modified_since_epoch_sec = ?2, size = ?3, changed_since_epoch_sec = ?4, inode = ?5,
+               checked_since_epoch_sec = (SELECT started_since_epoch_sec FROM runs WHERE id = ?6)
            WHERE path = ?1
           "#,
     )?;
     let MetadataValues {
         modified_since_epoch_sec,
         size,
+        changed_since_epoch_sec,
+        inode,
     } = metadata_values;
     let n = stmt
-        .execute(params![&path, modified_since_epoch_sec, size,])
-        .expect(&format!(
-            "should be able to update {path:?}, {metadata_values:?}"
-        ));
+        .execute(params![
+            &path,
+            modified_since_epoch_sec,
+            size,
+            changed_since_epoch_sec,
+            inode.map(|inode| inode as i64),
+            run_id,
+        ])
+        .unwrap_or_else(|e| panic!("should be able to update {path:?}, {metadata_values:?}: {e}"));
     debug_assert_eq!(1, n, "exactly one row should be updated for {path:?}");
     Ok(())
 }
 
+/// Replace the checksum of the file with one of the same content computed with another scheme.
+/// As the content didn’t change, neither its history nor its purge state are.
+pub fn rebaseline(
+    tx: &Transaction,
+    path: &RelPath,
+    checksum: Checksum,
+    scheme: Scheme,
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        r#"UPDATE OR FAIL files
+           SET checksum = ?2, checksum_algorithm = ?3, checksum_seed = ?4, checksum_chunk_size = ?5,
+               checksum_decompressed = ?6
+           WHERE path = ?1"#,
+    )?;
+    let Scheme {
+        algorithm,
+        seed,
+        chunk_size,
+        decompressed,
+    } = scheme;
+    stmt.execute(params![
+        path,
+        checksum,
+        algorithm,
+        seed as i64,
+        chunk_size,
+        decompressed
+    ])?;
+    Ok(())
+}
+
+/// Store the stronger digest of the content of the file, until it changes
+pub fn set_audit_digest(
+    tx: &Transaction,
+    path: &RelPath,
+    digest: Checksum,
+    algorithm: Algorithm,
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "UPDATE files SET audit_digest = ?2, audit_digest_algorithm = ?3 WHERE path = ?1",
+    )?;
+    stmt.execute(params![path, digest, algorithm])?;
+    Ok(())
+}
+
+/// Move the purge of the file to another state
+pub fn set_purge_state(tx: &Transaction, path: &RelPath, purge_state: PurgeState) -> Result<()> {
+    let mut stmt = tx.prepare_cached("UPDATE files SET purge_state = ?2 WHERE path = ?1")?;
+    stmt.execute(params![path, purge_state])?;
+    Ok(())
+}
+
+/// Record that the CDN cache was purged for the path, confirming its purge
+pub fn set_last_purged(tx: &Transaction, path: &RelPath, purged_at: SystemTime) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        r#"UPDATE files
+           SET last_purged_since_epoch_sec = ?2, purge_state = 'confirmed'
+           WHERE path = ?1"#,
+    )?;
+    stmt.execute(params![path, since_epoch_sec(purged_at)])?;
+    Ok(())
+}
+
+/// Record the ETag served by the CDN for the file, fetched at the given time
+pub fn set_cdn_etag(
+    tx: &Transaction,
+    path: &RelPath,
+    etag: Option<&str>,
+    fetched_at: SystemTime,
+) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "UPDATE files SET cdn_etag = ?2, cdn_etag_since_epoch_sec = ?3 WHERE path = ?1",
+    )?;
+    stmt.execute(params![path, etag, since_epoch_sec(fetched_at)])?;
+    Ok(())
+}
+
+/// Paths of all the tracked files
+pub fn paths(conn: &Connection) -> Result<Vec<RelPath>> {
+    let mut stmt = conn.prepare_cached("SELECT path FROM files")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Directory walked by a run, with its modification time in seconds since the UNIX epoch and the
+/// number of files tracked under it
+pub type Dir = (RelPath, f64, u64);
+
+/// Directories walked by the last run recording them
+pub fn dirs(conn: &Connection) -> Result<Vec<Dir>> {
+    let mut stmt = conn.prepare("SELECT path, modified_since_epoch_sec, file_count FROM dirs")?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?;
+    rows.collect()
+}
+
+/// Replace the directories recorded by the previous run
+pub fn replace_dirs(tx: &Transaction, dirs: &[Dir]) -> Result<()> {
+    tx.execute("DELETE FROM dirs", [])?;
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO dirs (path, modified_since_epoch_sec, file_count) VALUES (?1, ?2, ?3)",
+    )?;
+    for (path, modified_since_epoch_sec, file_count) in dirs {
+        stmt.execute(params![path, modified_since_epoch_sec, file_count])?;
+    }
+    Ok(())
+}
+
+/// Paths of the files whose purge isn’t confirmed, left by an interrupted or failed run
+pub fn unconfirmed_paths(conn: &Connection) -> Result<Vec<RelPath>> {
+    let mut stmt = conn
+        .prepare_cached("SELECT path FROM files WHERE purge_state != 'confirmed' ORDER BY path")?;
+    let rows = stmt.query_map([], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Scheme stored in the row, starting at the column with the algorithm
+fn scheme(row: &rusqlite::Row, first: usize) -> Result<Scheme> {
+    Ok(Scheme {
+        algorithm: row.get(first)?,
+        // Stored as the bits of a signed integer
+        seed: row.get::<_, i64>(first + 1)? as u64,
+        chunk_size: row.get(first + 2)?,
+        decompressed: row.get(first + 3)?,
+    })
+}
+
+/// Stop tracking the file, typically because it was deleted, keeping track of it in its history
+pub fn delete_entry(tx: &Transaction, run_id: i64, path: &RelPath) -> Result<()> {
+    record_history(tx, run_id, path, None)?;
+    let mut stmt = tx.prepare_cached("DELETE FROM files WHERE path = ?1")?;
+    stmt.execute(params![path])?;
+    Ok(())
+}
+
+/// Remove the history recorded before the time, and the runs that are left without any history.
+/// Returns the number of history rows and runs removed.
+pub fn prune_history(tx: &Transaction, before: SystemTime) -> Result<(usize, usize)> {
+    let before = since_epoch_sec(before);
+    let history = tx.execute(
+        r#"DELETE FROM file_history
+            WHERE recorded_since_epoch_sec < ?1 OR run_id NOT IN (SELECT id FROM runs)"#,
+        params![before],
+    )?;
+    let runs = tx.execute(
+        r#"DELETE FROM runs
+            WHERE started_since_epoch_sec < ?1
+                AND id NOT IN (SELECT run_id FROM file_history)"#,
+        params![before],
+    )?;
+    Ok((history, runs))
+}
+
+/// Let SQLite analyze the tables and rebuild the database file, to reclaim unused space. The
+/// write-ahead log is emptied too.
+pub fn vacuum(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "PRAGMA optimize; \
+         VACUUM; \
+         PRAGMA wal_checkpoint(TRUNCATE);",
+    )
+}
+
+/// Number of rows of each table, by name
+pub fn table_counts(conn: &Connection) -> Result<Vec<(String, u64)>> {
+    let mut stmt = conn.prepare(
+        "SELECT name FROM sqlite_schema WHERE type = 'table' AND name NOT LIKE 'sqlite_%' \
+         ORDER BY name",
+    )?;
+    let names = stmt
+        .query_map([], |row| row.get::<_, String>(0))?
+        .collect::<Result<Vec<_>>>()?;
+    names
+        .into_iter()
+        .map(|name| {
+            // Names come from the schema, so they are safe to use in the query
+            let count = conn.query_row(&format!("SELECT count(*) FROM \"{name}\""), [], |row| {
+                row.get(0)
+            })?;
+            Ok((name, count))
+        })
+        .collect()
+}
+
+/// Indexes of the database, with their table, including the ones SQLite creates automatically
+pub fn indexes(conn: &Connection) -> Result<Vec<(String, String)>> {
+    let mut stmt = conn.prepare(
+        "SELECT name, tbl_name FROM sqlite_schema WHERE type = 'index' ORDER BY tbl_name, name",
+    )?;
+    let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    rows.collect()
+}
+
+/// Call `f` with the metadata of every tracked file, without loading them all in memory
+pub fn for_each_metadata(conn: &Connection, mut f: impl FnMut(MetadataValues)) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT modified_since_epoch_sec, size FROM files")?;
+    let mut rows = stmt.query([])?;
+    while let Some(row) = rows.next()? {
+        f(MetadataValues::new(row.get(0)?, row.get(1)?));
+    }
+    Ok(())
+}
+
+/// Value cached under the key, typically looked up from the CDN API
+pub fn cached_value(conn: &Connection, key: &str) -> Result<Option<String>> {
+    let mut stmt = conn.prepare_cached("SELECT value FROM cache WHERE key = ?1")?;
+    let mut rows = stmt.query_map(params![key], |row| row.get(0))?;
+    rows.next().transpose()
+}
+
+pub fn set_cached_value(conn: &Connection, key: &str, value: &str) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        r#"INSERT INTO cache (key, value) VALUES (?1, ?2)
+           ON CONFLICT(key) DO UPDATE SET value = excluded.value"#,
+    )?;
+    stmt.execute(params![key, value])?;
+    Ok(())
+}
+
+/// Row of the files table
+#[derive(Debug)]
+pub struct FileEntry {
+    pub path: RelPath,
+    pub metadata_values: MetadataValues,
+    pub checksum: Checksum,
+    pub checksum_scheme: Scheme,
+    /// MIME type, unknown for files unchanged since it’s detected
+    pub content_type: Option<String>,
+    pub last_purged_since_epoch_sec: Option<f64>,
+    /// ETag served by the CDN after the last purge, when recorded
+    pub cdn_etag: Option<String>,
+    pub cdn_etag_since_epoch_sec: Option<f64>,
+    /// Stronger digest of the content, with its algorithm, when configured
+    pub audit_digest: Option<(Checksum, Algorithm)>,
+}
+
+/// All the tracked files, sorted by path. When a pattern is given, only the paths matching it are
+/// returned, with invalid UTF-8 replaced. The pattern follows the syntax of the SQLite GLOB operator, see
+/// https://www.sqlite.org/lang_expr.html#like
+pub fn list_entries(conn: &Connection, pattern: Option<&str>) -> Result<Vec<FileEntry>> {
+    let mut stmt = conn.prepare_cached(
+        r#"SELECT path, modified_since_epoch_sec, size, checksum, last_purged_since_epoch_sec,
+                checksum_algorithm, checksum_seed, checksum_chunk_size, checksum_decompressed,
+                content_type, cdn_etag, cdn_etag_since_epoch_sec, audit_digest, audit_digest_algorithm,
+                changed_since_epoch_sec, inode
+            FROM files
+            WHERE ?1 IS NULL OR display_path GLOB ?1
+            ORDER BY path"#,
+    )?;
+    let rows = stmt.query_map(params![pattern], |row| {
+        Ok(FileEntry {
+            path: row.get(0)?,
+            metadata_values: MetadataValues {
+                modified_since_epoch_sec: row.get(1)?,
+                size: row.get(2)?,
+                changed_since_epoch_sec: row.get(14)?,
+                inode: row.get::<_, Option<i64>>(15)?.map(|inode| inode as u64),
+            },
+            checksum: row.get(3)?,
+            last_purged_since_epoch_sec: row.get(4)?,
+            checksum_scheme: scheme(row, 5)?,
+            content_type: row.get(9)?,
+            cdn_etag: row.get(10)?,
+            cdn_etag_since_epoch_sec: row.get(11)?,
+            audit_digest: match (row.get(12)?, row.get(13)?) {
+                (Some(digest), Some(algorithm)) => Some((digest, algorithm)),
+                _ => None,
+            },
+        })
+    })?;
+    rows.collect()
+}
+
+/// Insert or replace a row of the files table as is, without going through the file system, to
+/// import the state of another machine
+pub fn upsert_file_entry(tx: &Transaction, entry: &FileEntry) -> Result<()> {
+    let mut stmt = tx.prepare_cached(
+        r#"INSERT OR REPLACE INTO files
+            (path, modified_since_epoch_sec, size, checksum, last_purged_since_epoch_sec,
+             checksum_algorithm, checksum_seed, checksum_chunk_size, content_type,
+             cdn_etag, cdn_etag_since_epoch_sec, display_path, checksum_decompressed,
+             audit_digest, audit_digest_algorithm, changed_since_epoch_sec, inode)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)"#,
+    )?;
+    let MetadataValues {
+        modified_since_epoch_sec,
+        size,
+        changed_since_epoch_sec,
+        inode,
+    } = &entry.metadata_values;
+    let Scheme {
+        algorithm,
+        seed,
+        chunk_size,
+        decompressed,
+    } = entry.checksum_scheme;
+    stmt.execute(params![
+        entry.path,
+        modified_since_epoch_sec,
+        size,
+        entry.checksum,
+        entry.last_purged_since_epoch_sec,
+        algorithm,
+        seed as i64,
+        chunk_size,
+        entry.content_type,
+        entry.cdn_etag,
+        entry.cdn_etag_since_epoch_sec,
+        entry.path.get_relative_path(),
+        decompressed,
+        entry.audit_digest.map(|(digest, _)| digest),
+        entry.audit_digest.map(|(_, algorithm)| algorithm),
+        changed_since_epoch_sec,
+        inode.map(|inode| inode as i64),
+    ])?;
+    Ok(())
+}
+
+/// Remove all the rows of the files table, returning how many were removed
+pub fn clear(tx: &Transaction) -> Result<usize> {
+    tx.execute("DELETE FROM files", [])
+}
+
+/// Version of the schema, as set by the migrations
+pub fn schema_version(conn: &Connection) -> Result<usize> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
 /// Holds the values for the metadata columns in the table
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct MetadataValues {
     modified_since_epoch_sec: f64,
     size: u64,
+    /// Time of the last change of the status of the file, which can’t be set like the
+    /// modification time, on systems having it
+    changed_since_epoch_sec: Option<f64>,
+    inode: Option<u64>,
+}
+
+impl MetadataValues {
+    pub fn new(modified_since_epoch_sec: f64, size: u64) -> Self {
+        Self {
+            modified_since_epoch_sec,
+            size,
+            changed_since_epoch_sec: None,
+            inode: None,
+        }
+    }
+
+    /// Whether the metadata are the same, ignoring the values unknown on either side
+    pub fn same_as(&self, other: &MetadataValues) -> bool {
+        fn same<T: PartialEq>(a: Option<T>, b: Option<T>) -> bool {
+            a.zip(b).is_none_or(|(a, b)| a == b)
+        }
+        self.modified_since_epoch_sec == other.modified_since_epoch_sec
+            && self.size == other.size
+            && same(self.changed_since_epoch_sec, other.changed_since_epoch_sec)
+            && same(self.inode, other.inode)
+    }
+
+    /// Values that differ from the other metadata, for humans, like "mtime differs: 2025-01-01T…
+    /// → 2025-02-01T…"
+    pub fn differences(&self, other: &MetadataValues) -> Vec<String> {
+        let time = |sec: f64| {
+            humantime::format_rfc3339_nanos(UNIX_EPOCH + Duration::from_secs_f64(sec.max(0.)))
+        };
+        let mut differences = Vec::new();
+        if self.modified_since_epoch_sec != other.modified_since_epoch_sec {
+            differences.push(format!(
+                "mtime differs: {} → {}",
+                time(self.modified_since_epoch_sec),
+                time(other.modified_since_epoch_sec)
+            ));
+        }
+        if self.size != other.size {
+            differences.push(format!("size differs: {} → {}", self.size, other.size));
+        }
+        if let (Some(a), Some(b)) = (self.changed_since_epoch_sec, other.changed_since_epoch_sec) {
+            if a != b {
+                differences.push(format!("ctime differs: {} → {}", time(a), time(b)));
+            }
+        }
+        if let (Some(a), Some(b)) = (self.inode, other.inode) {
+            if a != b {
+                differences.push(format!("inode differs: {a} → {b}"));
+            }
+        }
+        differences
+    }
+
+    pub fn modified_since_epoch_sec(&self) -> f64 {
+        self.modified_since_epoch_sec
+    }
+
+    pub fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs_f64(self.modified_since_epoch_sec)
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
 }
 
 impl From<&Metadata> for MetadataValues {
@@ -159,10 +1043,36 @@ impl From<&Metadata> for MetadataValues {
             // than 150 ns of precision are lost)
             modified_since_epoch_sec: modified_since_epoch.as_secs_f64(),
             size: value.len(),
+            changed_since_epoch_sec: changed_since_epoch_sec(value),
+            inode: inode(value),
         }
     }
 }
 
+#[cfg(unix)]
+fn changed_since_epoch_sec(metadata: &Metadata) -> Option<f64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.ctime() as f64 + metadata.ctime_nsec() as f64 / 1e9)
+}
+
+#[cfg(not(unix))]
+fn changed_since_epoch_sec(_metadata: &Metadata) -> Option<f64> {
+    None
+}
+
+#[cfg(unix)]
+fn inode(metadata: &Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    Some(metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
 // Convenience function to serialize for snapshotting, not meant to be used in the actual
 // application
 #[cfg(test)]
@@ -176,18 +1086,23 @@ fn read_all_files_rows(conn: &Connection) -> tabled::Table {
     let count = stmt.column_count();
 
     let mut table = Builder::default();
-    table.push_record(
-        stmt.column_names()
-            .into_iter()
-            .map(|s| s.to_string())
-            .collect::<Vec<_>>(),
-    );
+    let names: Vec<String> = stmt
+        .column_names()
+        .into_iter()
+        .map(|s| s.to_string())
+        .collect();
+    table.push_record(names.clone());
     stmt.query(params![])
         .unwrap()
         .mapped(|row| {
             Ok((0..count)
-                .into_iter()
-                .map(|i| format!("{:?}", row.get_unwrap::<_, Value>(i)))
+                .map(|i| match row.get_unwrap::<_, Value>(i) {
+                    // Start of the run, which changes with each test
+                    Value::Real(_) if names[i] == "checked_since_epoch_sec" => {
+                        "Real([run start])".to_owned()
+                    }
+                    value => format!("{value:?}"),
+                })
                 .collect::<Vec<_>>())
         })
         .for_each(|s| table.push_record(s.unwrap()));
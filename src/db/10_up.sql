@@ -0,0 +1,61 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Paths are stored as the bytes given by the OS, as file names aren't always
+-- valid UTF-8, with a version where invalid UTF-8 is replaced, to display them
+-- and match them with patterns. STRICT tables can't change the type of a
+-- column, so they are rebuilt
+CREATE TABLE new_files (
+    path BLOB PRIMARY KEY NOT NULL,
+    display_path TEXT NOT NULL,
+    modified_since_epoch_sec REAL NOT NULL, -- Float, number of seconds (and nanoseconds) since UNIX epoch
+    size INT NOT NULL,
+    checksum BLOB NOT NULL,
+    last_purged_since_epoch_sec REAL, -- Float, like modified_since_epoch_sec
+    purge_state TEXT NOT NULL DEFAULT 'confirmed'
+        CHECK (purge_state IN ('pending', 'submitted', 'confirmed', 'failed')),
+    checksum_algorithm TEXT NOT NULL DEFAULT 'xxh64',
+    checksum_seed INT NOT NULL DEFAULT 4835865193724066228,
+    checksum_chunk_size INT NOT NULL DEFAULT 65536,
+    content_type TEXT,
+    cdn_etag TEXT,
+    cdn_etag_since_epoch_sec REAL
+) STRICT;
+
+INSERT INTO new_files
+    SELECT CAST(path AS BLOB), path, modified_since_epoch_sec, size, checksum,
+        last_purged_since_epoch_sec, purge_state, checksum_algorithm,
+        checksum_seed, checksum_chunk_size, content_type, cdn_etag,
+        cdn_etag_since_epoch_sec
+    FROM files;
+
+DROP TABLE files;
+
+ALTER TABLE new_files RENAME TO files;
+
+CREATE TABLE new_file_history (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    path BLOB NOT NULL,
+    old_size INT,
+    old_checksum BLOB,
+    new_size INT,
+    new_checksum BLOB,
+    new_modified_since_epoch_sec REAL,
+    recorded_since_epoch_sec REAL NOT NULL
+) STRICT;
+
+INSERT INTO new_file_history
+    SELECT id, run_id, CAST(path AS BLOB), old_size, old_checksum, new_size,
+        new_checksum, new_modified_since_epoch_sec, recorded_since_epoch_sec
+    FROM file_history;
+
+DROP TABLE file_history;
+
+ALTER TABLE new_file_history RENAME TO file_history;
+
+CREATE INDEX file_history_path_idx ON file_history(path);
@@ -0,0 +1,11 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Intentionally empty: this migration used to strip quotes from paths stored
+-- with their Debug formatting, but paths were never stored that way, and it
+-- mangled the files whose names really start and end with quotes. It’s kept so
+-- that the later migrations keep their numbers.
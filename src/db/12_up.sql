@@ -0,0 +1,15 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Deployments tagged by the user, to purge everything changed since one of
+-- them. Files changed by runs after last_run_id changed since the marker.
+-- Runs are removed by garbage collection, so last_run_id isn't a foreign key.
+CREATE TABLE markers (
+    label TEXT PRIMARY KEY NOT NULL,
+    last_run_id INTEGER, -- NULL when no run happened before the marker
+    marked_since_epoch_sec REAL NOT NULL
+) STRICT;
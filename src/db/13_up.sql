@@ -0,0 +1,10 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Whether the checksum is of the decompressed content of a precompressed file,
+-- part of the scheme of the checksum
+ALTER TABLE files ADD COLUMN checksum_decompressed INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,11 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Stronger digest of the content, optionally stored next to the checksum for
+-- verify to catch what the checksum misses. Both are NULL when there is none.
+ALTER TABLE files ADD COLUMN audit_digest BLOB;
+ALTER TABLE files ADD COLUMN audit_digest_algorithm TEXT;
@@ -0,0 +1,12 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Time of the last change of the status of the file and its inode number, on
+-- systems having them. A file copied with its modification time, or restored
+-- from a backup, gets new ones. NULL when unknown.
+ALTER TABLE files ADD COLUMN changed_since_epoch_sec REAL;
+ALTER TABLE files ADD COLUMN inode INTEGER;
@@ -0,0 +1,15 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Directories walked by the last run, with their modification time and the
+-- number of files tracked under them, to skip those unchanged since with
+-- skip_unchanged_dirs. The root directory has an empty path.
+CREATE TABLE dirs (
+    path BLOB PRIMARY KEY NOT NULL,
+    modified_since_epoch_sec REAL NOT NULL,
+    file_count INTEGER NOT NULL
+) STRICT;
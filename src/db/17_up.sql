@@ -0,0 +1,11 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Start of the run that last checked the file, to tell whether it was modified
+-- too close to that run for its modification time to reflect the next writes,
+-- on file systems storing it coarsely, like FAT. NULL when unknown.
+ALTER TABLE files ADD COLUMN checked_since_epoch_sec REAL;
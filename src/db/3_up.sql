@@ -0,0 +1,9 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- NULL until the CDN cache for this path is purged for the first time
+ALTER TABLE files ADD COLUMN last_purged_since_epoch_sec REAL; -- Float, like modified_since_epoch_sec
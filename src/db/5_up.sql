@@ -0,0 +1,28 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Runs updating the files table, even when nothing changed
+CREATE TABLE runs (
+    id INTEGER PRIMARY KEY,
+    started_since_epoch_sec REAL NOT NULL -- Float, like modified_since_epoch_sec
+) STRICT;
+
+-- Changes of the content of the files, kept when the files table is updated. The
+-- old values are NULL when the file was added, the new ones when it was deleted
+CREATE TABLE file_history (
+    id INTEGER PRIMARY KEY,
+    run_id INTEGER NOT NULL REFERENCES runs(id),
+    path TEXT NOT NULL,
+    old_size INT,
+    old_checksum BLOB,
+    new_size INT,
+    new_checksum BLOB,
+    new_modified_since_epoch_sec REAL,
+    recorded_since_epoch_sec REAL NOT NULL
+) STRICT;
+
+CREATE INDEX file_history_path_idx ON file_history(path);
@@ -0,0 +1,12 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- Where the purge of the CDN cache for the last change of the file stands. Files
+-- are only known to be unchanged when their purge is confirmed, so those in the
+-- other states are purged again. Existing files were purged by previous versions
+ALTER TABLE files ADD COLUMN purge_state TEXT NOT NULL DEFAULT 'confirmed'
+    CHECK (purge_state IN ('pending', 'submitted', 'confirmed', 'failed'));
@@ -0,0 +1,14 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- How the checksum was computed, so that it's only compared with checksums
+-- computed the same way. Existing checksums were all computed with xxh64, this
+-- seed (0x431C71C5AD9939B4) and chunks of 64 KiB
+ALTER TABLE files ADD COLUMN checksum_algorithm TEXT NOT NULL DEFAULT 'xxh64';
+-- The bits of the u64 seed, as SQLite integers are signed
+ALTER TABLE files ADD COLUMN checksum_seed INT NOT NULL DEFAULT 4835865193724066228;
+ALTER TABLE files ADD COLUMN checksum_chunk_size INT NOT NULL DEFAULT 65536;
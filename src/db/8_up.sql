@@ -0,0 +1,10 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- MIME type detected when the file was last changed, NULL for files that
+-- haven't changed since it's detected
+ALTER TABLE files ADD COLUMN content_type TEXT;
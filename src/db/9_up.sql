@@ -0,0 +1,12 @@
+-- Copyright © 2025 Clément Joly
+--
+-- This Source Code Form is subject to the terms of the Mozilla Public
+-- License, v. 2.0. If a copy of the MPL was not distributed with this
+-- file, You can obtain one at https://mozilla.org/MPL/2.0/.
+--
+
+-- ETag served by the CDN for the file after its last purge, and when it was
+-- fetched, to compare what the edge serves with the local state. NULL when it
+-- wasn't recorded
+ALTER TABLE files ADD COLUMN cdn_etag TEXT;
+ALTER TABLE files ADD COLUMN cdn_etag_since_epoch_sec REAL;
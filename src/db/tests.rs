@@ -7,6 +7,7 @@
 
 use super::*;
 
+use crate::checksum;
 use crate::rel_path::RelPathBuilder;
 
 use anyhow::Result;
@@ -16,6 +17,31 @@ fn test_db_path() -> RelPath {
         .db_path("/made_up/for_testing/some_other_folder/some_other_file")
 }
 
+fn exists_by_metadata(
+    conn: &Connection,
+    path: &RelPath,
+    metadata_values: &MetadataValues,
+) -> Result<bool> {
+    Ok(known_files(conn)?
+        .get(path)
+        .is_some_and(|known| known.same_metadata(metadata_values)))
+}
+
+fn exists_by_len_and_checksum(
+    conn: &Connection,
+    path: &RelPath,
+    metadata_values: &MetadataValues,
+    checksum: Checksum,
+) -> Result<bool> {
+    Ok(known_files(conn)?
+        .get(path)
+        .is_some_and(|known| known.same_content(metadata_values, checksum)))
+}
+
+fn checksum_scheme(conn: &Connection, path: &RelPath) -> Result<Option<Scheme>> {
+    Ok(known_files(conn)?.get(path).map(Known::checksum_scheme))
+}
+
 #[test]
 fn migrations() -> Result<()> {
     Ok(MIGRATIONS.validate()?)
@@ -24,23 +50,18 @@ fn migrations() -> Result<()> {
 #[test]
 #[should_panic]
 fn update_fails_when_nothing_exists() {
-    let _ = open_transient().and_then(|mut c| {
-        let _ = c.transaction().and_then(|tx| {
+    let _ = open_transient().map(|mut c| {
+        let _ = c.transaction().map(|tx| {
             // This should panic and nothing else can in this test
-            let _ = update_metadata(&tx, &test_db_path(), &MetadataValues::default());
-            Ok(())
+            let _ = update_metadata(&tx, 1, &test_db_path(), &MetadataValues::default());
         });
-        Ok(())
     });
 }
 
 #[test]
 fn insertion_and_checks() -> Result<()> {
     let db_path = test_db_path();
-    let initial_metadata = MetadataValues {
-        modified_since_epoch_sec: 12.,
-        size: 10,
-    };
+    let initial_metadata = MetadataValues::new(12., 10);
     let updated_metadata = MetadataValues {
         size: 99,
         ..initial_metadata
@@ -50,45 +71,833 @@ fn insertion_and_checks() -> Result<()> {
     let mut conn = open_transient()?;
 
     assert!(
-        !exists_by_metadata(&mut conn, &db_path, &initial_metadata)?,
+        !exists_by_metadata(&conn, &db_path, &initial_metadata)?,
         "nothing should be inserted yet"
     );
     insta::assert_snapshot!("empty_table", read_all_files_rows(&conn));
 
     {
         let tx = conn.transaction()?;
-        upsert_entry(&tx, &db_path, &initial_metadata, initial_checksum)?;
+        let run_id = start_run(&tx)?;
+        upsert_entry(
+            &tx,
+            run_id,
+            &db_path,
+            &initial_metadata,
+            initial_checksum,
+            checksum::SCHEME,
+            "text/plain",
+            PurgeState::Confirmed,
+        )?;
         tx.commit()?;
     }
     insta::assert_snapshot!("first_instert", read_all_files_rows(&conn));
     assert!(
-        exists_by_metadata(&mut conn, &db_path, &initial_metadata)?,
+        exists_by_metadata(&conn, &db_path, &initial_metadata)?,
         "should be inserted now"
     );
     assert!(
-        exists_by_len_and_checksum(&mut conn, &db_path, &initial_metadata, initial_checksum)?,
+        exists_by_len_and_checksum(&conn, &db_path, &initial_metadata, initial_checksum)?,
         "should be inserted now, with the right checksum"
     );
 
     // Update
     {
         let tx = conn.transaction()?;
-        upsert_entry(&tx, &db_path, &updated_metadata, updated_checksum)?;
+        let run_id = start_run(&tx)?;
+        upsert_entry(
+            &tx,
+            run_id,
+            &db_path,
+            &updated_metadata,
+            updated_checksum,
+            checksum::SCHEME,
+            "text/plain",
+            PurgeState::Confirmed,
+        )?;
         tx.commit()?;
     }
     insta::assert_snapshot!("after_update", read_all_files_rows(&conn));
     assert!(
-        !exists_by_metadata(&mut conn, &db_path, &initial_metadata)?,
+        !exists_by_metadata(&conn, &db_path, &initial_metadata)?,
         "should not find the old version"
     );
     assert!(
-        exists_by_metadata(&mut conn, &db_path, &updated_metadata)?,
+        exists_by_metadata(&conn, &db_path, &updated_metadata)?,
         "should be updated"
     );
     assert!(
-        exists_by_len_and_checksum(&mut conn, &db_path, &updated_metadata, updated_checksum)?,
+        exists_by_len_and_checksum(&conn, &db_path, &updated_metadata, updated_checksum)?,
         "should be updated, with the right checksum"
     );
 
     Ok(())
 }
+
+#[test]
+fn list_with_pattern() -> Result<()> {
+    let builder = RelPathBuilder::new("/made_up/for_testing");
+    let mut conn = open_transient()?;
+    {
+        let tx = conn.transaction()?;
+        let run_id = start_run(&tx)?;
+        for path in ["index.html", "blog/post.html", "blog/image.png"] {
+            let db_path = builder.db_path(Path::new("/made_up/for_testing").join(path).as_path());
+            upsert_entry(
+                &tx,
+                run_id,
+                &db_path,
+                &MetadataValues::default(),
+                Checksum::from(1),
+                checksum::SCHEME,
+                "text/plain",
+                PurgeState::Confirmed,
+            )?;
+        }
+        tx.commit()?;
+    }
+
+    let paths = |pattern| -> Result<Vec<String>> {
+        Ok(list_entries(&conn, pattern)?
+            .into_iter()
+            .map(|e| e.path.get_relative_path().to_owned())
+            .collect())
+    };
+    assert_eq!(
+        vec!["blog/image.png", "blog/post.html", "index.html"],
+        paths(None)?
+    );
+    assert_eq!(vec!["blog/post.html", "index.html"], paths(Some("*.html"))?);
+    assert_eq!(vec!["blog/image.png"], paths(Some("blog/*.png"))?);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn non_utf8_paths() -> Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt as _;
+
+    let root = Path::new("/made_up/for_testing");
+    let db_path = RelPathBuilder::new(root).db_path(&root.join(OsStr::from_bytes(b"caf\xe9.html")));
+    let mut conn = open_transient()?;
+    {
+        let tx = conn.transaction()?;
+        let run_id = start_run(&tx)?;
+        upsert_entry(
+            &tx,
+            run_id,
+            &db_path,
+            &MetadataValues::default(),
+            Checksum::from(1),
+            checksum::SCHEME,
+            "text/html",
+            PurgeState::Confirmed,
+        )?;
+        tx.commit()?;
+    }
+
+    assert_eq!(vec![db_path.clone()], paths(&conn)?);
+    let entries = list_entries(&conn, Some("caf?.html"))?;
+    assert_eq!(1, entries.len());
+    assert_eq!(db_path, entries[0].path);
+    assert_eq!("caf\u{fffd}.html", entries[0].path.get_relative_path());
+
+    Ok(())
+}
+
+#[test]
+fn quoted_paths() -> Result<()> {
+    let mut conn = Connection::open_in_memory()?;
+    MIGRATIONS.to_version(&mut conn, 10)?;
+    for path in [r#""index.html""#, "index.html"] {
+        conn.execute(
+            "INSERT INTO files (path, display_path, modified_since_epoch_sec, size, checksum)
+                VALUES (CAST(?1 AS BLOB), ?1, 0, 0, zeroblob(8))",
+            params![path],
+        )?;
+    }
+    MIGRATIONS.to_latest(&mut conn)?;
+
+    let paths: Vec<String> = list_entries(&conn, None)?
+        .into_iter()
+        .map(|e| e.path.get_relative_path().to_owned())
+        .collect();
+    assert_eq!(vec![r#""index.html""#, "index.html"], paths);
+
+    Ok(())
+}
+
+#[test]
+fn purge_states() -> Result<()> {
+    let db_path = test_db_path();
+    let metadata = MetadataValues::default();
+    let checksum = Checksum::from(1);
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &metadata,
+        checksum,
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Pending,
+    )?;
+    tx.commit()?;
+    // Still to be purged, so changed
+    assert!(!exists_by_metadata(&conn, &db_path, &metadata)?);
+    assert!(!exists_by_len_and_checksum(
+        &conn, &db_path, &metadata, checksum
+    )?);
+
+    let tx = conn.transaction()?;
+    set_purge_state(&tx, &db_path, PurgeState::Failed)?;
+    tx.commit()?;
+    assert!(!exists_by_metadata(&conn, &db_path, &metadata)?);
+    let unconfirmed: Vec<_> = unconfirmed_paths(&conn)?
+        .iter()
+        .map(|path| path.get_relative_path().to_owned())
+        .collect();
+    assert_eq!(vec![db_path.get_relative_path()], unconfirmed);
+
+    let tx = conn.transaction()?;
+    set_last_purged(&tx, &db_path, SystemTime::now())?;
+    tx.commit()?;
+    assert!(unconfirmed_paths(&conn)?.is_empty());
+    assert!(exists_by_metadata(&conn, &db_path, &metadata)?);
+    assert!(exists_by_len_and_checksum(
+        &conn, &db_path, &metadata, checksum
+    )?);
+    Ok(())
+}
+
+#[test]
+fn paths_and_deletion() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::default(),
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    let found: Vec<_> = paths(&tx)?
+        .iter()
+        .map(|path| path.get_relative_path().to_owned())
+        .collect();
+    assert_eq!(vec![db_path.get_relative_path()], found);
+
+    assert_eq!(
+        (0, Checksum::from(1)),
+        known_files(&tx)?[&db_path].content()
+    );
+
+    delete_entry(&tx, run_id, &db_path)?;
+    assert!(paths(&tx)?.is_empty());
+    assert!(known_files(&tx)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn history() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let first_run = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        first_run,
+        &db_path,
+        &MetadataValues::default(),
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    set_last_purged(&tx, &db_path, SystemTime::now())?;
+    let second_run = start_run(&tx)?;
+    let metadata = MetadataValues::new(1., 2);
+    upsert_entry(
+        &tx,
+        second_run,
+        &db_path,
+        &metadata,
+        Checksum::from(2),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    delete_entry(&tx, second_run, &db_path)?;
+
+    let mut stmt = tx.prepare(
+        "SELECT run_id, old_size, old_checksum, new_size, new_checksum FROM file_history ORDER BY id",
+    )?;
+    type Content = (Option<u64>, Option<Checksum>);
+    let rows: Vec<(i64, Content, Content)> = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                (row.get(1)?, row.get(2)?),
+                (row.get(3)?, row.get(4)?),
+            ))
+        })?
+        .collect::<rusqlite::Result<_>>()?;
+    let some = |size, checksum: u64| (Some(size), Some(Checksum::from(checksum)));
+    assert_eq!(
+        vec![
+            (first_run, (None, None), some(0, 1)),
+            (second_run, some(0, 1), some(2, 2)),
+            (second_run, some(2, 2), (None, None)),
+        ],
+        rows
+    );
+
+    // Updates keep what’s not about the content
+    upsert_entry(
+        &tx,
+        second_run,
+        &db_path,
+        &metadata,
+        Checksum::from(3),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    set_last_purged(&tx, &db_path, SystemTime::now())?;
+    upsert_entry(
+        &tx,
+        second_run,
+        &db_path,
+        &metadata,
+        Checksum::from(4),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    assert!(list_entries(&tx, None)?[0]
+        .last_purged_since_epoch_sec
+        .is_some());
+    Ok(())
+}
+
+#[test]
+fn markers() -> Result<()> {
+    let builder = RelPathBuilder::new("/made_up/for_testing");
+    let db_path =
+        |path: &str| builder.db_path(Path::new("/made_up/for_testing").join(path).as_path());
+    let store = |conn: &mut Connection, paths: &[&str]| -> Result<()> {
+        let tx = conn.transaction()?;
+        let run_id = start_run(&tx)?;
+        for path in paths {
+            upsert_entry(
+                &tx,
+                run_id,
+                &db_path(path),
+                &MetadataValues::default(),
+                Checksum::from(1),
+                checksum::SCHEME,
+                "text/plain",
+                PurgeState::Confirmed,
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    };
+    let changed = |conn: &Connection, label| -> Result<Option<Vec<String>>> {
+        Ok(changed_since_marker(conn, label)?.map(|paths| {
+            paths
+                .iter()
+                .map(|path| path.get_relative_path().to_owned())
+                .collect()
+        }))
+    };
+
+    let mut conn = open_transient()?;
+    mark(&conn, "empty")?;
+    store(&mut conn, &["index.html", "style.css"])?;
+    mark(&conn, "v1")?;
+    assert_eq!(Some(vec![]), changed(&conn, "v1")?);
+    store(&mut conn, &["index.html"])?;
+    store(&mut conn, &["blog/post.html", "index.html"])?;
+
+    assert_eq!(None, changed(&conn, "unknown")?);
+    assert_eq!(
+        Some(vec!["blog/post.html".to_owned(), "index.html".to_owned()]),
+        changed(&conn, "v1")?
+    );
+    assert_eq!(Some(3), changed(&conn, "empty")?.map(|paths| paths.len()));
+    // Marking again moves the marker
+    mark(&conn, "v1")?;
+    assert_eq!(Some(vec![]), changed(&conn, "v1")?);
+    Ok(())
+}
+
+#[test]
+fn prune() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::default(),
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    assert_eq!((0, 0), prune_history(&tx, UNIX_EPOCH)?);
+    let later = SystemTime::now() + Duration::from_secs(1);
+    assert_eq!((1, 1), prune_history(&tx, later)?);
+    tx.commit()?;
+    // The files themselves are kept
+    assert_eq!(1, paths(&conn)?.len());
+    vacuum(&conn)?;
+    Ok(())
+}
+
+#[test]
+fn checksum_schemes() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    assert_eq!(None, checksum_scheme(&tx, &db_path)?);
+    let run_id = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::default(),
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    assert_eq!(Some(checksum::SCHEME), checksum_scheme(&tx, &db_path)?);
+
+    // Seeds with the highest bit set don’t fit in SQLite integers as is
+    let mut entry = list_entries(&tx, None)?.remove(0);
+    entry.checksum_scheme.seed = u64::MAX;
+    upsert_file_entry(&tx, &entry)?;
+    assert_eq!(u64::MAX, checksum_scheme(&tx, &db_path)?.unwrap().seed);
+
+    // SHA-256 digests are stored whole, with their own scheme
+    let sha256 = Checksum::Bits256([7; 32]);
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::default(),
+        sha256,
+        checksum::SHA256_SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    assert_eq!(
+        Some(checksum::SHA256_SCHEME),
+        checksum_scheme(&tx, &db_path)?
+    );
+    assert_eq!(sha256, list_entries(&tx, None)?[0].checksum);
+
+    // The size of precompressed files changes with the compression, not with the content
+    let decompressed = Scheme {
+        decompressed: true,
+        ..checksum::SCHEME
+    };
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::default(),
+        Checksum::from(1),
+        decompressed,
+        "application/gzip",
+        PurgeState::Confirmed,
+    )?;
+    assert_eq!(Some(decompressed), checksum_scheme(&tx, &db_path)?);
+    let recompressed = MetadataValues::new(1.0, 1);
+    assert!(exists_by_len_and_checksum(
+        &tx,
+        &db_path,
+        &recompressed,
+        Checksum::from(1)
+    )?);
+    Ok(())
+}
+
+#[test]
+fn rebaselined() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::default(),
+        Checksum::from(1),
+        checksum::LEGACY_SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    rebaseline(&tx, &db_path, Checksum::from(2), checksum::SCHEME)?;
+    assert_eq!(Some(checksum::SCHEME), checksum_scheme(&tx, &db_path)?);
+    assert_eq!(Checksum::from(2), list_entries(&tx, None)?[0].checksum);
+    // The content is the same, so it’s neither a change nor something to purge
+    let history: i64 = tx.query_row("SELECT count(*) FROM file_history", [], |row| row.get(0))?;
+    assert_eq!(1, history);
+    assert!(known_files(&tx)?[&db_path].same_metadata(&MetadataValues::default()));
+    Ok(())
+}
+
+#[test]
+fn audit_digests() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    let upsert = |checksum: u64| {
+        upsert_entry(
+            &tx,
+            run_id,
+            &db_path,
+            &MetadataValues::default(),
+            Checksum::from(checksum),
+            checksum::SCHEME,
+            "text/plain",
+            PurgeState::Confirmed,
+        )
+    };
+    upsert(1)?;
+    assert_eq!(None, list_entries(&tx, None)?[0].audit_digest);
+    let digest = Checksum::Bits256([1; 32]);
+    set_audit_digest(&tx, &db_path, digest, Algorithm::Blake3)?;
+    assert_eq!(
+        Some((digest, Algorithm::Blake3)),
+        list_entries(&tx, None)?[0].audit_digest
+    );
+    // The digest is of the old content
+    upsert(2)?;
+    assert_eq!(None, list_entries(&tx, None)?[0].audit_digest);
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let conn = open_transient()?;
+    let counts = table_counts(&conn)?;
+    assert!(counts.contains(&("files".to_owned(), 0)));
+    assert!(counts.iter().all(|(name, _)| !name.starts_with("sqlite_")));
+    assert!(indexes(&conn)?.contains(&(
+        "file_history_path_idx".to_owned(),
+        "file_history".to_owned()
+    )));
+    Ok(())
+}
+
+#[test]
+fn cache() -> Result<()> {
+    let conn = open_transient()?;
+    assert_eq!(None, cached_value(&conn, "key")?);
+    set_cached_value(&conn, "key", "first")?;
+    set_cached_value(&conn, "key", "second")?;
+    assert_eq!(Some("second".to_owned()), cached_value(&conn, "key")?);
+    Ok(())
+}
+
+#[test]
+fn settings() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db.sqlite");
+    let conn = open(&path, &Settings::default())?;
+    let journal_mode: String = conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+    assert_eq!("wal", journal_mode);
+    // Other connections can read and write
+    let other = open(&path, &Settings::default())?;
+    set_cached_value(&other, "key", "value")?;
+    assert_eq!(Some("value".to_owned()), cached_value(&conn, "key")?);
+    drop((conn, other));
+
+    let settings = Settings {
+        journal_mode: Some(JournalMode::Delete),
+        synchronous: Some(Synchronous::Full),
+        busy_timeout: Some(100),
+        cache_size: Some(-1000),
+        transaction_size: None,
+    };
+    let conn = open(&path, &settings)?;
+    let journal_mode: String = conn.pragma_query_value(None, "journal_mode", |row| row.get(0))?;
+    assert_eq!("delete", journal_mode);
+    let cache_size: i64 = conn.pragma_query_value(None, "cache_size", |row| row.get(0))?;
+    assert_eq!(-1000, cache_size);
+    Ok(())
+}
+
+#[test]
+fn corruption() -> Result<()> {
+    assert!(quick_check(&open_transient()?)?.is_empty());
+
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db.sqlite");
+    fs::write(&path, "not a database, ".repeat(100))?;
+    let e = open(&path, &Settings::default()).unwrap_err();
+    assert!(is_corruption(&e), "{e:?}");
+    assert!(!is_corruption(&anyhow::anyhow!("other error")));
+
+    let aside = set_aside(&path)?;
+    assert_eq!(dir.path().join("db.sqlite.corrupt"), aside);
+    assert!(aside.exists());
+    assert!(!path.exists());
+    open(&path, &Settings::default())?;
+    Ok(())
+}
+
+#[test]
+fn run_lock() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db.sqlite");
+    let held = lock(&path)?;
+    assert!(held.is_some());
+    assert!(lock(&path)?.is_none());
+    drop(held);
+    assert!(lock(&path)?.is_some());
+    Ok(())
+}
+
+#[test]
+fn cdn_etags() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::default(),
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    assert_eq!(None, list_entries(&tx, None)?[0].cdn_etag);
+    set_cdn_etag(&tx, &db_path, Some("\"abc\""), SystemTime::now())?;
+    let entry = list_entries(&tx, None)?.remove(0);
+    assert_eq!(Some("\"abc\""), entry.cdn_etag.as_deref());
+    assert!(entry.cdn_etag_since_epoch_sec.is_some());
+    Ok(())
+}
+
+#[test]
+fn snapshots() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("db.sqlite");
+    let mut conn = open(&path, &Settings::default())?;
+    set_cached_value(&conn, "key", "saved")?;
+
+    let snapshot = snapshot_path(&path, "before-upgrade")?;
+    assert_eq!(
+        dir.path().join("db.sqlite.snapshots/before-upgrade.sqlite"),
+        snapshot
+    );
+    fs::create_dir_all(snapshot.parent().unwrap())?;
+    backup(&conn, &snapshot)?;
+    set_cached_value(&conn, "key", "changed")?;
+    restore(&mut conn, &snapshot)?;
+    assert_eq!(Some("saved".to_owned()), cached_value(&conn, "key")?);
+
+    for name in ["", ".hidden", "../escape", "a/b"] {
+        assert!(snapshot_path(&path, name).is_err(), "{name:?}");
+    }
+    Ok(())
+}
+
+#[test]
+fn status_change_and_inode() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &MetadataValues::new(12., 10),
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    let on_disk = MetadataValues {
+        changed_since_epoch_sec: Some(13.),
+        inode: Some(u64::MAX),
+        ..MetadataValues::new(12., 10)
+    };
+    // Tracked by an older version
+    let known = &known_files(&tx)?[&db_path];
+    assert!(known.same_metadata(&on_disk));
+    assert!(known.lacks_metadata(&on_disk));
+
+    update_metadata(&tx, run_id, &db_path, &on_disk)?;
+    let known = &known_files(&tx)?[&db_path];
+    assert!(known.same_metadata(&on_disk));
+    assert!(!known.lacks_metadata(&on_disk));
+    assert_eq!(on_disk, list_entries(&tx, None)?[0].metadata_values);
+    // Copied with its modification time, like with `cp -p`
+    let copied = MetadataValues {
+        inode: Some(2),
+        ..on_disk
+    };
+    assert!(!known.same_metadata(&copied));
+    let restored = MetadataValues {
+        changed_since_epoch_sec: Some(14.),
+        ..on_disk
+    };
+    assert!(!known.same_metadata(&restored));
+    Ok(())
+}
+
+#[test]
+fn recorded_dirs() -> Result<()> {
+    let mut conn = open_transient()?;
+    assert!(dirs(&conn)?.is_empty());
+    let blog = RelPath::from("blog".to_owned());
+    let tx = conn.transaction()?;
+    replace_dirs(
+        &tx,
+        &[
+            (RelPath::from(String::new()), 1.5, 3),
+            (blog.clone(), 2.5, 2),
+        ],
+    )?;
+    tx.commit()?;
+    assert_eq!(2, dirs(&conn)?.len());
+
+    let tx = conn.transaction()?;
+    replace_dirs(&tx, &[(blog.clone(), 4.0, 1)])?;
+    tx.commit()?;
+    assert_eq!(vec![(blog, 4.0, 1)], dirs(&conn)?);
+    Ok(())
+}
+
+#[test]
+fn racy_modification_time() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    let now = since_epoch_sec(SystemTime::now());
+    // Modified as the run started, so a write in the same tick of the clock of FAT keeps it
+    let racy = MetadataValues::new(now - 1., 10);
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &racy,
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    assert!(!known_files(&tx)?[&db_path].same_metadata(&racy));
+
+    let settled = MetadataValues::new(now - 60., 10);
+    update_metadata(&tx, run_id, &db_path, &settled)?;
+    assert!(known_files(&tx)?[&db_path].same_metadata(&settled));
+    Ok(())
+}
+
+#[test]
+fn explanations() -> Result<()> {
+    let db_path = test_db_path();
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = start_run(&tx)?;
+    let stored = MetadataValues::new(1_700_000_000., 10);
+    upsert_entry(
+        &tx,
+        run_id,
+        &db_path,
+        &stored,
+        Checksum::from(1),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Submitted,
+    )?;
+    let known = &known_files(&tx)?[&db_path];
+    assert_eq!("its purge isn’t confirmed", known.why_check(&stored));
+
+    let on_disk = MetadataValues::new(1_700_000_060., 12);
+    assert_eq!(
+        "mtime differs: 2023-11-14T22:13:20.000000000Z → 2023-11-14T22:14:20.000000000Z, \
+         size differs: 10 → 12, its purge isn’t confirmed",
+        known.why_check(&on_disk)
+    );
+    assert_eq!(
+        "checksum matches, but size differs",
+        known.content_difference(&on_disk, Checksum::from(1))
+    );
+    assert!(known
+        .content_difference(&on_disk, Checksum::from(2))
+        .starts_with("checksum differs: "));
+    Ok(())
+}
+
+#[test]
+fn run_changes() -> Result<()> {
+    let builder = RelPathBuilder::new("/made_up/for_testing");
+    let db_path =
+        |path: &str| builder.db_path(Path::new("/made_up/for_testing").join(path).as_path());
+    let mut conn = open_transient()?;
+    let tx = conn.transaction()?;
+    let first_run = start_run(&tx)?;
+    for (path, size) in [("index.html", 10), ("style.css", 20)] {
+        upsert_entry(
+            &tx,
+            first_run,
+            &db_path(path),
+            &MetadataValues::new(1_700_000_000., size),
+            Checksum::from(1),
+            checksum::SCHEME,
+            "text/plain",
+            PurgeState::Confirmed,
+        )?;
+    }
+    start_run(&tx)?;
+    let third_run = start_run(&tx)?;
+    upsert_entry(
+        &tx,
+        third_run,
+        &db_path("index.html"),
+        &MetadataValues::new(1_700_000_060., 12),
+        Checksum::from(2),
+        checksum::SCHEME,
+        "text/plain",
+        PurgeState::Confirmed,
+    )?;
+    delete_entry(&tx, third_run, &db_path("style.css"))?;
+    tx.commit()?;
+
+    let changes = super::run_changes(&conn, 10)?;
+    let counts: Vec<_> = changes
+        .iter()
+        .map(|run| (run.added, run.changed, run.deleted, run.bytes))
+        .collect();
+    assert_eq!(vec![(2, 0, 0, 30), (0, 0, 0, 0), (0, 1, 1, 12)], counts);
+    assert!(changes.windows(2).all(|w| w[0].started <= w[1].started));
+    assert_eq!(2, super::run_changes(&conn, 2)?.len());
+    Ok(())
+}
@@ -0,0 +1,93 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::fmt::Display;
+use std::process::ExitCode;
+
+/// Category of failure, attached as context to errors to pick the exit code of the program. This
+/// lets wrapper scripts decide whether to retry or alert.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// Some files could not be read during the scan
+    Scan,
+    /// The database could not be opened, read or written
+    Db,
+    /// A call to the CDN API failed
+    Cdn,
+    /// The configuration could not be loaded
+    Config,
+    /// Some files on disk don’t match the database
+    Verify,
+    /// The user didn’t confirm a large purge
+    Aborted,
+    /// A hook command of the configuration failed
+    Hook,
+    /// Another run is using the same database
+    Locked,
+}
+
+impl Failure {
+    pub fn code(self) -> u8 {
+        match self {
+            Failure::Scan => 2,
+            Failure::Db => 3,
+            Failure::Cdn => 4,
+            Failure::Config => 5,
+            Failure::Verify => 6,
+            Failure::Aborted => 7,
+            Failure::Hook => 8,
+            Failure::Locked => 9,
+        }
+    }
+}
+
+impl Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Failure::Scan => "scan error",
+            Failure::Db => "database error",
+            Failure::Cdn => "CDN API error",
+            Failure::Config => "configuration error",
+            Failure::Verify => "files don’t match the database",
+            Failure::Aborted => "aborted, nothing was purged",
+            Failure::Hook => "hook failed",
+            Failure::Locked => "already running",
+        })
+    }
+}
+
+impl std::error::Error for Failure {}
+
+impl From<Failure> for ExitCode {
+    fn from(value: Failure) -> Self {
+        value.code().into()
+    }
+}
+
+/// Exit code for an error, from the [`Failure`] it was tagged with. Untagged errors are
+/// unexpected and get the generic exit code 1.
+pub fn code_for(err: &anyhow::Error) -> ExitCode {
+    err.downcast_ref::<Failure>()
+        .map_or(ExitCode::FAILURE, |&failure| failure.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::{anyhow, Context};
+
+    use super::*;
+
+    #[test]
+    fn tagged_errors() {
+        let err = Err::<(), _>(anyhow!("some error"))
+            .context(Failure::Db)
+            .unwrap_err();
+        assert_eq!(Some(&Failure::Db), err.downcast_ref::<Failure>());
+        assert_eq!(ExitCode::from(3), code_for(&err));
+        assert_eq!(ExitCode::FAILURE, code_for(&anyhow!("untagged")));
+    }
+}
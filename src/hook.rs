@@ -0,0 +1,76 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Commands of the configuration run around a scan, to chain builds, notifications or cache
+//! warming
+
+use std::io::Write as _;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use tracing::debug;
+
+use crate::exit::Failure;
+
+/// Command running `cmd` with the shell of the platform
+pub fn shell_command(cmd: &str) -> Command {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C");
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c");
+        command
+    };
+    command.arg(cmd);
+    command
+}
+
+/// Run the hook named after its configuration key, with the input on its standard input and the
+/// environment variables set. Its output goes to ours.
+pub fn run(name: &str, cmd: &str, input: Option<&str>, envs: &[(&str, &str)]) -> Result<()> {
+    run_command(cmd, input, envs)
+        .with_context(|| format!("{name} `{cmd}` failed"))
+        .context(Failure::Hook)
+}
+
+fn run_command(cmd: &str, input: Option<&str>, envs: &[(&str, &str)]) -> Result<()> {
+    debug!("running `{cmd}`");
+    let mut child = shell_command(cmd)
+        .envs(envs.iter().copied())
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .spawn()?;
+    if let Some(input) = input {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // The command may not read its input, that’s fine
+        if let Err(e) = stdin.write_all(input.as_bytes()) {
+            debug!("`{cmd}` didn’t read all its input: {e}");
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        bail!("exited with {status}");
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_and_status() {
+        assert!(run("hook", "grep -q b", Some("a\nb\n"), &[]).is_ok());
+        assert!(run("hook", "grep -q c", Some("a\nb\n"), &[]).is_err());
+        assert!(run("hook", "test \"$X\" = y", None, &[("X", "y")]).is_ok());
+    }
+}
@@ -5,146 +5,2061 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Result;
-use clap::Parser;
-use indicatif::ParallelProgressIterator;
-use log::error;
+use anyhow::{anyhow, Context, Result};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use globset::Glob;
+use indicatif::ProgressIterator;
 use rayon::iter::Either;
 use rayon::prelude::*;
-use walkdir::WalkDir;
+use rusqlite::Connection;
+use tracing::{debug, error, info, info_span, trace, warn};
+use url::Url;
 
 mod cdn;
 mod checksum;
+mod cmd;
 mod config;
 mod db;
+mod exit;
+mod hook;
+mod metrics;
+mod mime;
+mod notify;
+mod otlp;
+mod output;
 mod rel_path;
+mod report;
+mod secret;
+mod state;
 #[cfg(test)]
 mod tests;
+mod walk;
+mod xdg;
 
-use crate::checksum::Checksum;
+use crate::checksum::{Checksum, Scheme};
+use crate::config::{Config, Profile};
+use crate::exit::Failure;
+use crate::report::Report;
+use crate::state::RemoteState;
 
-use self::db::MetadataValues;
-use self::rel_path::{RelPath, RelPathBuilder};
+use self::db::{MetadataValues, PurgeState};
+use self::rel_path::{PathCase, RelPath};
 
 /// A CDN cache invalidation tool for your static site
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Args {
-    /// Directory holding the static site cached by the CDN
-    #[arg()]
-    root_dir: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Directories holding the static site cached by the CDN, defaults to the root_dirs of the
+    /// configuration. When there are several, their content is served together, under the same
+    /// base URL
+    root_dirs: Vec<PathBuf>,
 
     /// Whether to use fast change detection (relies on the filesystem metadata to detect some of the
     /// changes)
     #[arg(short, long, default_value_t = false)]
     force_deep_check: bool,
+
+    /// Don’t walk into the directories whose modification time didn’t change since the previous
+    /// run, like that of the directories under them. Much faster on large sites, but files written
+    /// to in place, instead of being replaced, are missed. Only with a single root directory
+    #[arg(long, default_value_t = false)]
+    skip_unchanged_dirs: bool,
+
+    /// Only hash the size and the first and last 64 KiB of the files, much faster on huge media
+    /// files, at the risk of missing changes in the middle of files keeping their size
+    #[arg(long, default_value_t = false)]
+    fast_hash: bool,
+
+    /// Only check the files listed in this file, one per line, instead of walking the root
+    /// directory. Use - for the standard input, like in `git diff --name-only | static-cdn
+    /// --paths-from - public/`
+    #[arg(long, value_name = "FILE")]
+    paths_from: Option<PathBuf>,
+
+    /// Skip the files last modified before this, either a duration (like 2h or 1day) or a date
+    /// and time (like 2025-01-31 or 2025-01-31T12:00:00Z), without even looking them up
+    #[arg(long, value_name = "WHEN", value_parser = parse_since)]
+    since: Option<SystemTime>,
+
+    /// Skip the files and directories matching this glob pattern, relative to the root directory
+    /// (like '*.tmp' or '.git'). Can be repeated
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<Glob>,
+
+    /// Only scan the files matching this glob pattern, relative to the root directory (like
+    /// '*.html'). Can be repeated, exclusions take precedence
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<Glob>,
+
+    /// Don’t scan files nested deeper than this in the root directory, 1 being the files directly
+    /// in it
+    #[arg(long)]
+    max_depth: Option<usize>,
+
+    /// Walk through symbolic links, as if they were the files or directories they point to
+    #[arg(long, default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Skip the files ignored by git, like build byproducts, when the root directory is in a git
+    /// repository
+    #[arg(long, default_value_t = false)]
+    respect_gitignore: bool,
+
+    /// Also scan the hidden files, like .htaccess, and those left by the system or editors, like
+    /// .DS_Store, Thumbs.db or index.html~. The .well-known directory is always scanned
+    #[arg(long, default_value_t = false)]
+    hidden: bool,
+
+    /// Don’t purge anything from the CDN, only print the URLs that would be purged
+    #[arg(short = 'n', long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Don’t ask for confirmation before purging many URLs
+    #[arg(short, long, default_value_t = false)]
+    yes: bool,
+
+    /// Maximum number of URLs purged by a single request to the CDN, defaults to the maximum
+    /// accepted by the CDN
+    #[arg(long)]
+    batch_size: Option<usize>,
+
+    /// Keep running, scanning again after this duration (like 30s or 5min). Files deleted since the
+    /// previous scan are purged by the next one, and forgotten once their purge succeeds. The
+    /// configuration is reloaded when its file changes, except for the number of threads
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    interval: Option<Duration>,
+
+    /// With --interval, wait for the files to stay unchanged for this duration (like 10s) before
+    /// purging, so that a site being generated is purged at once, by a later run
+    #[arg(
+        long,
+        value_name = "DURATION",
+        value_parser = humantime::parse_duration,
+        requires = "interval"
+    )]
+    quiet_period: Option<Duration>,
+
+    /// Maximum number of purge requests sent to the CDN at the same time, over a single HTTP/2
+    /// connection (defaults to 2)
+    #[arg(long)]
+    cdn_concurrency: Option<usize>,
+
+    /// Write a JSON report of the run to this file: the outcome of each file, the purge requests
+    /// with the response of the CDN, the errors and the time spent in each phase. With
+    /// --interval, it’s replaced after each run
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Write the report of the run as a single HTML page to this file, with sortable tables of
+    /// the files changed and of the purge requests, and charts of the changes of the last runs
+    #[arg(long, value_name = "FILE")]
+    report_html: Option<PathBuf>,
+
+    /// Print the relative paths (or the URLs, with --print-changed=urls) of the files new,
+    /// changed, moved or deleted to the standard output, one per line, for other deploy tools
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "paths"
+    )]
+    print_changed: Option<Changed>,
+
+    /// Configuration file to use (TOML, YAML or JSON, from its extension), instead of the closest
+    /// static-cdn.toml from the root directory or the current directory
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Site of the configuration file to use, from its [sites.<SITE>] table
+    #[arg(long, global = true)]
+    site: Option<String>,
+
+    /// Environment of the configuration file to use, like staging, from its [env.<ENV>] table
+    #[arg(long, global = true)]
+    env: Option<String>,
+
+    /// Path of the database holding the state of the files, overrides the configuration
+    #[arg(long, global = true)]
+    db_path: Option<PathBuf>,
+
+    /// Number of threads used to hash files and to walk the directories, defaults to the number of
+    /// CPUs
+    #[arg(long, global = true)]
+    threads: Option<usize>,
+
+    /// Without asking, set a corrupted database aside and create a new one, filled by a full
+    /// scan. Every file is then purged
+    #[arg(long, default_value_t = false, global = true)]
+    auto_rebuild: bool,
+
+    /// Run non-interactively, for CI jobs: no progress bars, errors as GitHub Actions annotations
+    /// and a compact summary
+    #[arg(long, default_value_t = false, global = true)]
+    ci: bool,
+
+    /// Print more details about what is done, repeat for even more details (-vv), like why each
+    /// file is considered changed or not
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only print errors, without progress bars
+    #[arg(
+        short,
+        long,
+        default_value_t = false,
+        conflicts_with = "verbose",
+        global = true
+    )]
+    quiet: bool,
+
+    /// Format of the messages printed
+    #[arg(long, value_enum, default_value_t, global = true)]
+    log_format: output::LogFormat,
+
+    /// Also append the messages to this file, rotated each day unless configured otherwise, for
+    /// instances running with --interval
+    #[arg(long, value_name = "FILE")]
+    log_file: Option<PathBuf>,
+}
+
+impl Args {
+    /// Profiles of the configuration file selected by --site and --env
+    fn profile(&self) -> Profile<'_> {
+        Profile {
+            site: self.site.as_deref(),
+            env: self.env.as_deref(),
+        }
+    }
+}
+
+/// What --print-changed lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Changed {
+    Paths,
+    Urls,
+}
+
+/// Parse the argument of --since, relative to the current time
+fn parse_since(since: &str) -> Result<SystemTime, String> {
+    if let Ok(duration) = humantime::parse_duration(since) {
+        return SystemTime::now()
+            .checked_sub(duration)
+            .ok_or_else(|| format!("{since} is too far in the past"));
+    }
+    humantime::parse_rfc3339_weak(since)
+        .or_else(|_| humantime::parse_rfc3339_weak(&format!("{since} 00:00:00")))
+        .map_err(|_| format!("{since} is neither a duration nor a date and time"))
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the shell completion script for the given shell
+    Completions {
+        #[arg()]
+        shell: Shell,
+    },
+    /// List the tracked files, with their size, modification time, checksum and last purge time
+    /// (tab separated)
+    List {
+        /// Only list the paths matching this glob pattern (like 'blog/*.html')
+        #[arg()]
+        pattern: Option<String>,
+    },
+    /// Hash every tracked file again, regardless of its metadata, and print those that don’t
+    /// match the database
+    ///
+    /// A file is “stale” when its content changed but not its metadata, so that the fast change
+    /// detection misses it.
+    Verify {
+        /// Directory holding the static site cached by the CDN
+        #[arg()]
+        root_dir: PathBuf,
+    },
+    /// Write the state of the database on the standard output, to move it to another machine or
+    /// inspect it with other tools
+    Export {
+        #[arg(long, value_enum, default_value_t)]
+        format: cmd::export::Format,
+    },
+    /// Maintain the database
+    Db {
+        #[command(subcommand)]
+        command: DbCommand,
+    },
+    /// Tag the current state as a deployment, like v1.2, marking again the same label moves it
+    Mark {
+        #[arg()]
+        label: String,
+    },
+    /// Purge every URL changed since the deployment marked with the label, after rolling the site
+    /// back to that build
+    ///
+    /// Changes are found in the history, so those removed by `db gc` are missed.
+    RollbackPurge {
+        #[arg()]
+        label: String,
+
+        /// Don’t purge anything from the CDN, only print the URLs that would be purged
+        #[arg(short = 'n', long, default_value_t = false)]
+        dry_run: bool,
+
+        /// Don’t ask for confirmation before purging many URLs
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+    },
+    /// Save the state of the database, to roll it back if a run misbehaves
+    Snapshot {
+        #[command(subcommand)]
+        command: SnapshotCommand,
+    },
+    /// Manage the API token stored in the OS keyring, used when the configuration sets no
+    /// api_token_cmd
+    Auth {
+        #[command(subcommand)]
+        command: AuthCommand,
+    },
+    /// Manage the configuration file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Load a state written by the export command
+    Import {
+        /// File to import, - for the standard input
+        #[arg()]
+        file: PathBuf,
+
+        #[arg(long, value_enum, default_value_t)]
+        format: cmd::export::Format,
+
+        /// Remove all the existing entries first, instead of merging with them
+        #[arg(long, default_value_t = false)]
+        replace: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum AuthCommand {
+    /// Store the API token of the site, read from the standard input
+    Login,
+    /// Remove the API token of the site
+    Logout,
+}
+
+#[derive(Subcommand, Debug)]
+enum DbCommand {
+    /// Remove the old history and reclaim unused space
+    Gc {
+        /// Keep the history of this period, like 30days
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "90days")]
+        keep: Duration,
+    },
+    /// Show the size of the tables, indexes and files tracked, to diagnose slow runs
+    Stats,
+    /// Replace the checksums computed by older versions or with another algorithm with those the
+    /// configuration asks for, when the files didn’t change since, without purging them
+    Rebaseline {
+        /// Directory holding the static site cached by the CDN
+        #[arg()]
+        root_dir: PathBuf,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum SnapshotCommand {
+    /// Save a consistent copy of the state of the database under the name
+    Save {
+        #[arg()]
+        name: String,
+
+        /// Overwrite the snapshot with the same name
+        #[arg(long, default_value_t = false)]
+        force: bool,
+    },
+    /// Replace the state of the database with the snapshot saved under the name
+    Restore {
+        #[arg()]
+        name: String,
+    },
 }
 
-fn main() -> Result<ExitCode> {
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Check the configuration file, reporting unknown keys and missing values, without running
+    /// anything else
+    Validate {
+        /// Also run the command retrieving the API token, to check that it works
+        #[arg(long, default_value_t = false)]
+        check_token: bool,
+    },
+    /// Upgrade the configuration file written for an older version, keeping its comments when
+    /// it’s in TOML
+    Migrate {
+        /// Don’t ask for confirmation before overwriting the file
+        #[arg(short, long, default_value_t = false)]
+        yes: bool,
+    },
+}
+
+fn main() -> ExitCode {
     let args = Args::parse();
+    if args.command.is_some() && !args.root_dirs.is_empty() {
+        // Not expressible with clap attributes, without preventing global arguments from
+        // appearing before the subcommand
+        Args::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "the root directory can’t be used with a subcommand",
+            )
+            .exit();
+    }
+    output::init(args.verbose, args.quiet, args.ci, args.log_format);
 
-    let config = config::load();
-    println!("Scanning {}...", args.root_dir);
-    let all_files = WalkDir::new(&args.root_dir)
-        .into_iter()
-        .filter_map(|entry| {
-            let entry = entry.unwrap();
-            if entry.file_type().is_file() {
-                Some(entry)
-            } else {
-                None
-            }
+    let result = match &args.command {
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(
+                *shell,
+                &mut Args::command(),
+                env!("CARGO_PKG_NAME"),
+                &mut std::io::stdout(),
+            );
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::List { pattern }) => open_db(&args)
+            .and_then(|conn| cmd::list::run(&conn, pattern.as_deref()))
+            .map(|()| ExitCode::SUCCESS),
+        Some(Command::Verify { root_dir }) => {
+            open_db(&args).and_then(|conn| cmd::verify::run(&conn, root_dir))
+        }
+        Some(Command::Export { format }) => open_db(&args)
+            .and_then(|conn| cmd::export::run(&conn, *format))
+            .map(|()| ExitCode::SUCCESS),
+        Some(Command::Db {
+            command: DbCommand::Gc { keep },
+        }) => with_locked_db(&args, |_, db_path, conn| cmd::db::gc(conn, db_path, *keep))
+            .map(|()| ExitCode::SUCCESS),
+        Some(Command::Db {
+            command: DbCommand::Stats,
+        }) => load_config(&args)
+            .and_then(|(config, db_path)| {
+                let conn = open_checked(&args, &config, &db_path)?;
+                cmd::db::stats(&conn, &db_path)
+            })
+            .map(|()| ExitCode::SUCCESS),
+        Some(Command::Db {
+            command: DbCommand::Rebaseline { root_dir },
+        }) => with_locked_db(&args, |config, _, conn| {
+            let fast_hash = args.fast_hash || config.fast_hash;
+            checksum::read_size::set(config.read_size.resolve(root_dir));
+            cmd::db::rebaseline(conn, root_dir, |path, size| {
+                current_scheme(config, fast_hash, path, size)
+            })
+        }),
+        Some(Command::Snapshot {
+            command: SnapshotCommand::Save { name, force },
+        }) => load_config(&args)
+            .and_then(|(config, db_path)| {
+                let conn = open_checked(&args, &config, &db_path)?;
+                cmd::snapshot::save(&conn, &db_path, name, *force)
+            })
+            .map(|()| ExitCode::SUCCESS),
+        Some(Command::Snapshot {
+            command: SnapshotCommand::Restore { name },
+        }) => with_locked_db(&args, |_, db_path, conn| {
+            cmd::snapshot::restore(conn, db_path, name)
         })
-        .collect::<Vec<_>>();
-    let file_count = all_files.len();
-
-    let mut conn = db::open()?;
-    let db_path_builder = RelPathBuilder::new(&args.root_dir);
-
-    println!("Detecting changes");
-    // A Vec<()> takes no memory per element, but it's useful to count how many such elements there
-    // are
-    let ((unchanged, updates), (store, errors)): ((Vec<()>, Vec<_>), (Vec<_>, Vec<_>)) = all_files
-        .par_iter()
-        .progress()
-        .map_init(
-            || db::open().unwrap(),
-            |conn, entry| -> Result<PathOutcome> {
-                let path = entry.path();
-                let db_path = db_path_builder.db_path(path);
-                let metadata_values = MetadataValues::from(&path.metadata()?);
-
-                if args.force_deep_check
-                    || !db::exists_by_metadata(conn, &db_path, &metadata_values)?
-                {
-                    let checksum = Checksum::compute(path)?;
-                    if db::exists_by_len_and_checksum(conn, &db_path, &metadata_values, checksum)? {
-                        Ok(PathOutcome::UpdateMetdata(db_path, metadata_values))
-                    } else {
-                        Ok(PathOutcome::StoreAndInvalidate(
-                            db_path,
-                            metadata_values,
-                            checksum,
-                        ))
+        .map(|()| ExitCode::SUCCESS),
+        Some(Command::Mark { label }) => with_locked_db(&args, |_, _, conn| {
+            db::mark(conn, label).context(Failure::Db)
+        })
+        .map(|()| ExitCode::SUCCESS),
+        Some(Command::RollbackPurge {
+            label,
+            dry_run,
+            yes,
+        }) => rollback_purge(&args, label, *dry_run, *yes),
+        Some(Command::Auth { command }) => {
+            config::load(args.config.as_deref(), args.profile(), None)
+                .context(Failure::Config)
+                .and_then(|config| match command {
+                    AuthCommand::Login => cmd::auth::login(&config),
+                    AuthCommand::Logout => cmd::auth::logout(&config),
+                })
+                .map(|()| ExitCode::SUCCESS)
+        }
+        Some(Command::Config {
+            command: ConfigCommand::Validate { check_token },
+        }) => cmd::config::validate(args.config.as_deref(), args.profile(), *check_token),
+        Some(Command::Config {
+            command: ConfigCommand::Migrate { yes },
+        }) => cmd::config::migrate(args.config.as_deref(), *yes).map(|()| ExitCode::SUCCESS),
+        Some(Command::Import {
+            file,
+            format,
+            replace,
+        }) => with_locked_db(&args, |_, _, conn| {
+            cmd::import::run(conn, file, *format, *replace)
+        })
+        .map(|()| ExitCode::SUCCESS),
+        None => scan(&args),
+    };
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            error!("{e:#}");
+            exit::code_for(&e)
+        }
+    }
+}
+
+/// Load the configuration, with the command line taking precedence. Returns the configuration
+/// and the path of the database.
+fn load_config(args: &Args) -> Result<(Config, PathBuf)> {
+    let config = config::load(
+        args.config.as_deref(),
+        args.profile(),
+        args.root_dirs.first().map(PathBuf::as_path),
+    )
+    .context(Failure::Config)?;
+    checksum::ignore_in_html(&config.html_ignore).context(Failure::Config)?;
+    let db_path = match args.db_path.clone().or_else(|| config.db_path.clone()) {
+        Some(db_path) => db_path,
+        None => {
+            // Sites selected from the same file share the base URL host, but not their name
+            let site = args.site.as_deref().unwrap_or(config.host());
+            // Each environment has its own cache, so its own state
+            let site = match &args.env {
+                Some(env) => format!("{site}.{env}"),
+                None => site.to_owned(),
+            };
+            match db::legacy_path() {
+                Some(legacy) if args.site.is_none() && args.env.is_none() => legacy,
+                legacy => {
+                    if let Some(legacy) = legacy {
+                        warn!(
+                            "Ignoring {}, shared by all the sites and environments, \
+                             in favor of the database of {site}",
+                            legacy.display()
+                        );
+                    }
+                    db::default_path(&site).context(Failure::Db)?
+                }
+            }
+        }
+    };
+    Ok((config, db_path))
+}
+
+/// Size the global thread pool, which can only be done once
+fn setup_threads(args: &Args, config: &Config) -> Result<()> {
+    if let Some(threads) = args.threads.or(config.threads) {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .context(Failure::Config)?;
+    }
+    Ok(())
+}
+
+/// Append the messages to the log file of the command line or the configuration, if any
+fn setup_log_file(args: &Args, config: &Config) -> Result<()> {
+    let Some(path) = args.log_file.as_ref().or(config.log_file.as_ref()) else {
+        return Ok(());
+    };
+    let kept = config
+        .log_files_kept
+        .unwrap_or(output::DEFAULT_LOG_FILES_KEPT);
+    output::log_to_file(path, config.log_rotation, kept)
+        .with_context(|| format!("failed to open the log file {}", path.display()))
+        .context(Failure::Config)
+}
+
+/// Open the database, for subcommands that only need that from the configuration
+fn open_db(args: &Args) -> Result<Connection> {
+    let (config, db_path) = load_config(args)?;
+    setup_threads(args, &config)?;
+    open_checked(args, &config, &db_path)
+}
+
+/// Run the subcommand writing to the database, with the database locked so that it doesn’t write
+/// at the same time as a run or another subcommand, and synced with the remote state
+fn with_locked_db<T>(
+    args: &Args,
+    f: impl FnOnce(&Config, &Path, &mut Connection) -> Result<T>,
+) -> Result<T> {
+    let (config, db_path) = load_config(args)?;
+    setup_threads(args, &config)?;
+    let _lock = lock_db(&db_path)?;
+    with_state(&config, &db_path, false, || {
+        let mut conn = open_checked(args, &config, &db_path)?;
+        f(&config, &db_path, &mut conn)
+    })
+}
+
+/// Make sure that no other run writes to the database until the returned lock is dropped
+fn lock_db(db_path: &Path) -> Result<File> {
+    db::lock(db_path).context(Failure::Db)?.ok_or_else(|| {
+        anyhow!(
+            "another run is using {}, wait for it to finish",
+            db_path.display()
+        )
+        .context(Failure::Locked)
+    })
+}
+
+/// Open the database after checking its integrity. A corrupted database is set aside, after
+/// confirmation unless `--auto-rebuild` is passed, and a new one is created in its place.
+fn open_checked(args: &Args, config: &Config, db_path: &Path) -> Result<Connection> {
+    let open = || -> Result<(Connection, Vec<String>)> {
+        let conn = db::open(db_path, &config.sqlite)?;
+        let problems = db::quick_check(&conn)?;
+        Ok((conn, problems))
+    };
+    let problem = match open() {
+        Ok((conn, problems)) if problems.is_empty() => return Ok(conn),
+        Ok((_, problems)) => problems.join(", "),
+        Err(e) if db::is_corruption(&e) => format!("{e:#}"),
+        Err(e) => return Err(e.context(Failure::Db)),
+    };
+    let corrupted = format!("the database {} is corrupted: {problem}", db_path.display());
+    if !args.auto_rebuild && !output::is_interactive() {
+        return Err(anyhow!(
+            "{corrupted}, pass --auto-rebuild to set it aside and rebuild it with a full scan"
+        )
+        .context(Failure::Db));
+    }
+    warn!("{corrupted}");
+    let question = "Set it aside and rebuild it with a full scan, purging every file?";
+    if !args.auto_rebuild && !output::confirm(question).context(Failure::Aborted)? {
+        return Err(Failure::Aborted.into());
+    }
+    let aside = db::set_aside(db_path).context(Failure::Db)?;
+    warn!(
+        "Moved the corrupted database to {}, the next scan rebuilds it",
+        aside.display()
+    );
+    db::open(db_path, &config.sqlite).context(Failure::Db)
+}
+
+/// Scan once or, with `--interval`, forever. In the latter case, failed runs don’t stop the next
+/// ones and the configuration is reloaded when its file changes.
+fn scan(args: &Args) -> Result<ExitCode> {
+    let (mut config, mut db_path) = load_config(args)?;
+    setup_threads(args, &config)?;
+    setup_log_file(args, &config)?;
+    let Some(interval) = args.interval else {
+        return run_with_hooks(args, &config, &db_path);
+    };
+
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+    let mut config_modified = modified(&config.path);
+    loop {
+        if let Err(e) = run_with_hooks(args, &config, &db_path) {
+            error!("{e:#}");
+        }
+        thread::sleep(interval);
+
+        let new_modified = modified(&config.path);
+        if new_modified != config_modified {
+            config_modified = new_modified;
+            match load_config(args) {
+                Ok((new_config, new_db_path)) => {
+                    info!("Reloaded the configuration from {}", config.path.display());
+                    (config, db_path) = (new_config, new_db_path);
+                    if let Err(e) = setup_log_file(args, &config) {
+                        error!("{e:#}");
                     }
-                } else {
-                    Ok(PathOutcome::Skip)
                 }
-            },
+                Err(e) => error!("{e:#}, keeping the previous configuration"),
+            }
+        }
+    }
+}
+
+/// Run once, with the pre and post run hooks of the configuration
+fn run_with_hooks(args: &Args, config: &Config, db_path: &Path) -> Result<ExitCode> {
+    let _lock = lock_db(db_path)?;
+    if let Some(cmd) = &config.pre_run_cmd {
+        hook::run("pre_run_cmd", cmd, None, &[])?;
+    }
+    let result = run_with_state(args, config, db_path);
+    if let Some(cmd) = &config.post_run_cmd {
+        let success = matches!(result, Ok(code) if code == ExitCode::SUCCESS);
+        let status = if success { "success" } else { "failure" };
+        if let Err(e) = hook::run("post_run_cmd", cmd, None, &[("STATIC_CDN_RESULT", status)]) {
+            if success {
+                return Err(e);
+            }
+            // The failure of the run matters more
+            error!("{e:#}");
+        }
+    }
+    result
+}
+
+/// Run with the database downloaded from the remote state, if configured, and uploaded back
+/// after the run, even a failed one, as it records what is left to purge
+fn run_with_state(args: &Args, config: &Config, db_path: &Path) -> Result<ExitCode> {
+    with_state(config, db_path, args.dry_run || config.dry_run, || {
+        run(args, config, db_path)
+    })
+}
+
+/// Call `f` on the database downloaded from the remote state, if one is configured, uploading
+/// the database afterwards, even when `f` fails, unless it’s a dry run
+fn with_state<T>(
+    config: &Config,
+    db_path: &Path,
+    dry_run: bool,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let Some(settings) = &config.state else {
+        return f();
+    };
+    let remote = RemoteState::new(settings).context(Failure::Db)?;
+    let etag = remote.download(db_path).context(Failure::Db)?;
+    let result = f();
+    // Dry runs leave the database untouched
+    if dry_run {
+        return result;
+    }
+    if let Err(e) = remote.upload(db_path, etag.as_deref()) {
+        let e = e.context(Failure::Db);
+        if result.is_err() {
+            // The failure of the run matters more
+            error!("{e:#}");
+        } else {
+            return Err(e);
+        }
+    }
+    result
+}
+
+/// Runs charted by the HTML report
+const HISTORY_CHART_RUNS: usize = 100;
+/// Files found but not checked yet, above which the walk waits
+const FOUND_FILES_QUEUED: usize = 4096;
+
+/// Run once, writing the report of the run with `--report`, sending the notifications and
+/// exporting the metrics and spans configured
+fn run(args: &Args, config: &Config, db_path: &Path) -> Result<ExitCode> {
+    let mut report = Report::new(args.dry_run || config.dry_run);
+    let result = scan_and_purge(args, config, db_path, &mut report);
+    report.finish(&result);
+    if let Some(settings) = &config.notify {
+        // The purges are done, a missed notification doesn’t fail them
+        for e in notify::send(settings, config.host(), &report) {
+            error!("{e:#}");
+        }
+    }
+    if let Some(settings) = &config.metrics {
+        for e in metrics::export(settings, config.host(), &report) {
+            error!("{e:#}");
+        }
+    }
+    if let Some(settings) = &config.otlp {
+        if let Err(e) = otlp::export(settings, config.host(), &report) {
+            error!("{e:#}");
+        }
+    }
+    let writes = [
+        args.report.as_ref().map(|path| report.write(path)),
+        args.report_html
+            .as_ref()
+            .map(|path| report.write_html(path, config.host())),
+    ];
+    for e in writes.into_iter().flatten().filter_map(Result::err) {
+        if result.is_err() {
+            // The failure of the run matters more
+            error!("{e:#}");
+        } else {
+            return Err(e);
+        }
+    }
+    result
+}
+
+fn scan_and_purge(
+    args: &Args,
+    config: &Config,
+    db_path: &Path,
+    report: &mut Report,
+) -> Result<ExitCode> {
+    let root_dirs = if args.root_dirs.is_empty() {
+        &config.root_dirs
+    } else {
+        &args.root_dirs
+    };
+    if root_dirs.is_empty() {
+        return Err(anyhow!(
+            "no root directory, pass one on the command line or set root_dirs in the configuration"
         )
-        .partition_map(|r| match r {
-            Ok(PathOutcome::Skip) => Either::Left(Either::Left(())),
+        .context(Failure::Config));
+    }
+    let root_dirs = &root_dirs
+        .iter()
+        .map(|root_dir| walk::long_path(root_dir))
+        .collect::<io::Result<Vec<_>>>()
+        .context(Failure::Scan)?;
+    info!(
+        "Scanning {}...",
+        root_dirs
+            .iter()
+            .map(|root_dir| root_dir.display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    let dry_run = args.dry_run || config.dry_run;
+    let force_deep_check = args.force_deep_check || config.force_deep_check;
+    let fast_hash = args.fast_hash || config.fast_hash;
+    if let Some(algorithm) = config.audit_digest.filter(|a| !a.is_cryptographic()) {
+        return Err(
+            anyhow!("audit_digest is {}, use sha256 or blake3", algorithm.name())
+                .context(Failure::Config),
+        );
+    }
+    let exclude = config
+        .exclude
+        .iter()
+        .map(|glob| Glob::new(glob))
+        .chain(args.exclude.iter().cloned().map(Ok))
+        .collect::<Result<Vec<_>, _>>()
+        .context(Failure::Config)?;
+    let filter = walk::Filter::new(&exclude, &args.include)
+        .context(Failure::Config)?
+        .with_max_depth(args.max_depth)
+        .with_follow_symlinks(args.follow_symlinks)
+        .with_respect_gitignore(args.respect_gitignore)
+        .with_hidden(args.hidden)
+        .with_path_case(config.path_case);
+    // The first root directory stands for the others, which are usually on the same file system
+    checksum::read_size::set(config.read_size.resolve(&root_dirs[0]));
+
+    let mut conn = open_checked(args, config, db_path)?;
+    let mtimes_unreliable = config.detect_unreliable_mtimes
+        && db::cached_value(&conn, UNRELIABLE_MTIMES_KEY)
+            .context(Failure::Db)?
+            .is_some_and(|value| value == "true");
+    if mtimes_unreliable {
+        info!("Modification times are unreliable, checking the content of every file");
+    }
+    let force_deep_check = force_deep_check || mtimes_unreliable;
+    let skip_unchanged_dirs = (args.skip_unchanged_dirs || config.skip_unchanged_dirs)
+        && !force_deep_check
+        && args.paths_from.is_none();
+    let filter = Arc::new(match root_dirs.as_slice() {
+        [root_dir] if skip_unchanged_dirs => {
+            let recorded = db::dirs(&conn).context(Failure::Db)?;
+            let tracked = db::paths(&conn).context(Failure::Db)?;
+            filter.with_unchanged_dirs(walk::UnchangedDirs::new(root_dir, &recorded, &tracked))
+        }
+        _ if skip_unchanged_dirs => {
+            warn!("Walking all the directories, as there are several root directories");
+            filter
+        }
+        _ => filter,
+    });
+    let phase = Instant::now();
+    let list: Option<Box<dyn BufRead + Send>> = match &args.paths_from {
+        None => None,
+        Some(paths_from) if paths_from == Path::new("-") => {
+            Some(Box::new(BufReader::new(io::stdin())))
+        }
+        Some(paths_from) => {
+            let file = File::open(paths_from)
+                .with_context(|| format!("failed to open {paths_from:?}"))
+                .context(Failure::Scan)?;
+            Some(Box::new(BufReader::new(file)))
+        }
+    };
+    let known = db::known_files(&conn).context(Failure::Db)?;
+    let legacy = known
+        .values()
+        .filter(|k| k.checksum_scheme().algorithm == checksum::Algorithm::XxHash64Legacy)
+        .count();
+    if legacy > 0 {
+        info!(
+            "{legacy} files have checksums computed by an older version, run `{} db rebaseline` \
+            to update them without purging",
+            env!("CARGO_PKG_NAME")
+        );
+    }
+    let statting = report::Stopwatch::default();
+    let hashing = report::Stopwatch::default();
+    let skipped = |metadata: &std::fs::Metadata| {
+        args.since
+            .is_some_and(|since| metadata.modified().is_ok_and(|m| m < since))
+    };
+    // Outcome of the file, from its metadata and, when they changed, its content
+    let linked = checksum::Linked::default();
+    let bytes_hashed = AtomicU64::new(0);
+    let detect = |path: &Path,
+                  db_path: RelPath,
+                  metadata: &std::fs::Metadata,
+                  prefetched: Option<&Checksum>|
+     -> Result<PathOutcome> {
+        let metadata_values = MetadataValues::from(metadata);
+        let known = known.get(&db_path);
+        let current_scheme = current_scheme(config, fast_hash, path, metadata_values.size());
+        let rel_path = db_path.get_relative_path();
+        if let Some(scheme) =
+            scheme_to_check(known, &metadata_values, force_deep_check, current_scheme)
+        {
+            if current_scheme == checksum::METADATA_SCHEME {
+                warn!(
+                    path = %rel_path,
+                    "not hashing the file, larger than max_hash_size: only its size and \
+                     modification time tell whether it changed"
+                );
+            } else {
+                bytes_hashed.fetch_add(metadata_values.size(), Ordering::Relaxed);
+            }
+            let mut checksum = match prefetched {
+                Some(checksum) => *checksum,
+                None => hashing.time(|| linked.compute(path, metadata, scheme))?,
+            };
+            // Only built with -vv
+            let why_check = || match known {
+                None => "new file".to_owned(),
+                Some(_) if force_deep_check => "deep check forced".to_owned(),
+                Some(known) => known.why_check(&metadata_values),
+            };
+            match known {
+                Some(known) if known.same_content(&metadata_values, checksum) => {
+                    trace!(
+                        path = %rel_path,
+                        "metadata updated: {}, but checksum matches",
+                        why_check()
+                    );
+                    return Ok(PathOutcome::UpdateMetdata(db_path, metadata_values));
+                }
+                Some(known) => trace!(
+                    path = %rel_path,
+                    "changed: {}, and {}",
+                    why_check(),
+                    known.content_difference(&metadata_values, checksum)
+                ),
+                None => trace!(path = %rel_path, "changed: new file"),
+            }
+            if scheme != current_scheme {
+                checksum = hashing.time(|| linked.compute(path, metadata, current_scheme))?;
+            }
+            let content_type = mime::detect(path)?;
+            let audit_digest = config
+                .audit_digest
+                .filter(|_| current_scheme != checksum::METADATA_SCHEME)
+                .map(|algorithm| -> Result<_> {
+                    Ok((
+                        hashing.time(|| linked.compute(path, metadata, algorithm.scheme()))?,
+                        algorithm,
+                    ))
+                })
+                .transpose()?;
+            Ok(PathOutcome::StoreAndInvalidate(
+                db_path,
+                metadata_values,
+                checksum,
+                current_scheme,
+                content_type,
+                audit_digest,
+            ))
+        } else if known.is_some_and(|k| k.lacks_metadata(&metadata_values)) {
+            trace!(
+                path = %rel_path,
+                "metadata updated: same metadata, recording the ctime and inode"
+            );
+            // Recorded for the next runs to catch more changes from the metadata
+            Ok(PathOutcome::UpdateMetdata(db_path, metadata_values))
+        } else {
+            trace!(path = %rel_path, "unchanged: same metadata");
+            Ok(PathOutcome::Skip(db_path))
+        }
+    };
+    // Files are checked as they are found, the walk waiting when too many of them are queued
+    let (sender, receiver) = mpsc::sync_channel::<walk::FoundFile>(FOUND_FILES_QUEUED);
+    let scan_span = info_span!("scan");
+    let span = info_span!("detect").entered();
+    info!("Detecting changes");
+    let hash_progress = output::bytes_spinner("Detecting changes");
+    let filter = &filter;
+    let (walked, detected) = thread::scope(|scope| {
+        let walk = scope.spawn(move || {
+            let _span = scan_span.entered();
+            let scan_progress = output::spinner("Scanning", "files found");
+            let mut scanned = HashSet::new();
+            let found = |file: walk::FoundFile| {
+                scanned.insert(file.1.clone());
+                // The receiver is only gone when the detection panicked
+                let _ = sender.send(file);
+            };
+            let (found_dirs, errors) = match list {
+                None => walk::files(root_dirs, filter, &scan_progress, found),
+                Some(list) => {
+                    let errors = walk::listed_files(root_dirs, filter, list, &scan_progress, found);
+                    (walk::FoundDirs::default(), errors)
+                }
+            };
+            scan_progress.finish();
+            (scanned, found_dirs, errors, Instant::now())
+        });
+        let check = |(path, db_path): walk::FoundFile,
+                     prefetched: Option<&(Checksum, std::fs::Metadata)>|
+         -> Result<PathOutcome> {
+            let path = path.as_path();
+            let (metadata, prefetched) = match prefetched {
+                // Taken before the content was read ahead, so that writes since are caught
+                Some((checksum, metadata)) => (metadata.clone(), Some(*checksum)),
+                None => (statting.time(|| path.metadata())?, None),
+            };
+            // Weighted by size, as most of the time goes into hashing
+            hash_progress.inc(metadata.len());
+            if skipped(&metadata) {
+                trace!(
+                    path = %db_path.get_relative_path(),
+                    "unchanged: modified before --since"
+                );
+                return Ok(PathOutcome::Skip(db_path));
+            }
+            check_unchanged(
+                path,
+                &db_path,
+                metadata,
+                prefetched,
+                |path| statting.time(|| path.metadata()),
+                |metadata, prefetched| detect(path, db_path.clone(), metadata, prefetched),
+            )
+        };
+        let prefetched;
+        let outcomes = if config.io_uring {
+            // Small files to hash are read ahead, many at a time, so they are all found first
+            let found: Vec<_> = receiver.into_iter().collect();
+            let to_hash: Vec<(&Path, Scheme, std::fs::Metadata)> = found
+                .par_iter()
+                .filter_map(|(path, db_path)| {
+                    let metadata = statting
+                        .time(|| path.metadata())
+                        .ok()
+                        .filter(|m| !skipped(m))?;
+                    let current_scheme = current_scheme(config, fast_hash, path, metadata.len());
+                    let scheme = scheme_to_check(
+                        known.get(db_path),
+                        &MetadataValues::from(&metadata),
+                        force_deep_check,
+                        current_scheme,
+                    )?;
+                    Some((path.as_path(), scheme, metadata))
+                })
+                .collect();
+            let files: Vec<(&Path, Scheme)> = to_hash
+                .iter()
+                .map(|&(path, scheme, _)| (path, scheme))
+                .collect();
+            let mut before: HashMap<&Path, std::fs::Metadata> = to_hash
+                .into_iter()
+                .map(|(path, _, metadata)| (path, metadata))
+                .collect();
+            prefetched = hashing
+                .time(|| checksum::prefetch(&files))
+                .into_iter()
+                .filter_map(|(path, checksum)| {
+                    let metadata = before.remove(path.as_path())?;
+                    Some((path, (checksum, metadata)))
+                })
+                .collect::<HashMap<_, _>>();
+            Either::Left(found.into_par_iter().map(|file| {
+                let prefetched = prefetched.get(&file.0);
+                check(file, prefetched)
+            }))
+        } else {
+            Either::Right(
+                receiver
+                    .into_iter()
+                    .par_bridge()
+                    .map(|file| check(file, None)),
+            )
+        };
+        let detected: ((Vec<_>, Vec<_>), (Vec<_>, Vec<_>)) = outcomes.partition_map(|r| match r {
+            Ok(PathOutcome::Skip(p)) => Either::Left(Either::Left(p)),
             Ok(PathOutcome::UpdateMetdata(p, mv)) => Either::Left(Either::Right((p, mv))),
-            Ok(PathOutcome::StoreAndInvalidate(p, mv, c)) => {
-                Either::Right(Either::Left((p, mv, c)))
+            Ok(PathOutcome::StoreAndInvalidate(p, mv, c, s, t, a)) => {
+                Either::Right(Either::Left((p, mv, c, s, t, a)))
             }
             Err(e) => Either::Right(Either::Right(e)),
         });
+        let walked = walk
+            .join()
+            .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        (walked, detected)
+    });
+    let (mut scanned, found_dirs, walk_errors, walked) = walked;
+    let ((unchanged, updates), (store, errors)) = detected;
+    hash_progress.finish();
+    report.bytes_hashed = bytes_hashed.into_inner();
+    report.timings.walking_sec = (walked - phase).as_secs_f64();
+    report.timings.statting_sec = statting.secs();
+    report.timings.hashing_sec = hashing.secs();
+    report.phase_between("scan", phase..walked);
+    let phase = report.phase("detect", phase);
+    drop(span);
+    let file_count = scanned.len();
+    if !found_dirs.skipped.is_empty() {
+        info!(
+            "Skipped {} directories unchanged since the previous run",
+            found_dirs.skipped.len()
+        );
+        // Their files are still there
+        scanned.extend(known.keys().filter(|path| found_dirs.skips(path)).cloned());
+    }
+    for e in &walk_errors {
+        error!("error encountered while scanning: {e}");
+        report.errors.push(format!("{e:#}"));
+    }
+    for e in &errors {
+        error!("error encountered: {e}");
+        report.errors.push(format!("{e:#}"));
+    }
+    let scan_failed = !errors.is_empty() || !walk_errors.is_empty();
+    if let Some(quiet_period) = args.quiet_period {
+        let settled = SystemTime::now() - quiet_period;
+        if store
+            .iter()
+            .any(|(_, metadata_values, ..)| metadata_values.modified() > settled)
+        {
+            info!(
+                "Files changed less than {} ago, waiting for them to settle",
+                humantime::format_duration(quiet_period)
+            );
+            // Nothing is written nor purged, the next run finds the same changes
+            report.deferred = true;
+            return Ok(if scan_failed {
+                Failure::Scan.into()
+            } else {
+                ExitCode::SUCCESS
+            });
+        }
+    }
+    let span = info_span!("write").entered();
 
-    println!("Updating the cache");
-    // Write operations are single-threaded in SQLite
-    let tx = conn.transaction()?;
-    for (path, metadata_values) in &updates {
-        db::update_metadata(&tx, path, &metadata_values)?;
+    if config.detect_unreliable_mtimes {
+        match unreliable_mtimes(&known, unchanged.len(), &updates, &store) {
+            Some(unreliable) if unreliable != mtimes_unreliable => {
+                if unreliable {
+                    info!(
+                        "All the files have a new modification time but the same content, \
+                        checking the content of every file from now on"
+                    );
+                } else {
+                    info!("Modification times are reliable again");
+                }
+                if !dry_run {
+                    db::set_cached_value(&conn, UNRELIABLE_MTIMES_KEY, &unreliable.to_string())
+                        .context(Failure::Db)?;
+                }
+            }
+            _ => (),
+        }
     }
-    for (path, metadata_values, checksum) in &store {
-        // TODO Coordinate this with calls to the CDN API
-        db::upsert_entry(&tx, path, &metadata_values, *checksum)?;
+
+    let deleted = if walk_errors.is_empty() {
+        deleted_files(&known, root_dirs, &scanned, config.path_case)
+    } else {
+        // Files that couldn’t be scanned would look deleted
+        warn!("Not looking for deleted files, as some files couldn’t be scanned");
+        Vec::new()
+    };
+    for path in &deleted {
+        debug!(path = %path.get_relative_path(), "deleted");
     }
-    tx.commit()?;
+    let moved = moved_files(&known, &store, &deleted);
+    // The files of renamed directories are purged at once, by the prefix of their old URLs
+    let prefixes = if config.provider.prefix_purge() {
+        renamed_dirs(&known, &moved, &deleted)
+            .into_iter()
+            .map(|(dir, files)| cdn::prefix(&config.base_url, dir).map(|prefix| (prefix, files)))
+            .collect::<Result<Vec<_>>>()
+            .context(Failure::Config)?
+    } else {
+        Vec::new()
+    };
+    let purged_by_prefix: HashSet<&RelPath> = prefixes
+        .iter()
+        .flat_map(|(_, files)| files.iter().copied())
+        .collect();
 
-    for e in &errors {
-        error!("error encountered: {e}")
+    // Moved files are new URLs, so not cached yet, only their old URL is purged
+    let mut to_purge = store
+        .iter()
+        .map(|(path, ..)| path)
+        .filter(|path| !moved.contains_key(path))
+        .chain(
+            deleted
+                .iter()
+                .filter(|path| !purged_by_prefix.contains(path)),
+        )
+        .map(|path| cdn::urls(&config.base_url, path.as_bytes()).map(|urls| (path, urls)))
+        .collect::<Result<Vec<_>>>()
+        .context(Failure::Config)?;
+    cdn::dedup(&mut to_purge);
+    let url_count = to_purge.iter().map(|(_, urls)| urls.len()).sum::<usize>() + prefixes.len();
+    if !dry_run && !args.yes {
+        confirm_purge(config, url_count)?;
     }
 
-    dbg!(store.chunks(30).len());
+    // Leave the database untouched on dry runs, so that the next run still purges the changes
+    let run_id = if dry_run {
+        None
+    } else {
+        Some(db::start_run(&conn).context(Failure::Db)?)
+    };
+    let (mut purge_failures, mut purged) = match run_id {
+        Some(run_id) => {
+            let handled: HashSet<&RelPath> = store
+                .iter()
+                .map(|(path, ..)| path)
+                .chain(&deleted)
+                .collect();
+            resume(config, args, &mut conn, run_id, root_dirs, &handled, report)?
+        }
+        None => (0, Vec::new()),
+    };
+
+    if let Some(run_id) = run_id {
+        info!("Updating the cache");
+        let db_progress = output::progress_bar(
+            "Updating the cache",
+            (updates.len() + store.len() + deleted.len()) as u64,
+        );
+        let writes: Vec<_> = updates
+            .iter()
+            .map(|(path, metadata_values)| Write::Metadata(path, metadata_values))
+            .chain(store.iter().map(
+                |(path, metadata_values, checksum, scheme, content_type, audit_digest)| {
+                    // Until the purge is confirmed, the next runs see the file as changed
+                    let purge_state = if moved.contains_key(path) {
+                        PurgeState::Confirmed
+                    } else {
+                        PurgeState::Pending
+                    };
+                    Write::Content(
+                        path,
+                        metadata_values,
+                        *checksum,
+                        *scheme,
+                        content_type,
+                        purge_state,
+                        *audit_digest,
+                    )
+                },
+            ))
+            // Removed once their purge is confirmed
+            .chain(deleted.iter().map(Write::Deletion))
+            .collect();
+        // Write operations are single-threaded in SQLite. Committing in chunks doesn’t hold the
+        // lock for too long on huge sites. Every change is complete on its own, and files are
+        // pending until purged, so an interrupted run is resumed by the next one.
+        let writing = Instant::now();
+        for chunk in writes.chunks(config.sqlite.transaction_size()) {
+            let tx = conn.transaction().context(Failure::Db)?;
+            for write in chunk.iter().progress_with(db_progress.clone()) {
+                match *write {
+                    Write::Metadata(path, metadata_values) => {
+                        db::update_metadata(&tx, run_id, path, metadata_values)
+                    }
+                    Write::Content(
+                        path,
+                        metadata_values,
+                        checksum,
+                        scheme,
+                        content_type,
+                        purge_state,
+                        audit_digest,
+                    ) => db::upsert_entry(
+                        &tx,
+                        run_id,
+                        path,
+                        metadata_values,
+                        checksum,
+                        scheme,
+                        content_type,
+                        purge_state,
+                    )
+                    .and_then(|()| match audit_digest {
+                        Some((digest, algorithm)) => {
+                            db::set_audit_digest(&tx, path, digest, algorithm)
+                        }
+                        None => Ok(()),
+                    }),
+                    Write::Deletion(path) => db::set_purge_state(&tx, path, PurgeState::Pending),
+                }
+                .context(Failure::Db)?;
+            }
+            tx.commit().context(Failure::Db)?;
+            debug!("committed {} changes", chunk.len());
+        }
+        report.timings.db_write_sec = writing.elapsed().as_secs_f64();
+        db_progress.finish();
+    }
+    let phase = report.phase("write", phase);
+    drop(span);
+    let span = info_span!("purge", provider = config.provider.name()).entered();
 
-    dbg!(store.chunks(30).count());
-    // TODO Actually perform the update
-    //.for_each(|u| println!("update: {u:?}"));
+    match run_id {
+        None => {
+            for url in to_purge.iter().flat_map(|(_, urls)| urls) {
+                info!("Would purge {url}");
+            }
+            for (prefix, _) in &prefixes {
+                info!("Would purge everything under {prefix}");
+            }
+        }
+        Some(run_id) => {
+            let deleted: HashSet<&RelPath> = deleted.iter().collect();
+            let (failures, urls) =
+                purge(config, args, &mut conn, run_id, &to_purge, &deleted, report)?;
+            purge_failures += failures;
+            purged.extend(urls);
+            let (failures, urls) = purge_prefixes(config, &mut conn, run_id, &prefixes, report)?;
+            purge_failures += failures;
+            purged.extend(urls);
+            if skip_unchanged_dirs {
+                // Deleted files stay tracked until their purge succeeds
+                let complete = walk_errors.is_empty() && errors.is_empty() && purge_failures == 0;
+                record_dirs(&mut conn, &found_dirs, complete).context(Failure::Db)?;
+            }
+        }
+    }
+    report.phase("purge", phase);
+    drop(span);
+    for path in &unchanged {
+        report.file(path, report::Outcome::Unchanged);
+    }
+    for (path, _) in &updates {
+        report.file(path, report::Outcome::MetadataUpdated);
+    }
+    for (path, ..) in &store {
+        match moved.get(path) {
+            Some(from) => report.moved(path, from),
+            None if known.contains_key(path) => report.file(path, report::Outcome::Changed),
+            None => report.file(path, report::Outcome::New),
+        }
+    }
+    let moved_from: HashSet<&RelPath> = moved.values().copied().collect();
+    for path in deleted.iter().filter(|path| !moved_from.contains(path)) {
+        report.file(path, report::Outcome::Deleted);
+    }
+    if args.report_html.is_some() {
+        match db::run_changes(&conn, HISTORY_CHART_RUNS) {
+            Ok(history) => report.history = history,
+            Err(e) => warn!("failed to read the history for the HTML report: {e:#}"),
+        }
+    }
+    if let Some(what) = args.print_changed {
+        let paths = store.iter().map(|(path, ..)| path).chain(&deleted);
+        print_changed(&mut io::stdout().lock(), what, &config.base_url, paths)?;
+    }
+    let mut hook_failed = false;
+    if let (Some(cmd), false) = (&config.post_purge_cmd, purged.is_empty()) {
+        let input: String = purged.iter().map(|url| format!("{url}\n")).collect();
+        if let Err(e) = hook::run("post_purge_cmd", cmd, Some(&input), &[]) {
+            error!("{e:#}");
+            hook_failed = true;
+        }
+    }
 
-    log::debug!(
-        "Summary: {} unchanged, {} with different metadata and {} changed files.",
-        unchanged.len(),
-        updates.len(),
-        store.len()
-    );
-    println!("Total: {file_count} files.");
-    Ok(if errors.len() > 0 {
-        2.into()
+    if args.ci {
+        // A single line, easy to spot in job logs
+        info!(
+            "{file_count} files: {} unchanged, {} with different metadata, {} changed, {} moved, \
+             {} deleted, {} errors, {purge_failures} failed purge batches",
+            unchanged.len(),
+            updates.len(),
+            store.len() - moved.len(),
+            moved.len(),
+            deleted.len() - moved.len(),
+            errors.len() + walk_errors.len(),
+        );
+    } else {
+        for row in report.summary() {
+            info!("{row}");
+        }
+    }
+    Ok(if purge_failures > 0 {
+        Failure::Cdn.into()
+    } else if scan_failed {
+        Failure::Scan.into()
+    } else if hook_failed {
+        Failure::Hook.into()
+    } else {
+        ExitCode::SUCCESS
+    })
+}
+
+/// Write the files, or their URLs, sorted, one per line
+fn print_changed<'a>(
+    out: &mut impl io::Write,
+    what: Changed,
+    base_url: &Url,
+    paths: impl Iterator<Item = &'a RelPath>,
+) -> Result<()> {
+    let lines: BTreeSet<String> = match what {
+        Changed::Paths => paths
+            .map(|path| path.get_relative_path().to_owned())
+            .collect(),
+        Changed::Urls => paths
+            .map(|path| cdn::urls(base_url, path.as_bytes()))
+            .collect::<Result<Vec<_>>>()
+            .context(Failure::Config)?
+            .into_iter()
+            .flatten()
+            .map(String::from)
+            .collect(),
+    };
+    for line in lines {
+        writeln!(out, "{line}")?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Scheme to hash the file with, to compare it with the stored checksum, computed with the scheme
+/// of the time. There is nothing to compare when its metadata tell it’s unchanged.
+fn scheme_to_check(
+    known: Option<&db::Known>,
+    metadata_values: &MetadataValues,
+    force_deep_check: bool,
+    current_scheme: Scheme,
+) -> Option<Scheme> {
+    if force_deep_check || !known.is_some_and(|k| k.same_metadata(metadata_values)) {
+        // Files too large to hash are compared by their metadata, even if they were hashed before
+        Some(match known {
+            Some(known) if current_scheme != checksum::METADATA_SCHEME => known.checksum_scheme(),
+            _ => current_scheme,
+        })
+    } else {
+        None
+    }
+}
+
+/// Scheme of the checksums computed from now on for the file
+fn current_scheme(config: &Config, fast_hash: bool, path: &Path, size: u64) -> Scheme {
+    if config.max_hash_size.is_some_and(|max| size > max) {
+        return checksum::METADATA_SCHEME;
+    }
+    let scheme = if fast_hash {
+        checksum::PARTIAL_SCHEME
+    } else {
+        config.checksum.scheme_for(path, size)
+    };
+    if config.hash_decompressed {
+        scheme.decompressing(path)
+    } else {
+        scheme
+    }
+}
+
+/// Ask for confirmation before purging more URLs than the threshold of the configuration
+fn confirm_purge(config: &Config, url_count: usize) -> Result<()> {
+    let confirm_threshold = config
+        .confirm_threshold
+        .unwrap_or(cdn::DEFAULT_CONFIRM_THRESHOLD);
+    if url_count > confirm_threshold {
+        let question = format!("{url_count} URLs are about to be purged, continue?");
+        if !output::confirm(&question).context(Failure::Aborted)? {
+            return Err(Failure::Aborted.into());
+        }
+    }
+    Ok(())
+}
+
+/// Purge the URLs of the files changed since the deployment marker, leaving their state to the
+/// next scan, which finds them changed again on disk
+fn rollback_purge(args: &Args, label: &str, dry_run: bool, yes: bool) -> Result<ExitCode> {
+    with_locked_db(args, |config, _, conn| {
+        rollback_purge_locked(args, config, conn, label, dry_run, yes)
+    })
+}
+
+fn rollback_purge_locked(
+    args: &Args,
+    config: &Config,
+    conn: &mut Connection,
+    label: &str,
+    dry_run: bool,
+    yes: bool,
+) -> Result<ExitCode> {
+    let Some(changed) = db::changed_since_marker(conn, label).context(Failure::Db)? else {
+        return Err(anyhow!("no deployment is marked {label}")).context(Failure::Config);
+    };
+    info!("{} files changed since {label}", changed.len());
+    let to_purge = changed
+        .iter()
+        .map(|path| cdn::urls(&config.base_url, path.as_bytes()).map(|urls| (path, urls)))
+        .collect::<Result<Vec<_>>>()
+        .context(Failure::Config)?;
+    if dry_run || config.dry_run {
+        for url in to_purge.iter().flat_map(|(_, urls)| urls) {
+            info!("Would purge {url}");
+        }
+        return Ok(ExitCode::SUCCESS);
+    }
+    if !yes {
+        confirm_purge(config, to_purge.iter().map(|(_, urls)| urls.len()).sum())?;
+    }
+    let run_id = db::start_run(conn).context(Failure::Db)?;
+    let mut report = Report::new(false);
+    let (failures, _) = purge(
+        config,
+        args,
+        conn,
+        run_id,
+        &to_purge,
+        &HashSet::new(),
+        &mut report,
+    )?;
+    Ok(if failures > 0 {
+        Failure::Cdn.into()
     } else {
         ExitCode::SUCCESS
     })
 }
 
+/// Purge again the files whose purge wasn’t confirmed by a previous run, because it was
+/// interrupted or failed, unless this run handles them already, as changed or deleted. Returns the
+/// number of batches that failed and the URLs purged.
+fn resume(
+    config: &Config,
+    args: &Args,
+    conn: &mut Connection,
+    run_id: i64,
+    root_dirs: &[PathBuf],
+    handled: &HashSet<&RelPath>,
+    report: &mut Report,
+) -> Result<(usize, Vec<url::Url>)> {
+    let unconfirmed: Vec<RelPath> = db::unconfirmed_paths(conn)
+        .context(Failure::Db)?
+        .into_iter()
+        .filter(|path| !handled.contains(path))
+        .collect();
+    if unconfirmed.is_empty() {
+        return Ok((0, Vec::new()));
+    }
+    info!(
+        "Resuming the purge of {} files from a previous run",
+        unconfirmed.len()
+    );
+    let deleted: HashSet<&RelPath> = unconfirmed
+        .iter()
+        .filter(|path| !on_disk(root_dirs, path))
+        .collect();
+    let to_purge = unconfirmed
+        .iter()
+        .map(|path| cdn::urls(&config.base_url, path.as_bytes()).map(|urls| (path, urls)))
+        .collect::<Result<Vec<_>>>()
+        .context(Failure::Config)?;
+    let _span = info_span!("resume", provider = config.provider.name()).entered();
+    purge(config, args, conn, run_id, &to_purge, &deleted, report)
+}
+
+/// Whether the file is in one of the root directories
+fn on_disk(root_dirs: &[PathBuf], path: &RelPath) -> bool {
+    let path = path.to_path();
+    root_dirs
+        .iter()
+        .any(|root_dir| root_dir.join(&path).symlink_metadata().is_ok())
+}
+
+/// Record the directories walked, with the number of files now tracked under them, for the next
+/// run to skip those unchanged. Skipped directories keep their record. After errors, the files
+/// tracked may not be those on disk, so all the directories are forgotten and the next run walks
+/// them all.
+fn record_dirs(
+    conn: &mut Connection,
+    found_dirs: &walk::FoundDirs,
+    complete: bool,
+) -> rusqlite::Result<()> {
+    let dirs: Vec<db::Dir> = if complete {
+        let tracked = db::paths(conn)?;
+        let counts = walk::file_counts(&tracked);
+        found_dirs
+            .walked
+            .iter()
+            .map(|(dir, modified_since_epoch_sec)| {
+                let file_count = counts.get(dir.as_bytes()).copied().unwrap_or(0);
+                (dir.clone(), *modified_since_epoch_sec, file_count)
+            })
+            .chain(
+                db::dirs(conn)?
+                    .into_iter()
+                    .filter(|(dir, ..)| found_dirs.skips(dir)),
+            )
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let tx = conn.transaction()?;
+    db::replace_dirs(&tx, &dirs)?;
+    tx.commit()
+}
+
+/// Key of the database cache recording whether modification times were found unreliable
+const UNRELIABLE_MTIMES_KEY: &str = "unreliable_mtimes";
+
+/// Whether the run shows that modification times are unreliable, as when deploys reset them: all
+/// the tracked files checked have a new modification time, yet none of their content changed.
+/// They are reliable again once some files keep theirs. `None` when the run tells neither.
+fn unreliable_mtimes(
+    known: &HashMap<RelPath, db::Known>,
+    unchanged: usize,
+    updates: &[(RelPath, MetadataValues)],
+    store: &[Stored],
+) -> Option<bool> {
+    let (mut retimed, mut steady) = (0, unchanged);
+    for (path, metadata_values) in updates {
+        match known.get(path) {
+            Some(known)
+                if known.modified_since_epoch_sec()
+                    == metadata_values.modified_since_epoch_sec() =>
+            {
+                steady += 1
+            }
+            Some(_) => retimed += 1,
+            None => (),
+        }
+    }
+    let changed = store
+        .iter()
+        .filter(|(path, ..)| known.contains_key(path))
+        .count();
+    if steady > 0 {
+        Some(false)
+    } else if retimed > 0 && changed == 0 {
+        Some(true)
+    } else {
+        None
+    }
+}
+
+/// Tracked files that were neither scanned nor are on disk anymore. Files filtered out, or not
+/// listed by --paths-from, are still on disk.
+fn deleted_files(
+    known: &HashMap<RelPath, db::Known>,
+    root_dirs: &[PathBuf],
+    scanned: &HashSet<RelPath>,
+    path_case: PathCase,
+) -> Vec<RelPath> {
+    // When the case of the name of a file changes on a case-insensitive file system, the file is
+    // still on disk under its old name too, yet it’s tracked under the new one from now on
+    let scanned_keys: HashSet<Vec<u8>> = match path_case {
+        PathCase::Sensitive => HashSet::new(),
+        PathCase::Insensitive => scanned.iter().map(|path| path_case.key(path)).collect(),
+    };
+    known
+        .keys()
+        .filter(|path| {
+            !scanned.contains(*path)
+                && ((!scanned_keys.is_empty() && scanned_keys.contains(&path_case.key(path)))
+                    || !on_disk(root_dirs, path))
+        })
+        .cloned()
+        .collect()
+}
+
+/// New files with the same size and checksum as a deleted file, which were moved there. Returns
+/// their paths, along with the path they were moved from.
+fn moved_files<'a, 'b>(
+    known: &HashMap<RelPath, db::Known>,
+    store: &'a [Stored],
+    deleted: &'b [RelPath],
+) -> HashMap<&'a RelPath, &'b RelPath> {
+    let mut deleted_by_content: HashMap<(u64, Checksum), Vec<&RelPath>> = HashMap::new();
+    for path in deleted {
+        if let Some(known) = known.get(path) {
+            deleted_by_content
+                .entry(known.content())
+                .or_default()
+                .push(path);
+        }
+    }
+
+    let mut moved = HashMap::new();
+    if deleted_by_content.is_empty() {
+        return moved;
+    }
+    for (path, metadata_values, checksum, ..) in store {
+        let Some(old_paths) = deleted_by_content.get_mut(&(metadata_values.size(), *checksum))
+        else {
+            continue;
+        };
+        // A changed file is not moved, even with the content of a deleted one
+        if old_paths.is_empty() || known.contains_key(path) {
+            continue;
+        }
+        let old_path = old_paths.remove(0);
+        info!(
+            "{} moved to {}",
+            old_path.get_relative_path(),
+            path.get_relative_path()
+        );
+        moved.insert(path, old_path);
+    }
+    moved
+}
+
+/// Directories whose tracked files were all deleted, with files moved to another directory under
+/// the same names. Returns the old directories, along with their deleted files.
+fn renamed_dirs<'a>(
+    known: &HashMap<RelPath, db::Known>,
+    moved: &HashMap<&RelPath, &'a RelPath>,
+    deleted: &'a [RelPath],
+) -> Vec<(&'a [u8], Vec<&'a RelPath>)> {
+    let mut new_dirs: BTreeMap<&'a [u8], HashSet<&[u8]>> = BTreeMap::new();
+    for (new_path, old_path) in moved {
+        if let Some((old_dir, new_dir)) = renamed_dir(old_path.as_bytes(), new_path.as_bytes()) {
+            new_dirs.entry(old_dir).or_default().insert(new_dir);
+        }
+    }
+    if new_dirs.is_empty() {
+        return Vec::new();
+    }
+
+    let mut renamed: Vec<(&[u8], Vec<&RelPath>)> = Vec::new();
+    for (old_dir, dirs) in new_dirs {
+        // Files went to several directories, or the directory is in a renamed one
+        if dirs.len() > 1 || renamed.iter().any(|(parent, _)| in_dir(old_dir, parent)) {
+            continue;
+        }
+        let files: Vec<&RelPath> = deleted
+            .iter()
+            .filter(|path| in_dir(path.as_bytes(), old_dir))
+            .collect();
+        let tracked_count = known
+            .keys()
+            .filter(|path| in_dir(path.as_bytes(), old_dir))
+            .count();
+        if files.len() == tracked_count {
+            info!(
+                "{} renamed to {}",
+                String::from_utf8_lossy(old_dir),
+                String::from_utf8_lossy(dirs.into_iter().next().expect("a new directory"))
+            );
+            renamed.push((old_dir, files));
+        }
+    }
+    renamed
+}
+
+/// Directories of a file moved from the old path to the new one, without the trailing path
+/// components they share, like `blog` and `posts` for `blog/2024/a.html` and `posts/2024/a.html`.
+/// There are none when the file was renamed or moved to or from the root directory.
+fn renamed_dir<'a, 'b>(old_path: &'a [u8], new_path: &'b [u8]) -> Option<(&'a [u8], &'b [u8])> {
+    let shared = old_path
+        .rsplit(|&b| b == b'/')
+        .zip(new_path.rsplit(|&b| b == b'/'))
+        .take_while(|(old, new)| old == new)
+        .count();
+    let dir = |path: &[u8]| {
+        let mut separators = path.iter().enumerate().rev().filter(|(_, &b)| b == b'/');
+        separators.nth(shared.checked_sub(1)?).map(|(i, _)| i)
+    };
+    let (old_end, new_end) = (dir(old_path)?, dir(new_path)?);
+    Some((&old_path[..old_end], &new_path[..new_end]))
+}
+
+/// Whether the path is below the directory
+fn in_dir(path: &[u8], dir: &[u8]) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.first() == Some(&b'/'))
+}
+
+/// Purge the URLs of the files from the CDN and record when they were purged, removing the
+/// deleted files once purged. Returns the number of batches that failed and the URLs purged.
+fn purge(
+    config: &Config,
+    args: &Args,
+    conn: &mut Connection,
+    run_id: i64,
+    to_purge: &[(&RelPath, Vec<url::Url>)],
+    deleted: &HashSet<&RelPath>,
+    report: &mut Report,
+) -> Result<(usize, Vec<url::Url>)> {
+    if to_purge.is_empty() {
+        return Ok((0, Vec::new()));
+    }
+
+    let cdn = match cdn::from_config(config, conn) {
+        Ok(cdn) => cdn,
+        Err(e) => {
+            set_purge_state(
+                conn,
+                to_purge.iter().map(|(path, _)| *path),
+                PurgeState::Failed,
+            )?;
+            return Err(e.context(Failure::Cdn));
+        }
+    };
+    let batch_size = args
+        .batch_size
+        .or(config.batch_size)
+        .map_or(cdn.max_batch_size(), |size| {
+            size.clamp(1, cdn.max_batch_size())
+        });
+    let batches = cdn::batches(to_purge, batch_size);
+    info!(
+        "Purging {} URLs in {} batches",
+        to_purge.iter().map(|(_, urls)| urls.len()).sum::<usize>(),
+        batches.len()
+    );
+    let concurrency = args
+        .cdn_concurrency
+        .or(config.cdn_concurrency)
+        .unwrap_or(cdn::DEFAULT_CONCURRENCY);
+    set_purge_state(
+        conn,
+        to_purge.iter().map(|(path, _)| *path),
+        PurgeState::Submitted,
+    )?;
+    let purge_progress = output::progress_bar("Purging", batches.len() as u64);
+    let results = cdn::purge_batches(cdn.as_ref(), batches, concurrency, &purge_progress);
+    purge_progress.finish();
+
+    let purged_at = SystemTime::now();
+    let mut failures = 0;
+    let mut purged = Vec::new();
+    let mut to_fetch = Vec::new();
+    let tx = conn.transaction().context(Failure::Db)?;
+    for (batch, result, timing) in results {
+        // Index of the batch in the report
+        let batch_id = report.batches.len();
+        report.batch(
+            batch
+                .iter()
+                .flat_map(|(_, urls)| urls.iter().cloned())
+                .collect(),
+            false,
+            batch.len(),
+            timing,
+            &result,
+        );
+        match result {
+            Ok(()) => {
+                for (path, urls) in batch {
+                    debug!(path = %path.get_relative_path(), "purged");
+                    if deleted.contains(path) {
+                        db::delete_entry(&tx, run_id, path).context(Failure::Db)?;
+                    } else {
+                        db::set_last_purged(&tx, path, purged_at).context(Failure::Db)?;
+                        to_fetch.extend(urls.first().map(|url| (*path, url.clone())));
+                    }
+                    purged.extend(urls.iter().cloned());
+                }
+            }
+            Err(e) => {
+                failures += 1;
+                error!(
+                    batch_id,
+                    files = batch.len(),
+                    "failed to purge a batch of files: {e:#}"
+                );
+                for (path, _) in batch {
+                    db::set_purge_state(&tx, path, PurgeState::Failed).context(Failure::Db)?;
+                }
+            }
+        }
+    }
+    tx.commit().context(Failure::Db)?;
+    if config.record_cdn_etags {
+        record_cdn_etags(conn, to_fetch, concurrency)?;
+    }
+    Ok((failures, purged))
+}
+
+/// Purge everything under the old URL of the renamed directories, removing their deleted files
+/// once purged. The files of the directories whose purge failed are purged one by one by the next
+/// run. Returns the number of batches that failed and the prefixes purged.
+fn purge_prefixes(
+    config: &Config,
+    conn: &mut Connection,
+    run_id: i64,
+    prefixes: &[(url::Url, Vec<&RelPath>)],
+    report: &mut Report,
+) -> Result<(usize, Vec<url::Url>)> {
+    if prefixes.is_empty() {
+        return Ok((0, Vec::new()));
+    }
+
+    let files = || prefixes.iter().flat_map(|(_, files)| files.iter().copied());
+    let cdn = match cdn::from_config(config, conn) {
+        Ok(cdn) => cdn,
+        Err(e) => {
+            set_purge_state(conn, files(), PurgeState::Failed)?;
+            return Err(e.context(Failure::Cdn));
+        }
+    };
+    info!("Purging {} renamed directories", prefixes.len());
+    let mut failures = 0;
+    let mut purged = Vec::new();
+    let tx = conn.transaction().context(Failure::Db)?;
+    for batch in prefixes.chunks(cdn.max_batch_size()) {
+        let urls: Vec<url::Url> = batch.iter().map(|(prefix, _)| prefix.clone()).collect();
+        let started = Instant::now();
+        let result = cdn::block_on(cdn.purge_prefixes(&urls));
+        let files = batch.iter().map(|(_, files)| files.len()).sum();
+        let batch_id = report.batches.len();
+        report.batch(urls.clone(), true, files, started..Instant::now(), &result);
+        match result {
+            Ok(()) => {
+                for (prefix, files) in batch {
+                    debug!(%prefix, "purged everything under the prefix");
+                    for path in files {
+                        db::delete_entry(&tx, run_id, path).context(Failure::Db)?;
+                    }
+                }
+                purged.extend(urls);
+            }
+            Err(e) => {
+                failures += 1;
+                error!(
+                    batch_id,
+                    directories = batch.len(),
+                    "failed to purge a batch of directories: {e:#}"
+                );
+                for path in batch.iter().flat_map(|(_, files)| files) {
+                    db::set_purge_state(&tx, path, PurgeState::Failed).context(Failure::Db)?;
+                }
+            }
+        }
+    }
+    tx.commit().context(Failure::Db)?;
+    Ok((failures, purged))
+}
+
+/// Fetch the URLs of the files from the CDN, now that they are purged, to record the ETag it
+/// serves. Files that can’t be fetched are only reported.
+fn record_cdn_etags(
+    conn: &mut Connection,
+    to_fetch: Vec<(&RelPath, url::Url)>,
+    concurrency: usize,
+) -> Result<()> {
+    let progress = output::progress_bar("Fetching ETags", to_fetch.len() as u64);
+    let etags = cdn::edge_etags(to_fetch, concurrency, &progress).context(Failure::Cdn)?;
+    progress.finish();
+    let fetched_at = SystemTime::now();
+    let tx = conn.transaction().context(Failure::Db)?;
+    for (path, etag) in etags {
+        match etag {
+            Ok(etag) => {
+                db::set_cdn_etag(&tx, path, etag.as_deref(), fetched_at).context(Failure::Db)?
+            }
+            Err(e) => warn!("{e:#}"),
+        }
+    }
+    tx.commit().context(Failure::Db)
+}
+
+/// Move the purge of all the files to the state, in a single transaction
+fn set_purge_state<'a>(
+    conn: &mut Connection,
+    paths: impl Iterator<Item = &'a RelPath>,
+    purge_state: PurgeState,
+) -> Result<()> {
+    let tx = conn.transaction().context(Failure::Db)?;
+    for path in paths {
+        db::set_purge_state(&tx, path, purge_state).context(Failure::Db)?;
+    }
+    tx.commit().context(Failure::Db)
+}
+
+// Change of the database after a scan
+enum Write<'a> {
+    // Only the metadata changed
+    Metadata(&'a RelPath, &'a MetadataValues),
+    // The content changed, or the file is new
+    Content(
+        &'a RelPath,
+        &'a MetadataValues,
+        Checksum,
+        Scheme,
+        &'a str,
+        PurgeState,
+        Option<(Checksum, checksum::Algorithm)>,
+    ),
+    // The file was deleted
+    Deletion(&'a RelPath),
+}
+
+/// Changed file, with what’s stored about its new content
+type Stored = (
+    RelPath,
+    MetadataValues,
+    Checksum,
+    Scheme,
+    &'static str,
+    Option<(Checksum, checksum::Algorithm)>,
+);
+
+/// Times a file is checked when it keeps changing while being checked
+const CHECK_ATTEMPTS: usize = 3;
+
+/// Detect how the file changed, from its metadata taken before its content was read, even ahead,
+/// and from the checksum prefetched, if any. The content hashed may not match the metadata stored
+/// when the file is written to meanwhile, so it’s checked again, hashed anew, until its metadata
+/// are the same after the check.
+fn check_unchanged(
+    path: &Path,
+    db_path: &RelPath,
+    mut metadata: std::fs::Metadata,
+    mut prefetched: Option<Checksum>,
+    stat: impl Fn(&Path) -> io::Result<std::fs::Metadata>,
+    detect: impl Fn(&std::fs::Metadata, Option<&Checksum>) -> Result<PathOutcome>,
+) -> Result<PathOutcome> {
+    for _ in 0..CHECK_ATTEMPTS {
+        let outcome = detect(&metadata, prefetched.as_ref())?;
+        if matches!(outcome, PathOutcome::Skip(_)) {
+            return Ok(outcome);
+        }
+        let after = stat(path)?;
+        if MetadataValues::from(&after).same_as(&MetadataValues::from(&metadata)) {
+            return Ok(outcome);
+        }
+        debug!(path = %db_path.get_relative_path(), "changed while being checked");
+        (metadata, prefetched) = (after, None);
+    }
+    Err(anyhow!(
+        "{} kept changing while being checked, it’s checked again by the next run",
+        db_path.get_relative_path()
+    ))
+}
+
 // Control what do with the paths
 enum PathOutcome {
     // Path is unchanged, nothing to do (no CDN or DB update)
-    Skip,
+    Skip(RelPath),
     // Path medata have changed, but the checksum is the same, only update the DB
     UpdateMetdata(RelPath, MetadataValues),
-    // Path checksum and metadata have changed, update both the DB and the CDN
-    StoreAndInvalidate(RelPath, MetadataValues, Checksum),
+    // Path checksum and metadata have changed, update both the DB and the CDN. The scheme of the
+    // checksum, the MIME type of the new content and its audit digest are stored too.
+    StoreAndInvalidate(
+        RelPath,
+        MetadataValues,
+        Checksum,
+        Scheme,
+        &'static str,
+        Option<(Checksum, checksum::Algorithm)>,
+    ),
 }
@@ -0,0 +1,246 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Metrics of each run in the Prometheus text format, written for the textfile collector of the
+//! node exporter or pushed to a Pushgateway, to alert on failed purges
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_derive::Deserialize;
+use tracing::debug;
+use url::Url;
+
+use crate::cdn;
+use crate::report::{Outcome, Report};
+
+/// Content type of the text format, see
+/// https://prometheus.io/docs/instrumenting/exposition_formats/#text-based-format
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Settings of the `[metrics]` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// File read by the textfile collector, like
+    /// /var/lib/node_exporter/textfile_collector/static-cdn.prom
+    pub textfile: Option<PathBuf>,
+    /// Pushgateway receiving the metrics, like http://pushgateway:9091
+    pub pushgateway_url: Option<Url>,
+    /// Job of the metrics pushed
+    #[serde(default = "default_job")]
+    pub job: String,
+}
+
+fn default_job() -> String {
+    env!("CARGO_PKG_NAME").to_owned()
+}
+
+/// Write or push the metrics of the run, returning the errors of the outputs that failed
+pub fn export(settings: &Settings, site: &str, report: &Report) -> Vec<anyhow::Error> {
+    let text = text(site, report, SystemTime::now());
+    let mut errors = Vec::new();
+    if let Some(path) = &settings.textfile {
+        errors.extend(write(path, &text).err());
+    }
+    if let Some(url) = &settings.pushgateway_url {
+        errors.extend(push(url, &settings.job, site, &text).err());
+    }
+    errors
+}
+
+/// Replace the file, at once so that the collector never reads half of it
+fn write(path: &Path, text: &str) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let write = || -> Result<()> {
+        fs::write(&tmp, text)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    };
+    write().with_context(|| format!("failed to write the metrics to {}", path.display()))?;
+    debug!("metrics written to {}", path.display());
+    Ok(())
+}
+
+/// Replace the metrics of the job and site on the Pushgateway
+fn push(pushgateway: &Url, job: &str, site: &str, text: &str) -> Result<()> {
+    let mut url = pushgateway.clone();
+    url.path_segments_mut()
+        .map_err(|()| anyhow!("{pushgateway} can’t be a Pushgateway URL"))?
+        .pop_if_empty()
+        .extend(["metrics", "job", job, "site", site]);
+    let send = async {
+        let response = cdn::http_client()?
+            .put(url.as_str())
+            .header("Content-Type", CONTENT_TYPE)
+            .body(text.to_owned())
+            .send()
+            .await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("the Pushgateway returned {status}: {body}");
+        }
+        Ok(())
+    };
+    cdn::block_on(send).with_context(|| format!("failed to push the metrics to {url}"))?;
+    debug!("metrics pushed to {url}");
+    Ok(())
+}
+
+/// Metrics of the run, in the text format, labeled with the site
+fn text(site: &str, report: &Report, now: SystemTime) -> String {
+    let site = escape(site);
+    let mut text = String::new();
+    let mut metric = |name: &str, help: &str, samples: &[(Option<(&str, &str)>, f64)]| {
+        let name = format!("{}_{name}", env!("CARGO_PKG_NAME").replace('-', "_"));
+        text.push_str(&format!("# HELP {name} {help}\n# TYPE {name} gauge\n"));
+        for (label, value) in samples {
+            let labels = match label {
+                Some((label, label_value)) => {
+                    format!("site=\"{site}\",{label}=\"{}\"", escape(label_value))
+                }
+                None => format!("site=\"{site}\""),
+            };
+            text.push_str(&format!("{name}{{{labels}}} {value}\n"));
+        }
+    };
+
+    let scanned = report
+        .files
+        .iter()
+        .filter(|file| file.outcome != Outcome::Deleted)
+        .count();
+    metric(
+        "files_scanned",
+        "Files found on disk by the last run.",
+        &[(None, scanned as f64)],
+    );
+    let outcomes = [
+        ("unchanged", Outcome::Unchanged),
+        ("metadata-updated", Outcome::MetadataUpdated),
+        ("new", Outcome::New),
+        ("changed", Outcome::Changed),
+        ("moved", Outcome::Moved),
+        ("deleted", Outcome::Deleted),
+    ];
+    let samples: Vec<_> = outcomes
+        .iter()
+        .map(|&(label, outcome)| {
+            let count = report.files.iter().filter(|f| f.outcome == outcome).count();
+            (Some(("outcome", label)), count as f64)
+        })
+        .collect();
+    metric("files", "Files of the last run, by outcome.", &samples);
+    metric(
+        "bytes_hashed",
+        "Size of the files hashed by the last run.",
+        &[(None, report.bytes_hashed as f64)],
+    );
+    let failed = report.batches.iter().filter(|b| b.error.is_some()).count();
+    metric(
+        "purge_requests",
+        "Purge requests sent to the CDN by the last run, by result.",
+        &[
+            (
+                Some(("result", "succeeded")),
+                (report.batches.len() - failed) as f64,
+            ),
+            (Some(("result", "failed")), failed as f64),
+        ],
+    );
+    let samples: Vec<_> = report
+        .phases
+        .iter()
+        .map(|phase| (Some(("phase", phase.name)), phase.duration_sec))
+        .collect();
+    metric(
+        "phase_duration_seconds",
+        "Time spent in each phase of the last run.",
+        &samples,
+    );
+    metric(
+        "duration_seconds",
+        "Duration of the last run.",
+        &[(None, report.duration_sec)],
+    );
+    metric(
+        "exit_code",
+        "Exit code of the last run, 0 when it succeeded.",
+        &[(None, report.exit_code.into())],
+    );
+    let finished = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    metric(
+        "last_run_timestamp_seconds",
+        "End of the last run, since the Unix epoch.",
+        &[(None, finished.as_secs() as f64)],
+    );
+    text
+}
+
+/// Label value with its backslashes, quotes and line feeds escaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::rel_path::RelPath;
+
+    #[test]
+    fn text() -> Result<()> {
+        let mut report = Report::new(false);
+        report.file(&RelPath::from("a.html".to_owned()), Outcome::Unchanged);
+        report.file(&RelPath::from("b.html".to_owned()), Outcome::Changed);
+        report.file(&RelPath::from("c.html".to_owned()), Outcome::Deleted);
+        let url = Url::parse("https://example.com/b.html")?;
+        let now = Instant::now();
+        report.batch(vec![url], false, 2, now..now, &Err(anyhow!("rate limited")));
+        report.phase("scan", Instant::now());
+        report.bytes_hashed = 1024;
+        report.finish(&Ok(std::process::ExitCode::from(4)));
+
+        let now = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let text = super::text("example.com", &report, now);
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in [
+            "# TYPE static_cdn_files_scanned gauge",
+            "static_cdn_files_scanned{site=\"example.com\"} 2",
+            "static_cdn_files{site=\"example.com\",outcome=\"deleted\"} 1",
+            "static_cdn_bytes_hashed{site=\"example.com\"} 1024",
+            "static_cdn_purge_requests{site=\"example.com\",result=\"succeeded\"} 0",
+            "static_cdn_purge_requests{site=\"example.com\",result=\"failed\"} 1",
+            "static_cdn_exit_code{site=\"example.com\"} 4",
+            "static_cdn_last_run_timestamp_seconds{site=\"example.com\"} 1700000000",
+        ] {
+            assert!(lines.contains(&expected), "{expected} missing from\n{text}");
+        }
+        assert!(text
+            .contains("static_cdn_phase_duration_seconds{site=\"example.com\",phase=\"scan\"} "));
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("static-cdn.prom");
+        write(&path, &text)?;
+        assert_eq!(text, fs::read_to_string(&path)?);
+        assert_eq!(1, fs::read_dir(dir.path())?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(r#"a\"b\\c\nd"#, super::escape("a\"b\\c\nd"));
+    }
+}
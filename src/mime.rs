@@ -0,0 +1,183 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Detection of the MIME type of files, from their extension or, failing that, from their first
+//! bytes. Only the types commonly found on static sites are known.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::Result;
+
+/// Type of the files that are neither known nor text
+pub const DEFAULT: &str = "application/octet-stream";
+
+/// Number of bytes read to sniff the type
+const SNIFF_LEN: usize = 512;
+
+/// Types by lowercase extension
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("7z", "application/x-7z-compressed"),
+    ("atom", "application/atom+xml"),
+    ("avif", "image/avif"),
+    ("bmp", "image/bmp"),
+    ("br", "application/x-brotli"),
+    ("css", "text/css"),
+    ("csv", "text/csv"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("gif", "image/gif"),
+    ("gz", "application/gzip"),
+    ("htm", "text/html"),
+    ("html", "text/html"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("ics", "text/calendar"),
+    ("jpeg", "image/jpeg"),
+    ("jpg", "image/jpeg"),
+    ("js", "text/javascript"),
+    ("json", "application/json"),
+    ("jsonld", "application/ld+json"),
+    ("map", "application/json"),
+    ("md", "text/markdown"),
+    ("mjs", "text/javascript"),
+    ("mp3", "audio/mpeg"),
+    ("mp4", "video/mp4"),
+    ("oga", "audio/ogg"),
+    ("ogg", "audio/ogg"),
+    ("ogv", "video/ogg"),
+    ("opus", "audio/opus"),
+    ("otf", "font/otf"),
+    ("pdf", "application/pdf"),
+    ("png", "image/png"),
+    ("rss", "application/rss+xml"),
+    ("svg", "image/svg+xml"),
+    ("tar", "application/x-tar"),
+    ("toml", "application/toml"),
+    ("ttf", "font/ttf"),
+    ("txt", "text/plain"),
+    ("wasm", "application/wasm"),
+    ("wav", "audio/wav"),
+    ("webm", "video/webm"),
+    ("webmanifest", "application/manifest+json"),
+    ("webp", "image/webp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("xml", "application/xml"),
+    ("yaml", "application/yaml"),
+    ("yml", "application/yaml"),
+    ("zip", "application/zip"),
+];
+
+/// Types by the bytes files start with
+const SIGNATURES: &[(&[u8], &str)] = &[
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"%PDF-", "application/pdf"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"\0asm", "application/wasm"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OggS", "audio/ogg"),
+    (b"\x1a\x45\xdf\xa3", "video/webm"),
+    (b"ID3", "audio/mpeg"),
+];
+
+/// MIME type of the file
+pub fn detect(path: &Path) -> Result<&'static str> {
+    if let Some(mime) = from_extension(path) {
+        return Ok(mime);
+    }
+    let mut start = Vec::with_capacity(SNIFF_LEN);
+    File::open(path)?
+        .take(SNIFF_LEN as u64)
+        .read_to_end(&mut start)?;
+    Ok(sniff(&start))
+}
+
+/// MIME type known for the extension of the path
+fn from_extension(path: &Path) -> Option<&'static str> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    EXTENSIONS
+        .binary_search_by_key(&extension.as_str(), |(extension, _)| extension)
+        .ok()
+        .map(|i| EXTENSIONS[i].1)
+}
+
+/// MIME type guessed from the first bytes of a file
+fn sniff(start: &[u8]) -> &'static str {
+    if let Some((_, mime)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| start.starts_with(signature))
+    {
+        return mime;
+    }
+    if start.len() >= 12 && &start[..4] == b"RIFF" && &start[8..12] == b"WEBP" {
+        return "image/webp";
+    }
+    if start.len() >= 8 && &start[4..8] == b"ftyp" {
+        return "video/mp4";
+    }
+    // Text, which may be cut in the middle of a character
+    let text = match std::str::from_utf8(start) {
+        Ok(text) => text,
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&start[..e.valid_up_to()]).expect("valid up to there")
+        }
+        Err(_) => return DEFAULT,
+    };
+    if text.contains('\0') {
+        return DEFAULT;
+    }
+    let lowercase = text.trim_start().to_ascii_lowercase();
+    if lowercase.starts_with("<!doctype html") || lowercase.starts_with("<html") {
+        "text/html"
+    } else if lowercase.starts_with("<svg") {
+        "image/svg+xml"
+    } else if lowercase.starts_with("<?xml") {
+        "application/xml"
+    } else {
+        "text/plain"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extensions_are_sorted() {
+        assert!(EXTENSIONS.windows(2).all(|w| w[0].0 < w[1].0));
+    }
+
+    #[test]
+    fn extension() {
+        assert_eq!(Some("text/html"), from_extension(Path::new("a/index.HTML")));
+        assert_eq!(Some("font/woff2"), from_extension(Path::new("f.woff2")));
+        assert_eq!(None, from_extension(Path::new("Makefile")));
+        assert_eq!(None, from_extension(Path::new("a.unknown")));
+    }
+
+    #[test]
+    fn sniffing() {
+        assert_eq!("image/png", sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"));
+        assert_eq!("image/webp", sniff(b"RIFF\0\0\0\0WEBPVP8 "));
+        assert_eq!("video/mp4", sniff(b"\0\0\0\x20ftypisom"));
+        assert_eq!("text/html", sniff(b"\n  <!DOCTYPE html>\n<html>"));
+        assert_eq!(
+            "image/svg+xml",
+            sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\">")
+        );
+        assert_eq!("text/plain", sniff("caf\u{e9}".as_bytes()));
+        // Cut in the middle of the é
+        assert_eq!("text/plain", sniff(&"caf\u{e9}".as_bytes()[..4]));
+        assert_eq!(DEFAULT, sniff(b"\xff\xfe\0\x01"));
+        assert_eq!("text/plain", sniff(b""));
+    }
+}
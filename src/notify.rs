@@ -0,0 +1,270 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Notifications sent after each run, so that failed purges are noticed without reading the logs
+//! of the CI or of cron
+
+use anyhow::{bail, Context, Result};
+use serde_derive::Deserialize;
+use serde_json::json;
+use tracing::debug;
+use url::Url;
+
+use crate::cdn;
+use crate::hook;
+use crate::report::Report;
+
+/// Errors listed in a notification, the others are in the logs
+const MAX_ERRORS: usize = 5;
+/// Longest message Discord accepts
+const DISCORD_MAX_LEN: usize = 2000;
+
+/// Settings of the `[notify]` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Incoming webhook of Slack, Discord or another chat accepting the same messages
+    pub webhook_url: Option<Url>,
+    /// Only post the runs that failed to the webhook
+    #[serde(default)]
+    pub only_failures: bool,
+    /// Addresses emailed the errors of the runs that failed, separated by commas
+    pub email_to: Option<String>,
+    /// Sender of the emails, set by the mail command by default
+    pub email_from: Option<String>,
+    /// Command sending the emails, reading the message with its headers on its standard input
+    #[serde(default = "default_sendmail_cmd")]
+    pub sendmail_cmd: String,
+}
+
+fn default_sendmail_cmd() -> String {
+    "sendmail -t".to_owned()
+}
+
+/// Send the notifications configured for the run, returning the errors of those that failed
+pub fn send(settings: &Settings, site: &str, report: &Report) -> Vec<anyhow::Error> {
+    let failed = report.exit_code != 0;
+    let mut errors = Vec::new();
+    if let Some(url) = &settings.webhook_url {
+        if failed || !settings.only_failures {
+            errors.extend(post(url, site, report).err());
+        }
+    }
+    if let (Some(to), true) = (&settings.email_to, failed) {
+        let mail = mail(to, settings.email_from.as_deref(), site, report);
+        errors.extend(
+            hook::run(
+                "notify.sendmail_cmd",
+                &settings.sendmail_cmd,
+                Some(&mail),
+                &[],
+            )
+            .err(),
+        );
+    }
+    errors
+}
+
+/// Post the summary of the run to the webhook
+fn post(url: &Url, site: &str, report: &Report) -> Result<()> {
+    // The URL holds the secret of the webhook, so it’s left out of the errors
+    let send = async {
+        let response = cdn::http_client()?
+            .post(url.as_str())
+            .json(&payload(url, site, report))
+            .send()
+            .await
+            .map_err(reqwest::Error::without_url)?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("the webhook returned {status}: {body}");
+        }
+        Ok(())
+    };
+    cdn::block_on(send).with_context(|| {
+        format!(
+            "failed to post the notification to {}",
+            url.host_str().unwrap_or_default()
+        )
+    })?;
+    debug!("notification posted");
+    Ok(())
+}
+
+/// Body of the request, with the message in the field the chat expects
+fn payload(url: &Url, site: &str, report: &Report) -> serde_json::Value {
+    let text = message(site, report);
+    match url.host_str() {
+        Some("discord.com" | "discordapp.com") => {
+            json!({ "content": truncate(text, DISCORD_MAX_LEN) })
+        }
+        _ => json!({ "text": text }),
+    }
+}
+
+/// Outcome of the run, on a single line
+fn title(site: &str, report: &Report) -> String {
+    let pkg = env!("CARGO_PKG_NAME");
+    let mut title = match report.exit_code {
+        0 => format!("{pkg} run for {site} succeeded"),
+        code => format!("{pkg} run for {site} failed with exit code {code}"),
+    };
+    if report.dry_run {
+        title.push_str(" (dry run)");
+    }
+    if report.deferred {
+        title.push_str(" (deferred)");
+    }
+    title
+}
+
+/// Outcome of the run, its summary table and its first errors
+fn message(site: &str, report: &Report) -> String {
+    let mut text = title(site, report);
+    text.push_str("\n```\n");
+    for row in report.summary() {
+        text.push_str(&row);
+        text.push('\n');
+    }
+    text.push_str("```");
+    for e in report.errors.iter().take(MAX_ERRORS) {
+        text.push_str("\n- ");
+        text.push_str(e);
+    }
+    if report.errors.len() > MAX_ERRORS {
+        let more = report.errors.len() - MAX_ERRORS;
+        text.push_str(&format!("\n- and {more} more errors"));
+    }
+    text
+}
+
+/// Email with the summary of the run and all its errors, headers included, as read by
+/// `sendmail -t`
+fn mail(to: &str, from: Option<&str>, site: &str, report: &Report) -> String {
+    let mut mail = format!("To: {to}\n");
+    if let Some(from) = from {
+        mail.push_str(&format!("From: {from}\n"));
+    }
+    mail.push_str(&format!("Subject: {}\n", title(site, report)));
+    mail.push_str("MIME-Version: 1.0\n");
+    mail.push_str("Content-Type: text/plain; charset=utf-8\n");
+    mail.push_str("Content-Transfer-Encoding: 8bit\n\n");
+    for row in report.summary() {
+        mail.push_str(&row);
+        mail.push('\n');
+    }
+    if !report.errors.is_empty() {
+        mail.push_str("\nErrors\n");
+        for e in &report.errors {
+            mail.push_str(&format!("  {e}\n"));
+        }
+    }
+    mail
+}
+
+/// Text cut to the given number of characters, at most
+fn truncate(text: String, max_len: usize) -> String {
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => text[..end].to_owned(),
+        None => text,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::exit::Failure;
+
+    #[test]
+    fn payload() -> Result<()> {
+        let mut report = Report::new(false);
+        for i in 0..7 {
+            report.errors.push(format!("error {i}"));
+        }
+        report.finish(&Err(anyhow!("failed").context(Failure::Cdn)));
+
+        let slack = Url::parse("https://hooks.slack.com/services/T0/B0/secret")?;
+        let body = super::payload(&slack, "example.com", &report);
+        let text = body["text"].as_str().unwrap();
+        assert!(text.starts_with("static-cdn run for example.com failed with exit code 4\n```"));
+        assert!(text.contains("\n  Failed "));
+        assert!(text.contains("\n- error 0\n"));
+        assert!(!text.contains("error 5"));
+        assert!(text.ends_with("\n- and 3 more errors"));
+
+        let discord = Url::parse("https://discord.com/api/webhooks/0/secret")?;
+        report.errors = vec!["é".repeat(3000)];
+        let body = super::payload(&discord, "example.com", &report);
+        assert!(body.get("text").is_none());
+        assert_eq!(
+            DISCORD_MAX_LEN,
+            body["content"].as_str().unwrap().chars().count()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_failures() -> Result<()> {
+        let settings = Settings {
+            // Nothing listens there, so the notification fails if it’s sent
+            webhook_url: Some(Url::parse("http://127.0.0.1:1/")?),
+            only_failures: true,
+            email_to: None,
+            email_from: None,
+            sendmail_cmd: default_sendmail_cmd(),
+        };
+        let mut report = Report::new(false);
+        report.finish(&Ok(std::process::ExitCode::SUCCESS));
+        assert!(send(&settings, "example.com", &report).is_empty());
+        report.finish(&Err(anyhow!("failed").context(Failure::Cdn)));
+        assert_eq!(1, send(&settings, "example.com", &report).len());
+        Ok(())
+    }
+
+    #[test]
+    fn secret_left_out_of_errors() -> Result<()> {
+        let url = Url::parse("http://127.0.0.1:1/services/T0/B0/secret")?;
+        let report = Report::new(false);
+        let e = post(&url, "example.com", &report).unwrap_err();
+        let e = format!("{e:#}");
+        assert!(e.starts_with("failed to post the notification to 127.0.0.1"));
+        assert!(!e.contains("secret"), "{e}");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn email() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("mail");
+        let settings = Settings {
+            webhook_url: None,
+            only_failures: false,
+            email_to: Some("ops@example.com".to_owned()),
+            email_from: Some("cdn@example.com".to_owned()),
+            sendmail_cmd: format!("cat > '{}'", path.display()),
+        };
+        let mut report = Report::new(false);
+        report.finish(&Ok(std::process::ExitCode::SUCCESS));
+        assert!(send(&settings, "example.com", &report).is_empty());
+        assert!(!path.exists(), "successful runs aren’t emailed");
+
+        report.finish(&Err(anyhow!("failed").context(Failure::Cdn)));
+        assert!(send(&settings, "example.com", &report).is_empty());
+        let mail = std::fs::read_to_string(&path)?;
+        assert!(mail.starts_with(
+            "To: ops@example.com\nFrom: cdn@example.com\n\
+             Subject: static-cdn run for example.com failed with exit code 4\n"
+        ));
+        assert!(mail.contains("\n\nSummary\n"));
+        assert!(mail.ends_with("\nErrors\n  CDN API error: failed\n"));
+        Ok(())
+    }
+}
@@ -0,0 +1,283 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Spans of each run, exported over OTLP/HTTP to an OpenTelemetry collector, so that the runs
+//! show up in the traces of the deploy pipelines. See
+//! https://opentelemetry.io/docs/specs/otlp/#otlphttp
+
+use std::collections::BTreeMap;
+use std::env;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, warn};
+use url::Url;
+
+use crate::cdn;
+use crate::report::Report;
+use crate::state::hex;
+
+/// Environment variable holding the context of the parent span, set by the pipeline, see
+/// https://www.w3.org/TR/trace-context/#traceparent-header
+const TRACEPARENT_VAR: &str = "TRACEPARENT";
+/// Kind of the spans: internal, as they are not requests received
+const SPAN_KIND_INTERNAL: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// Settings of the `[otlp]` table of the configuration
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// Collector receiving the spans over OTLP/HTTP, like http://localhost:4318
+    pub endpoint: Url,
+    /// Headers of the requests, like the API key of the tracing backend
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+    /// Name of the service of the spans
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+}
+
+fn default_service_name() -> String {
+    env!("CARGO_PKG_NAME").to_owned()
+}
+
+/// Span the run is part of, from `TRACEPARENT`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Parent {
+    trace_id: String,
+    span_id: String,
+}
+
+impl Parent {
+    /// Parent from the value of a `traceparent` header, like
+    /// 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01
+    fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let (_version, trace_id, span_id) = (parts.next()?, parts.next()?, parts.next()?);
+        let valid = |id: &str, len| {
+            id.len() == len
+                && id.bytes().all(|b| b.is_ascii_hexdigit())
+                && id.bytes().any(|b| b != b'0')
+        };
+        (valid(trace_id, 32) && valid(span_id, 16)).then(|| Self {
+            trace_id: trace_id.to_ascii_lowercase(),
+            span_id: span_id.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Export the spans of the run: one for the whole run, with one for each of its phases and one
+/// for each purge request
+pub fn export(settings: &Settings, site: &str, report: &Report) -> Result<()> {
+    let traceparent = env::var(TRACEPARENT_VAR).ok();
+    let parent = traceparent.as_deref().and_then(|traceparent| {
+        let parent = Parent::parse(traceparent);
+        if parent.is_none() {
+            warn!("ignoring {TRACEPARENT_VAR}, it’s not a valid trace context: {traceparent}");
+        }
+        parent
+    });
+    let body = payload(
+        &settings.service_name,
+        site,
+        report,
+        parent,
+        &SystemRandom::new(),
+    )?;
+    let mut url = settings.endpoint.clone();
+    url.path_segments_mut()
+        .map_err(|()| anyhow!("{} can’t be an OTLP endpoint", settings.endpoint))?
+        .pop_if_empty()
+        .extend(["v1", "traces"]);
+    let send = async {
+        let mut request = cdn::http_client()?
+            .post(url.as_str())
+            .timeout(Duration::from_secs(10));
+        for (name, value) in &settings.headers {
+            request = request.header(name, value);
+        }
+        let response = request.json(&body).send().await?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            bail!("the collector returned {status}: {body}");
+        }
+        Ok(())
+    };
+    cdn::block_on(send).with_context(|| format!("failed to export the spans to {url}"))?;
+    debug!("spans exported to {url}");
+    Ok(())
+}
+
+/// Request exporting the spans of the run, in the JSON encoding of OTLP
+fn payload(
+    service_name: &str,
+    site: &str,
+    report: &Report,
+    parent: Option<Parent>,
+    rng: &dyn SecureRandom,
+) -> Result<Value> {
+    let id = |len: usize| -> Result<String> {
+        let mut bytes = vec![0; len];
+        rng.fill(&mut bytes)
+            .map_err(|_| anyhow!("failed to generate a span ID"))?;
+        Ok(hex(&bytes))
+    };
+    let trace_id = match &parent {
+        Some(parent) => parent.trace_id.clone(),
+        None => id(16)?,
+    };
+    let run_id = id(8)?;
+    // Spans are placed in time from the start of the run
+    let at = |offset_sec: f64| -> String {
+        let time = report.started_time + Duration::from_secs_f64(offset_sec.max(0.));
+        let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        nanos.as_nanos().to_string()
+    };
+
+    let mut run = json!({
+        "traceId": trace_id,
+        "spanId": run_id,
+        "name": "run",
+        "kind": SPAN_KIND_INTERNAL,
+        "startTimeUnixNano": at(0.),
+        "endTimeUnixNano": at(report.duration_sec),
+        "attributes": [
+            attribute("static_cdn.site", json!({ "stringValue": site })),
+            attribute("static_cdn.dry_run", json!({ "boolValue": report.dry_run })),
+            attribute("static_cdn.deferred", json!({ "boolValue": report.deferred })),
+            attribute("static_cdn.files", json!({ "intValue": report.files.len().to_string() })),
+            attribute("process.exit.code", json!({ "intValue": report.exit_code.to_string() })),
+        ],
+    });
+    if let Some(parent) = &parent {
+        run["parentSpanId"] = json!(parent.span_id);
+    }
+    if report.exit_code != 0 {
+        let message = report.errors.last().map_or("", String::as_str);
+        run["status"] = json!({ "code": STATUS_CODE_ERROR, "message": message });
+    }
+    let mut spans = vec![run];
+    for phase in &report.phases {
+        spans.push(json!({
+            "traceId": trace_id,
+            "spanId": id(8)?,
+            "parentSpanId": run_id,
+            "name": phase.name,
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": at(phase.offset_sec),
+            "endTimeUnixNano": at(phase.offset_sec + phase.duration_sec),
+        }));
+    }
+    for batch in &report.batches {
+        let mut span = json!({
+            "traceId": trace_id,
+            "spanId": id(8)?,
+            "parentSpanId": run_id,
+            "name": "purge batch",
+            "kind": SPAN_KIND_INTERNAL,
+            "startTimeUnixNano": at(batch.offset_sec),
+            "endTimeUnixNano": at(batch.offset_sec + batch.duration_sec),
+            "attributes": [
+                attribute("static_cdn.urls", json!({ "intValue": batch.urls.len().to_string() })),
+                attribute("static_cdn.files", json!({ "intValue": batch.files.to_string() })),
+                attribute("static_cdn.prefixes", json!({ "boolValue": batch.prefixes })),
+            ],
+        });
+        if let Some(error) = &batch.error {
+            span["status"] = json!({ "code": STATUS_CODE_ERROR, "message": error });
+        }
+        spans.push(span);
+    }
+
+    Ok(json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", json!({ "stringValue": service_name }))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    }))
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+
+    #[test]
+    fn parent() {
+        assert_eq!(
+            Some(Parent {
+                trace_id: "4bf92f3577b34da6a3ce929d0e0e4736".to_owned(),
+                span_id: "00f067aa0ba902b7".to_owned(),
+            }),
+            Parent::parse("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01\n")
+        );
+        assert_eq!(
+            None,
+            Parent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-01")
+        );
+        assert_eq!(
+            None,
+            Parent::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01")
+        );
+        assert_eq!(
+            None,
+            Parent::parse("00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01")
+        );
+    }
+
+    #[test]
+    fn payload() -> Result<()> {
+        let mut report = Report::new(false);
+        report.phase("scan", Instant::now());
+        let url = Url::parse("https://example.com/index.html")?;
+        let now = Instant::now();
+        report.batch(vec![url], false, 1, now..now, &Err(anyhow!("rate limited")));
+        report.finish(&Ok(std::process::ExitCode::from(4)));
+
+        let parent = Parent::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01");
+        let body = super::payload("cdn", "example.com", &report, parent, &SystemRandom::new())?;
+        let resource = &body["resourceSpans"][0];
+        assert_eq!(
+            "cdn",
+            resource["resource"]["attributes"][0]["value"]["stringValue"]
+        );
+        let spans = resource["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(3, spans.len());
+        let (run, scan, batch) = (&spans[0], &spans[1], &spans[2]);
+        assert!(spans
+            .iter()
+            .all(|span| span["traceId"] == "4bf92f3577b34da6a3ce929d0e0e4736"));
+        assert_eq!("00f067aa0ba902b7", run["parentSpanId"]);
+        assert_eq!(16, run["spanId"].as_str().unwrap().len());
+        assert_eq!(run["spanId"], scan["parentSpanId"]);
+        assert_eq!(run["spanId"], batch["parentSpanId"]);
+        assert_ne!(scan["spanId"], batch["spanId"]);
+        assert_eq!("scan", scan["name"]);
+        assert_eq!(STATUS_CODE_ERROR, run["status"]["code"]);
+        assert_eq!("rate limited", batch["status"]["message"]);
+        let nanos =
+            |span: &Value, key: &str| -> u128 { span[key].as_str().unwrap().parse().unwrap() };
+        assert!(nanos(run, "startTimeUnixNano") <= nanos(scan, "startTimeUnixNano"));
+        assert!(nanos(scan, "endTimeUnixNano") <= nanos(run, "endTimeUnixNano"));
+        Ok(())
+    }
+}
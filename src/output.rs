@@ -0,0 +1,311 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Output policy of the program. Everything meant for the user goes through the `tracing`
+//! macros:
+//! - `error!` and `warn!` are always shown, except `warn!` with `--quiet`,
+//! - `info!` is for the normal progress messages, hidden with `--quiet`,
+//! - `debug!` (`-v`) and `trace!` (`-vv`) are for details, like individual files.
+//!
+//! Details, like the path of a file, are passed as fields, printed after the message or as JSON
+//! with `--log-format json`. Each phase of a run is a span, named after its progress bar.
+//!
+//! Progress bars follow the same rule as `info!` messages, so that they don’t show up when the
+//! user asked for a quiet run. They are all drawn together, one per phase of the run.
+//!
+//! In CI mode, progress bars are never drawn and, on GitHub Actions, errors and warnings are
+//! printed as workflow commands, so that they show up as annotations.
+//!
+//! With a log file, the messages are also appended to it, with their time and level.
+
+use std::fmt;
+use std::io::{self, IsTerminal as _, Write as _};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::filter::{EnvFilter, LevelFilter};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt as _;
+use tracing_subscriber::{filter, Layer as _};
+
+use log_file::LogFile;
+pub use log_file::{Rotation, DEFAULT_KEPT as DEFAULT_LOG_FILES_KEPT};
+
+mod log_file;
+
+/// Holds all the progress bars, so that they are drawn together and log messages are printed
+/// above them
+static PROGRESS: LazyLock<MultiProgress> = LazyLock::new(MultiProgress::new);
+/// File the messages are appended to, if any
+static LOG_FILE: Mutex<Option<LogFile>> = Mutex::new(None);
+/// Whether there is a log file, checked before formatting messages for it
+static LOGGING_TO_FILE: AtomicBool = AtomicBool::new(false);
+
+const BAR_TEMPLATE: &str = "{prefix:>18} [{bar:40}] {pos}/{len} {msg}";
+const SPINNER_TEMPLATE: &str = "{prefix:>18} {spinner} {pos} {msg}";
+const BYTES_SPINNER_TEMPLATE: &str =
+    "{prefix:>18} {spinner} {binary_bytes} ({binary_bytes_per_sec})";
+
+/// Format of the messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Messages for humans, with their fields after them
+    #[default]
+    Text,
+    /// One JSON object per message, with its fields and spans, for log collectors
+    Json,
+}
+
+/// Set up the logger, from the number of `-v` flags, `-q`, `--ci` and `--log-format`. The
+/// `RUST_LOG` environment variable, if set, takes precedence.
+pub fn init(verbose: u8, quiet: bool, ci: bool, format: LogFormat) {
+    let github_actions = ci && std::env::var_os("GITHUB_ACTIONS").is_some_and(|v| v == "true");
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::ERROR,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.is_empty() => EnvFilter::new(directives),
+        // Dependencies are only interesting when something goes wrong
+        _ => EnvFilter::new(format!(
+            "{},{}={level}",
+            level.min(LevelFilter::WARN),
+            env!("CARGO_CRATE_NAME")
+        )),
+    };
+
+    // Fields are formatted once for both layers, so without colors, which the log file can’t
+    // have. `Text` colors the levels itself.
+    let stderr = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(|| ProgressWriter);
+    let file = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(|| FileWriter);
+    let (stderr, file) = match format {
+        LogFormat::Text => (
+            stderr
+                .event_format(Text {
+                    github_actions,
+                    ansi: io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+                })
+                .boxed(),
+            file.boxed(),
+        ),
+        LogFormat::Json => (stderr.json().boxed(), file.json().boxed()),
+    };
+    let file = file.with_filter(filter::filter_fn(|_| {
+        LOGGING_TO_FILE.load(Ordering::Relaxed)
+    }));
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr)
+        .with(file)
+        .init();
+
+    if ci || !tracing::enabled!(Level::INFO) {
+        PROGRESS.set_draw_target(ProgressDrawTarget::hidden());
+    }
+}
+
+/// Writes the messages to the standard error, without garbling the progress bars
+struct ProgressWriter;
+
+impl io::Write for ProgressWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        PROGRESS.suspend(|| io::stderr().write(buf))
+    }
+
+    /// Each message is written at once
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        PROGRESS.suspend(|| io::stderr().write_all(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+/// Append the next messages to the file, rotated as configured, instead of the previous log file
+pub fn log_to_file(path: &Path, rotation: Rotation, kept: usize) -> io::Result<()> {
+    let mut log_file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+    if log_file
+        .as_ref()
+        .is_some_and(|f| f.is(path, rotation, kept))
+    {
+        return Ok(());
+    }
+    *log_file = Some(LogFile::open(path, rotation, kept)?);
+    LOGGING_TO_FILE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Appends the messages to the log file, if any
+struct FileWriter;
+
+impl io::Write for FileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    /// Each message is written at once, so that it’s never split by a rotation
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let mut log_file = LOG_FILE.lock().unwrap_or_else(|e| e.into_inner());
+        match log_file.as_mut() {
+            Some(log_file) => log_file.write(buf),
+            None => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Messages for humans: `info!` ones as is, the others prefixed by their level
+struct Text {
+    github_actions: bool,
+    ansi: bool,
+}
+
+impl<S, N> FormatEvent<S, N> for Text
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let level = *event.metadata().level();
+        let mut message = String::new();
+        ctx.format_fields(Writer::new(&mut message), event)?;
+        if level == Level::INFO {
+            writeln!(writer, "{message}")
+        } else if self.github_actions && level <= Level::WARN {
+            // See https://docs.github.com/en/actions/reference/workflow-commands-for-github-actions
+            let command = match level {
+                Level::ERROR => "error",
+                _ => "warning",
+            };
+            let message = message
+                .replace('%', "%25")
+                .replace('\r', "%0D")
+                .replace('\n', "%0A");
+            writeln!(writer, "::{command}::{message}")
+        } else {
+            let name = level.as_str().to_lowercase();
+            if self.ansi {
+                let color = match level {
+                    Level::ERROR => "31",
+                    Level::WARN => "33",
+                    Level::DEBUG => "34",
+                    _ => "36",
+                };
+                writeln!(writer, "\x1b[{color}m{name}\x1b[0m: {message}")
+            } else {
+                writeln!(writer, "{name}: {message}")
+            }
+        }
+    }
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .expect("templates are valid")
+        .progress_chars("=> ")
+}
+
+/// Progress bar counting items, like files or batches
+pub fn progress_bar(prefix: &'static str, len: u64) -> ProgressBar {
+    PROGRESS.add(
+        ProgressBar::new(len)
+            .with_style(style(BAR_TEMPLATE))
+            .with_prefix(prefix),
+    )
+}
+
+/// Spinner counting items when their total is not known, like when walking directories
+pub fn spinner(prefix: &'static str, msg: &'static str) -> ProgressBar {
+    let spinner = PROGRESS.add(
+        ProgressBar::new_spinner()
+            .with_style(style(SPINNER_TEMPLATE))
+            .with_prefix(prefix)
+            .with_message(msg),
+    );
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
+/// Spinner counting bytes, with the throughput, when their total is not known, like when files
+/// are checked as they are found
+pub fn bytes_spinner(prefix: &'static str) -> ProgressBar {
+    let spinner = PROGRESS.add(
+        ProgressBar::new_spinner()
+            .with_style(style(BYTES_SPINNER_TEMPLATE))
+            .with_prefix(prefix),
+    );
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner
+}
+
+/// Whether questions can be asked, that is whether the standard input is a terminal
+pub fn is_interactive() -> bool {
+    io::stdin().is_terminal()
+}
+
+/// Ask a yes/no question on the terminal, defaulting to no. Fails when the standard input is not
+/// a terminal, as nobody can answer.
+pub fn confirm(question: &str) -> Result<bool> {
+    if !is_interactive() {
+        bail!("{question} Not running interactively, pass --yes to confirm");
+    }
+    PROGRESS.suspend(|| {
+        eprint!("{question} [y/N] ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+    })
+}
+
+/// Read a secret, like an API token, from the first line of the standard input. When it’s a
+/// terminal, the question is asked first and the secret isn’t echoed, so that it doesn’t stay on
+/// the screen.
+pub fn read_secret(question: &str) -> Result<String> {
+    let stdin = io::stdin();
+    let is_terminal = stdin.is_terminal();
+    PROGRESS.suspend(|| {
+        let mut answer = String::new();
+        if is_terminal {
+            eprint!("{question} ");
+            io::stderr().flush()?;
+            answer = rpassword::read_password()?;
+        } else {
+            stdin.read_line(&mut answer)?;
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            bail!("nothing was entered");
+        }
+        Ok(answer.to_owned())
+    })
+}
@@ -0,0 +1,192 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Log file of long running instances, rotated when it grows too large or each day, so that they
+//! don’t depend on something else to capture and rotate their logs
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write as _};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_derive::Deserialize;
+
+/// Rotated files kept, unless configured otherwise
+pub const DEFAULT_KEPT: usize = 7;
+
+/// When the log file is rotated, like 10485760 (bytes) or "daily"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(untagged, expecting = "a number of bytes above 0 or \"daily\"")]
+pub enum Rotation {
+    /// Rotated on the first message of each day, in UTC
+    Daily(Daily),
+    /// Rotated before it grows above this size
+    Bytes(NonZeroU64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Daily {
+    Daily,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self::Daily(Daily::Daily)
+    }
+}
+
+/// Log file, appended to, with the previous ones next to it: static-cdn.log.1 is the most recent
+pub struct LogFile {
+    path: PathBuf,
+    rotation: Rotation,
+    /// Rotated files kept, the older ones are removed
+    kept: usize,
+    file: File,
+    size: u64,
+    /// Day the file was created or, for an existing file, last written to, since the Unix epoch
+    day: u64,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, rotation: Rotation, kept: usize) -> io::Result<Self> {
+        let (file, size, day) = open(path)?;
+        Ok(Self {
+            path: path.to_owned(),
+            rotation,
+            kept,
+            file,
+            size,
+            day,
+        })
+    }
+
+    /// Whether the log file is this one, configured in the same way
+    pub fn is(&self, path: &Path, rotation: Rotation, kept: usize) -> bool {
+        self.path == path && self.rotation == rotation && self.kept == kept
+    }
+
+    /// Append the message, rotating the file first if needed
+    pub fn write(&mut self, message: &[u8]) -> io::Result<()> {
+        let rotate = match self.rotation {
+            Rotation::Daily(_) => day(SystemTime::now()) != self.day,
+            // An empty file is never rotated, even if the message doesn’t fit
+            Rotation::Bytes(max) => self.size > 0 && self.size + message.len() as u64 > max.get(),
+        };
+        if rotate {
+            self.rotate()?;
+        }
+        self.file.write_all(message)?;
+        self.size += message.len() as u64;
+        Ok(())
+    }
+
+    /// Shift the rotated files, removing the oldest, and start a new file
+    fn rotate(&mut self) -> io::Result<()> {
+        if self.kept == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for i in (1..self.kept).rev() {
+                match fs::rename(self.rotated(i), self.rotated(i + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        (self.file, self.size, self.day) = open(&self.path)?;
+        Ok(())
+    }
+
+    /// Path of the rotated file, 1 being the most recent
+    fn rotated(&self, i: usize) -> OsString {
+        let mut rotated = self.path.as_os_str().to_owned();
+        rotated.push(format!(".{i}"));
+        rotated
+    }
+}
+
+/// The file opened for appending, with its size and the day it was last written to
+fn open(path: &Path) -> io::Result<(File, u64, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    let modified = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+    Ok((file, metadata.len(), day(modified)))
+}
+
+fn day(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        / (24 * 3600)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_rotation() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("static-cdn.log");
+        let rotation = Rotation::Bytes(NonZeroU64::new(10).unwrap());
+        let mut log_file = LogFile::open(&path, rotation, 2)?;
+        for message in [
+            "a\n",
+            "bbbbbbbb\n",
+            "c\n",
+            "d\n",
+            "eeeeeeeeeeeeeeee\n",
+            "f\n",
+        ] {
+            log_file.write(message.as_bytes())?;
+        }
+        let read = |name: &str| fs::read_to_string(dir.path().join(name));
+        assert_eq!("f\n", read("static-cdn.log")?);
+        assert_eq!("eeeeeeeeeeeeeeee\n", read("static-cdn.log.1")?);
+        assert_eq!("c\nd\n", read("static-cdn.log.2")?);
+        assert!(read("static-cdn.log.3").is_err());
+
+        // Appended to when opened again
+        let mut log_file = LogFile::open(&path, rotation, 0)?;
+        log_file.write(b"g\n")?;
+        assert_eq!("f\ng\n", read("static-cdn.log")?);
+        log_file.write(b"hhhhhhhhh\n")?;
+        assert_eq!("hhhhhhhhh\n", read("static-cdn.log")?);
+        Ok(())
+    }
+
+    #[test]
+    fn daily_rotation() -> io::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("static-cdn.log");
+        fs::write(&path, "yesterday\n")?;
+        let yesterday = SystemTime::now() - std::time::Duration::from_secs(24 * 3600);
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(yesterday)?;
+
+        let mut log_file = LogFile::open(&path, Rotation::default(), DEFAULT_KEPT)?;
+        log_file.write(b"today\n")?;
+        log_file.write(b"still today\n")?;
+        assert_eq!("today\nstill today\n", fs::read_to_string(&path)?);
+        assert_eq!(
+            "yesterday\n",
+            fs::read_to_string(dir.path().join("static-cdn.log.1"))?
+        );
+
+        assert_eq!(
+            Rotation::default(),
+            serde_json::from_value("daily".into()).unwrap()
+        );
+        assert!(serde_json::from_value::<Rotation>(0.into()).is_err());
+        Ok(())
+    }
+}
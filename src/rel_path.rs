@@ -5,31 +5,108 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 
-use std::path::Path;
+#[cfg(unix)]
+use std::ffi::OsStr;
+#[cfg(unix)]
+use std::os::unix::ffi::OsStrExt as _;
+use std::path::{Path, PathBuf};
 
+use rusqlite::types::{FromSql, FromSqlResult, ToSqlOutput, ValueRef};
 use rusqlite::ToSql;
+use serde_derive::Deserialize;
 
 /// The database should hold paths relative to the folder walked through and the path part of urls
 /// is also the relative path to that folder. Therefore, it makes sense to work most of the time
 /// with those relative paths, as strings. The pair of types [`RelPath`] and [`RelPathBuilder`]
 /// should make it easier to get that right, by keeping the root folder and returning relative
 /// paths each time it's called. It also enforces that the right type is passed to DB functions.
-#[derive(Debug)]
+///
+/// File names aren’t always valid UTF-8, so the raw bytes of the path are kept too, and are what’s
+/// stored in the database.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct RelPath {
-    // This is a relative path, to the same root folder, i.e. the base folder used for the walk
+    // Bytes of the path relative to the root folder, as given by the OS
+    bytes: Vec<u8>,
+    // Same path, with the invalid UTF-8 replaced, to display it
     rel_path: String,
 }
 
 impl RelPath {
-    /// Returns a path relative to the root folder walked through
+    fn from_bytes(bytes: Vec<u8>) -> Self {
+        let rel_path = String::from_utf8_lossy(&bytes).into_owned();
+        Self { bytes, rel_path }
+    }
+
+    /// Returns a path relative to the root folder walked through, with the invalid UTF-8 replaced
     pub fn get_relative_path(&self) -> &str {
         &self.rel_path
     }
+
+    /// Bytes of the path relative to the root folder, as given by the OS
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Path relative to the root folder, to find the file on disk
+    pub fn to_path(&self) -> PathBuf {
+        #[cfg(unix)]
+        return PathBuf::from(OsStr::from_bytes(&self.bytes));
+        // With the separators of the platform, as paths with the `\\?\` prefix of Windows are
+        // taken verbatim
+        #[cfg(not(unix))]
+        return self.rel_path.split('/').collect();
+    }
+}
+
+/// Whether paths differing only by their case are the same file, as on the default file systems of
+/// macOS and Windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PathCase {
+    #[default]
+    Sensitive,
+    Insensitive,
+}
+
+impl PathCase {
+    /// Key under which the paths of the same file are equal
+    pub fn key(self, path: &RelPath) -> Vec<u8> {
+        match self {
+            PathCase::Sensitive => path.bytes.clone(),
+            PathCase::Insensitive => match std::str::from_utf8(&path.bytes) {
+                Ok(path) => path.to_lowercase().into_bytes(),
+                Err(_) => path.bytes.to_ascii_lowercase(),
+            },
+        }
+    }
+}
+
+/// Paths of other machines, imported from an export, are valid UTF-8
+impl From<String> for RelPath {
+    fn from(rel_path: String) -> Self {
+        // Stored with forward slashes, as in URLs, and file names can’t have backslashes on Windows
+        #[cfg(windows)]
+        let rel_path = rel_path.replace('\\', "/");
+        Self {
+            bytes: rel_path.as_bytes().to_vec(),
+            rel_path,
+        }
+    }
 }
 
 impl ToSql for RelPath {
-    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
-        ToSql::to_sql(&self.rel_path)
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.bytes.as_slice()))
+    }
+}
+
+/// Paths are stored relative to the root folder, so they can be read back as is
+impl FromSql for RelPath {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value {
+            ValueRef::Text(bytes) | ValueRef::Blob(bytes) => Ok(Self::from_bytes(bytes.to_vec())),
+            _ => Err(rusqlite::types::FromSqlError::InvalidType),
+        }
     }
 }
 
@@ -58,13 +135,17 @@ impl<'a> RelPathBuilder<'a> {
             "{rel_path:?} should be relative for storage in DB"
         );
 
-        RelPath {
-            rel_path: rel_path
-                .to_str()
-                // Would require some downstream support when generating urls, give up for now
-                .expect("invalid unicode in file names is not supported")
-                .to_owned(),
-        }
+        #[cfg(unix)]
+        return RelPath::from_bytes(rel_path.as_os_str().as_bytes().to_vec());
+        // Canonical form, with forward slashes, as in URLs
+        #[cfg(not(unix))]
+        return RelPath::from(
+            rel_path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/"),
+        );
     }
 }
 
@@ -72,6 +153,46 @@ impl<'a> RelPathBuilder<'a> {
 mod tests {
     use super::*;
 
+    #[cfg(unix)]
+    #[test]
+    fn invalid_utf8() {
+        let name = OsStr::from_bytes(b"caf\xe9.html");
+        let root = Path::new("/root");
+        let rel_path = RelPathBuilder::new(root).db_path(&root.join(name));
+        assert_eq!(b"caf\xe9.html", rel_path.as_bytes());
+        assert_eq!("caf\u{fffd}.html", rel_path.get_relative_path());
+        assert_eq!(Path::new(name), rel_path.to_path());
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn forward_slashes() {
+        let root = Path::new(r"\\?\C:\site");
+        let rel_path = RelPathBuilder::new(root).db_path(&root.join(r"blog\post.html"));
+        assert_eq!("blog/post.html", rel_path.get_relative_path());
+        assert_eq!(b"blog/post.html", rel_path.as_bytes());
+        assert_eq!(Path::new(r"blog\post.html"), rel_path.to_path());
+        assert_eq!(rel_path, RelPath::from(r"blog\post.html".to_owned()));
+    }
+
+    #[test]
+    fn path_case() {
+        let path = RelPath::from("Blog/Été.html".to_owned());
+        assert_eq!(
+            b"Blog/\xc3\x89t\xc3\xa9.html",
+            PathCase::Sensitive.key(&path).as_slice()
+        );
+        assert_eq!(
+            PathCase::Insensitive.key(&RelPath::from("blog/été.html".to_owned())),
+            PathCase::Insensitive.key(&path)
+        );
+        let invalid = RelPath::from_bytes(b"Caf\xe9.HTML".to_vec());
+        assert_eq!(
+            b"caf\xe9.html",
+            PathCase::Insensitive.key(&invalid).as_slice()
+        );
+    }
+
     #[test]
     #[should_panic]
     fn db_path_not_relative_to_root() {
@@ -0,0 +1,431 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Report of a run, written as JSON with `--report` or as HTML with `--report-html`, to archive it
+//! alongside the deploy artifacts
+
+use std::fs::File;
+use std::io::{BufWriter, Write as _};
+use std::ops::Range;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::{Context, Result};
+use indicatif::HumanBytes;
+use serde_derive::Serialize;
+use url::Url;
+
+use crate::db::RunChanges;
+use crate::exit;
+use crate::rel_path::RelPath;
+
+mod html;
+
+/// What a run did, filled as it goes
+#[derive(Debug, Serialize)]
+pub struct Report {
+    /// Start of the run, in RFC 3339 format
+    pub started: String,
+    #[serde(skip)]
+    started_at: Instant,
+    #[serde(skip)]
+    pub started_time: SystemTime,
+    pub dry_run: bool,
+    /// Files were still being written, so the changes were left to the next run
+    pub deferred: bool,
+    pub files: Vec<FileOutcome>,
+    /// Purge requests sent to the CDN, in the order they completed
+    pub batches: Vec<Batch>,
+    pub errors: Vec<String>,
+    /// Size of the files whose content was hashed
+    pub bytes_hashed: u64,
+    pub phases: Vec<Phase>,
+    pub timings: Timings,
+    pub duration_sec: f64,
+    pub exit_code: u8,
+    /// Changes of the last runs, from the history of the database, for the charts of the HTML
+    /// report
+    #[serde(skip)]
+    pub history: Vec<RunChanges>,
+}
+
+/// Outcome of a file found on disk, or tracked and deleted since
+#[derive(Debug, Serialize)]
+pub struct FileOutcome {
+    pub path: String,
+    pub outcome: Outcome,
+    /// Previous path of a moved file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub moved_from: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Unchanged,
+    /// Same content, with different metadata
+    MetadataUpdated,
+    New,
+    Changed,
+    Moved,
+    Deleted,
+}
+
+/// Purge request sent to the CDN, with the response it got
+#[derive(Debug, Serialize)]
+pub struct Batch {
+    /// URLs purged, or prefixes of the URLs purged
+    pub urls: Vec<Url>,
+    pub prefixes: bool,
+    /// Files purged by the request
+    pub files: usize,
+    /// When the request was sent, in seconds since the start of the run
+    pub offset_sec: f64,
+    pub duration_sec: f64,
+    /// Error returned by the CDN, `None` when the purge succeeded
+    pub error: Option<String>,
+}
+
+/// Part of the run, like the scan of the root directories
+#[derive(Debug, Serialize)]
+pub struct Phase {
+    pub name: &'static str,
+    /// Start of the phase, in seconds since the start of the run
+    pub offset_sec: f64,
+    pub duration_sec: f64,
+}
+
+/// Time spent on each kind of work, to tell whether the run is slowed down by the file system, the
+/// hashing or the CDN. Files are statted and hashed by several threads at once, and purge
+/// requests sent at the same time, so that time is summed over the threads and the requests.
+#[derive(Debug, Default, Serialize)]
+pub struct Timings {
+    /// Walking the root directories, to find the files
+    pub walking_sec: f64,
+    /// Reading the metadata of the files
+    pub statting_sec: f64,
+    /// Reading and hashing the content of the files
+    pub hashing_sec: f64,
+    /// Writing the changes to the database
+    pub db_write_sec: f64,
+    /// Waiting for the responses of the CDN to the purge requests
+    pub cdn_sec: f64,
+}
+
+/// Time accumulated by several threads
+#[derive(Debug, Default)]
+pub struct Stopwatch {
+    nanos: AtomicU64,
+}
+
+impl Stopwatch {
+    /// Call the function, adding the time it took
+    pub fn time<T>(&self, f: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = f();
+        let nanos = started.elapsed().as_nanos() as u64;
+        self.nanos.fetch_add(nanos, Ordering::Relaxed);
+        result
+    }
+
+    pub fn secs(&self) -> f64 {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed)).as_secs_f64()
+    }
+}
+
+impl Report {
+    pub fn new(dry_run: bool) -> Self {
+        let started_time = SystemTime::now();
+        Self {
+            started: humantime::format_rfc3339_millis(started_time).to_string(),
+            started_at: Instant::now(),
+            started_time,
+            dry_run,
+            deferred: false,
+            files: Vec::new(),
+            batches: Vec::new(),
+            errors: Vec::new(),
+            bytes_hashed: 0,
+            phases: Vec::new(),
+            timings: Timings::default(),
+            duration_sec: 0.,
+            exit_code: 0,
+            history: Vec::new(),
+        }
+    }
+
+    pub fn file(&mut self, path: &RelPath, outcome: Outcome) {
+        self.files.push(FileOutcome {
+            path: path.get_relative_path().to_owned(),
+            outcome,
+            moved_from: None,
+        });
+    }
+
+    pub fn moved(&mut self, path: &RelPath, from: &RelPath) {
+        self.files.push(FileOutcome {
+            path: path.get_relative_path().to_owned(),
+            outcome: Outcome::Moved,
+            moved_from: Some(from.get_relative_path().to_owned()),
+        });
+    }
+
+    pub fn batch(
+        &mut self,
+        urls: Vec<Url>,
+        prefixes: bool,
+        files: usize,
+        timing: Range<Instant>,
+        result: &Result<()>,
+    ) {
+        self.timings.cdn_sec += (timing.end - timing.start).as_secs_f64();
+        self.batches.push(Batch {
+            urls,
+            prefixes,
+            files,
+            offset_sec: self.offset_sec(timing.start),
+            duration_sec: (timing.end - timing.start).as_secs_f64(),
+            error: result.as_ref().err().map(|e| format!("{e:#}")),
+        });
+    }
+
+    /// Record the phase that started at the given instant and ended now, returning the start of
+    /// the next phase
+    pub fn phase(&mut self, name: &'static str, started: Instant) -> Instant {
+        let now = Instant::now();
+        self.phase_between(name, started..now);
+        now
+    }
+
+    /// Record the phase that ran over the given time, like one running alongside another
+    pub fn phase_between(&mut self, name: &'static str, timing: Range<Instant>) {
+        self.phases.push(Phase {
+            name,
+            offset_sec: self.offset_sec(timing.start),
+            duration_sec: (timing.end - timing.start).as_secs_f64(),
+        });
+    }
+
+    /// Seconds between the start of the run and the instant
+    fn offset_sec(&self, instant: Instant) -> f64 {
+        instant
+            .saturating_duration_since(self.started_at)
+            .as_secs_f64()
+    }
+
+    /// Table summing up the run so far, printed at its end. Files failed are those whose purge
+    /// failed, and the errors that kept files from being checked. The time spent on each kind of
+    /// work comes last.
+    pub fn summary(&self) -> Vec<String> {
+        let count = |outcome| self.files.iter().filter(|f| f.outcome == outcome).count();
+        let batch_files = |failed: bool| -> usize {
+            self.batches
+                .iter()
+                .filter(|b| b.error.is_some() == failed)
+                .map(|b| b.files)
+                .sum()
+        };
+        let purged = if self.dry_run {
+            (
+                "Would purge",
+                self.files.len() - count(Outcome::Unchanged) - count(Outcome::MetadataUpdated),
+            )
+        } else {
+            ("Purged", batch_files(false))
+        };
+        // Milliseconds are plenty for a whole run
+        let duration = |duration: Duration| -> String {
+            let duration = Duration::from_millis(duration.as_millis() as u64);
+            humantime::format_duration(duration).to_string()
+        };
+        let secs = |secs: f64| duration(Duration::from_secs_f64(secs.max(0.)));
+        let rows = [
+            ("Unchanged", count(Outcome::Unchanged).to_string()),
+            (
+                "Metadata updated",
+                count(Outcome::MetadataUpdated).to_string(),
+            ),
+            ("New", count(Outcome::New).to_string()),
+            ("Changed", count(Outcome::Changed).to_string()),
+            ("Moved", count(Outcome::Moved).to_string()),
+            ("Deleted", count(Outcome::Deleted).to_string()),
+            (purged.0, purged.1.to_string()),
+            (
+                "Failed",
+                (batch_files(true) + self.errors.len()).to_string(),
+            ),
+            ("Bytes hashed", HumanBytes(self.bytes_hashed).to_string()),
+            ("API calls", self.batches.len().to_string()),
+            ("Duration", duration(self.started_at.elapsed())),
+            ("Walking", secs(self.timings.walking_sec)),
+            ("Statting", secs(self.timings.statting_sec)),
+            ("Hashing", secs(self.timings.hashing_sec)),
+            ("Writing the DB", secs(self.timings.db_write_sec)),
+            ("Calling the CDN", secs(self.timings.cdn_sec)),
+        ];
+        let title = if self.deferred {
+            "Summary, deferred to the next run as files were still being written"
+        } else {
+            "Summary"
+        };
+        std::iter::once(title.to_owned())
+            .chain(
+                rows.into_iter()
+                    .map(|(name, value)| format!("  {name:<16} {value:>10}")),
+            )
+            .collect()
+    }
+
+    /// Record the result of the run, once it’s over
+    pub fn finish(&mut self, result: &Result<ExitCode>) {
+        self.duration_sec = self.started_at.elapsed().as_secs_f64();
+        let code = match result {
+            Ok(code) => *code,
+            Err(e) => {
+                self.errors.push(format!("{e:#}"));
+                exit::code_for(e)
+            }
+        };
+        // The value of an exit code can’t be read back
+        self.exit_code = (0..=u8::MAX)
+            .find(|&c| ExitCode::from(c) == code)
+            .unwrap_or(1);
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let write = || -> Result<()> {
+            let mut writer = BufWriter::new(File::create(path)?);
+            serde_json::to_writer_pretty(&mut writer, self)?;
+            writeln!(writer)?;
+            writer.flush()?;
+            Ok(())
+        };
+        write().with_context(|| format!("failed to write the report to {}", path.display()))
+    }
+
+    /// Write the report as a single HTML page, for the site
+    pub fn write_html(&self, path: &Path, site: &str) -> Result<()> {
+        std::fs::write(path, html::page(site, self))
+            .with_context(|| format!("failed to write the report to {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::exit::Failure;
+
+    #[test]
+    fn json() -> Result<()> {
+        let mut report = Report::new(false);
+        report.file(&RelPath::from("index.html".to_owned()), Outcome::Changed);
+        report.moved(
+            &RelPath::from("posts/a.html".to_owned()),
+            &RelPath::from("blog/a.html".to_owned()),
+        );
+        let url = Url::parse("https://example.com/index.html")?;
+        report.batch(
+            vec![url.clone()],
+            false,
+            2,
+            report.started_at..report.started_at,
+            &Ok(()),
+        );
+        report.batch(
+            vec![url],
+            false,
+            1,
+            report.started_at..report.started_at,
+            &Err(anyhow!("rate limited")),
+        );
+        report.phase("scan", report.started_at);
+        report.finish(&Err(anyhow!("failed").context(Failure::Cdn)));
+
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("report.json");
+        report.write(&path)?;
+        let json: serde_json::Value = serde_json::from_reader(File::open(&path)?)?;
+        assert_eq!(4, json["exit_code"]);
+        assert_eq!("changed", json["files"][0]["outcome"]);
+        assert!(json["files"][0].get("moved_from").is_none());
+        assert_eq!("blog/a.html", json["files"][1]["moved_from"]);
+        assert_eq!(serde_json::Value::Null, json["batches"][0]["error"]);
+        assert_eq!("rate limited", json["batches"][1]["error"]);
+        assert_eq!(2, json["batches"][0]["files"]);
+        assert_eq!("scan", json["phases"][0]["name"]);
+        assert!(json["timings"]["hashing_sec"].is_f64());
+        assert_eq!("CDN API error: failed", json["errors"][0]);
+        Ok(())
+    }
+
+    #[test]
+    fn summary() {
+        let mut report = Report::new(false);
+        report.file(&RelPath::from("a.html".to_owned()), Outcome::Unchanged);
+        report.file(&RelPath::from("b.html".to_owned()), Outcome::Unchanged);
+        report.file(&RelPath::from("c.html".to_owned()), Outcome::Changed);
+        report.file(&RelPath::from("d.html".to_owned()), Outcome::Deleted);
+        let url = Url::parse("https://example.com/c.html").unwrap();
+        report.batch(
+            vec![url.clone()],
+            false,
+            2,
+            report.started_at..report.started_at,
+            &Ok(()),
+        );
+        report.batch(
+            vec![url],
+            false,
+            3,
+            report.started_at..report.started_at,
+            &Err(anyhow!("rate limited")),
+        );
+        report.errors.push("permission denied".to_owned());
+        report.bytes_hashed = 2048;
+        let hashing = Stopwatch::default();
+        let hashed = hashing.time(|| {
+            std::thread::sleep(Duration::from_millis(2));
+            "hashed"
+        });
+        assert_eq!("hashed", hashed);
+        report.timings.hashing_sec = hashing.secs() + 1.5;
+
+        let summary = report.summary();
+        assert_eq!("Summary", summary[0]);
+        let row = |name: &str| -> String {
+            let row = summary
+                .iter()
+                .find(|row| row.trim_start().starts_with(name))
+                .unwrap();
+            row[2 + 16..].trim().to_owned()
+        };
+        assert_eq!("2", row("Unchanged"));
+        assert_eq!("1", row("Deleted"));
+        assert_eq!("2", row("Purged"));
+        assert_eq!("4", row("Failed"));
+        assert_eq!("2.00 KiB", row("Bytes hashed"));
+        assert_eq!("2", row("API calls"));
+        assert!(row("Hashing").starts_with("1s "));
+        assert_eq!("0s", row("Calling the CDN"));
+        // Aligned, as long as the values fit
+        assert!(summary[1..].iter().all(|row| row.len() == summary[1].len()));
+
+        let mut report = Report::new(true);
+        report.file(&RelPath::from("a.html".to_owned()), Outcome::Unchanged);
+        report.file(&RelPath::from("c.html".to_owned()), Outcome::New);
+        assert!(report
+            .summary()
+            .contains(&format!("  {:<16} {:>10}", "Would purge", 1)));
+
+        report.deferred = true;
+        assert!(report.summary()[0].contains("deferred"));
+    }
+}
@@ -0,0 +1,314 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Report of a run as a single HTML page, with its styles, scripts and charts inlined, so that it
+//! can be opened from the artifacts of a CI job or sent by email
+
+use std::fmt::Write as _;
+
+use indicatif::HumanBytes;
+
+use super::{Outcome, Report};
+use crate::db::RunChanges;
+
+const WIDTH: f64 = 720.;
+const HEIGHT: f64 = 160.;
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 2em auto; max-width: 60em; color: #222; }
+h1 { font-size: 1.5em; }
+h2 { font-size: 1.2em; margin-top: 2em; }
+pre { background: #f4f4f4; padding: 1em; }
+table { border-collapse: collapse; width: 100%; }
+th, td { border-bottom: 1px solid #ddd; padding: .3em .5em; text-align: left; vertical-align: top; }
+th { cursor: pointer; user-select: none; background: #f4f4f4; }
+th[aria-sort=ascending]::after { content: " ▲"; }
+th[aria-sort=descending]::after { content: " ▼"; }
+td.num { text-align: right; font-variant-numeric: tabular-nums; }
+.failed { color: #b00020; }
+svg text { font-size: 11px; fill: #555; }
+.new { fill: #2e7d32; } .changed { fill: #1565c0; } .deleted { fill: #b00020; } .bytes { fill: #6a1b9a; }
+.legend span { display: inline-block; width: .8em; height: .8em; margin: 0 .3em 0 1em; }
+"#;
+
+/// Sorts the tables by the column whose header is clicked, by number when the cells have a
+/// data-sort attribute
+const SCRIPT: &str = r#"
+document.querySelectorAll("table.sortable th").forEach((th, column) => {
+  th.addEventListener("click", () => {
+    const tbody = th.closest("table").tBodies[0];
+    const ascending = th.getAttribute("aria-sort") !== "ascending";
+    th.parentNode.querySelectorAll("th").forEach(other => other.removeAttribute("aria-sort"));
+    th.setAttribute("aria-sort", ascending ? "ascending" : "descending");
+    const key = row => {
+      const cell = row.cells[column];
+      return cell.dataset.sort !== undefined ? parseFloat(cell.dataset.sort) : cell.textContent;
+    };
+    const rows = Array.from(tbody.rows).sort((a, b) => {
+      const [x, y] = [key(a), key(b)];
+      const order = typeof x === "number" ? x - y : x.localeCompare(y);
+      return ascending ? order : -order;
+    });
+    tbody.append(...rows);
+  });
+});
+"#;
+
+/// The whole page
+pub fn page(site: &str, report: &Report) -> String {
+    let pkg = env!("CARGO_PKG_NAME");
+    let mut html = String::new();
+    let title = format!("{pkg} run for {site}, {}", report.started);
+    html.push_str("<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n");
+    let _ = writeln!(html, "<title>{}</title>", escape(&title));
+    let _ = writeln!(html, "<style>{STYLE}</style>\n</head>\n<body>");
+    let _ = writeln!(html, "<h1>{}</h1>", escape(&title));
+    let outcome = match report.exit_code {
+        0 => "Succeeded".to_owned(),
+        code => format!("<span class=\"failed\">Failed with exit code {code}</span>"),
+    };
+    let dry_run = if report.dry_run { ", dry run" } else { "" };
+    let deferred = if report.deferred {
+        ", deferred to the next run"
+    } else {
+        ""
+    };
+    let _ = writeln!(html, "<p>{outcome}{dry_run}{deferred}</p>");
+    let _ = writeln!(html, "<pre>{}</pre>", escape(&report.summary().join("\n")));
+
+    if !report.errors.is_empty() {
+        html.push_str("<h2>Errors</h2>\n<ul>\n");
+        for e in &report.errors {
+            let _ = writeln!(html, "<li class=\"failed\">{}</li>", escape(e));
+        }
+        html.push_str("</ul>\n");
+    }
+
+    if !report.history.is_empty() {
+        html.push_str("<h2>Changes over time</h2>\n");
+        html.push_str(
+            "<p class=\"legend\"><span class=\"new\"></span>New\
+             <span class=\"changed\"></span>Changed<span class=\"deleted\"></span>Deleted</p>\n",
+        );
+        html.push_str(&chart(
+            &report.history,
+            |run| {
+                vec![
+                    ("new", run.added as f64, format!("{} new", run.added)),
+                    (
+                        "changed",
+                        run.changed as f64,
+                        format!("{} changed", run.changed),
+                    ),
+                    (
+                        "deleted",
+                        run.deleted as f64,
+                        format!("{} deleted", run.deleted),
+                    ),
+                ]
+            },
+            |max| format!("{max:.0}"),
+        ));
+        html.push_str("<p class=\"legend\"><span class=\"bytes\"></span>Bytes written</p>\n");
+        html.push_str(&chart(
+            &report.history,
+            |run| vec![("bytes", run.bytes as f64, HumanBytes(run.bytes).to_string())],
+            |max| HumanBytes(max as u64).to_string(),
+        ));
+    }
+
+    let changed: Vec<_> = report
+        .files
+        .iter()
+        .filter(|file| !matches!(file.outcome, Outcome::Unchanged | Outcome::MetadataUpdated))
+        .collect();
+    let _ = writeln!(html, "<h2>Changed files ({})</h2>", changed.len());
+    html.push_str("<table class=\"sortable\">\n<thead><tr><th>Path</th><th>Outcome</th>");
+    html.push_str("<th>Moved from</th></tr></thead>\n<tbody>\n");
+    for file in changed {
+        let outcome = serde_json::to_value(file.outcome).unwrap_or_default();
+        let _ = writeln!(
+            html,
+            "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&file.path),
+            outcome.as_str().unwrap_or_default(),
+            escape(file.moved_from.as_deref().unwrap_or_default())
+        );
+    }
+    html.push_str("</tbody>\n</table>\n");
+
+    let _ = writeln!(html, "<h2>Purge requests ({})</h2>", report.batches.len());
+    html.push_str("<table class=\"sortable\">\n<thead><tr><th>Sent at</th><th>Duration</th>");
+    html.push_str("<th>Files</th><th>URLs</th><th>Result</th></tr></thead>\n<tbody>\n");
+    for batch in &report.batches {
+        let urls: Vec<String> = batch
+            .urls
+            .iter()
+            .map(|url| {
+                let url = escape(url.as_str());
+                if batch.prefixes {
+                    format!("{url}*")
+                } else {
+                    url
+                }
+            })
+            .collect();
+        let result = match &batch.error {
+            None => "Purged".to_owned(),
+            Some(e) => format!("<span class=\"failed\">{}</span>", escape(e)),
+        };
+        let _ = writeln!(
+            html,
+            "<tr><td class=\"num\" data-sort=\"{0}\">{0:.3} s</td>\
+             <td class=\"num\" data-sort=\"{1}\">{1:.3} s</td>\
+             <td class=\"num\" data-sort=\"{2}\">{2}</td><td>{3}</td><td>{4}</td></tr>",
+            batch.offset_sec,
+            batch.duration_sec,
+            batch.files,
+            urls.join("<br>"),
+            result
+        );
+    }
+    html.push_str("</tbody>\n</table>\n");
+    let _ = writeln!(html, "<script>{SCRIPT}</script>\n</body>\n</html>");
+    html
+}
+
+/// Stacked bar chart, in SVG, with a bar for each run, made of the (class, value, label) of each
+/// of the series, and the highest value on top
+fn chart(
+    runs: &[RunChanges],
+    series: impl Fn(&RunChanges) -> Vec<(&'static str, f64, String)>,
+    format_max: impl Fn(f64) -> String,
+) -> String {
+    let bars: Vec<_> = runs.iter().map(|run| (run, series(run))).collect();
+    let max = bars
+        .iter()
+        .map(|(_, stack)| stack.iter().map(|(_, value, _)| value).sum::<f64>())
+        .fold(0., f64::max)
+        .max(1.);
+    let width = WIDTH / bars.len().max(1) as f64;
+    let mut svg = format!(
+        "<svg viewBox=\"0 -12 {WIDTH} {}\" width=\"100%\" role=\"img\">\n",
+        HEIGHT + 30.
+    );
+    for (i, (run, stack)) in bars.iter().enumerate() {
+        let started = humantime::format_rfc3339_seconds(run.started).to_string();
+        let x = i as f64 * width;
+        let mut y = HEIGHT;
+        for (class, value, label) in stack {
+            let height = value / max * HEIGHT;
+            y -= height;
+            let _ = writeln!(
+                svg,
+                "<rect class=\"{class}\" x=\"{:.1}\" y=\"{y:.1}\" width=\"{:.1}\" \
+                 height=\"{height:.1}\"><title>{started}: {}</title></rect>",
+                x + width * 0.1,
+                width * 0.8,
+                escape(label)
+            );
+        }
+    }
+    let _ = writeln!(svg, "<text x=\"0\" y=\"-2\">{}</text>", format_max(max));
+    if let (Some((first, _)), Some((last, _))) = (bars.first(), bars.last()) {
+        let date = |run: &RunChanges| humantime::format_rfc3339_seconds(run.started).to_string();
+        let _ = writeln!(
+            svg,
+            "<text x=\"0\" y=\"{0}\">{1}</text>\
+             <text x=\"{WIDTH}\" y=\"{0}\" text-anchor=\"end\">{2}</text>",
+            HEIGHT + 16.,
+            date(first),
+            date(last)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Text with the characters that have a meaning in HTML escaped
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use anyhow::{anyhow, Result};
+    use url::Url;
+
+    use super::*;
+    use crate::rel_path::RelPath;
+
+    #[test]
+    fn page() -> Result<()> {
+        let mut report = Report::new(false);
+        report.file(&RelPath::from("a.html".to_owned()), Outcome::Unchanged);
+        report.file(&RelPath::from("<b>.html".to_owned()), Outcome::Changed);
+        let url = Url::parse("https://example.com/%3Cb%3E.html")?;
+        let now = Instant::now();
+        report.batch(
+            vec![url],
+            false,
+            1,
+            now..now,
+            &Err(anyhow!("rate <limited>")),
+        );
+        report.history = vec![
+            RunChanges {
+                started: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+                added: 3,
+                changed: 0,
+                deleted: 1,
+                bytes: 2048,
+            },
+            RunChanges {
+                started: UNIX_EPOCH + Duration::from_secs(1_700_086_400),
+                added: 0,
+                changed: 2,
+                deleted: 0,
+                bytes: 100,
+            },
+        ];
+        report.finish(&Ok(std::process::ExitCode::from(4)));
+
+        let html = super::page("example.com", &report);
+        assert!(html.starts_with("<!DOCTYPE html>"));
+        assert!(html.contains("Failed with exit code 4"));
+        assert!(html.contains("<tr><td>&lt;b&gt;.html</td><td>changed</td><td></td></tr>"));
+        assert!(
+            !html.contains("<td>a.html</td>"),
+            "unchanged files aren’t listed"
+        );
+        assert!(html.contains("rate &lt;limited&gt;"));
+        assert!(html.contains("<title>2023-11-14T22:13:20Z: 3 new</title>"));
+        assert!(html.contains("<title>2023-11-15T22:13:20Z: 100 B</title>"));
+        // Nothing is loaded from elsewhere
+        assert!(!html.contains(" src="));
+        assert!(!html.contains(" href="));
+        Ok(())
+    }
+
+    #[test]
+    fn escape() {
+        assert_eq!(
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;&lt;/a&gt;",
+            super::escape("<a href=\"x\">&'</a>")
+        );
+    }
+}
@@ -0,0 +1,61 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Secrets encrypted with age or sops, so that they can be committed with the configuration.
+//! They are decrypted with the `age` and `sops` commands, which handle the keys of the user.
+
+use std::io::Write as _;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use tracing::debug;
+
+/// Key holding the metadata of sops in the files it encrypts
+pub const SOPS_KEY: &str = "sops";
+
+/// Decrypt the ASCII-armored age file with the identity file
+pub fn age_decrypt(armored: &str, identity: &Path) -> Result<String> {
+    let mut command = Command::new("age");
+    command.arg("--decrypt").arg("--identity").arg(identity);
+    output(command, Some(armored)).context("failed to decrypt with age")
+}
+
+/// Decrypt the file encrypted by sops, in the given format (`yaml` or `json`)
+pub fn sops_decrypt(path: &Path, format: &str) -> Result<String> {
+    let mut command = Command::new("sops");
+    command
+        .arg("--decrypt")
+        .args(["--input-type", format, "--output-type", format])
+        .arg(path);
+    output(command, None).with_context(|| format!("failed to decrypt {path:?} with sops"))
+}
+
+/// Standard output of the command, fed with the input. Errors and prompts, like for the
+/// passphrase of a key, go to our standard error.
+fn output(mut command: Command, input: Option<&str>) -> Result<String> {
+    debug!("running {command:?}");
+    let mut child = command
+        .stdin(if input.is_some() {
+            Stdio::piped()
+        } else {
+            Stdio::null()
+        })
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("failed to run {:?}", command.get_program()))?;
+    if let Some(input) = input {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        stdin.write_all(input.as_bytes())?;
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!("{:?} exited with {}", command.get_program(), output.status);
+    }
+    Ok(String::from_utf8(output.stdout)?)
+}
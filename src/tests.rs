@@ -11,3 +11,312 @@ use super::*;
 fn basic_argument_parsing() {
     let _ = Args::parse_from(["binary", "some-folder"]);
 }
+
+#[test]
+fn completions_subcommand() {
+    let args = Args::parse_from(["binary", "completions", "bash"]);
+    assert!(matches!(
+        args.command,
+        Some(Command::Completions { shell: Shell::Bash })
+    ));
+    // The root directories can come from the configuration
+    assert!(Args::try_parse_from(["binary"])
+        .unwrap()
+        .root_dirs
+        .is_empty());
+}
+
+#[test]
+fn global_arguments_before_subcommand() {
+    let args = Args::parse_from(["binary", "-c", "some.toml", "list"]);
+    assert!(matches!(args.command, Some(Command::List { .. })));
+    assert!(args.root_dirs.is_empty());
+}
+
+#[test]
+fn several_root_directories() {
+    let args = Args::parse_from(["binary", "public", "static"]);
+    assert_eq!(
+        vec![PathBuf::from("public"), PathBuf::from("static")],
+        args.root_dirs
+    );
+}
+
+#[test]
+fn since_argument() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let two_hours_ago = parse_since("2h").unwrap();
+    let elapsed = SystemTime::now().duration_since(two_hours_ago).unwrap();
+    assert!(elapsed >= Duration::from_secs(2 * 3600));
+    assert!(elapsed < Duration::from_secs(2 * 3600 + 60));
+
+    let date = UNIX_EPOCH + Duration::from_secs(1_738_281_600);
+    assert_eq!(Ok(date), parse_since("2025-01-31"));
+    assert_eq!(Ok(date), parse_since("2025-01-31T00:00:00Z"));
+    assert_eq!(
+        Ok(date + Duration::from_secs(12 * 3600)),
+        parse_since("2025-01-31 12:00:00")
+    );
+    assert!(parse_since("yesterday-ish").is_err());
+}
+
+#[test]
+fn site_argument() {
+    let args = Args::try_parse_from(["binary", "--site", "blog"]).unwrap();
+    assert_eq!(Some("blog"), args.site.as_deref());
+    let args = Args::try_parse_from(["binary", "--site", "docs", "list"]).unwrap();
+    assert_eq!(Some("docs"), args.site.as_deref());
+}
+
+#[test]
+fn env_argument() {
+    let args = Args::try_parse_from(["binary", "--site", "blog", "--env", "staging"]).unwrap();
+    assert_eq!(Some("staging"), args.profile().env);
+    assert_eq!(Some("blog"), args.profile().site);
+    let args = Args::try_parse_from(["binary", "config", "validate", "--env", "prod"]).unwrap();
+    assert_eq!(Some("prod"), args.env.as_deref());
+}
+
+#[test]
+fn config_validate_subcommand() {
+    let args = Args::try_parse_from(["binary", "config", "validate", "--check-token"]).unwrap();
+    assert!(matches!(
+        args.command,
+        Some(Command::Config {
+            command: ConfigCommand::Validate { check_token: true }
+        })
+    ));
+}
+
+#[test]
+fn config_migrate_subcommand() {
+    let args = Args::try_parse_from(["binary", "config", "migrate", "--yes"]).unwrap();
+    assert!(matches!(
+        args.command,
+        Some(Command::Config {
+            command: ConfigCommand::Migrate { yes: true }
+        })
+    ));
+}
+
+#[test]
+fn db_gc_subcommand() {
+    let args = Args::try_parse_from(["binary", "db", "gc", "--keep", "30days"]).unwrap();
+    assert!(matches!(
+        args.command,
+        Some(Command::Db {
+            command: DbCommand::Gc { keep }
+        }) if keep == Duration::from_secs(30 * 24 * 3600)
+    ));
+}
+
+#[test]
+fn auth_subcommand() {
+    let args = Args::try_parse_from(["binary", "--site", "blog", "auth", "login"]).unwrap();
+    assert!(matches!(
+        args.command,
+        Some(Command::Auth {
+            command: AuthCommand::Login
+        })
+    ));
+}
+
+#[test]
+fn interval_argument() {
+    let args = Args::try_parse_from(["binary", "--interval", "5min", "public"]).unwrap();
+    assert_eq!(Some(Duration::from_secs(300)), args.interval);
+    assert!(Args::try_parse_from(["binary", "--interval", "soon", "public"]).is_err());
+
+    let args = Args::try_parse_from([
+        "binary",
+        "--interval",
+        "5min",
+        "--quiet-period",
+        "10s",
+        "public",
+    ])
+    .unwrap();
+    assert_eq!(Some(Duration::from_secs(10)), args.quiet_period);
+    assert!(Args::try_parse_from(["binary", "--quiet-period", "10s", "public"]).is_err());
+}
+
+#[test]
+fn renamed_directories() {
+    fn renamed<'a>(old: &'a str, new: &'a str) -> Option<(&'a str, &'a str)> {
+        let (old, new) = renamed_dir(old.as_bytes(), new.as_bytes())?;
+        Some((
+            std::str::from_utf8(old).ok()?,
+            std::str::from_utf8(new).ok()?,
+        ))
+    }
+    assert_eq!(
+        Some(("blog", "posts")),
+        renamed("blog/2024/a.html", "posts/2024/a.html")
+    );
+    assert_eq!(
+        Some(("blog/drafts", "blog/2025")),
+        renamed("blog/drafts/a.html", "blog/2025/a.html")
+    );
+    assert_eq!(Some(("a/b", "c")), renamed("a/b/x.html", "c/x.html"));
+    // Renamed files, or moved from or to the root directory
+    assert_eq!(None, renamed("blog/a.html", "blog/b.html"));
+    assert_eq!(None, renamed("blog/a.html", "a.html"));
+    assert_eq!(None, renamed("a.html", "blog/a.html"));
+    assert_eq!(None, renamed("a.html", "a.html"));
+
+    assert!(in_dir(b"blog/a.html", b"blog"));
+    assert!(!in_dir(b"blog-old/a.html", b"blog"));
+    assert!(!in_dir(b"blog", b"blog"));
+}
+
+#[test]
+fn case_insensitive_deletions() -> anyhow::Result<()> {
+    let root = tempfile::tempdir()?;
+    // Both names are on disk, like on a case-insensitive file system
+    for dir in ["Blog", "blog"] {
+        std::fs::create_dir(root.path().join(dir))?;
+    }
+    for path in ["Blog/Post.html", "blog/post.html", "index.html"] {
+        std::fs::write(root.path().join(path), "")?;
+    }
+    let mut conn = db::open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = db::start_run(&tx)?;
+    // Tracked before the file was renamed, or not scanned because excluded
+    for path in ["Blog/Post.html", "index.html"] {
+        db::upsert_entry(
+            &tx,
+            run_id,
+            &RelPath::from(path.to_owned()),
+            &MetadataValues::default(),
+            Checksum::from(1),
+            checksum::SCHEME,
+            "text/html",
+            PurgeState::Confirmed,
+        )?;
+    }
+    tx.commit()?;
+    let known = db::known_files(&conn)?;
+    let root_dirs = [root.path().to_owned()];
+    let scanned = HashSet::from([RelPath::from("blog/post.html".to_owned())]);
+
+    assert!(deleted_files(&known, &root_dirs, &scanned, PathCase::Sensitive).is_empty());
+    assert_eq!(
+        vec![RelPath::from("Blog/Post.html".to_owned())],
+        deleted_files(&known, &root_dirs, &scanned, PathCase::Insensitive)
+    );
+    Ok(())
+}
+
+#[test]
+fn unreliable_modification_times() -> anyhow::Result<()> {
+    let mut conn = db::open_transient()?;
+    let tx = conn.transaction()?;
+    let run_id = db::start_run(&tx)?;
+    let paths = ["a.html", "b.html"].map(|path| RelPath::from(path.to_owned()));
+    for path in &paths {
+        db::upsert_entry(
+            &tx,
+            run_id,
+            path,
+            &MetadataValues::new(12., 10),
+            Checksum::from(1),
+            checksum::SCHEME,
+            "text/html",
+            PurgeState::Confirmed,
+        )?;
+    }
+    tx.commit()?;
+    let known = db::known_files(&conn)?;
+    let update = |path: &RelPath, modified| (path.clone(), MetadataValues::new(modified, 10));
+    let changed: Stored = (
+        paths[1].clone(),
+        MetadataValues::new(20., 11),
+        Checksum::from(2),
+        checksum::SCHEME,
+        "text/html",
+        None,
+    );
+
+    // Reset by the deploy
+    let reset = [update(&paths[0], 20.), update(&paths[1], 20.)];
+    assert_eq!(Some(true), unreliable_mtimes(&known, 0, &reset, &[]));
+    // A file changed, or a file kept its modification time
+    assert_eq!(None, unreliable_mtimes(&known, 0, &reset[..1], &[changed]));
+    assert_eq!(Some(false), unreliable_mtimes(&known, 1, &reset[..1], &[]));
+    let checked = [update(&paths[0], 12.), update(&paths[1], 20.)];
+    assert_eq!(Some(false), unreliable_mtimes(&known, 0, &checked, &[]));
+    // Only new files
+    assert_eq!(None, unreliable_mtimes(&HashMap::new(), 0, &reset, &[]));
+    Ok(())
+}
+
+#[test]
+fn print_changed() -> anyhow::Result<()> {
+    let args = Args::parse_from(["binary", "--print-changed", "public"]);
+    assert_eq!(Some(Changed::Paths), args.print_changed);
+    assert_eq!(vec![PathBuf::from("public")], args.root_dirs);
+    let args = Args::parse_from(["binary", "--print-changed=urls"]);
+    assert_eq!(Some(Changed::Urls), args.print_changed);
+
+    let base_url = Url::parse("https://example.com/")?;
+    let paths = [
+        RelPath::from("posts/index.html".to_owned()),
+        RelPath::from("a b.css".to_owned()),
+    ];
+    let mut out = Vec::new();
+    super::print_changed(&mut out, Changed::Paths, &base_url, paths.iter())?;
+    assert_eq!("a b.css\nposts/index.html\n", String::from_utf8(out)?);
+    let mut out = Vec::new();
+    super::print_changed(&mut out, Changed::Urls, &base_url, paths.iter())?;
+    assert_eq!(
+        "https://example.com/a%20b.css\nhttps://example.com/posts/\n\
+         https://example.com/posts/index.html\n",
+        String::from_utf8(out)?
+    );
+    Ok(())
+}
+
+#[test]
+fn written_after_prefetch() -> anyhow::Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("index.html");
+    let db_path = RelPath::from("index.html".to_owned());
+    std::fs::write(&path, "before")?;
+    let before = path.metadata()?;
+    let prefetched = Checksum::compute(&path, checksum::SCHEME)?;
+    // Written between the read ahead and the check
+    std::fs::write(&path, "after the prefetch")?;
+
+    let hashed = std::cell::RefCell::new(Vec::new());
+    let outcome = check_unchanged(
+        &path,
+        &db_path,
+        before,
+        Some(prefetched),
+        |path| path.metadata(),
+        |metadata, prefetched| {
+            hashed.borrow_mut().push(prefetched.is_some());
+            let checksum = match prefetched {
+                Some(checksum) => *checksum,
+                None => Checksum::compute(&path, checksum::SCHEME)?,
+            };
+            Ok(PathOutcome::StoreAndInvalidate(
+                db_path.clone(),
+                MetadataValues::from(metadata),
+                checksum,
+                checksum::SCHEME,
+                "text/html",
+                None,
+            ))
+        },
+    )?;
+    assert_eq!(vec![true, false], hashed.into_inner());
+    let PathOutcome::StoreAndInvalidate(_, metadata_values, checksum, ..) = outcome else {
+        panic!("the file changed");
+    };
+    assert_eq!(18, metadata_values.size());
+    assert_eq!(Checksum::compute(&path, checksum::SCHEME)?, checksum);
+    Ok(())
+}
@@ -0,0 +1,1028 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::anyhow;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use indicatif::ProgressBar;
+use jwalk::{Parallelism, WalkDirGeneric};
+use tracing::warn;
+
+use crate::db;
+use crate::rel_path::{PathCase, RelPath, RelPathBuilder};
+
+/// Which files to take into account when walking the root directory
+#[derive(Debug, Default, Clone)]
+pub struct Filter {
+    exclude: GlobSet,
+    /// When set, only the files matching it are taken into account
+    include: Option<GlobSet>,
+    /// Maximum depth of the files taken into account, 1 being the files directly in the root
+    /// directory
+    max_depth: Option<usize>,
+    /// Whether to walk through symbolic links, as if they were the files or directories they
+    /// point to
+    follow_symlinks: bool,
+    /// Whether to skip the files ignored by git, when the root directory is in a git repository
+    respect_gitignore: bool,
+    /// Whether to take into account the hidden files and those left by the system or editors
+    hidden: bool,
+    /// Directories not to walk into, as nothing changed in them since the previous run
+    unchanged_dirs: UnchangedDirs,
+    /// Whether files of several root directories at paths differing only by their case are the
+    /// same
+    path_case: PathCase,
+}
+
+fn glob_set(globs: &[Glob]) -> Result<GlobSet, globset::Error> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    builder.build()
+}
+
+impl Filter {
+    /// An empty `include` list means that all the files are included
+    pub fn new(exclude: &[Glob], include: &[Glob]) -> Result<Self, globset::Error> {
+        Ok(Self {
+            exclude: glob_set(exclude)?,
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(glob_set(include)?)
+            },
+            max_depth: None,
+            follow_symlinks: false,
+            respect_gitignore: false,
+            hidden: false,
+            unchanged_dirs: UnchangedDirs::default(),
+            path_case: PathCase::default(),
+        })
+    }
+
+    pub fn with_max_depth(self, max_depth: Option<usize>) -> Self {
+        Self { max_depth, ..self }
+    }
+
+    pub fn with_follow_symlinks(self, follow_symlinks: bool) -> Self {
+        Self {
+            follow_symlinks,
+            ..self
+        }
+    }
+
+    pub fn with_respect_gitignore(self, respect_gitignore: bool) -> Self {
+        Self {
+            respect_gitignore,
+            ..self
+        }
+    }
+
+    pub fn with_hidden(self, hidden: bool) -> Self {
+        Self { hidden, ..self }
+    }
+
+    pub fn with_path_case(self, path_case: PathCase) -> Self {
+        Self { path_case, ..self }
+    }
+
+    pub fn with_unchanged_dirs(self, unchanged_dirs: UnchangedDirs) -> Self {
+        Self {
+            unchanged_dirs,
+            ..self
+        }
+    }
+
+    /// Whether the file or directory is skipped for its name, see [`is_hidden`]
+    fn hides(&self, name: &OsStr) -> bool {
+        !self.hidden && is_hidden(name)
+    }
+
+    /// Rules of the git repository of the root directory, when they are to be respected
+    fn git_ignores(&self, root_dir: &Path) -> Option<GitIgnores> {
+        if !self.respect_gitignore {
+            return None;
+        }
+        let git_ignores = GitIgnores::new(root_dir);
+        if git_ignores.is_none() {
+            warn!("{root_dir:?} is not in a git repository, no .gitignore file applies");
+        }
+        git_ignores
+    }
+
+    /// Whether the path, relative to the root directory, is excluded. Excluding a directory
+    /// excludes everything it contains.
+    fn excludes(&self, rel_path: &Path) -> bool {
+        self.exclude.is_match(rel_path)
+    }
+
+    /// Whether the file, relative to the root directory, is included. Unlike exclusions, this
+    /// applies only to files, so that `*.html` doesn’t prevent from walking into directories.
+    fn includes(&self, rel_path: &Path) -> bool {
+        self.include
+            .as_ref()
+            .is_none_or(|include| include.is_match(rel_path))
+    }
+
+    /// Whether the file, relative to the root directory, would be found by walking the root
+    /// directory with this filter
+    fn lets_through(&self, rel_path: &Path) -> bool {
+        let depth = rel_path.components().count();
+        self.max_depth.is_none_or(|max_depth| depth <= max_depth)
+            && !rel_path
+                .components()
+                .any(|component| self.hides(component.as_os_str()))
+            && !rel_path
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| self.excludes(ancestor))
+            && self.includes(rel_path)
+    }
+}
+
+/// Directories of the root directory unchanged since the previous run, relative to it
+#[derive(Debug, Default, Clone)]
+pub struct UnchangedDirs(HashSet<PathBuf>);
+
+impl UnchangedDirs {
+    /// Recorded directories whose modification time didn’t change, like that of every recorded
+    /// directory under them, and under which as many files as recorded are tracked. Files can’t be
+    /// added, removed or renamed without changing the modification time of their directory, but
+    /// they can be written to in place, which goes unnoticed.
+    pub fn new(root_dir: &Path, recorded: &[db::Dir], tracked: &[RelPath]) -> Self {
+        let counts = file_counts(tracked);
+        let mut unchanged: HashSet<&[u8]> = recorded
+            .iter()
+            .filter(|(dir, modified_since_epoch_sec, file_count)| {
+                let modified = root_dir
+                    .join(dir.to_path())
+                    .metadata()
+                    .and_then(|metadata| metadata.modified());
+                modified.is_ok_and(|modified| {
+                    db::since_epoch_sec(modified) == *modified_since_epoch_sec
+                }) && counts.get(dir.as_bytes()).copied().unwrap_or(0) == *file_count
+            })
+            .map(|(dir, ..)| dir.as_bytes())
+            .collect();
+        for (dir, ..) in recorded {
+            if !unchanged.contains(dir.as_bytes()) {
+                for parent in parents(dir.as_bytes()) {
+                    unchanged.remove(parent);
+                }
+            }
+        }
+        Self(
+            recorded
+                .iter()
+                .filter(|(dir, ..)| unchanged.contains(dir.as_bytes()))
+                .map(|(dir, ..)| dir.to_path())
+                .collect(),
+        )
+    }
+
+    fn contains(&self, rel_path: &Path) -> bool {
+        self.0.contains(rel_path)
+    }
+}
+
+/// Directories above the path, relative to the root directory, the closest first and the root
+/// directory last, as an empty path
+fn parents(path: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let separators = path
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|(_, &b)| b == b'/')
+        .map(|(i, _)| &path[..i]);
+    separators.chain((!path.is_empty()).then_some(&path[..0]))
+}
+
+/// Number of paths under each directory, relative to the root directory
+pub fn file_counts(paths: &[RelPath]) -> HashMap<&[u8], u64> {
+    let mut counts = HashMap::new();
+    for path in paths {
+        for parent in parents(path.as_bytes()) {
+            *counts.entry(parent).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Directories found while walking the root directories
+#[derive(Debug, Default)]
+pub struct FoundDirs {
+    /// Walked into, with their modification time in seconds since the UNIX epoch
+    pub walked: Vec<(RelPath, f64)>,
+    /// Unchanged since the previous run, so not walked into
+    pub skipped: Vec<RelPath>,
+}
+
+impl FoundDirs {
+    /// Whether the path is a skipped directory or under one
+    pub fn skips(&self, path: &RelPath) -> bool {
+        let path = path.as_bytes();
+        self.skipped.iter().any(|dir| {
+            dir.as_bytes() == path || parents(path).any(|parent| parent == dir.as_bytes())
+        })
+    }
+}
+
+/// Hidden directory served on purpose, see RFC 8615
+const WELL_KNOWN: &str = ".well-known";
+
+/// Whether the file or directory is hidden, like `.htaccess`, or left by the system or an editor,
+/// like `Thumbs.db`, `post.html~` or `#post.html#`. Such files are rarely meant to be served.
+fn is_hidden(name: &OsStr) -> bool {
+    let name = name.as_encoded_bytes();
+    (name.starts_with(b".") && name != WELL_KNOWN.as_bytes())
+        || name == b"Thumbs.db"
+        || name == b"desktop.ini"
+        || name.ends_with(b"~")
+        || name.ends_with(b".swp")
+        || (name.starts_with(b"#") && name.ends_with(b"#"))
+}
+
+/// Path reaching files nested deeper than the 260 characters Windows allows by default, with the
+/// `\\?\` prefix. It’s made absolute first, as such paths aren’t normalized.
+#[cfg(windows)]
+pub fn long_path(path: &Path) -> std::io::Result<PathBuf> {
+    use std::path::{Component, Prefix};
+
+    let path = std::path::absolute(path)?;
+    let (Some(Component::Prefix(prefix)), Some(name)) = (path.components().next(), path.to_str())
+    else {
+        return Ok(path);
+    };
+    Ok(match prefix.kind() {
+        Prefix::Disk(_) => PathBuf::from(format!(r"\\?\{name}")),
+        Prefix::UNC(..) => PathBuf::from(format!(r"\\?\UNC\{}", &name[2..])),
+        // Already verbatim, or a device
+        _ => path.clone(),
+    })
+}
+
+#[cfg(not(windows))]
+pub fn long_path(path: &Path) -> std::io::Result<PathBuf> {
+    Ok(path.to_owned())
+}
+
+/// File listing, with the gitignore syntax, the paths of the root directory to skip, so that
+/// per-site exclusions live next to the content
+const IGNORE_FILE: &str = ".cdnignore";
+
+/// Patterns of the ignore file of the root directory, empty if there is none. Invalid patterns
+/// are reported but the others still apply.
+fn ignore_file(root_dir: &Path, errors: &mut Vec<anyhow::Error>) -> Gitignore {
+    let path = root_dir.join(IGNORE_FILE);
+    if !path.is_file() {
+        return Gitignore::empty();
+    }
+    let mut builder = GitignoreBuilder::new(root_dir);
+    if let Some(e) = builder.add(&path) {
+        errors.push(anyhow!("invalid {path:?}: {e}"));
+    }
+    builder.build().unwrap_or_else(|e| {
+        errors.push(anyhow!("invalid {path:?}: {e}"));
+        Gitignore::empty()
+    })
+}
+
+/// Rules of the git repository holding a root directory: those of its `.gitignore` files, loaded
+/// as directories are walked, of `.git/info/exclude` and of the global excludes file of git
+struct GitIgnores {
+    /// Root directory, absolute
+    root_dir: PathBuf,
+    /// Root directory of the repository
+    repo: PathBuf,
+    /// Rules applying to the whole repository, the least specific first
+    base: [Arc<Gitignore>; 2],
+    /// Rules of the `.gitignore` file of each directory, loaded so far
+    dirs: Mutex<HashMap<PathBuf, Arc<Gitignore>>>,
+}
+
+impl GitIgnores {
+    /// Rules of the repository holding the root directory, if it’s in one
+    fn new(root_dir: &Path) -> Option<Self> {
+        let root_dir = root_dir.canonicalize().ok()?;
+        let repo = root_dir
+            .ancestors()
+            .find(|dir| dir.join(".git").exists())?
+            .to_owned();
+        let (global, e) = Gitignore::global();
+        if let Some(e) = e {
+            warn!("invalid global excludes file of git: {e}");
+        }
+        let exclude = git_ignore_file(&repo, &repo.join(".git/info/exclude"));
+        Some(Self {
+            root_dir,
+            repo,
+            base: [Arc::new(global), Arc::new(exclude)],
+            dirs: Mutex::default(),
+        })
+    }
+
+    /// Rules of the `.gitignore` file of the directory. Directories walked at the same time may
+    /// still load it each.
+    fn dir(&self, dir: &Path) -> Arc<Gitignore> {
+        if let Some(gitignore) = self.dirs.lock().expect("not poisoned").get(dir) {
+            return gitignore.clone();
+        }
+        let gitignore = Arc::new(git_ignore_file(dir, &dir.join(".gitignore")));
+        self.dirs
+            .lock()
+            .expect("not poisoned")
+            .entry(dir.to_owned())
+            .or_insert(gitignore)
+            .clone()
+    }
+
+    /// Whether git ignores the path, relative to the root directory, regardless of its parent
+    /// directories
+    fn ignores(&self, rel_path: &Path, is_dir: bool) -> bool {
+        let path = self.root_dir.join(rel_path);
+        if path.file_name() == Some(".git".as_ref()) {
+            return true;
+        }
+        // The rules of the closest `.gitignore` file take precedence
+        let dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.repo))
+            .map(|dir| self.dir(dir));
+        for gitignore in dirs.chain(self.base.iter().rev().cloned()) {
+            match gitignore.matched(&path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => (),
+            }
+        }
+        false
+    }
+
+    /// Whether git ignores the path, relative to the root directory, or one of its parent
+    /// directories
+    fn ignores_with_parents(&self, rel_path: &Path, is_dir: bool) -> bool {
+        rel_path
+            .ancestors()
+            .skip(1)
+            .filter(|ancestor| !ancestor.as_os_str().is_empty())
+            .any(|ancestor| self.ignores(ancestor, true))
+            || self.ignores(rel_path, is_dir)
+    }
+}
+
+/// Rules of the ignore file of git, for the files under the directory. Invalid rules are reported,
+/// but the others still apply.
+fn git_ignore_file(dir: &Path, path: &Path) -> Gitignore {
+    if !path.is_file() {
+        return Gitignore::empty();
+    }
+    let mut builder = GitignoreBuilder::new(dir);
+    if let Some(e) = builder.add(path) {
+        warn!("invalid {path:?}: {e}");
+    }
+    builder.build().unwrap_or_else(|e| {
+        warn!("invalid {path:?}: {e}");
+        Gitignore::empty()
+    })
+}
+
+fn rel_path<'a>(root_dir: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root_dir)
+        .expect("walked paths are in the root directory")
+}
+
+/// Directory above the path, in the root directory, that the path resolves to, making it a
+/// symbolic link loop
+fn loop_ancestor<'a>(root_dir: &Path, path: &'a Path) -> Option<&'a Path> {
+    let target = path.canonicalize().ok()?;
+    path.ancestors()
+        .skip(1)
+        .take_while(|ancestor| ancestor.starts_with(root_dir))
+        .find(|ancestor| {
+            ancestor
+                .canonicalize()
+                .is_ok_and(|ancestor| ancestor == target)
+        })
+}
+
+/// Walk of a root directory, whose entries are marked when they are directories skipped as
+/// unchanged since the previous run
+type Walk = WalkDirGeneric<((), bool)>;
+
+type Entry = jwalk::DirEntry<((), bool)>;
+
+/// What the filter and the ignore files of a root directory let through. It’s applied to the
+/// entries of each directory as soon as it’s read, by the thread that read it, so that excluded
+/// directories are not walked into.
+struct Rules {
+    root_dir: PathBuf,
+    filter: Arc<Filter>,
+    ignore: Gitignore,
+    git_ignores: Option<GitIgnores>,
+}
+
+impl Rules {
+    /// Drop the entries of a directory that are not let through, and mark the unchanged
+    /// directories so that they are not walked into
+    fn retain(&self, entries: &mut Vec<jwalk::Result<Entry>>) {
+        entries.retain_mut(|entry| {
+            let Ok(entry) = entry else {
+                return true;
+            };
+            let path = entry.path();
+            let rel_path = rel_path(&self.root_dir, &path);
+            let is_dir = entry.file_type().is_dir();
+            if is_dir && self.filter.unchanged_dirs.contains(rel_path) {
+                entry.client_state = true;
+                entry.read_children_path = None;
+                return true;
+            }
+            // The root itself is never excluded
+            if entry.depth() == 0 {
+                return true;
+            }
+            if is_dir && entry.path_is_symlink() {
+                if let Some(ancestor) = loop_ancestor(&self.root_dir, &path) {
+                    warn!("skipping symbolic link loop: {path:?} points to {ancestor:?}");
+                    return false;
+                }
+            }
+            !self.filter.hides(entry.file_name())
+                && !self.filter.excludes(rel_path)
+                && !self.ignore.matched(rel_path, is_dir).is_ignore()
+                && !self
+                    .git_ignores
+                    .as_ref()
+                    .is_some_and(|git_ignores| git_ignores.ignores(rel_path, is_dir))
+        });
+    }
+}
+
+/// A file on disk, with its path relative to the root directory it was found in
+pub type FoundFile = (PathBuf, RelPath);
+
+/// Passes on the files found in the root directories. When several root directories have a file
+/// at the same relative path, only the first one is kept, as they would have the same URL.
+struct Found<F> {
+    found: F,
+    path_case: PathCase,
+    /// Keys of the relative paths found, see [`PathCase::key`]
+    rel_paths: HashSet<Vec<u8>>,
+}
+
+impl<F: FnMut(FoundFile)> Found<F> {
+    fn new(path_case: PathCase, found: F) -> Self {
+        Self {
+            found,
+            path_case,
+            rel_paths: HashSet::new(),
+        }
+    }
+
+    fn push(&mut self, path: PathBuf, rel_path: RelPath, progress: &ProgressBar) {
+        if self.rel_paths.insert(self.path_case.key(&rel_path)) {
+            (self.found)((path, rel_path));
+            progress.inc(1);
+        } else {
+            warn!(
+                "skipping {path:?}, {:?} was already found in another root directory",
+                rel_path.get_relative_path()
+            );
+        }
+    }
+}
+
+/// Pass each file in the root directories that the filter and their ignore file let through to
+/// `found`, as soon as it’s found, returning the directories walked and those skipped, as
+/// unchanged since the previous run, and the errors encountered while walking. Directories are
+/// read in parallel, on as many threads as are used to hash files, as a single thread spends most
+/// of its time waiting for network file systems or on very wide trees. Symbolic link loops are
+/// skipped with a warning. The progress is incremented for each file found.
+pub fn files(
+    root_dirs: &[PathBuf],
+    filter: &Arc<Filter>,
+    progress: &ProgressBar,
+    found: impl FnMut(FoundFile),
+) -> (FoundDirs, Vec<anyhow::Error>) {
+    let mut found = Found::new(filter.path_case, found);
+    let mut dirs = FoundDirs::default();
+    let mut errors = Vec::new();
+    for root_dir in root_dirs {
+        let rel_path_builder = RelPathBuilder::new(root_dir);
+        let rules = Rules {
+            root_dir: root_dir.clone(),
+            filter: filter.clone(),
+            ignore: ignore_file(root_dir, &mut errors),
+            git_ignores: filter.git_ignores(root_dir),
+        };
+        let mut walk_dir = Walk::new(root_dir)
+            .follow_links(filter.follow_symlinks)
+            .skip_hidden(false)
+            // Separate from the global pool, busy hashing the files found
+            .parallelism(Parallelism::RayonNewPool(rayon::current_num_threads()))
+            .process_read_dir(move |_, _, _, entries| rules.retain(entries));
+        if let Some(max_depth) = filter.max_depth {
+            walk_dir = walk_dir.max_depth(max_depth);
+        }
+
+        for entry in walk_dir {
+            match entry {
+                Ok(entry) if entry.client_state => {
+                    dirs.skipped.push(rel_path_builder.db_path(&entry.path()));
+                }
+                Ok(mut entry) if entry.file_type().is_dir() => {
+                    if let Some(e) = entry.read_children_error.take() {
+                        errors.push(e.into());
+                    }
+                    let modified = || -> anyhow::Result<_> { Ok(entry.metadata()?.modified()?) };
+                    match modified() {
+                        Ok(modified) => dirs.walked.push((
+                            rel_path_builder.db_path(&entry.path()),
+                            db::since_epoch_sec(modified),
+                        )),
+                        Err(e) => errors.push(e),
+                    }
+                }
+                Ok(entry) => {
+                    let path = entry.path();
+                    if entry.file_type().is_file() && filter.includes(rel_path(root_dir, &path)) {
+                        let rel_path = rel_path_builder.db_path(&path);
+                        found.push(path, rel_path, progress);
+                    }
+                }
+                Err(e) if e.loop_ancestor().is_some() => {
+                    warn!("skipping symbolic link loop: {e}")
+                }
+                Err(e) => errors.push(e.into()),
+            }
+        }
+    }
+    (dirs, errors)
+}
+
+/// Pass the files listed one per line by the reader to `found`, instead of walking the root
+/// directories, returning the errors encountered. Paths are relative to a root directory, or
+/// absolute but in a root directory, and are looked up in all the root directories. The filter
+/// and the ignore files apply as if the root directories were walked. Paths that are not a file
+/// in any root directory are skipped with a warning, as they are typically files that were
+/// removed since the list was generated.
+pub fn listed_files(
+    root_dirs: &[PathBuf],
+    filter: &Filter,
+    reader: impl BufRead,
+    progress: &ProgressBar,
+    found: impl FnMut(FoundFile),
+) -> Vec<anyhow::Error> {
+    let rel_path_builders: Vec<_> = root_dirs.iter().map(RelPathBuilder::new).collect();
+    let mut found = Found::new(filter.path_case, found);
+    let mut errors = Vec::new();
+    let ignores: Vec<_> = root_dirs
+        .iter()
+        .map(|root_dir| {
+            (
+                ignore_file(root_dir, &mut errors),
+                filter.git_ignores(root_dir),
+            )
+        })
+        .collect();
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                errors.push(e.into());
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let (mut in_root_dir, mut let_through, mut is_file) = (false, false, false);
+        for ((root_dir, rel_path_builder), (ignore, git_ignores)) in
+            root_dirs.iter().zip(&rel_path_builders).zip(&ignores)
+        {
+            let path = root_dir.join(&line);
+            let Ok(rel_path) = path.strip_prefix(root_dir) else {
+                continue;
+            };
+            in_root_dir = true;
+            if !filter.lets_through(rel_path)
+                || ignore
+                    .matched_path_or_any_parents(rel_path, path.is_dir())
+                    .is_ignore()
+                || git_ignores.as_ref().is_some_and(|git_ignores| {
+                    git_ignores.ignores_with_parents(rel_path, path.is_dir())
+                })
+            {
+                continue;
+            }
+            let_through = true;
+            if !path.is_file() {
+                continue;
+            }
+            is_file = true;
+            let rel_path = rel_path_builder.db_path(&path);
+            found.push(path, rel_path, progress);
+        }
+
+        if !in_root_dir {
+            errors.push(anyhow!("{line:?} is not in any root directory"));
+        } else if let_through && !is_file {
+            warn!("skipping {line:?}, it’s not a file");
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exclusions() -> Result<(), globset::Error> {
+        let filter = Filter::new(&[Glob::new("*.tmp")?, Glob::new(".git")?], &[])?;
+        assert!(filter.excludes(Path::new("draft.tmp")));
+        assert!(filter.excludes(Path::new("blog/draft.tmp")));
+        assert!(filter.excludes(Path::new(".git")));
+        assert!(!filter.excludes(Path::new("index.html")));
+        assert!(!Filter::default().excludes(Path::new("draft.tmp")));
+        Ok(())
+    }
+
+    #[test]
+    fn inclusions() -> Result<(), globset::Error> {
+        let filter = Filter::new(&[Glob::new("drafts")?], &[Glob::new("*.html")?])?;
+        assert!(filter.includes(Path::new("index.html")));
+        assert!(filter.includes(Path::new("blog/post.html")));
+        assert!(!filter.includes(Path::new("style.css")));
+        assert!(Filter::default().includes(Path::new("style.css")));
+        Ok(())
+    }
+
+    /// All the files in the root directories, as passed on by [`files`]
+    fn all_files(
+        root_dirs: &[PathBuf],
+        filter: &Filter,
+        progress: &ProgressBar,
+    ) -> (Vec<FoundFile>, FoundDirs, Vec<anyhow::Error>) {
+        let mut found = Vec::new();
+        let filter = Arc::new(filter.clone());
+        let (dirs, errors) = super::files(root_dirs, &filter, progress, |file| found.push(file));
+        (found, dirs, errors)
+    }
+
+    /// All the files listed, as passed on by [`listed_files`]
+    fn all_listed_files(
+        root_dirs: &[PathBuf],
+        filter: &Filter,
+        reader: impl BufRead,
+        progress: &ProgressBar,
+    ) -> (Vec<FoundFile>, Vec<anyhow::Error>) {
+        let mut found = Vec::new();
+        let errors =
+            super::listed_files(root_dirs, filter, reader, progress, |file| found.push(file));
+        (found, errors)
+    }
+
+    /// Paths of the files found, for easier comparisons
+    fn paths(found: Vec<FoundFile>) -> Vec<PathBuf> {
+        found.into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn listed_files_filtering() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let other_root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("drafts/deep"))?;
+        for path in [
+            "index.html",
+            "style.css",
+            "drafts/post.html",
+            "drafts/deep/a.html",
+        ] {
+            std::fs::write(root.path().join(path), "")?;
+        }
+        std::fs::write(other_root.path().join("other.html"), "")?;
+        std::fs::write(other_root.path().join("index.html"), "")?;
+        let absolute = root.path().join("index.html");
+        let list = format!(
+            "{}\n\nstyle.css\ndrafts/post.html\ndrafts/deep/a.html\nmissing.html\nother.html\n",
+            absolute.display()
+        );
+        let filter = Filter::new(&[Glob::new("drafts/deep")?], &[Glob::new("*.html")?])?;
+        let root_dirs = [root.path().to_owned(), other_root.path().to_owned()];
+
+        let (files, errors) =
+            all_listed_files(&root_dirs, &filter, list.as_bytes(), &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        assert_eq!(
+            vec![
+                absolute,
+                root.path().join("drafts/post.html"),
+                other_root.path().join("other.html"),
+            ],
+            paths(files)
+        );
+
+        let (files, errors) = all_listed_files(
+            &root_dirs,
+            &Filter::default(),
+            "/elsewhere/index.html".as_bytes(),
+            &ProgressBar::hidden(),
+        );
+        assert!(files.is_empty());
+        assert_eq!(1, errors.len());
+        Ok(())
+    }
+
+    #[test]
+    fn same_path_in_several_roots() -> std::io::Result<()> {
+        let roots = [tempfile::tempdir()?, tempfile::tempdir()?];
+        for root in &roots {
+            std::fs::write(root.path().join("index.html"), "")?;
+        }
+        std::fs::write(roots[1].path().join("style.css"), "")?;
+        std::fs::write(roots[1].path().join("Index.html"), "")?;
+        let root_dirs: Vec<_> = roots.iter().map(|root| root.path().to_owned()).collect();
+
+        let (files, _, errors) = all_files(&root_dirs, &Filter::default(), &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        assert_eq!(3, files.len());
+
+        let filter = Filter::default().with_path_case(PathCase::Insensitive);
+        let (files, ..) = all_files(&root_dirs, &filter, &ProgressBar::hidden());
+        assert_eq!(
+            vec![
+                roots[0].path().join("index.html"),
+                roots[1].path().join("style.css")
+            ],
+            paths(files)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn ignore_file_in_root() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("drafts"))?;
+        for path in ["index.html", "notes.md", "drafts/post.html", "keep.md"] {
+            std::fs::write(root.path().join(path), "")?;
+        }
+        std::fs::write(root.path().join(IGNORE_FILE), "*.md\n!keep.md\ndrafts/\n")?;
+        let root_dirs = [root.path().to_owned()];
+
+        let (files, _, errors) = all_files(&root_dirs, &Filter::default(), &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        let mut found = paths(files);
+        found.sort();
+        assert_eq!(
+            vec![root.path().join("index.html"), root.path().join("keep.md")],
+            found
+        );
+
+        let list = "notes.md\ndrafts/post.html\nkeep.md\n";
+        let (files, errors) = all_listed_files(
+            &root_dirs,
+            &Filter::default(),
+            list.as_bytes(),
+            &ProgressBar::hidden(),
+        );
+        assert!(errors.is_empty());
+        assert_eq!(vec![root.path().join("keep.md")], paths(files));
+        Ok(())
+    }
+
+    #[test]
+    fn hidden_files() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join(".well-known"))?;
+        std::fs::create_dir_all(root.path().join(".cache"))?;
+        for path in [
+            "index.html",
+            "index.html~",
+            "#index.html#",
+            ".index.html.swp",
+            ".DS_Store",
+            "Thumbs.db",
+            ".htaccess",
+            ".cache/page.html",
+            ".well-known/security.txt",
+        ] {
+            std::fs::write(root.path().join(path), "")?;
+        }
+        let root_dirs = [root.path().to_owned()];
+
+        let (files, _, errors) = all_files(&root_dirs, &Filter::default(), &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        let mut found = paths(files);
+        found.sort();
+        assert_eq!(
+            vec![
+                root.path().join(".well-known/security.txt"),
+                root.path().join("index.html"),
+            ],
+            found
+        );
+        let list = ".htaccess\n.cache/page.html\nindex.html\n";
+        let (files, _) = all_listed_files(
+            &root_dirs,
+            &Filter::default(),
+            list.as_bytes(),
+            &ProgressBar::hidden(),
+        );
+        assert_eq!(vec![root.path().join("index.html")], paths(files));
+
+        let hidden = Filter::default().with_hidden(true);
+        let (files, ..) = all_files(&root_dirs, &hidden, &ProgressBar::hidden());
+        assert_eq!(9, files.len());
+        let (files, _) =
+            all_listed_files(&root_dirs, &hidden, list.as_bytes(), &ProgressBar::hidden());
+        assert_eq!(3, files.len());
+        Ok(())
+    }
+
+    #[test]
+    fn gitignore() -> anyhow::Result<()> {
+        let repo = tempfile::tempdir()?;
+        let root = repo.path().join("site");
+        std::fs::create_dir_all(repo.path().join(".git/info"))?;
+        std::fs::create_dir_all(root.join("build"))?;
+        std::fs::create_dir_all(root.join("sub"))?;
+        for path in [
+            "index.html",
+            "debug.log",
+            "draft.html",
+            "build/out.html",
+            "sub/keep.log",
+            "sub/tmp.html",
+        ] {
+            std::fs::write(root.join(path), "")?;
+        }
+        std::fs::write(repo.path().join(".gitignore"), "*.log\nsite/build/\n")?;
+        std::fs::write(repo.path().join(".git/info/exclude"), "draft.html\n")?;
+        std::fs::write(root.join("sub/.gitignore"), "!keep.log\ntmp.html\n")?;
+        let root_dirs = [root.clone()];
+        let filter = Filter::default().with_respect_gitignore(true);
+
+        let (files, _, errors) = all_files(&root_dirs, &filter, &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        let mut found = paths(files);
+        found.sort();
+        assert_eq!(
+            vec![root.join("index.html"), root.join("sub/keep.log")],
+            found
+        );
+
+        let list = "debug.log\nbuild/out.html\nsub/keep.log\nsub/tmp.html\n";
+        let (files, errors) =
+            all_listed_files(&root_dirs, &filter, list.as_bytes(), &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        assert_eq!(vec![root.join("sub/keep.log")], paths(files));
+
+        // Without the option, or outside of a repository
+        let (all, ..) = all_files(&root_dirs, &Filter::default(), &ProgressBar::hidden());
+        assert_eq!(6, all.len());
+        std::fs::remove_dir_all(repo.path().join(".git"))?;
+        let (all, ..) = all_files(&root_dirs, &filter, &ProgressBar::hidden());
+        assert_eq!(6, all.len());
+        Ok(())
+    }
+
+    #[test]
+    fn unchanged_dirs_are_skipped() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir_all(root.path().join("blog/2024"))?;
+        std::fs::create_dir_all(root.path().join("docs"))?;
+        for path in ["index.html", "blog/2024/post.html", "docs/guide.html"] {
+            std::fs::write(root.path().join(path), "")?;
+        }
+        let root_dirs = [root.path().to_owned()];
+        let (files, dirs, errors) =
+            all_files(&root_dirs, &Filter::default(), &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        assert!(dirs.skipped.is_empty());
+        let tracked: Vec<RelPath> = files.into_iter().map(|(_, rel_path)| rel_path).collect();
+        let counts = file_counts(&tracked);
+        let recorded: Vec<db::Dir> = dirs
+            .walked
+            .into_iter()
+            .map(|(dir, modified)| {
+                let file_count = counts[dir.as_bytes()];
+                (dir, modified, file_count)
+            })
+            .collect();
+        assert_eq!(4, recorded.len());
+
+        // Nothing changed, not even the root directory
+        let unchanged = UnchangedDirs::new(root.path(), &recorded, &tracked);
+        let filter = Filter::default().with_unchanged_dirs(unchanged);
+        let (files, dirs, _) = all_files(&root_dirs, &filter, &ProgressBar::hidden());
+        assert!(files.is_empty());
+        assert!(dirs.walked.is_empty());
+        assert!(tracked.iter().all(|path| dirs.skips(path)));
+
+        std::fs::write(root.path().join("docs/api.html"), "")?;
+        let unchanged = UnchangedDirs::new(root.path(), &recorded, &tracked);
+        let filter = Filter::default().with_unchanged_dirs(unchanged);
+        let (files, dirs, _) = all_files(&root_dirs, &filter, &ProgressBar::hidden());
+        let mut found = paths(files);
+        found.sort();
+        assert_eq!(
+            vec![
+                root.path().join("docs/api.html"),
+                root.path().join("docs/guide.html"),
+                root.path().join("index.html"),
+            ],
+            found
+        );
+        // Only the blog is left unchanged
+        assert_eq!(vec![RelPath::from("blog".to_owned())], dirs.skipped);
+        assert!(dirs.skips(&RelPath::from("blog/2024/post.html".to_owned())));
+        assert!(!dirs.skips(&RelPath::from("docs/guide.html".to_owned())));
+        Ok(())
+    }
+
+    #[test]
+    fn wide_and_deep_trees() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        for i in 0..20 {
+            let dir = root.path().join(format!("{i}/a/b"));
+            std::fs::create_dir_all(&dir)?;
+            for name in ["index.html", "style.css"] {
+                std::fs::write(dir.join(name), "")?;
+            }
+        }
+        let root_dirs = [root.path().to_owned()];
+        let filter = Filter::new(&[Glob::new("7")?, Glob::new("*.css")?], &[])?;
+        let (files, dirs, errors) = all_files(&root_dirs, &filter, &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        assert_eq!(19, files.len());
+        assert!(files
+            .iter()
+            .all(|(path, _)| path.ends_with("a/b/index.html")
+                && !path.starts_with(root.path().join("7"))));
+        // The root directory and three directories for each of the other 19
+        assert_eq!(1 + 19 * 3, dirs.walked.len());
+
+        let filter = Filter::default().with_max_depth(Some(2));
+        let (files, dirs, _) = all_files(&root_dirs, &filter, &ProgressBar::hidden());
+        assert!(files.is_empty());
+        assert_eq!(1 + 20 * 2, dirs.walked.len());
+        Ok(())
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn long_paths() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let long_root = long_path(root.path())?;
+        assert!(long_root.to_string_lossy().starts_with(r"\\?\"));
+        let nested = "directory/".repeat(30);
+        std::fs::create_dir_all(long_root.join(RelPath::from(nested.clone()).to_path()))?;
+        let rel_path = RelPath::from(format!("{nested}index.html"));
+        std::fs::write(long_root.join(rel_path.to_path()), "")?;
+
+        let (files, _, errors) =
+            all_files(&[long_root], &Filter::default(), &ProgressBar::hidden());
+        assert!(errors.is_empty());
+        assert_eq!(
+            vec![rel_path],
+            files
+                .into_iter()
+                .map(|(_, rel_path)| rel_path)
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_loops_are_skipped() -> std::io::Result<()> {
+        let root = tempfile::tempdir()?;
+        std::fs::create_dir(root.path().join("dir"))?;
+        std::fs::write(root.path().join("dir/index.html"), "")?;
+        std::os::unix::fs::symlink("..", root.path().join("dir/loop"))?;
+
+        let (files, _, errors) = all_files(
+            &[root.path().to_owned()],
+            &Filter::default().with_follow_symlinks(true),
+            &ProgressBar::hidden(),
+        );
+        assert_eq!(1, files.len());
+        assert!(errors.is_empty());
+        Ok(())
+    }
+}
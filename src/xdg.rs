@@ -0,0 +1,33 @@
+/* Copyright © 2025 Clément Joly
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+//! Default locations of the files, following the XDG base directory specification. See
+//! https://specifications.freedesktop.org/basedir-spec/latest/
+
+use std::env;
+use std::path::PathBuf;
+
+/// Directory of the program under the base directory set by `var`, or under `fallback` in the
+/// home directory. `None` when neither is set, as then there is no sensible location.
+fn dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        // Relative paths are invalid and should be ignored, as per the specification
+        .filter(|base| base.is_absolute())
+        .or_else(|| env::home_dir().map(|home| home.join(fallback)))
+        .map(|base| base.join(env!("CARGO_PKG_NAME")))
+}
+
+/// Where the configuration is stored, like `~/.config/static-cdn`
+pub fn config_dir() -> Option<PathBuf> {
+    dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Where the state, like the database, is stored, like `~/.local/state/static-cdn`
+pub fn state_dir() -> Option<PathBuf> {
+    dir("XDG_STATE_HOME", ".local/state")
+}